@@ -0,0 +1,280 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::os::raw::c_void;
+use std::ptr;
+use zeromq_messages::kind::ZeromqMessageKind;
+use zmq::Socket;
+
+const XPUB_SUBSCRIBE_EVENT: u8 = 1;
+const XPUB_UNSUBSCRIBE_EVENT: u8 = 0;
+
+//-----------------------------------------------------------------------------------------
+// AclRule
+//-----------------------------------------------------------------------------------------
+
+/// Kinds which one authenticated client may publish and subscribe to.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AclRule {
+    /// Public CURVE key of the client in Z85 form, ZAP handler gives it to the client as
+    /// its user id.
+    pub identity: String,
+    pub publish_kinds: Vec<u32>,
+    pub subscribe_kinds: Vec<u32>,
+}
+
+//-----------------------------------------------------------------------------------------
+// AccessControl
+//-----------------------------------------------------------------------------------------
+
+#[derive(Debug, Default)]
+struct Permissions {
+    publish_kinds: HashSet<ZeromqMessageKind>,
+    subscribe_kinds: HashSet<ZeromqMessageKind>,
+}
+
+/// Permissions of authenticated clients, clients without rule are denied everything.
+#[derive(Debug, Default)]
+pub struct AccessControl {
+    permissions: HashMap<String, Permissions>,
+}
+
+impl AccessControl {
+    /// Unknown kinds of rules are skipped, configuration is validated so there are none.
+    #[must_use]
+    pub fn new(rules: &[AclRule]) -> Self {
+        let known_kinds = |kinds: &[u32]| {
+            kinds
+                .iter()
+                .filter_map(|kind| ZeromqMessageKind::try_from(*kind).ok())
+                .collect()
+        };
+
+        Self {
+            permissions: rules
+                .iter()
+                .map(|rule| {
+                    (
+                        rule.identity.clone(),
+                        Permissions {
+                            publish_kinds: known_kinds(rule.publish_kinds.as_slice()),
+                            subscribe_kinds: known_kinds(rule.subscribe_kinds.as_slice()),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    #[must_use]
+    pub fn may_publish(&self, user_id: Option<&str>, kind: ZeromqMessageKind) -> bool {
+        self.permissions(user_id)
+            .is_some_and(|permissions| permissions.publish_kinds.contains(&kind))
+    }
+
+    #[must_use]
+    pub fn may_subscribe(&self, user_id: Option<&str>, kind: ZeromqMessageKind) -> bool {
+        self.permissions(user_id)
+            .is_some_and(|permissions| permissions.subscribe_kinds.contains(&kind))
+    }
+
+    /// Subscription prefix gives every message whose bytes start with it, so it is allowed
+    /// only when all kinds it matches are allowed. Empty prefix matches all kinds.
+    #[must_use]
+    pub fn may_subscribe_prefix(&self, user_id: Option<&str>, prefix: &[u8]) -> bool {
+        ZeromqMessageKind::ALL
+            .iter()
+            .filter(|kind| kind_matches_prefix(**kind, prefix))
+            .all(|kind| self.may_subscribe(user_id, *kind))
+    }
+
+    /// Applies subscription event which publisher with manual subscriptions has received
+    /// from the client, so subscriber never gets messages of prefixes it may not subscribe
+    /// to. Denied subscriptions were never applied, so are their unsubscriptions. Returns
+    /// whether the event has been applied.
+    pub fn apply_subscription_event(
+        &self,
+        publisher: &Socket,
+        user_id: Option<&str>,
+        event: &[u8],
+    ) -> zmq::Result<bool> {
+        match event.split_first() {
+            Some((&XPUB_SUBSCRIBE_EVENT, prefix))
+                if self.may_subscribe_prefix(user_id, prefix) =>
+            {
+                publisher.set_subscribe(prefix)?;
+            }
+            Some((&XPUB_UNSUBSCRIBE_EVENT, prefix))
+                if self.may_subscribe_prefix(user_id, prefix) =>
+            {
+                publisher.set_unsubscribe(prefix)?;
+            }
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    fn permissions(&self, user_id: Option<&str>) -> Option<&Permissions> {
+        user_id.and_then(|user_id| self.permissions.get(user_id))
+    }
+}
+
+/// Messages start with big-endian kind, prefix longer than it is compared with the kind
+/// bytes only, the rest of it narrows the subscription down further.
+fn kind_matches_prefix(kind: ZeromqMessageKind, prefix: &[u8]) -> bool {
+    let kind_bytes = (kind as u32).to_be_bytes();
+    let compared_length = prefix.len().min(kind_bytes.len());
+    kind_bytes[..compared_length] == prefix[..compared_length]
+}
+
+/// Makes publisher socket hand subscriptions over to BUS instead of applying them, so BUS
+/// applies only the allowed ones through `set_subscribe`. The `zmq` crate has no setter
+/// for this option, so it is set through `zmq-sys` which is pinned to the version `zmq`
+/// itself is built on.
+#[allow(unsafe_code)]
+pub(crate) fn set_xpub_manual(socket: &mut Socket) -> zmq::Result<()> {
+    let value: i32 = 1;
+    // SAFETY: socket pointer is valid for the lifetime of the socket and option value is
+    // an int which libzmq copies before returning.
+    let result = unsafe {
+        zmq_sys::zmq_setsockopt(
+            socket.as_mut_ptr(),
+            zmq_sys::ZMQ_XPUB_MANUAL.cast_signed(),
+            ptr::addr_of!(value).cast::<c_void>(),
+            size_of::<i32>(),
+        )
+    };
+
+    if result == -1 {
+        // SAFETY: errno of the calling thread is read right after the failed call, it
+        // takes no arguments and only reads thread-local state of libzmq.
+        return Err(zmq::Error::from_raw(unsafe { zmq_sys::zmq_errno() }));
+    }
+
+    Ok(())
+}
+
+//-----------------------------------------------------------------------------------------
+// Tests
+//-----------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::acl::set_xpub_manual;
+    use crate::acl::AccessControl;
+    use crate::acl::AclRule;
+    use zeromq_messages::kind::ZeromqMessageKind;
+    use zmq::Context;
+    use zmq::PollEvents;
+    use zmq::SocketType;
+
+    const IDENTITY: &str = "Yne@$w-vo<fVvi]a<NY6T1ed:M$fCG*[IaLV{hID";
+
+    #[test]
+    fn allows_listed_kinds_of_listed_identities_only() {
+        let access_control = AccessControl::new(&[AclRule {
+            identity: IDENTITY.to_string(),
+            publish_kinds: vec![ZeromqMessageKind::ValueMultiplicationRequest as u32],
+            subscribe_kinds: vec![ZeromqMessageKind::ValueMultiplicationResponse as u32],
+        }]);
+
+        assert!(access_control.may_publish(
+            Some(IDENTITY),
+            ZeromqMessageKind::ValueMultiplicationRequest
+        ));
+        assert!(!access_control.may_publish(
+            Some(IDENTITY),
+            ZeromqMessageKind::ValueMultiplicationResponse
+        ));
+        assert!(!access_control.may_publish(
+            Some("stranger"),
+            ZeromqMessageKind::ValueMultiplicationRequest
+        ));
+        assert!(
+            !access_control.may_publish(None, ZeromqMessageKind::ValueMultiplicationRequest)
+        );
+
+        let response_kind_bytes =
+            (ZeromqMessageKind::ValueMultiplicationResponse as u32).to_be_bytes();
+        assert!(access_control.may_subscribe_prefix(Some(IDENTITY), &response_kind_bytes));
+        assert!(access_control.may_subscribe_prefix(
+            Some(IDENTITY),
+            &[&response_kind_bytes[..], b"uuid"].concat()
+        ));
+        assert!(!access_control.may_subscribe_prefix(Some(IDENTITY), b""));
+        assert!(!access_control.may_subscribe_prefix(Some(IDENTITY), &[0]));
+        assert!(!access_control.may_subscribe_prefix(
+            Some(IDENTITY),
+            &(ZeromqMessageKind::ValueMultiplicationRequest as u32).to_be_bytes()
+        ));
+    }
+
+    #[test]
+    fn manual_publisher_delivers_applied_subscriptions_only() {
+        let context = Context::new();
+        let mut publisher = context.socket(SocketType::XPUB).unwrap();
+        set_xpub_manual(&mut publisher).unwrap();
+        publisher.bind("inproc://acl-xpub-manual-test").unwrap();
+        let subscriber = context.socket(SocketType::SUB).unwrap();
+        subscriber.connect("inproc://acl-xpub-manual-test").unwrap();
+        subscriber.set_subscribe(b"denied").unwrap();
+
+        assert_eq!(b"\x01denied".to_vec(), publisher.recv_bytes(0).unwrap());
+        publisher.send("denied message", 0).unwrap();
+
+        subscriber.set_subscribe(b"allowed").unwrap();
+        assert_eq!(b"\x01allowed".to_vec(), publisher.recv_bytes(0).unwrap());
+        publisher.set_subscribe(b"allowed").unwrap();
+        publisher.send("allowed message", 0).unwrap();
+
+        assert_eq!(
+            b"allowed message".to_vec(),
+            subscriber.recv_bytes(0).unwrap()
+        );
+    }
+
+    #[test]
+    fn denied_prefix_subscription_is_not_forwarded() {
+        let access_control = AccessControl::new(&[AclRule {
+            identity: IDENTITY.to_string(),
+            publish_kinds: vec![],
+            subscribe_kinds: vec![ZeromqMessageKind::ValueMultiplicationResponse as u32],
+        }]);
+        let request_kind_bytes =
+            (ZeromqMessageKind::ValueMultiplicationRequest as u32).to_be_bytes();
+        let response_kind_bytes =
+            (ZeromqMessageKind::ValueMultiplicationResponse as u32).to_be_bytes();
+
+        let context = Context::new();
+        let mut publisher = context.socket(SocketType::XPUB).unwrap();
+        set_xpub_manual(&mut publisher).unwrap();
+        publisher.bind("inproc://acl-denied-prefix-test").unwrap();
+        let subscriber = context.socket(SocketType::SUB).unwrap();
+        subscriber
+            .connect("inproc://acl-denied-prefix-test")
+            .unwrap();
+
+        for prefix in &[&request_kind_bytes[..], &response_kind_bytes[..], b""] {
+            subscriber.set_subscribe(prefix).unwrap();
+            let event = publisher.recv_bytes(0).unwrap();
+            assert_eq!(
+                *prefix == &response_kind_bytes[..],
+                access_control
+                    .apply_subscription_event(&publisher, Some(IDENTITY), &event)
+                    .unwrap()
+            );
+        }
+
+        let request = [&request_kind_bytes[..], b"request"].concat();
+        let response = [&response_kind_bytes[..], b"response"].concat();
+        publisher.send(&request, 0).unwrap();
+        publisher.send(&response, 0).unwrap();
+
+        assert_eq!(response, subscriber.recv_bytes(0).unwrap());
+        assert_eq!(0, subscriber.poll(PollEvents::POLLIN, 100).unwrap());
+    }
+}
@@ -0,0 +1,736 @@
+// Rust flags
+#![warn(nonstandard_style)]
+#![warn(future_incompatible)]
+#![warn(rust_2018_compatibility)]
+#![warn(rust_2018_idioms)]
+#![warn(unused)]
+#![warn(missing_debug_implementations)]
+#![warn(missing_copy_implementations)]
+#![warn(trivial_casts)]
+#![warn(trivial_numeric_casts)]
+#![warn(unsafe_code)]
+#![warn(unused_extern_crates)]
+#![warn(unused_import_braces)]
+#![warn(unused_qualifications)]
+#![warn(unused_results)]
+#![warn(variant_size_differences)]
+#![recursion_limit = "1024"]
+// Clippy flags
+#![warn(clippy::all)]
+#![warn(clippy::pedantic)]
+#![allow(clippy::module_name_repetitions)]
+#![allow(clippy::missing_panics_doc)]
+#![allow(clippy::missing_errors_doc)]
+#![allow(clippy::uninlined_format_args)]
+
+use rust_impl::secure_client_socket;
+use rust_impl::BusClient;
+use rust_impl::BusClientEvent;
+use rust_impl::CaptureReader;
+use rust_impl::CaptureSource;
+use rust_impl::Config;
+use rust_impl::ConfigOptions;
+use rust_impl::PublishConfirmationOutcome;
+use rust_impl::ShutdownSignal;
+use rust_impl::SHUTDOWN_CHECK_INTERVAL_MILLISECONDS;
+use serde_json::json;
+use serde_json::Value;
+use std::convert::TryFrom;
+use std::fmt::Write;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use structopt::StructOpt;
+use uuid::Uuid;
+use zeromq_messages::codec::decode_message_header;
+use zeromq_messages::codec::decode_message_kind_and_uuid;
+use zeromq_messages::codec::encode_message;
+use zeromq_messages::codec::encode_message_json;
+use zeromq_messages::codec::message_payload_bytes;
+use zeromq_messages::header::MessageHeader;
+use zeromq_messages::kind::ZeromqMessageKind;
+use zeromq_messages::messages::ControlCommand;
+use zeromq_messages::messages::ServiceQuery;
+use zmq::Context;
+use zmq::Socket;
+use zmq::SocketType;
+
+/// Time given to subscriptions to reach BUS before the request is published, messages
+/// published earlier are not delivered to the subscriber.
+const SUBSCRIPTION_SETTLE_TIME: Duration = Duration::from_millis(200);
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "busctl", about = "Publishes, tails and inspects BUS messages")]
+struct BusctlOptions {
+    #[structopt(flatten)]
+    config_options: ConfigOptions,
+
+    /// Time to wait for BUS answers
+    #[structopt(long, default_value = "5000")]
+    timeout_ms: u64,
+
+    #[structopt(subcommand)]
+    command: BusctlCommand,
+}
+
+#[derive(Debug, StructOpt)]
+enum BusctlCommand {
+    /// Publishes message and waits until BUS accepts it, prints its uuid
+    Publish {
+        /// Kind name like `ValueMultiplicationRequest`, schema title or number
+        kind: ZeromqMessageKind,
+        /// JSON payload, read from standard input when absent
+        payload: Option<String>,
+        #[structopt(long)]
+        uuid: Option<Uuid>,
+    },
+    /// Prints published messages as JSON until interrupted
+    Tail {
+        /// Prints only messages of the given kinds, can be repeated
+        #[structopt(long = "kind")]
+        kinds: Vec<ZeromqMessageKind>,
+        /// Prints only messages with the given uuid
+        #[structopt(long)]
+        uuid: Option<Uuid>,
+        /// Exits after printing the given count of messages
+        #[structopt(long)]
+        count: Option<usize>,
+    },
+    /// Publishes message and prints the response which carries the same uuid
+    Request {
+        kind: ZeromqMessageKind,
+        /// JSON payload, read from standard input when absent
+        payload: Option<String>,
+        /// Kind of the expected response, any other kind is accepted when absent
+        #[structopt(long)]
+        response_kind: Option<ZeromqMessageKind>,
+    },
+    /// Sends command to BUS control endpoint and prints the reply
+    Control(ControlAction),
+    /// Prints registered services, exits with error when a required kind has no provider
+    Services {
+        /// Prints only services which handle the kind
+        #[structopt(long)]
+        kind: Option<ZeromqMessageKind>,
+        /// Prints only services with the name
+        #[structopt(long)]
+        name: Option<String>,
+        /// Kind which should be handled by at least one service, can be repeated
+        #[structopt(long = "require-kind")]
+        required_kinds: Vec<ZeromqMessageKind>,
+    },
+    /// Publishes messages which clients have sent to BUS while it was capturing traffic
+    Replay {
+        #[structopt(parse(from_os_str))]
+        capture_file: PathBuf,
+        /// Multiplier of the original pace, 0 publishes messages without any delay
+        #[structopt(long, default_value = "1.0")]
+        speed: f64,
+        /// Replays only messages of the given kinds, can be repeated
+        #[structopt(long = "kind")]
+        kinds: Vec<ZeromqMessageKind>,
+        /// Prints messages which would be replayed instead of publishing them
+        #[structopt(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum ControlAction {
+    Stats,
+    Clients,
+    Subscriptions,
+    /// Holds messages of the kind inside BUS until it is resumed
+    Pause {
+        kind: ZeromqMessageKind,
+    },
+    Resume {
+        kind: ZeromqMessageKind,
+    },
+    PurgeRetryBuffer,
+    DumpDeadLetters,
+    /// One of: off, error, warn, info, debug, trace
+    SetLogLevel {
+        log_level: String,
+    },
+}
+
+impl ControlAction {
+    fn command(self) -> ControlCommand {
+        let (action, kind, log_level) = match self {
+            Self::Stats => ("stats", None, None),
+            Self::Clients => ("clients", None, None),
+            Self::Subscriptions => ("subscriptions", None, None),
+            Self::Pause { kind } => ("pause-kind", Some(kind), None),
+            Self::Resume { kind } => ("resume-kind", Some(kind), None),
+            Self::PurgeRetryBuffer => ("purge-retry-buffer", None, None),
+            Self::DumpDeadLetters => ("dump-dead-letters", None, None),
+            Self::SetLogLevel { log_level } => ("set-log-level", None, Some(log_level)),
+        };
+
+        ControlCommand {
+            action: action.to_string(),
+            kind: kind.map(|kind| i64::from(kind as u32)),
+            log_level,
+        }
+    }
+}
+
+fn main() {
+    let options = BusctlOptions::from_args();
+
+    // Decoded messages go to the standard output, so only problems are logged by default.
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
+    let config = Config::load(&options.config_options).unwrap_or_else(|error| {
+        eprintln!("invalid configuration: {}", error);
+        process::exit(1);
+    });
+
+    if options.config_options.print_config {
+        print!("{}", config.to_toml());
+        return;
+    }
+
+    let timeout = Duration::from_millis(options.timeout_ms);
+    let context = Context::new();
+
+    let result = match options.command {
+        BusctlCommand::Publish {
+            kind,
+            payload,
+            uuid,
+        } => publish(&context, &config, timeout, kind, payload, uuid).map(|uuid| {
+            println!("{}", uuid);
+        }),
+        BusctlCommand::Tail { kinds, uuid, count } => {
+            tail(&context, &config, kinds.as_slice(), uuid, count)
+        }
+        BusctlCommand::Request {
+            kind,
+            payload,
+            response_kind,
+        } => request(&context, &config, timeout, kind, payload, response_kind),
+        BusctlCommand::Control(action) => control(&context, &config, timeout, action),
+        BusctlCommand::Services {
+            kind,
+            name,
+            required_kinds,
+        } => services(
+            &context,
+            &config,
+            timeout,
+            ServiceQuery {
+                kind: kind.map(|kind| i64::from(kind as u32)),
+                name,
+            },
+            required_kinds.as_slice(),
+        ),
+        BusctlCommand::Replay {
+            capture_file,
+            speed,
+            kinds,
+            dry_run,
+        } => {
+            let replay_filter = ReplayFilter {
+                kinds,
+                dry_run,
+                speed,
+            };
+            replay(&context, &config, timeout, &capture_file, &replay_filter)
+        }
+    };
+
+    if let Err(error) = result {
+        eprintln!("busctl: {}", error);
+        process::exit(1);
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// Commands
+//-----------------------------------------------------------------------------------------
+
+fn publish(
+    context: &Context,
+    config: &Config,
+    timeout: Duration,
+    kind: ZeromqMessageKind,
+    payload: Option<String>,
+    uuid: Option<Uuid>,
+) -> Result<Uuid, String> {
+    let uuid = uuid.unwrap_or_else(Uuid::new_v4);
+    let message_bytes =
+        encode_message_json(kind, uuid, &MessageHeader::now(), read_payload(payload)?)
+            .map_err(|error| {
+                format!("payload does not match {:?} schema: {:?}", kind, error)
+            })?;
+
+    let mut bus_client = BusClient::connect_endpoints(context, &config.endpoints)
+        .map_err(|error| format!("failed to connect to BUS router socket: {}", error))?;
+    bus_client
+        .socket()
+        .set_linger(0)
+        .map_err(|error| format!("failed to set linger on sender socket: {}", error))?;
+    bus_client
+        .publish_bytes(uuid, message_bytes)
+        .map_err(|error| format!("failed to publish message: {}", error))?;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        wait_readable(bus_client.socket(), deadline)?;
+
+        match bus_client.receive(zmq::DONTWAIT) {
+            Ok(BusClientEvent::Confirmation {
+                uuid: confirmed_uuid,
+                outcome,
+            }) if confirmed_uuid == uuid => {
+                return match outcome {
+                    PublishConfirmationOutcome::Accepted => Ok(uuid),
+                    PublishConfirmationOutcome::Rejected(reason) => {
+                        Err(format!("BUS rejected message {}: {}", uuid, reason))
+                    }
+                };
+            }
+            Ok(_) => {}
+            Err(error) => return Err(format!("failed to receive confirmation: {}", error)),
+        }
+    }
+}
+
+fn tail(
+    context: &Context,
+    config: &Config,
+    kinds: &[ZeromqMessageKind],
+    uuid: Option<Uuid>,
+    count: Option<usize>,
+) -> Result<(), String> {
+    let prefixes = if kinds.is_empty() {
+        vec![Vec::new()]
+    } else {
+        kinds
+            .iter()
+            .map(|kind| (*kind as u32).to_be_bytes().to_vec())
+            .collect()
+    };
+    let subscriber = subscribe(context, config, prefixes.as_slice())?;
+
+    let shutdown_signal = ShutdownSignal::new();
+    shutdown_signal
+        .register_termination_signals()
+        .map_err(|error| {
+            format!("failed to register termination signals handler: {}", error)
+        })?;
+
+    let mut printed_messages_count: usize = 0;
+    while !shutdown_signal.is_requested()
+        && count.is_none_or(|count| printed_messages_count < count)
+    {
+        match subscriber.poll(zmq::POLLIN, i64::from(SHUTDOWN_CHECK_INTERVAL_MILLISECONDS)) {
+            // Nothing received or interrupted by the termination signal.
+            Ok(0) | Err(zmq::Error::EINTR) => continue,
+            Ok(_) => {}
+            Err(error) => return Err(format!("failed to poll subscriber socket: {}", error)),
+        }
+
+        let message_bytes = subscriber
+            .recv_bytes(zmq::DONTWAIT)
+            .map_err(|error| format!("failed to receive message: {}", error))?;
+
+        if let Some(uuid) = uuid {
+            if decode_message_kind_and_uuid(message_bytes.as_slice())
+                .map(|(_, message_uuid)| message_uuid)
+                != Ok(uuid)
+            {
+                continue;
+            }
+        }
+
+        print_message(message_bytes.as_slice());
+        printed_messages_count += 1;
+    }
+
+    Ok(())
+}
+
+fn request(
+    context: &Context,
+    config: &Config,
+    timeout: Duration,
+    kind: ZeromqMessageKind,
+    payload: Option<String>,
+    response_kind: Option<ZeromqMessageKind>,
+) -> Result<(), String> {
+    let uuid = Uuid::new_v4();
+
+    // Subscription prefix includes uuid when response kind is known, so BUS does not send
+    // unrelated traffic to this subscriber at all.
+    let prefix = response_kind.map_or_else(Vec::new, |response_kind| {
+        let mut prefix = (response_kind as u32).to_be_bytes().to_vec();
+        prefix.extend_from_slice(uuid.as_bytes());
+        prefix
+    });
+    let subscriber = subscribe(context, config, &[prefix])?;
+    thread::sleep(SUBSCRIPTION_SETTLE_TIME);
+
+    let _ = publish(context, config, timeout, kind, payload, Some(uuid))?;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        wait_readable(&subscriber, deadline)?;
+
+        let message_bytes = subscriber
+            .recv_bytes(zmq::DONTWAIT)
+            .map_err(|error| format!("failed to receive response: {}", error))?;
+
+        match decode_message_kind_and_uuid(message_bytes.as_slice()) {
+            Ok((message_kind, message_uuid))
+                if message_uuid == uuid && message_kind != kind =>
+            {
+                print_message(message_bytes.as_slice());
+                return Ok(());
+            }
+            _ => {}
+        }
+    }
+}
+
+fn control(
+    context: &Context,
+    config: &Config,
+    timeout: Duration,
+    action: ControlAction,
+) -> Result<(), String> {
+    let control_endpoint = config
+        .endpoints
+        .control
+        .as_deref()
+        .ok_or_else(|| "control endpoint is not configured".to_string())?;
+
+    let socket = context
+        .socket(SocketType::REQ)
+        .map_err(|error| format!("failed to create control socket: {}", error))?;
+    socket
+        .set_linger(0)
+        .map_err(|error| format!("failed to set linger on control socket: {}", error))?;
+    secure_client_socket(&socket, &config.endpoints)
+        .map_err(|error| format!("failed to set CURVE keys on control socket: {}", error))?;
+    socket
+        .connect(control_endpoint)
+        .map_err(|error| format!("failed to connect to BUS control socket: {}", error))?;
+
+    let command_bytes = encode_message(Uuid::new_v4(), action.command())
+        .map_err(|error| format!("failed to encode control command: {:?}", error))?;
+    socket
+        .send(command_bytes, zmq::DONTWAIT)
+        .map_err(|error| format!("failed to send control command: {}", error))?;
+
+    wait_readable(&socket, Instant::now() + timeout)?;
+    let reply_bytes = socket
+        .recv_bytes(zmq::DONTWAIT)
+        .map_err(|error| format!("failed to receive control reply: {}", error))?;
+
+    let reply = describe_message(reply_bytes.as_slice())?;
+    println!("{}", pretty(&reply));
+
+    match reply["payload"]["error"].as_str() {
+        Some(error) => Err(format!("BUS has failed to execute command: {}", error)),
+        None => Ok(()),
+    }
+}
+
+fn services(
+    context: &Context,
+    config: &Config,
+    timeout: Duration,
+    query: ServiceQuery,
+    required_kinds: &[ZeromqMessageKind],
+) -> Result<(), String> {
+    let mut bus_client = BusClient::connect_endpoints(context, &config.endpoints)
+        .map_err(|error| format!("failed to connect to BUS router socket: {}", error))?;
+    bus_client
+        .socket()
+        .set_linger(0)
+        .map_err(|error| format!("failed to set linger on sender socket: {}", error))?;
+
+    let providers = bus_client
+        .query_services(query, timeout)
+        .map_err(|error| format!("failed to query services: {}", error))?;
+    println!("{}", pretty(&json!({ "providers": providers })));
+
+    if required_kinds.is_empty() {
+        return Ok(());
+    }
+
+    bus_client
+        .require_providers(required_kinds, timeout)
+        .map_err(|error| error.to_string())
+}
+
+#[derive(Debug)]
+struct ReplayFilter {
+    kinds: Vec<ZeromqMessageKind>,
+    dry_run: bool,
+    speed: f64,
+}
+
+impl ReplayFilter {
+    /// Messages which BUS consumes itself are skipped, replayed `WorkerReady` would
+    /// register a worker which does not exist.
+    fn accepts(&self, kind: ZeromqMessageKind) -> bool {
+        let is_bus_message = matches!(
+            kind,
+            ZeromqMessageKind::WorkerReady
+                | ZeromqMessageKind::Heartbeat
+                | ZeromqMessageKind::PublishConfirmation
+                | ZeromqMessageKind::MessageRejection
+                | ZeromqMessageKind::DeadLetterCommand
+                | ZeromqMessageKind::DeadLetterReport
+                | ZeromqMessageKind::ServiceRegistration
+                | ZeromqMessageKind::ServiceQuery
+                | ZeromqMessageKind::ServiceQueryReply
+                | ZeromqMessageKind::DeliveryCancellation
+                | ZeromqMessageKind::ControlCommand
+                | ZeromqMessageKind::ControlReply
+                | ZeromqMessageKind::ControlStatsReply
+                | ZeromqMessageKind::ControlClientsReply
+                | ZeromqMessageKind::ControlSubscriptionsReply
+        );
+
+        !is_bus_message && (self.kinds.is_empty() || self.kinds.contains(&kind))
+    }
+}
+
+/// Publishes received frames of the capture with the original pace scaled by the speed.
+/// Frames are sent unchanged, so replayed messages keep their uuids and headers.
+fn replay(
+    context: &Context,
+    config: &Config,
+    timeout: Duration,
+    capture_file: &Path,
+    replay_filter: &ReplayFilter,
+) -> Result<(), String> {
+    let capture_reader = CaptureReader::open(capture_file).map_err(|error| {
+        format!(
+            "failed to open capture {}: {}",
+            capture_file.display(),
+            error
+        )
+    })?;
+
+    let mut bus_client = if replay_filter.dry_run {
+        None
+    } else {
+        Some(
+            BusClient::connect_endpoints(context, &config.endpoints).map_err(|error| {
+                format!("failed to connect to BUS router socket: {}", error)
+            })?,
+        )
+    };
+
+    let started_at = Instant::now();
+    let mut first_captured_at = None;
+    let mut replayed_messages_count: u64 = 0;
+    let mut rejected_messages_count: u64 = 0;
+
+    for record in capture_reader {
+        let CaptureSource::Received(identity) = &record.source else {
+            continue;
+        };
+        let Ok((kind, uuid)) = decode_message_kind_and_uuid(record.message_bytes.as_slice())
+        else {
+            continue;
+        };
+        if !replay_filter.accepts(kind) {
+            continue;
+        }
+
+        let first_captured_at = *first_captured_at.get_or_insert(record.captured_at);
+        replayed_messages_count += 1;
+
+        let Some(bus_client) = bus_client.as_mut() else {
+            if let Ok(mut description) = describe_message(record.message_bytes.as_slice()) {
+                description["captured_at"] = json!(record.captured_at);
+                description["identity"] = json!(hex(identity.as_slice()));
+                println!("{}", pretty(&description));
+            }
+            continue;
+        };
+
+        let send_at =
+            started_at + record.replay_offset(first_captured_at, replay_filter.speed);
+        rejected_messages_count += receive_confirmations(bus_client, send_at, false)?;
+        bus_client
+            .publish_bytes(uuid, record.message_bytes)
+            .map_err(|error| format!("failed to publish message {}: {}", uuid, error))?;
+    }
+
+    let mut unconfirmed_messages_count = 0;
+    if let Some(bus_client) = bus_client.as_mut() {
+        rejected_messages_count +=
+            receive_confirmations(bus_client, Instant::now() + timeout, true)?;
+        unconfirmed_messages_count = bus_client.unconfirmed_messages_count();
+    }
+
+    println!(
+        "{}",
+        pretty(&json!({
+            "replayed_messages": replayed_messages_count,
+            "rejected_messages": rejected_messages_count,
+            "unconfirmed_messages": unconfirmed_messages_count,
+        }))
+    );
+
+    if rejected_messages_count > 0 || unconfirmed_messages_count > 0 {
+        return Err("not every replayed message was accepted by BUS".to_string());
+    }
+
+    Ok(())
+}
+
+/// Receives confirmations until the deadline, or until every published message is
+/// confirmed when `until_confirmed` is set. Returns count of rejected messages.
+fn receive_confirmations(
+    bus_client: &mut BusClient,
+    deadline: Instant,
+    until_confirmed: bool,
+) -> Result<u64, String> {
+    let mut rejected_messages_count = 0;
+
+    loop {
+        let now = Instant::now();
+        if now >= deadline || (until_confirmed && bus_client.unconfirmed_messages_count() == 0)
+        {
+            return Ok(rejected_messages_count);
+        }
+
+        let timeout = i64::try_from((deadline - now).as_millis()).unwrap_or(i64::MAX);
+        match bus_client.socket().poll(zmq::POLLIN, timeout) {
+            Ok(0) => continue,
+            Ok(_) => {}
+            Err(error) => return Err(format!("failed to poll sender socket: {}", error)),
+        }
+
+        match bus_client.receive(zmq::DONTWAIT) {
+            Ok(BusClientEvent::Confirmation {
+                uuid,
+                outcome: PublishConfirmationOutcome::Rejected(reason),
+            }) => {
+                log::warn!("BUS rejected message {} because of: {}", uuid, reason);
+                rejected_messages_count += 1;
+            }
+            Ok(_) => {}
+            Err(error) => return Err(format!("failed to receive confirmation: {}", error)),
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// Helpers
+//-----------------------------------------------------------------------------------------
+
+fn read_payload(payload: Option<String>) -> Result<Value, String> {
+    let payload = if let Some(payload) = payload {
+        payload
+    } else {
+        let mut payload = String::new();
+        let _ = io::stdin().read_to_string(&mut payload).map_err(|error| {
+            format!("failed to read payload from standard input: {}", error)
+        })?;
+        payload
+    };
+
+    serde_json::from_str(payload.as_str())
+        .map_err(|error| format!("invalid JSON payload: {}", error))
+}
+
+fn subscribe(
+    context: &Context,
+    config: &Config,
+    prefixes: &[Vec<u8>],
+) -> Result<Socket, String> {
+    let subscriber = context
+        .socket(SocketType::SUB)
+        .map_err(|error| format!("failed to create subscriber socket: {}", error))?;
+    subscriber
+        .set_linger(0)
+        .map_err(|error| format!("failed to set linger on subscriber socket: {}", error))?;
+    secure_client_socket(&subscriber, &config.endpoints).map_err(|error| {
+        format!("failed to set CURVE keys on subscriber socket: {}", error)
+    })?;
+
+    for publisher_endpoint in &config.endpoints.client_publishers() {
+        subscriber
+            .connect(publisher_endpoint.as_str())
+            .map_err(|error| {
+                format!("failed to connect to {}: {}", publisher_endpoint, error)
+            })?;
+    }
+    for prefix in prefixes {
+        subscriber
+            .set_subscribe(prefix.as_slice())
+            .map_err(|error| format!("failed to subscribe: {}", error))?;
+    }
+
+    Ok(subscriber)
+}
+
+fn wait_readable(socket: &Socket, deadline: Instant) -> Result<(), String> {
+    let timeout = deadline.saturating_duration_since(Instant::now());
+    match socket.poll(
+        zmq::POLLIN,
+        i64::try_from(timeout.as_millis()).unwrap_or(i64::MAX),
+    ) {
+        Ok(0) => Err("timed out waiting for BUS".to_string()),
+        Ok(_) => Ok(()),
+        Err(error) => Err(format!("failed to poll socket: {}", error)),
+    }
+}
+
+/// Message as JSON with kind name, uuid, header and payload.
+fn describe_message(message_bytes: &[u8]) -> Result<Value, String> {
+    let (kind, uuid) = decode_message_kind_and_uuid(message_bytes)
+        .map_err(|error| format!("failed to decode message kind: {}", error))?;
+    let header = decode_message_header(message_bytes)
+        .map_err(|error| format!("failed to decode message header: {}", error))?;
+    let payload = message_payload_bytes(message_bytes)
+        .map_err(|error| format!("failed to decode message payload: {}", error))
+        .and_then(|payload_bytes| {
+            serde_json::from_slice::<Value>(payload_bytes)
+                .map_err(|error| format!("failed to decode message payload: {}", error))
+        })?;
+
+    let mut description = json!({
+        "kind": format!("{:?}", kind),
+        "uuid": uuid.to_string(),
+        "payload": payload,
+    });
+    if !header.is_empty() {
+        description["header"] = json!(header);
+    }
+
+    Ok(description)
+}
+
+fn print_message(message_bytes: &[u8]) {
+    match describe_message(message_bytes) {
+        Ok(description) => println!("{}", pretty(&description)),
+        Err(error) => log::warn!("skipped message {:?} because of: {}", message_bytes, error),
+    }
+}
+
+fn pretty(value: &Value) -> String {
+    serde_json::to_string_pretty(value).unwrap_or_else(|_| value.to_string())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut output, byte| {
+        let _ = write!(output, "{:02x}", byte);
+        output
+    })
+}
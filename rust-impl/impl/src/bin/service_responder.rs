@@ -21,57 +21,121 @@
 #![allow(clippy::module_name_repetitions)]
 #![allow(clippy::missing_panics_doc)]
 #![allow(clippy::missing_errors_doc)]
+#![allow(clippy::uninlined_format_args)]
 
 use core::panic;
-use rust_impl::BUS_PUBLISHERS_SOCKET_ADDRS;
-use rust_impl::BUS_ROUTER_SOCKET_ADDR;
-use rust_impl::LOG_LEVEL;
-use rust_impl::REQUESTS_COUNT_INSIDE_ONE_GROUP;
+use rust_impl::load_config_from_args;
+use rust_impl::secure_client_socket;
+use rust_impl::BusClient;
+use rust_impl::BusClientEvent;
+use rust_impl::ProcessedRequests;
+use rust_impl::PublishConfirmationOutcome;
+use rust_impl::ShutdownSignal;
 use rust_impl::RUST_LOG_ENVIRONMENT_VARIABLE_NAME;
-use rust_impl::ZEROMQ_ZERO_FLAG;
+use rust_impl::SHUTDOWN_CHECK_INTERVAL_MILLISECONDS;
 use std::convert::From;
+use std::convert::TryFrom;
 use std::env;
+use std::time::Duration;
+use std::time::Instant;
 use std::time::SystemTime;
-use zeromq_messages::codec::decode_message_kind;
+use uuid::Uuid;
+use zeromq_messages::codec::decode_message_kind_and_uuid;
 use zeromq_messages::codec::decode_message_payload;
-use zeromq_messages::codec::decode_message_uuid;
-use zeromq_messages::codec::encode_message;
+use zeromq_messages::codec::encode_message_with_header;
+use zeromq_messages::codec::message_payload_bytes;
+use zeromq_messages::header::MessageHeader;
 use zeromq_messages::kind::ZeromqMessageKind;
+use zeromq_messages::messages::ServiceRegistration;
 use zeromq_messages::messages::ValueMultiplicationRequest;
 use zeromq_messages::messages::ValueMultiplicationResponse;
+use zeromq_messages::messages::WorkerReady;
 use zmq::Context;
-use zmq::Message;
 use zmq::SocketType;
 
+/// Maximum count of requests which BUS gives to this worker without receiving responses.
+const WORKER_CREDIT: usize = 1_000;
+
+/// Time and count of the most recent requests whose responses are given again to resent
+/// requests.
+const PROCESSED_REQUESTS_WINDOW: Duration = Duration::from_secs(30_u64);
+const PROCESSED_REQUESTS_CAPACITY: usize = 100_000;
+
 #[allow(clippy::too_many_lines)]
 fn main() {
+    let config = load_config_from_args();
+
     if env::var(RUST_LOG_ENVIRONMENT_VARIABLE_NAME).is_err() {
-        env::set_var(RUST_LOG_ENVIRONMENT_VARIABLE_NAME, LOG_LEVEL);
+        env::set_var(
+            RUST_LOG_ENVIRONMENT_VARIABLE_NAME,
+            config.log_level.as_str(),
+        );
     }
 
     env_logger::init();
 
-    let context = Context::new();
+    let shutdown_signal = ShutdownSignal::new();
+    shutdown_signal
+        .register_termination_signals()
+        .expect("failed to register termination signals handler");
 
-    let sender = context
-        .socket(SocketType::DEALER)
-        .expect("failed to initialize sender socket");
-
-    log::debug!("initialized sender socket");
+    let context = Context::new();
 
-    sender
-        .connect(BUS_ROUTER_SOCKET_ADDR.as_str())
+    let mut bus_client = BusClient::connect_endpoints(&context, &config.endpoints)
         .expect("failed to connect to BUS router socket.");
 
+    bus_client
+        .socket()
+        .set_linger(config.shutdown_linger())
+        .expect("failed to set linger on sender socket");
+
     log::debug!("sender has connected to BUS router socket");
 
+    bus_client
+        .publish(
+            Uuid::new_v4(),
+            WorkerReady {
+                kinds: vec![i64::from(
+                    ZeromqMessageKind::ValueMultiplicationRequest as u32,
+                )],
+                credit: i64::try_from(WORKER_CREDIT)
+                    .expect("worker credit does not fit in i64"),
+            },
+        )
+        .expect("failed to send worker ready message");
+
+    log::debug!("registered as worker for value multiplication requests");
+
+    bus_client
+        .register_service(ServiceRegistration {
+            name: "service_responder".to_string(),
+            instance_id: Uuid::new_v4().to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            handled_kinds: vec![i64::from(
+                ZeromqMessageKind::ValueMultiplicationRequest as u32,
+            )],
+            produced_kinds: vec![i64::from(
+                ZeromqMessageKind::ValueMultiplicationResponse as u32,
+            )],
+        })
+        .expect("failed to send service registration");
+
+    bus_client.set_heartbeat_interval(config.heartbeat_interval());
+
     let receiver = context
         .socket(SocketType::SUB)
         .expect("failed to initialize receiver socket");
 
+    receiver
+        .set_linger(config.shutdown_linger())
+        .expect("failed to set linger on receiver socket");
+
+    secure_client_socket(&receiver, &config.endpoints)
+        .expect("failed to set CURVE keys on receiver socket");
+
     log::debug!("initialized receiver socket");
 
-    for publisher_address in BUS_PUBLISHERS_SOCKET_ADDRS.iter() {
+    for publisher_address in &config.endpoints.client_publishers() {
         receiver
             .connect(publisher_address.as_str())
             .unwrap_or_else(|error| {
@@ -91,49 +155,112 @@ fn main() {
 
     log::debug!(
         "receiver has connected to all BUS publishers: {}",
-        BUS_PUBLISHERS_SOCKET_ADDRS.join(", ")
+        config.endpoints.client_publishers().join(", ")
     );
 
     let mut total_processed_messages_count = 0;
+    let mut processed_requests =
+        ProcessedRequests::new(PROCESSED_REQUESTS_WINDOW, PROCESSED_REQUESTS_CAPACITY);
+
+    'messages_processing: while !shutdown_signal.is_requested() {
+        if let Err(error) = bus_client.send_heartbeat_if_due() {
+            log::error!("failed to send heartbeat because of: {}", error);
+        }
 
-    'messages_processing: loop {
-        let message_bytes = match receiver.recv_bytes(ZEROMQ_ZERO_FLAG) {
-            Ok(message_bytes) => message_bytes,
+        let mut poll_items = [
+            receiver.as_poll_item(zmq::POLLIN),
+            bus_client.socket().as_poll_item(zmq::POLLIN),
+        ];
+
+        match zmq::poll(
+            &mut poll_items,
+            i64::from(SHUTDOWN_CHECK_INTERVAL_MILLISECONDS),
+        ) {
+            Ok(0) => continue 'messages_processing,
+            Ok(_) => {}
             Err(error) => {
-                log::error!("failed to receive message because of: {}", error);
+                log::error!("failed to poll sockets because of: {}", error);
                 continue 'messages_processing;
             }
-        };
-
-        log::trace!("< {:?}", message_bytes);
+        }
 
-        let (message_kind, message_bytes_without_kind) =
-            match decode_message_kind(message_bytes) {
-                Ok(message_kind_and_left_bytes) => message_kind_and_left_bytes,
+        // Requests of work-queue kinds are given by BUS directly to this worker through the
+        // sender socket, all other messages come through the receiver socket.
+        let message_bytes = if poll_items[1].is_readable() {
+            match bus_client.receive(zmq::DONTWAIT) {
+                Ok(BusClientEvent::Message(message_bytes)) => message_bytes,
+                Ok(BusClientEvent::Confirmation {
+                    uuid,
+                    outcome: PublishConfirmationOutcome::Rejected(reason),
+                }) => {
+                    log::error!("BUS rejected message {} because of: {}", uuid, reason);
+                    continue 'messages_processing;
+                }
+                Ok(BusClientEvent::Confirmation { .. }) => continue 'messages_processing,
+                Err(error) => {
+                    log::error!("failed to receive message because of: {}", error);
+                    continue 'messages_processing;
+                }
+            }
+        } else {
+            match receiver.recv_bytes(zmq::DONTWAIT) {
+                Ok(message_bytes) => message_bytes,
                 Err(error) => {
-                    log::error!("failed to decode message kind because of: {}", error);
+                    log::error!("failed to receive message because of: {}", error);
                     continue 'messages_processing;
                 }
-            };
+            }
+        };
+
+        log::trace!("< {:?}", message_bytes);
+
+        let (message_kind, uuid) = match decode_message_kind_and_uuid(message_bytes.as_slice())
+        {
+            Ok(message_kind_and_uuid) => message_kind_and_uuid,
+            Err(error) => {
+                log::error!("failed to decode message kind because of: {}", error);
+                continue 'messages_processing;
+            }
+        };
 
         if !(matches!(message_kind, ZeromqMessageKind::ValueMultiplicationRequest)) {
             log::trace!("ignored message with unexpected kind {:?}", message_kind);
             continue 'messages_processing;
         }
 
-        let (uuid, message_payload_bytes) = decode_message_uuid(message_bytes_without_kind);
-        let payload = match decode_message_payload::<'_, ValueMultiplicationRequest>(
-            message_payload_bytes.as_slice(),
-        ) {
+        // Request is resent when its response got lost, processing it again could repeat
+        // its side effects, so the remembered response is given instead.
+        if let Some(response_message_bytes) = processed_requests.response(&uuid) {
+            log::trace!("answered resent request {}", uuid);
+            if let Err(error) = bus_client.publish_bytes(uuid, response_message_bytes.to_vec())
+            {
+                log::error!("failed to send message because of: {}", error);
+            }
+            continue 'messages_processing;
+        }
+
+        let payload = match message_payload_bytes(message_bytes.as_slice())
+            .and_then(decode_message_payload::<'_, ValueMultiplicationRequest>)
+        {
             Ok(payload) => payload,
             Err(error) => {
                 log::error!("failed to decode message payload because of: {}", error);
+
+                // Poison message is handed over to the BUS dead-letter queue instead of
+                // being silently lost.
+                if let Err(error) = bus_client.reject(
+                    message_bytes.as_slice(),
+                    format!("failed to decode payload: {}", error),
+                ) {
+                    log::error!("failed to reject message because of: {}", error);
+                }
                 continue 'messages_processing;
             }
         };
 
-        let response_message_bytes = match encode_message(
+        let response_message_bytes = match encode_message_with_header(
             uuid,
+            &MessageHeader::now(),
             ValueMultiplicationResponse {
                 result: payload.value * payload.multiplier,
             },
@@ -145,19 +272,18 @@ fn main() {
             }
         };
 
-        if let Err(error) = sender.send(
-            Message::from(response_message_bytes.clone()),
-            ZEROMQ_ZERO_FLAG,
-        ) {
+        if let Err(error) = bus_client.publish_bytes(uuid, response_message_bytes.clone()) {
             log::error!("failed to send message because of: {}", error);
             continue 'messages_processing;
         }
 
         log::trace!("> {:?}", response_message_bytes);
 
+        processed_requests.remember(uuid, response_message_bytes);
+
         total_processed_messages_count += 1;
 
-        if total_processed_messages_count % REQUESTS_COUNT_INSIDE_ONE_GROUP == 0 {
+        if total_processed_messages_count % config.requests_count_inside_one_group == 0 {
             log::debug!(
                 "{:?} | total processed {} messages",
                 SystemTime::now(),
@@ -165,4 +291,17 @@ fn main() {
             );
         }
     }
+
+    // New requests are not taken anymore, responses which were already sent are waited
+    // for BUS confirmation.
+    log::info!("shutting down, waiting for BUS to confirm sent responses");
+
+    let unconfirmed_messages_count =
+        bus_client.flush(Instant::now() + config.shutdown_deadline());
+
+    log::info!(
+        "stopped after processing {} requests, {} responses were not confirmed by BUS",
+        total_processed_messages_count,
+        unconfirmed_messages_count
+    );
 }
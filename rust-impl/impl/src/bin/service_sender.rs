@@ -21,16 +21,20 @@
 #![allow(clippy::module_name_repetitions)]
 #![allow(clippy::missing_panics_doc)]
 #![allow(clippy::missing_errors_doc)]
+#![allow(clippy::uninlined_format_args)]
 
 use rand::thread_rng;
 use rand::Rng;
+use rust_impl::load_config_from_args;
+use rust_impl::secure_client_socket;
+use rust_impl::BusClient;
+use rust_impl::BusClientError;
+use rust_impl::BusClientEvent;
 use rust_impl::DeadLockSafeRwLock;
-use rust_impl::BUS_PUBLISHERS_SOCKET_ADDRS;
-use rust_impl::BUS_ROUTER_SOCKET_ADDR;
-use rust_impl::LOG_LEVEL;
-use rust_impl::REQUESTS_COUNT_INSIDE_ONE_GROUP;
+use rust_impl::PublishConfirmationOutcome;
+use rust_impl::ShutdownSignal;
 use rust_impl::RUST_LOG_ENVIRONMENT_VARIABLE_NAME;
-use rust_impl::ZEROMQ_ZERO_FLAG;
+use rust_impl::SHUTDOWN_CHECK_INTERVAL_MILLISECONDS;
 use std::cmp::Ord;
 use std::cmp::Ordering;
 use std::collections::HashMap;
@@ -44,15 +48,16 @@ use std::time::Duration;
 use std::time::Instant;
 use std::time::SystemTime;
 use uuid::Uuid;
-use zeromq_messages::codec::decode_message_kind;
+use zeromq_messages::codec::decode_message_kind_and_uuid;
 use zeromq_messages::codec::decode_message_payload;
-use zeromq_messages::codec::decode_message_uuid;
-use zeromq_messages::codec::encode_message;
+use zeromq_messages::codec::encode_message_with_header;
+use zeromq_messages::codec::message_payload_bytes;
+use zeromq_messages::header::MessageHeader;
 use zeromq_messages::kind::ZeromqMessageKind;
+use zeromq_messages::messages::ServiceRegistration;
 use zeromq_messages::messages::ValueMultiplicationRequest;
 use zeromq_messages::messages::ValueMultiplicationResponse;
 use zmq::Context as ZmqContext;
-use zmq::Message;
 use zmq::SocketType;
 
 const RESEND_REQUESTS_EVERY_DURATION: Duration = Duration::from_secs(5_u64);
@@ -87,36 +92,97 @@ impl RequestData {
     }
 }
 
+fn receive_publish_confirmations(bus_client: &mut BusClient) {
+    loop {
+        match bus_client.receive(zmq::DONTWAIT) {
+            Ok(BusClientEvent::Confirmation {
+                uuid,
+                outcome: PublishConfirmationOutcome::Rejected(reason),
+            }) => {
+                log::error!(
+                    "[SENDER] BUS rejected request {} because of: {}",
+                    uuid,
+                    reason
+                );
+            }
+            Ok(BusClientEvent::Confirmation { .. }) => {}
+            Ok(BusClientEvent::Message(message_bytes)) => {
+                log::trace!("[SENDER] ignored direct message {:?}", message_bytes);
+            }
+            Err(BusClientError::Zmq(zmq::Error::EAGAIN)) => return,
+            Err(error) => {
+                log::error!(
+                    "[SENDER] failed to receive confirmation because of: {}",
+                    error
+                );
+                return;
+            }
+        }
+    }
+}
+
 #[allow(clippy::too_many_lines)]
 fn main() {
+    let config = load_config_from_args();
+
     if env::var(RUST_LOG_ENVIRONMENT_VARIABLE_NAME).is_err() {
-        env::set_var(RUST_LOG_ENVIRONMENT_VARIABLE_NAME, LOG_LEVEL);
+        env::set_var(
+            RUST_LOG_ENVIRONMENT_VARIABLE_NAME,
+            config.log_level.as_str(),
+        );
     }
 
     env_logger::init();
 
+    let shutdown_signal = ShutdownSignal::new();
+    shutdown_signal
+        .register_termination_signals()
+        .expect("[SYSTEM] failed to register termination signals handler");
+
+    let requests_count_inside_one_group = config.requests_count_inside_one_group;
     let context = ZmqContext::new();
     let awaiting_requests_storage: AwaitingRequestsStorage = DeadLockSafeRwLock::default();
 
-    let sender = context
-        .socket(SocketType::DEALER)
-        .expect("[SYSTEM] failed to initialize sender socket");
-
-    log::debug!("[SYSTEM] initialized sender socket");
-
-    sender
-        .connect(BUS_ROUTER_SOCKET_ADDR.as_str())
+    let mut bus_client = BusClient::connect_endpoints(&context, &config.endpoints)
         .expect("[SYSTEM] failed to connect to BUS router socket.");
 
+    bus_client
+        .socket()
+        .set_linger(config.shutdown_linger())
+        .expect("[SYSTEM] failed to set linger on sender socket");
+
     log::debug!("[SYSTEM] sender has connected to BUS router socket");
 
+    bus_client.set_heartbeat_interval(config.heartbeat_interval());
+
+    bus_client
+        .register_service(ServiceRegistration {
+            name: "service_sender".to_string(),
+            instance_id: Uuid::new_v4().to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            handled_kinds: vec![i64::from(
+                ZeromqMessageKind::ValueMultiplicationResponse as u32,
+            )],
+            produced_kinds: vec![i64::from(
+                ZeromqMessageKind::ValueMultiplicationRequest as u32,
+            )],
+        })
+        .expect("[SYSTEM] failed to send service registration");
+
     let receiver = context
         .socket(SocketType::SUB)
         .expect("[SYSTEM] failed to initialize receiver socket");
 
+    receiver
+        .set_linger(config.shutdown_linger())
+        .expect("[SYSTEM] failed to set linger on receiver socket");
+
+    secure_client_socket(&receiver, &config.endpoints)
+        .expect("[SYSTEM] failed to set CURVE keys on receiver socket");
+
     log::debug!("[SYSTEM] initialized receiver socket");
 
-    for publisher_address in BUS_PUBLISHERS_SOCKET_ADDRS.iter() {
+    for publisher_address in &config.endpoints.client_publishers() {
         receiver
             .connect(publisher_address.as_str())
             .unwrap_or_else(|error| {
@@ -136,115 +202,163 @@ fn main() {
 
     log::debug!(
         "[SYSTEM] receiver has connected to all BUS publishers: {}",
-        BUS_PUBLISHERS_SOCKET_ADDRS.join(", ")
+        config.endpoints.client_publishers().join(", ")
     );
 
+    // Receiver thread has its own connection to the BUS router socket, which is used to
+    // reject messages that can't be processed.
+    let mut rejections_bus_client = BusClient::connect_endpoints(&context, &config.endpoints)
+        .expect("[SYSTEM] failed to connect to BUS router socket.");
+
+    rejections_bus_client
+        .socket()
+        .set_linger(config.shutdown_linger())
+        .expect("[SYSTEM] failed to set linger on rejections socket");
+
     let mut total_received_messages_count = 0;
     let awaiting_requests_storage_clone = awaiting_requests_storage.clone();
+    let shutdown_awaiting_requests_storage = awaiting_requests_storage.clone();
 
-    log::debug!("[SYSTEM] running messages receiving loop");
-
-    drop(thread::spawn(move || 'receive_messages: loop {
-        let message_bytes = match receiver.recv_bytes(ZEROMQ_ZERO_FLAG) {
-            Ok(message_bytes) => message_bytes,
-            Err(error) => {
-                log::error!("[RECEIVER] failed to receive message because of: {}", error);
-                continue 'receive_messages;
-            }
-        };
+    // Receiver keeps running after shutdown is requested, so responses on already sent
+    // requests are still counted, and is stopped by its own signal.
+    let receiver_shutdown_signal = ShutdownSignal::new();
+    let receiver_thread_shutdown_signal = receiver_shutdown_signal.clone();
 
-        log::trace!("< {:?}", message_bytes);
+    log::debug!("[SYSTEM] running messages receiving loop");
 
-        let (message_kind, message_bytes_without_kind) =
-            match decode_message_kind(message_bytes) {
-                Ok(message_kind_and_left_bytes) => message_kind_and_left_bytes,
+    let receiver_loop_join_handle = thread::spawn(move || {
+        'receive_messages: while !receiver_thread_shutdown_signal.is_requested() {
+            match receiver.poll(zmq::POLLIN, i64::from(SHUTDOWN_CHECK_INTERVAL_MILLISECONDS)) {
+                Ok(0) => continue 'receive_messages,
+                Ok(_) => {}
                 Err(error) => {
                     log::error!(
-                        "[RECEIVER] failed to decode message kind because of: {}",
+                        "[RECEIVER] failed to poll receiver socket because of: {}",
                         error
                     );
                     continue 'receive_messages;
                 }
-            };
-
-        if !(matches!(message_kind, ZeromqMessageKind::ValueMultiplicationResponse)) {
-            log::trace!(
-                "[RECEIVER] ignored message with unexpected kind {:?}",
-                message_kind
-            );
-            continue 'receive_messages;
-        }
+            }
 
-        let (uuid, message_payload_bytes) = decode_message_uuid(message_bytes_without_kind);
+            let message_bytes = match receiver.recv_bytes(zmq::DONTWAIT) {
+                Ok(message_bytes) => message_bytes,
+                Err(error) => {
+                    log::error!("[RECEIVER] failed to receive message because of: {}", error);
+                    continue 'receive_messages;
+                }
+            };
 
-        match awaiting_requests_storage_clone.read(move |awaiting_requests_storage| {
-            awaiting_requests_storage.get(&uuid).cloned()
-        }) {
-            Some(RequestData {
-                expected_result, ..
-            }) => {
-                log::trace!("[RECEIVER] attempt to decode payload");
+            log::trace!("< {:?}", message_bytes);
 
-                let payload = match decode_message_payload::<'_, ValueMultiplicationResponse>(
-                    message_payload_bytes.as_slice(),
-                ) {
-                    Ok(payload) => payload,
+            let (message_kind, uuid) =
+                match decode_message_kind_and_uuid(message_bytes.as_slice()) {
+                    Ok(message_kind_and_uuid) => message_kind_and_uuid,
                     Err(error) => {
                         log::error!(
-                            "[RECEIVER] failed to decode message payload because of: {}",
+                            "[RECEIVER] failed to decode message kind because of: {}",
                             error
                         );
                         continue 'receive_messages;
                     }
                 };
 
-                log::trace!("[RECEIVER] compare expected and received values");
+            if !(matches!(message_kind, ZeromqMessageKind::ValueMultiplicationResponse)) {
+                log::trace!(
+                    "[RECEIVER] ignored message with unexpected kind {:?}",
+                    message_kind
+                );
+                continue 'receive_messages;
+            }
 
-                match expected_result.cmp(&payload.result) {
-                    Ordering::Greater | Ordering::Less => {
-                        log::error!("[RECEIVER] received message with unexpected payload");
-                    }
-                    Ordering::Equal => {
-                        total_received_messages_count += 1;
-                    }
-                }
+            match awaiting_requests_storage_clone.read(move |awaiting_requests_storage| {
+                awaiting_requests_storage.get(&uuid).cloned()
+            }) {
+                Some(RequestData {
+                    expected_result, ..
+                }) => {
+                    log::trace!("[RECEIVER] attempt to decode payload");
 
-                log::trace!("[RECEIVER] request completed, removing from storage");
+                    let payload = match message_payload_bytes(message_bytes.as_slice())
+                        .and_then(decode_message_payload::<'_, ValueMultiplicationResponse>)
+                    {
+                        Ok(payload) => payload,
+                        Err(error) => {
+                            log::error!(
+                                "[RECEIVER] failed to decode message payload because of: {}",
+                                error
+                            );
+
+                            if let Err(error) = rejections_bus_client.reject(
+                                message_bytes.as_slice(),
+                                format!("failed to decode payload: {}", error),
+                            ) {
+                                log::error!(
+                                    "[RECEIVER] failed to reject message because of: {}",
+                                    error
+                                );
+                            }
+                            receive_publish_confirmations(&mut rejections_bus_client);
+                            continue 'receive_messages;
+                        }
+                    };
 
-                // Drop copy allowed because dropped value is not written in any variable.
-                #[allow(clippy::drop_copy)]
-                drop(awaiting_requests_storage_clone.write(
-                    move |awaiting_requests_storage| awaiting_requests_storage.remove(&uuid),
-                ));
+                    log::trace!("[RECEIVER] compare expected and received values");
 
-                log::trace!(
-                    "[RECEIVER] request {} completed and removed from storage",
-                    uuid
-                );
+                    match expected_result.cmp(&payload.result) {
+                        Ordering::Greater | Ordering::Less => {
+                            log::error!("[RECEIVER] received message with unexpected payload");
+                        }
+                        Ordering::Equal => {
+                            total_received_messages_count += 1;
+                        }
+                    }
 
-                if total_received_messages_count % REQUESTS_COUNT_INSIDE_ONE_GROUP == 0 {
-                    log::debug!(
-                        "[RECEIVER] {:?} - total received {} messages",
-                        SystemTime::now(),
-                        total_received_messages_count
+                    log::trace!("[RECEIVER] request completed, removing from storage");
+
+                    // Drop copy allowed because dropped value is not written in any variable.
+                    #[allow(clippy::drop_non_drop)]
+                    drop(awaiting_requests_storage_clone.write(
+                        move |awaiting_requests_storage| {
+                            awaiting_requests_storage.remove(&uuid)
+                        },
+                    ));
+
+                    log::trace!(
+                        "[RECEIVER] request {} completed and removed from storage",
+                        uuid
                     );
+
+                    if total_received_messages_count % requests_count_inside_one_group == 0 {
+                        log::debug!(
+                            "[RECEIVER] {:?} - total received {} messages",
+                            SystemTime::now(),
+                            total_received_messages_count
+                        );
+                    }
+                }
+                None => {
+                    log::error!("[RECEIVER] received message with unexpected uuid: {}", uuid);
                 }
-            }
-            None => {
-                log::error!("[RECEIVER] received message with unexpected uuid: {}", uuid);
             }
         }
-    }));
+
+        total_received_messages_count
+    });
 
     log::debug!("[SYSTEM] running messages sending loop");
 
     let mut total_sended_messages_count = 0;
+    let sender_thread_shutdown_signal = shutdown_signal.clone();
     let sender_loop_join_handle = thread::spawn(move || {
         let mut rng = thread_rng();
         let mut last_resend_check = Instant::now();
 
         #[allow(unused_labels)]
-        'send_messages: loop {
+        'send_messages: while !sender_thread_shutdown_signal.is_requested() {
+            if let Err(error) = bus_client.send_heartbeat_if_due() {
+                log::error!("[SENDER] failed to send heartbeat because of: {}", error);
+            }
+
             let should_resend_requests = Instant::now().duration_since(last_resend_check)
                 > RESEND_REQUESTS_EVERY_DURATION;
             let resend_requests: Rc<VecDeque<(Uuid, RequestData)>> =
@@ -267,13 +381,24 @@ fn main() {
                     Rc::make_mut(&mut resend_requests_clone).extend(resend_requests_iter);
                 });
 
-                log::debug!("[SENDER] resend {} requests", resend_requests.len());
+                // Messages which BUS has not confirmed were most likely never received by it,
+                // the rest of resent requests were accepted but no service has answered them.
+                let unconfirmed_messages_count = bus_client
+                    .take_unconfirmed_messages(RESEND_REQUESTS_EVERY_DURATION)
+                    .len();
+
+                log::debug!(
+                    "[SENDER] resend {} requests, {} messages were not confirmed by BUS",
+                    resend_requests.len(),
+                    unconfirmed_messages_count
+                );
             }
 
             let mut total_messages_sent_inside_current_group = 0;
 
             'send_messages_group: while total_messages_sent_inside_current_group
-                < REQUESTS_COUNT_INSIDE_ONE_GROUP
+                < requests_count_inside_one_group
+                && !sender_thread_shutdown_signal.is_requested()
             {
                 let mut is_resend = false;
                 let mut cloned_resend_requests = Rc::clone(&resend_requests);
@@ -309,7 +434,13 @@ fn main() {
                         )
                     };
 
-                let message_bytes = match encode_message(current_uuid, current_request) {
+                // Request is resent when it is not answered in time, so BUS does not need
+                // to deliver the previous copy after that.
+                let message_bytes = match encode_message_with_header(
+                    current_uuid,
+                    &MessageHeader::with_ttl(RESEND_REQUESTS_EVERY_DURATION),
+                    current_request,
+                ) {
                     Ok(message_bytes) => message_bytes,
                     Err(error) => {
                         log::error!("[SENDER] failed to encode message because of: {}", error);
@@ -318,7 +449,7 @@ fn main() {
                 };
 
                 if let Err(error) =
-                    sender.send(Message::from(message_bytes.clone()), ZEROMQ_ZERO_FLAG)
+                    bus_client.publish_bytes(current_uuid, message_bytes.clone())
                 {
                     log::error!("[SENDER] failed to send message because of: {}", error);
                     continue 'send_messages_group;
@@ -330,7 +461,7 @@ fn main() {
                 // If we resend the request, then it has already been written to the storage.
                 if !is_resend {
                     // Drop copy allowed because dropped value is not written in any variable.
-                    #[allow(clippy::drop_copy)]
+                    #[allow(clippy::drop_non_drop)]
                     drop(
                         awaiting_requests_storage.write(move |awaiting_requests_storage| {
                             awaiting_requests_storage.insert(
@@ -342,9 +473,11 @@ fn main() {
                 }
 
                 total_sended_messages_count += 1;
+
+                receive_publish_confirmations(&mut bus_client);
             }
 
-            if total_sended_messages_count % REQUESTS_COUNT_INSIDE_ONE_GROUP == 0 {
+            if total_sended_messages_count % requests_count_inside_one_group == 0 {
                 log::debug!(
                     "[SENDER] {:?} - total sended {} messages",
                     SystemTime::now(),
@@ -352,11 +485,36 @@ fn main() {
                 );
             }
         }
+
+        (total_sended_messages_count, bus_client)
     });
 
-    sender_loop_join_handle
+    let (total_sended_messages_count, mut bus_client) = sender_loop_join_handle
         .join()
         .expect("[SYSTEM] failed to wait sender thread to finish");
 
-    unreachable!("[SYSTEM] somethink gone wrong");
+    // New requests are not sent anymore, the ones which were already sent are given time
+    // to be confirmed by BUS and answered by services.
+    log::info!("[SYSTEM] shutting down, waiting for responses on sent requests");
+
+    let deadline = Instant::now() + config.shutdown_deadline();
+    let unconfirmed_messages_count = bus_client.flush(deadline);
+    let _ = ShutdownSignal::wait_while_until(deadline, || {
+        shutdown_awaiting_requests_storage
+            .read(|awaiting_requests_storage| !awaiting_requests_storage.is_empty())
+    });
+
+    receiver_shutdown_signal.request();
+    let total_received_messages_count = receiver_loop_join_handle
+        .join()
+        .expect("[SYSTEM] failed to wait receiver thread to finish");
+    let unanswered_requests_count = shutdown_awaiting_requests_storage.read(HashMap::len);
+
+    log::info!(
+        "[SYSTEM] stopped after sending {} and receiving {} messages, {} requests were not answered, {} were not confirmed by BUS",
+        total_sended_messages_count,
+        total_received_messages_count,
+        unanswered_requests_count,
+        unconfirmed_messages_count
+    );
 }
@@ -0,0 +1,410 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::time::Duration;
+
+//-----------------------------------------------------------------------------------------
+// OverflowPolicy
+//-----------------------------------------------------------------------------------------
+
+/// Defines what happens with a message which is pushed into the full queue.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    /// Producer waits until consumer frees space.
+    Block,
+    /// The oldest queued message is dropped in favour of the new one.
+    DropOldest,
+    /// The new message is dropped, sender still gets positive confirmation.
+    DropNewest,
+    /// The new message is dropped and refused to the sender.
+    RejectWithNack,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "block" => Ok(Self::Block),
+            "drop-oldest" => Ok(Self::DropOldest),
+            "drop-newest" => Ok(Self::DropNewest),
+            "reject-with-nack" => Ok(Self::RejectWithNack),
+            _ => Err(format!("unknown overflow policy '{}'", value)),
+        }
+    }
+}
+
+impl fmt::Display for OverflowPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Block => "block",
+            Self::DropOldest => "drop-oldest",
+            Self::DropNewest => "drop-newest",
+            Self::RejectWithNack => "reject-with-nack",
+        })
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// BoundedQueuePushOutcome
+//-----------------------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum BoundedQueuePushOutcome<T> {
+    /// Item was queued without dropping anything.
+    Pushed,
+    /// Item was queued after producer has waited for free space.
+    PushedAfterBlocking,
+    /// Item was queued and the returned oldest item was dropped.
+    DroppedOldest(T),
+    /// Item was not queued, because of `DropNewest` or `RejectWithNack` policy.
+    Refused(T),
+}
+
+//-----------------------------------------------------------------------------------------
+// BoundedQueue
+//-----------------------------------------------------------------------------------------
+
+#[derive(Debug)]
+struct BoundedQueueLane<T> {
+    items: VecDeque<T>,
+    weight: i64,
+    /// Credit of smooth weighted round-robin, non-empty lane with the greatest credit
+    /// gives the next item.
+    credit: i64,
+}
+
+/// Items of all lanes, lanes go in order of importance.
+#[derive(Debug)]
+struct BoundedQueueLanes<T> {
+    lanes: Vec<BoundedQueueLane<T>>,
+    len: usize,
+}
+
+impl<T> BoundedQueueLanes<T> {
+    fn push_back(&mut self, lane_index: usize, item: T) {
+        let lane_index = lane_index.min(self.lanes.len() - 1);
+        self.lanes[lane_index].items.push_back(item);
+        self.len += 1;
+    }
+
+    /// Every non-empty lane gives items in proportion to its weight, so items of important
+    /// lanes overtake the backlog of less important ones which still keep moving.
+    fn pop_next(&mut self) -> Option<T> {
+        let mut total_weight = 0;
+        for lane in &mut self.lanes {
+            if !lane.items.is_empty() {
+                lane.credit += lane.weight;
+                total_weight += lane.weight;
+            }
+        }
+
+        // Ties go to the more important lane.
+        let (lane_index, _) = self
+            .lanes
+            .iter()
+            .enumerate()
+            .filter(|(_, lane)| !lane.items.is_empty())
+            .max_by_key(|(index, lane)| (lane.credit, Reverse(*index)))?;
+        self.lanes[lane_index].credit -= total_weight;
+        self.pop_from(lane_index)
+    }
+
+    /// Overflow sacrifices the oldest item of the least important non-empty lane.
+    fn pop_least_important(&mut self) -> Option<T> {
+        let lane_index = self.lanes.iter().rposition(|lane| !lane.items.is_empty())?;
+        self.pop_from(lane_index)
+    }
+
+    fn pop_from(&mut self, lane_index: usize) -> Option<T> {
+        let lane = &mut self.lanes[lane_index];
+        let item = lane.items.pop_front()?;
+        // Lane which has been idle does not get a burst for the time it was empty.
+        if lane.items.is_empty() {
+            lane.credit = 0;
+        }
+        self.len -= 1;
+        Some(item)
+    }
+}
+
+#[derive(Debug)]
+struct BoundedQueueState<T> {
+    items: Mutex<BoundedQueueLanes<T>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+/// Multi-producer queue with fixed capacity, cloned handles share the same items. Items
+/// are kept in lanes which share the capacity and are served by their weights.
+#[derive(Debug)]
+pub struct BoundedQueue<T> {
+    state: Arc<BoundedQueueState<T>>,
+    capacity: usize,
+    policy: OverflowPolicy,
+}
+
+impl<T> BoundedQueue<T> {
+    /// Capacity should be greater than zero, because zero-sized queue can't pass anything.
+    #[must_use]
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        Self::with_lanes(capacity, policy, &[1])
+    }
+
+    /// Lanes go in order of importance, which decides ties and overflow. Zero weight is
+    /// raised to one, so no lane starves.
+    #[must_use]
+    pub fn with_lanes(capacity: usize, policy: OverflowPolicy, lane_weights: &[u32]) -> Self {
+        let mut lanes = lane_weights
+            .iter()
+            .map(|weight| BoundedQueueLane {
+                items: VecDeque::new(),
+                weight: i64::from((*weight).max(1)),
+                credit: 0,
+            })
+            .collect::<Vec<BoundedQueueLane<T>>>();
+        if lanes.is_empty() {
+            lanes.push(BoundedQueueLane {
+                items: VecDeque::new(),
+                weight: 1,
+                credit: 0,
+            });
+        }
+
+        Self {
+            state: Arc::new(BoundedQueueState {
+                items: Mutex::new(BoundedQueueLanes { lanes, len: 0 }),
+                not_empty: Condvar::new(),
+                not_full: Condvar::new(),
+            }),
+            capacity,
+            policy,
+        }
+    }
+
+    #[must_use]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    #[must_use]
+    pub fn policy(&self) -> OverflowPolicy {
+        self.policy
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.items().len
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.items().len == 0
+    }
+
+    /// Queues item into the most important lane.
+    pub fn push(&self, item: T) -> BoundedQueuePushOutcome<T> {
+        self.push_to_lane(0, item)
+    }
+
+    /// Lane index beyond the last lane stands for the last one.
+    pub fn push_to_lane(&self, lane_index: usize, item: T) -> BoundedQueuePushOutcome<T> {
+        let mut items = self.items();
+        let mut outcome = BoundedQueuePushOutcome::Pushed;
+
+        if items.len >= self.capacity {
+            match self.policy {
+                OverflowPolicy::Block => {
+                    while items.len >= self.capacity {
+                        items = self
+                            .state
+                            .not_full
+                            .wait(items)
+                            .unwrap_or_else(|_| panic!("bounded queue mutex poisoned"));
+                    }
+                    outcome = BoundedQueuePushOutcome::PushedAfterBlocking;
+                }
+                OverflowPolicy::DropOldest => {
+                    if let Some(oldest_item) = items.pop_least_important() {
+                        outcome = BoundedQueuePushOutcome::DroppedOldest(oldest_item);
+                    }
+                }
+                OverflowPolicy::DropNewest | OverflowPolicy::RejectWithNack => {
+                    return BoundedQueuePushOutcome::Refused(item);
+                }
+            }
+        }
+
+        items.push_back(lane_index, item);
+        self.state.not_empty.notify_one();
+        outcome
+    }
+
+    /// Takes the next item by lane weights, waits while queue is empty.
+    #[must_use]
+    pub fn pop(&self) -> T {
+        let mut items = self.items();
+
+        loop {
+            if let Some(item) = items.pop_next() {
+                self.state.not_full.notify_one();
+                return item;
+            }
+            items = self
+                .state
+                .not_empty
+                .wait(items)
+                .unwrap_or_else(|_| panic!("bounded queue mutex poisoned"));
+        }
+    }
+
+    /// Takes the next item by lane weights, waits at most the given time while queue is
+    /// empty.
+    #[must_use]
+    pub fn pop_timeout(&self, timeout: Duration) -> Option<T> {
+        let (mut items, _) = self
+            .state
+            .not_empty
+            .wait_timeout_while(self.items(), timeout, |items| items.len == 0)
+            .unwrap_or_else(|_| panic!("bounded queue mutex poisoned"));
+
+        let item = items.pop_next();
+        if item.is_some() {
+            self.state.not_full.notify_one();
+        }
+        item
+    }
+
+    /// Takes the next item by lane weights without waiting.
+    #[must_use]
+    pub fn try_pop(&self) -> Option<T> {
+        let item = self.items().pop_next();
+        if item.is_some() {
+            self.state.not_full.notify_one();
+        }
+        item
+    }
+
+    fn items(&self) -> MutexGuard<'_, BoundedQueueLanes<T>> {
+        self.state
+            .items
+            .lock()
+            .unwrap_or_else(|_| panic!("bounded queue mutex poisoned"))
+    }
+}
+
+// Implemented manually, because derive requires `T: Clone` while only `Arc` is cloned.
+impl<T> Clone for BoundedQueue<T> {
+    fn clone(&self) -> Self {
+        Self {
+            state: Arc::clone(&self.state),
+            capacity: self.capacity,
+            policy: self.policy,
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// Tests
+//-----------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::bounded_queue::BoundedQueue;
+    use crate::bounded_queue::BoundedQueuePushOutcome;
+    use crate::bounded_queue::OverflowPolicy;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn drop_oldest() {
+        let queue = BoundedQueue::new(2, OverflowPolicy::DropOldest);
+        assert_eq!(BoundedQueuePushOutcome::Pushed, queue.push(1));
+        assert_eq!(BoundedQueuePushOutcome::Pushed, queue.push(2));
+        assert_eq!(BoundedQueuePushOutcome::DroppedOldest(1), queue.push(3));
+        assert_eq!(2, queue.pop());
+        assert_eq!(3, queue.pop());
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn drop_newest_and_reject() {
+        for policy in &[OverflowPolicy::DropNewest, OverflowPolicy::RejectWithNack] {
+            let queue = BoundedQueue::new(1, *policy);
+            assert_eq!(BoundedQueuePushOutcome::Pushed, queue.push(1));
+            assert_eq!(BoundedQueuePushOutcome::Refused(2), queue.push(2));
+            assert_eq!(Some(1), queue.try_pop());
+            assert_eq!(None, queue.try_pop());
+        }
+    }
+
+    #[test]
+    fn block_waits_for_consumer() {
+        let queue = BoundedQueue::new(1, OverflowPolicy::Block);
+        assert_eq!(BoundedQueuePushOutcome::Pushed, queue.push(1));
+
+        let consumer_queue = queue.clone();
+        let consumer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            consumer_queue.pop()
+        });
+
+        assert_eq!(BoundedQueuePushOutcome::PushedAfterBlocking, queue.push(2));
+        assert_eq!(1, consumer.join().unwrap());
+        assert_eq!(1, queue.len());
+    }
+
+    #[test]
+    fn important_lanes_overtake_backlog() {
+        let queue = BoundedQueue::with_lanes(100, OverflowPolicy::Block, &[3, 1]);
+        for item in 0..10 {
+            let _ = queue.push_to_lane(1, item);
+        }
+        assert_eq!(Some(0), queue.try_pop());
+
+        for item in 100..104 {
+            let _ = queue.push_to_lane(0, item);
+        }
+
+        // Backlog keeps moving with the weight of its lane.
+        let popped_items = (0..6).filter_map(|_| queue.try_pop()).collect::<Vec<i32>>();
+        assert_eq!(vec![100, 101, 1, 102, 103, 2], popped_items);
+        assert_eq!(7, queue.len());
+    }
+
+    #[test]
+    fn drop_oldest_sacrifices_least_important_lane() {
+        let queue = BoundedQueue::with_lanes(2, OverflowPolicy::DropOldest, &[1, 1]);
+        assert_eq!(BoundedQueuePushOutcome::Pushed, queue.push_to_lane(0, 1));
+        assert_eq!(BoundedQueuePushOutcome::Pushed, queue.push_to_lane(1, 2));
+        assert_eq!(
+            BoundedQueuePushOutcome::DroppedOldest(2),
+            queue.push_to_lane(0, 3)
+        );
+        assert_eq!(Some(1), queue.try_pop());
+        assert_eq!(Some(3), queue.try_pop());
+    }
+
+    #[test]
+    fn pop_timeout_waits_for_producer() {
+        let queue = BoundedQueue::new(1, OverflowPolicy::Block);
+        assert_eq!(None, queue.pop_timeout(Duration::from_millis(10)));
+
+        let producer_queue = queue.clone();
+        let producer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            producer_queue.push(1)
+        });
+
+        assert_eq!(Some(1), queue.pop_timeout(Duration::from_secs(5)));
+        assert_eq!(BoundedQueuePushOutcome::Pushed, producer.join().unwrap());
+    }
+}
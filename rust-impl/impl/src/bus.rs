@@ -0,0 +1,3615 @@
+use crate::acl::set_xpub_manual;
+use crate::control::BusControl;
+use crate::control::SenderControlCommand;
+use crate::helpers::hex;
+use crate::helpers::is_control_message;
+use crate::security::secure_server_socket;
+use crate::AccessControl;
+use crate::AllowedClients;
+use crate::BoundedQueue;
+use crate::BoundedQueuePushOutcome;
+use crate::BusControlState;
+use crate::BusPublisherData;
+use crate::BusStats;
+use crate::BusStatsSnapshot;
+use crate::BusTakeover;
+use crate::CaptureRecord;
+use crate::CaptureSource;
+use crate::CaptureWriter;
+use crate::Config;
+use crate::ConfigError;
+use crate::DeadLetter;
+use crate::DeadLetterQueue;
+use crate::DeadLetterQueueOptions;
+use crate::DeadLetterReason;
+use crate::DeadLockSafeMutex;
+use crate::DeduplicationWindow;
+use crate::ExpiryPolicy;
+use crate::Federation;
+use crate::Membership;
+use crate::MetricsServer;
+use crate::OverflowPolicy;
+use crate::PausedMessage;
+use crate::PausedMessageHoldOutcome;
+use crate::PausedMessages;
+use crate::PublisherSelector;
+use crate::RateLimitPolicy;
+use crate::RateLimitedClient;
+use crate::RateLimiter;
+use crate::Replication;
+use crate::ScheduledMessage;
+use crate::ScheduledMessages;
+use crate::SecurityError;
+use crate::ServiceInstance;
+use crate::ServiceRegistry;
+use crate::ShutdownSignal;
+use crate::UnroutablePolicy;
+use crate::WorkQueue;
+use crate::WorkQueueDispatch;
+use crate::WorkQueueDispatchOutcome;
+use crate::WorkerIdentity;
+use crate::WriteAheadLog;
+use crate::WriteAheadLogOptions;
+use crate::WriteAheadLogRecord;
+use crate::ZapHandler;
+use crate::BUS_FEDERATION_QUEUE_CAPACITY;
+use crate::SHUTDOWN_CHECK_INTERVAL_MILLISECONDS;
+use crate::ZEROMQ_ZERO_FLAG;
+use serde::de::IgnoredAny;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::convert::From;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::iter::Iterator;
+use std::net::SocketAddr;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use uuid::Uuid;
+use zeromq_messages::codec::decode_message_header;
+use zeromq_messages::codec::decode_message_kind_and_uuid;
+use zeromq_messages::codec::decode_message_payload;
+use zeromq_messages::codec::encode_message;
+use zeromq_messages::codec::encode_message_with_header;
+use zeromq_messages::codec::message_payload_bytes;
+use zeromq_messages::codec::MESSAGE_KIND_AND_UUID_LENGTH;
+use zeromq_messages::header::milliseconds_since_unix_epoch;
+use zeromq_messages::header::MessageHeader;
+use zeromq_messages::header::MessagePriority;
+use zeromq_messages::kind::ZeromqMessageKind;
+use zeromq_messages::messages::DeadLetterCommand;
+use zeromq_messages::messages::DeadLetterReport;
+use zeromq_messages::messages::DeliveryCancellation;
+use zeromq_messages::messages::Heartbeat;
+use zeromq_messages::messages::MessageRejection;
+use zeromq_messages::messages::PublishConfirmation;
+use zeromq_messages::messages::ServiceJoined;
+use zeromq_messages::messages::ServiceLeft;
+use zeromq_messages::messages::ServiceQuery;
+use zeromq_messages::messages::ServiceQueryReply;
+use zeromq_messages::messages::ServiceRegistration;
+use zeromq_messages::messages::WorkerReady;
+use zeromq_messages::template::ZeromqMessageTrait;
+use zmq::Context;
+use zmq::Message;
+use zmq::Socket;
+use zmq::SocketType;
+
+const SUBSCRIPTIONS_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+const MEMBERSHIP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+//-----------------------------------------------------------------------------------------
+// Errors
+//-----------------------------------------------------------------------------------------
+
+#[derive(Debug, thiserror::Error)]
+pub enum BusError {
+    #[error("ZeroMQ operation failed")]
+    Zmq(#[from] zmq::Error),
+
+    #[error("Invalid BUS configuration")]
+    Config(#[from] ConfigError),
+
+    #[error("Failed to bind BUS socket on {endpoint}")]
+    Bind {
+        endpoint: String,
+        #[source]
+        source: zmq::Error,
+    },
+
+    #[error("Failed to open write-ahead log")]
+    WriteAheadLog(#[source] io::Error),
+
+    #[error("Failed to open dead-letter queue")]
+    DeadLetterQueue(#[source] io::Error),
+
+    #[error("Failed to open capture file")]
+    Capture(#[source] io::Error),
+
+    #[error("Failed to bind replication socket")]
+    Replication(#[source] zmq::Error),
+
+    #[error("Failed to take over messages of the active BUS")]
+    Takeover(#[source] io::Error),
+
+    #[error("Failed to connect federation peers")]
+    Federation(#[source] zmq::Error),
+
+    #[error("Failed to secure BUS sockets")]
+    Security(#[source] SecurityError),
+
+    #[error("Failed to serve metrics")]
+    Metrics(#[source] io::Error),
+
+    #[error("BUS {0} thread has panicked")]
+    ThreadPanicked(&'static str),
+}
+
+//-----------------------------------------------------------------------------------------
+// BusBuilder
+//-----------------------------------------------------------------------------------------
+
+/// Configures BUS which runs inside the current process. Endpoints given to the builder
+/// override the ones from configuration.
+pub struct BusBuilder {
+    config: Config,
+    context: Option<Context>,
+    takeover: Option<BusTakeover>,
+}
+
+impl BusBuilder {
+    #[must_use]
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            context: None,
+            takeover: None,
+        }
+    }
+
+    #[must_use]
+    pub fn router_endpoint<E: Into<String>>(mut self, endpoint: E) -> Self {
+        self.config.endpoints.router = endpoint.into();
+        self
+    }
+
+    #[must_use]
+    pub fn publisher_endpoints<I: IntoIterator<Item = E>, E: Into<String>>(
+        mut self,
+        endpoints: I,
+    ) -> Self {
+        self.config.endpoints.publishers = endpoints.into_iter().map(Into::into).collect();
+        self
+    }
+
+    #[must_use]
+    pub fn control_endpoint<E: Into<String>>(mut self, endpoint: E) -> Self {
+        self.config.endpoints.control = Some(endpoint.into());
+        self
+    }
+
+    /// Context which BUS sockets are created in. `inproc://` endpoints are reachable only
+    /// from the same context, so clients living in this process should share it, by
+    /// default BUS creates its own one which is available through `Bus::context`.
+    #[must_use]
+    pub fn context(mut self, context: Context) -> Self {
+        self.context = Some(context);
+        self
+    }
+
+    /// State which standby has mirrored from the active BUS. Its messages are persisted
+    /// and routed before anything else is received.
+    #[must_use]
+    pub fn takeover(mut self, takeover: BusTakeover) -> Self {
+        self.takeover = Some(takeover);
+        self
+    }
+
+    /// Binds BUS sockets and runs BUS threads, the returned handle stops them on shutdown
+    /// or drop.
+    #[allow(clippy::too_many_lines)]
+    pub fn start(self) -> Result<Bus, BusError> {
+        let Self {
+            config,
+            context,
+            takeover,
+        } = self;
+        let takeover = takeover.unwrap_or_default();
+        config.validate()?;
+
+        let context = context.unwrap_or_default();
+        let stats = Arc::new(BusStats::new(config.endpoints.publishers.len()));
+
+        let (write_ahead_log, undelivered_records) = match &config.bus.wal_directory {
+            Some(directory) => {
+                let (mut write_ahead_log, mut undelivered_records) =
+                    WriteAheadLog::open(WriteAheadLogOptions {
+                        segment_max_bytes: config.bus.wal_segment_max_bytes,
+                        fsync_policy: config.bus.wal_fsync_policy,
+                        ..WriteAheadLogOptions::new(directory)
+                    })
+                    .map_err(BusError::WriteAheadLog)?;
+
+                for message_bytes in takeover.messages {
+                    let sequence = write_ahead_log
+                        .append(message_bytes.as_slice())
+                        .map_err(BusError::Takeover)?;
+                    undelivered_records.push(WriteAheadLogRecord {
+                        sequence,
+                        message_bytes,
+                    });
+                }
+
+                log::debug!(
+                    "opened write-ahead log in {} with {} undelivered messages",
+                    directory.display(),
+                    undelivered_records.len()
+                );
+
+                (
+                    Some(DeadLockSafeMutex::new(write_ahead_log)),
+                    undelivered_records,
+                )
+            }
+            None => (None, Vec::new()),
+        };
+
+        let replication = match (&config.bus.replication_endpoint, &write_ahead_log) {
+            (Some(replication_endpoint), Some(write_ahead_log)) => {
+                let replication = Replication::bind(
+                    &context,
+                    replication_endpoint.as_str(),
+                    config.shutdown_linger(),
+                    undelivered_records.as_slice(),
+                )
+                .map_err(BusError::Replication)?;
+                let replica = replication.clone();
+                write_ahead_log.lock(move |write_ahead_log| {
+                    write_ahead_log.set_replica(Box::new(replica));
+                });
+
+                log::debug!("BUS replication socket binded on {}", replication_endpoint);
+                Some(replication)
+            }
+            _ => None,
+        };
+
+        let dead_letter_queue = DeadLetterQueue::open(DeadLetterQueueOptions {
+            capacity: config.bus.dead_letter_queue_capacity,
+            file: config.bus.dead_letter_file.clone(),
+        })
+        .map_err(BusError::DeadLetterQueue)?;
+
+        log::debug!(
+            "opened dead-letter queue with {} messages",
+            dead_letter_queue.len()
+        );
+
+        let dead_letter_queue = DeadLockSafeMutex::new(dead_letter_queue);
+
+        let capture_writer = config
+            .bus
+            .capture_file
+            .as_ref()
+            .map(|path| {
+                log::debug!("capturing traffic into {}", path.display());
+                CaptureWriter::open(path).map(DeadLockSafeMutex::new)
+            })
+            .transpose()
+            .map_err(BusError::Capture)?;
+
+        // Handler has to be bound before clients connect to secured sockets, its thread is
+        // started together with the other ones.
+        let zap_handler = match &config.endpoints.curve_server_secret_key {
+            Some(_) => {
+                let allowed_clients = config
+                    .endpoints
+                    .curve_allowed_clients_file
+                    .as_deref()
+                    .map(AllowedClients::load)
+                    .transpose()
+                    .map_err(BusError::Security)?;
+                if let Some(allowed_clients) = &allowed_clients {
+                    log::debug!("loaded {} allowed client keys", allowed_clients.len());
+                }
+                Some(
+                    ZapHandler::bind(&context, allowed_clients, Arc::clone(&stats))
+                        .map_err(BusError::Security)?,
+                )
+            }
+            None => None,
+        };
+
+        let router_socket = context.socket(SocketType::ROUTER)?;
+        secure_server_socket(&router_socket, &config.endpoints)?;
+
+        // Sending of work-queue requests to a disconnected worker must fail instead of being
+        // silently dropped, so request can be given to another worker.
+        router_socket.set_router_mandatory(true)?;
+
+        // Bounded socket queues make slow clients feel backpressure instead of growing BUS
+        // memory without limit.
+        router_socket.set_rcvhwm(config.bus.router_high_water_mark)?;
+        router_socket.set_sndhwm(config.bus.router_high_water_mark)?;
+        router_socket.set_linger(config.shutdown_linger())?;
+
+        bind(&router_socket, config.endpoints.router.as_str())?;
+
+        log::debug!(
+            "BUS router socket binded on {}",
+            config.endpoints.router.as_str()
+        );
+
+        let mut publishers: Vec<BusPublisherData> =
+            Vec::with_capacity(config.endpoints.publishers.len());
+
+        let access_control = if config.bus.acl.is_empty() {
+            None
+        } else {
+            Some(Arc::new(AccessControl::new(config.bus.acl.as_slice())))
+        };
+
+        for publisher_address in &config.endpoints.publishers {
+            let mut publisher = context.socket(SocketType::XPUB)?;
+            secure_server_socket(&publisher, &config.endpoints)?;
+            // Subscriptions are applied by the sender thread once they are allowed.
+            if access_control.is_some() {
+                set_xpub_manual(&mut publisher)?;
+            }
+            publisher.set_sndhwm(config.bus.publishers_high_water_mark)?;
+            publisher.set_linger(config.shutdown_linger())?;
+            bind(&publisher, publisher_address.as_str())?;
+            publishers.push(BusPublisherData::new(publisher));
+        }
+
+        log::debug!(
+            "initialized BUS publisher sockets and binded on {}",
+            config.endpoints.publishers.join(", ")
+        );
+
+        let publishing_queue = BoundedQueue::<PublishingMessage>::with_lanes(
+            config.bus.publishing_queue_capacity,
+            config.bus.publishing_queue_overflow_policy,
+            config.bus.priority_weights.as_slice(),
+        );
+
+        let control_state = BusControlState::new(publishers.len());
+
+        // Subscribers of the previous active BUS resubscribe after reconnection, until then
+        // their subscriptions are known from the mirrored state.
+        for (publisher_index, prefix) in takeover.subscriptions {
+            let mut event = vec![1_u8];
+            event.extend_from_slice(prefix.as_slice());
+            if let Some(replication) = &replication {
+                replication.subscription(publisher_index, event.as_slice());
+            }
+            control_state.apply_subscription_event(publisher_index, event);
+        }
+        let (sender_commands, control_commands) = mpsc::channel();
+
+        let control = match &config.endpoints.control {
+            Some(control_endpoint) => {
+                let control_socket = context.socket(SocketType::REP)?;
+                secure_server_socket(&control_socket, &config.endpoints)?;
+                control_socket.set_linger(config.shutdown_linger())?;
+                bind(&control_socket, control_endpoint.as_str())?;
+
+                log::debug!("BUS control socket binded on {}", control_endpoint);
+
+                Some(BusControl {
+                    socket: control_socket,
+                    state: control_state.clone(),
+                    stats: Arc::clone(&stats),
+                    dead_letter_queue: dead_letter_queue.clone(),
+                    sender_commands,
+                    access_control: access_control.clone(),
+                })
+            }
+            None => None,
+        };
+
+        let sender_shutdown_signal = ShutdownSignal::new();
+        let messages_sender = MessagesSender {
+            publishers,
+            publisher_selector: config.bus.publisher_selection_strategy.selector(),
+            publishing_queue: publishing_queue.clone(),
+            errored_messages_buffer: VecDeque::with_capacity(config.bus.retry_buffer_capacity),
+            write_ahead_log: write_ahead_log.clone(),
+            dead_letter_queue: dead_letter_queue.clone(),
+            capture_writer: capture_writer.clone(),
+            stats: Arc::clone(&stats),
+            expiry_policy: config.bus.expiry_policy,
+            unroutable_policy: config.bus.unroutable_policy,
+            publish_retry_budget: config.bus.publish_retry_budget,
+            retry_buffer_capacity: config.bus.retry_buffer_capacity,
+            requests_count_inside_one_group: config.requests_count_inside_one_group,
+            shutdown_signal: sender_shutdown_signal.clone(),
+            shutdown_deadline: config.shutdown_deadline(),
+            control_state: control_state.clone(),
+            control_commands,
+            replication: replication.clone(),
+            access_control: access_control.clone(),
+        };
+
+        log::debug!("running sender thread");
+        let sender_thread = thread::spawn(move || messages_sender.run());
+
+        let federation_shutdown_signal = ShutdownSignal::new();
+        let (federation, federation_thread) = if config.bus.federation_peers.is_empty()
+            || config.bus.federation_kinds.is_empty()
+        {
+            (None, None)
+        } else {
+            let instance_id = config
+                .bus
+                .instance_id
+                .clone()
+                .unwrap_or_else(|| Uuid::new_v4().to_string());
+            let (federation, mut forwarder) = Federation::connect(
+                &context,
+                instance_id,
+                config.bus.federation_kinds().as_slice(),
+                config.bus.federation_peers.as_slice(),
+                BUS_FEDERATION_QUEUE_CAPACITY,
+                config.shutdown_linger(),
+                Arc::clone(&stats),
+            )
+            .map_err(BusError::Federation)?;
+
+            log::debug!(
+                "running federation thread of BUS {} with peers {}",
+                federation.instance_id(),
+                config.bus.federation_peers.join(", ")
+            );
+            let shutdown_signal = federation_shutdown_signal.clone();
+            (
+                Some(federation),
+                Some(thread::spawn(move || forwarder.run(&shutdown_signal))),
+            )
+        };
+
+        let receiver_shutdown_signal = ShutdownSignal::new();
+        let mut messages_router = MessagesRouter {
+            router_socket,
+            work_queue: WorkQueue::new(
+                config.bus.work_queue_strategy,
+                config.bus.work_queue_kinds().as_slice(),
+                config.bus.work_queue_capacity,
+                config.bus.work_queue_overflow_policy,
+            ),
+            work_queue_wal_sequences: HashMap::new(),
+            write_ahead_log,
+            dead_letter_queue,
+            capture_writer,
+            publishing_queue,
+            kind_priorities: config.bus.kind_priorities(),
+            stats: Arc::clone(&stats),
+            expiry_policy: config.bus.expiry_policy,
+            control_state,
+            paused_messages: PausedMessages::new(
+                config.bus.paused_messages_capacity,
+                config.bus.paused_messages_overflow_policy,
+            ),
+            membership: Membership::new(config.heartbeat_grace()),
+            client_activity_ttl: config.client_activity_ttl(),
+            service_registry: ServiceRegistry::new(),
+            federation,
+            scheduled_messages: ScheduledMessages::new(
+                config.bus.scheduled_messages_capacity,
+                config.bus.schedule_max_delay_ms,
+            ),
+            dedup_window: config.dedup_window().map(|max_age| {
+                DeduplicationWindow::new(max_age, config.bus.dedup_window_capacity)
+            }),
+            rate_limiter: config.bus.rate_limit().map(|limit| {
+                RateLimiter::new(limit, config.bus.rate_limited_kinds().as_slice())
+            }),
+            rate_limit_policy: config.bus.rate_limit_policy,
+            access_control,
+            is_replaying: false,
+        };
+        let shutdown_signal = receiver_shutdown_signal.clone();
+
+        let metrics_server = config
+            .bus
+            .metrics_address
+            .as_deref()
+            .map(|address| MetricsServer::start(address, Arc::clone(&stats)))
+            .transpose()
+            .map_err(BusError::Metrics)?;
+
+        log::debug!("running receiver thread");
+        let receiver_thread = thread::spawn(move || {
+            messages_router.replay(undelivered_records);
+            messages_router.run(&shutdown_signal);
+        });
+
+        let replication_shutdown_signal = ShutdownSignal::new();
+        let heartbeat_interval = config.heartbeat_interval();
+        let replication_thread = replication.map(|replication| {
+            let shutdown_signal = replication_shutdown_signal.clone();
+            log::debug!("running replication thread");
+            thread::spawn(move || replication.run(heartbeat_interval, &shutdown_signal))
+        });
+
+        let control_shutdown_signal = ShutdownSignal::new();
+        let control_thread = control.map(|mut control| {
+            let shutdown_signal = control_shutdown_signal.clone();
+            log::debug!("running control thread");
+            thread::spawn(move || control.run(&shutdown_signal))
+        });
+
+        let zap_shutdown_signal = ShutdownSignal::new();
+        let zap_thread = zap_handler.map(|mut zap_handler| {
+            let shutdown_signal = zap_shutdown_signal.clone();
+            log::debug!("running ZAP handler thread");
+            thread::spawn(move || zap_handler.run(&shutdown_signal))
+        });
+
+        Ok(Bus {
+            context,
+            stats,
+            metrics_server,
+            receiver_shutdown_signal,
+            sender_shutdown_signal,
+            control_shutdown_signal,
+            federation_shutdown_signal,
+            replication_shutdown_signal,
+            zap_shutdown_signal,
+            receiver_thread: Some(receiver_thread),
+            sender_thread: Some(sender_thread),
+            control_thread,
+            federation_thread,
+            replication_thread,
+            zap_thread,
+        })
+    }
+}
+
+impl fmt::Debug for BusBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BusBuilder")
+            .field("config", &self.config)
+            .field("context", &self.context.is_some())
+            .field("takeover", &self.takeover)
+            .finish()
+    }
+}
+
+fn bind(socket: &Socket, endpoint: &str) -> Result<(), BusError> {
+    socket.bind(endpoint).map_err(|source| BusError::Bind {
+        endpoint: endpoint.to_string(),
+        source,
+    })
+}
+
+//-----------------------------------------------------------------------------------------
+// Bus
+//-----------------------------------------------------------------------------------------
+
+/// Handle of the running BUS. Dropping it shuts BUS down as well.
+pub struct Bus {
+    context: Context,
+    stats: Arc<BusStats>,
+    metrics_server: Option<MetricsServer>,
+    receiver_shutdown_signal: ShutdownSignal,
+    sender_shutdown_signal: ShutdownSignal,
+    control_shutdown_signal: ShutdownSignal,
+    federation_shutdown_signal: ShutdownSignal,
+    replication_shutdown_signal: ShutdownSignal,
+    zap_shutdown_signal: ShutdownSignal,
+    receiver_thread: Option<JoinHandle<()>>,
+    sender_thread: Option<JoinHandle<BusShutdownSummary>>,
+    control_thread: Option<JoinHandle<()>>,
+    federation_thread: Option<JoinHandle<()>>,
+    replication_thread: Option<JoinHandle<()>>,
+    zap_thread: Option<JoinHandle<()>>,
+}
+
+impl Bus {
+    /// Builder which starts from the default configuration.
+    #[must_use]
+    pub fn builder() -> BusBuilder {
+        BusBuilder::new(Config::default())
+    }
+
+    /// Context of BUS sockets, clients of `inproc://` endpoints have to be created in it.
+    #[must_use]
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    #[must_use]
+    pub fn stats(&self) -> BusStatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    /// Address where metrics are served, when `bus.metrics_address` is configured.
+    #[must_use]
+    pub fn metrics_address(&self) -> Option<SocketAddr> {
+        self.metrics_server.as_ref().map(MetricsServer::address)
+    }
+
+    /// Whether all BUS threads are still running, they finish only on shutdown or panic.
+    #[must_use]
+    pub fn is_running(&self) -> bool {
+        [
+            self.receiver_thread.as_ref().map(JoinHandle::is_finished),
+            self.sender_thread.as_ref().map(JoinHandle::is_finished),
+        ]
+        .iter()
+        .all(|is_finished| *is_finished == Some(false))
+            && ![
+                &self.control_thread,
+                &self.federation_thread,
+                &self.replication_thread,
+                &self.zap_thread,
+            ]
+            .iter()
+            .any(|thread| thread.as_ref().is_some_and(JoinHandle::is_finished))
+    }
+
+    /// Stops receiving new messages, publishes already accepted ones within the shutdown
+    /// deadline and waits for BUS threads to finish.
+    pub fn shutdown(mut self) -> Result<BusShutdownSummary, BusError> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> Result<BusShutdownSummary, BusError> {
+        self.control_shutdown_signal.request();
+        let control_result = self
+            .control_thread
+            .take()
+            .map_or(Ok(()), JoinHandle::join)
+            .map_err(|_| BusError::ThreadPanicked("control"));
+
+        // Receiver is stopped first, so nothing is added to the publishing queue while
+        // sender drains it.
+        self.receiver_shutdown_signal.request();
+        let receiver_result = self
+            .receiver_thread
+            .take()
+            .map_or(Ok(()), JoinHandle::join)
+            .map_err(|_| BusError::ThreadPanicked("receiver"));
+
+        // Forwarder sends what receiver has queued for peers before it stops.
+        self.federation_shutdown_signal.request();
+        let federation_result = self
+            .federation_thread
+            .take()
+            .map_or(Ok(()), JoinHandle::join)
+            .map_err(|_| BusError::ThreadPanicked("federation"));
+
+        self.sender_shutdown_signal.request();
+        let sender_result = self
+            .sender_thread
+            .take()
+            .map_or(Ok(BusShutdownSummary::default()), JoinHandle::join)
+            .map_err(|_| BusError::ThreadPanicked("sender"));
+
+        // Standby takes over once heartbeats stop, so they are sent until every message
+        // which could be delivered is marked as delivered.
+        self.replication_shutdown_signal.request();
+        let replication_result = self
+            .replication_thread
+            .take()
+            .map_or(Ok(()), JoinHandle::join)
+            .map_err(|_| BusError::ThreadPanicked("replication"));
+
+        // Subscribers which reconnect while messages are drained still get authenticated.
+        self.zap_shutdown_signal.request();
+        let zap_result = self
+            .zap_thread
+            .take()
+            .map_or(Ok(()), JoinHandle::join)
+            .map_err(|_| BusError::ThreadPanicked("ZAP handler"));
+
+        // Metrics stay available while messages are drained.
+        if let Some(metrics_server) = self.metrics_server.as_mut() {
+            metrics_server.stop();
+        }
+
+        control_result
+            .and(receiver_result)
+            .and(federation_result)
+            .and(replication_result)
+            .and(zap_result)
+            .and(sender_result)
+    }
+}
+
+impl Drop for Bus {
+    fn drop(&mut self) {
+        if self.receiver_thread.is_none() && self.sender_thread.is_none() {
+            return;
+        }
+        if let Err(error) = self.stop() {
+            log::error!("failed to stop BUS because of: {}", error);
+        }
+    }
+}
+
+impl fmt::Debug for Bus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Bus")
+            .field("stats", &self.stats.snapshot())
+            .field("running", &self.receiver_thread.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// BusShutdownSummary
+//-----------------------------------------------------------------------------------------
+
+/// Outcome of draining accepted messages on shutdown.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct BusShutdownSummary {
+    /// Messages published after shutdown was requested.
+    pub flushed_messages: u64,
+    /// Messages left in the publishing queue and retry buffer when the deadline passed,
+    /// they are replayed on the next start when write-ahead log is enabled.
+    pub dropped_messages: u64,
+}
+
+impl fmt::Display for BusShutdownSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "flushed {} messages, dropped {} messages",
+            self.flushed_messages, self.dropped_messages
+        )
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// MessagesSender
+//-----------------------------------------------------------------------------------------
+
+#[derive(Debug)]
+struct PublishingMessage {
+    wal_sequence: Option<u64>,
+    attempts: usize,
+    /// Message comes from the previous run or from the taken over BUS, it is published
+    /// before subscribers could reconnect, so it is never taken as unroutable.
+    is_replayed: bool,
+    message_bytes: Vec<u8>,
+}
+
+/// State of the sender thread which publishes accepted messages to subscribers.
+#[derive(Debug)]
+struct MessagesSender {
+    publishers: Vec<BusPublisherData>,
+    publisher_selector: Box<dyn PublisherSelector>,
+    publishing_queue: BoundedQueue<PublishingMessage>,
+    errored_messages_buffer: VecDeque<PublishingMessage>,
+    write_ahead_log: Option<DeadLockSafeMutex<WriteAheadLog>>,
+    dead_letter_queue: DeadLockSafeMutex<DeadLetterQueue>,
+    capture_writer: Option<DeadLockSafeMutex<CaptureWriter>>,
+    stats: Arc<BusStats>,
+    expiry_policy: ExpiryPolicy,
+    unroutable_policy: UnroutablePolicy,
+    publish_retry_budget: usize,
+    retry_buffer_capacity: usize,
+    requests_count_inside_one_group: usize,
+    shutdown_signal: ShutdownSignal,
+    shutdown_deadline: Duration,
+    control_state: BusControlState,
+    control_commands: mpsc::Receiver<SenderControlCommand>,
+    replication: Option<Replication>,
+    access_control: Option<Arc<AccessControl>>,
+}
+
+impl MessagesSender {
+    /// Publishes messages until shutdown is requested and then drains the publishing
+    /// queue and retry buffer until they are empty or the shutdown deadline passes.
+    fn run(mut self) -> BusShutdownSummary {
+        let mut total_processed_messages_count: usize = 0;
+        let mut drain_deadline: Option<Instant> = None;
+        let mut summary = BusShutdownSummary::default();
+        let mut last_subscriptions_check = Instant::now();
+
+        loop {
+            self.execute_control_commands();
+
+            if last_subscriptions_check.elapsed() >= SUBSCRIPTIONS_CHECK_INTERVAL {
+                self.receive_subscription_events();
+                last_subscriptions_check = Instant::now();
+            }
+
+            if drain_deadline.is_none() && self.shutdown_signal.is_requested() {
+                log::debug!(
+                    "draining {} queued and {} retried messages",
+                    self.publishing_queue.len(),
+                    self.errored_messages_buffer.len()
+                );
+                drain_deadline = Some(Instant::now() + self.shutdown_deadline);
+            }
+
+            if let Some(drain_deadline) = drain_deadline {
+                let left_messages_count =
+                    self.publishing_queue.len() + self.errored_messages_buffer.len();
+                if left_messages_count == 0 || Instant::now() >= drain_deadline {
+                    summary.dropped_messages = left_messages_count as u64;
+                    break;
+                }
+            }
+
+            let message =
+                match self.errored_messages_buffer.pop_front() {
+                    Some(message) => message,
+                    None => match self.publishing_queue.pop_timeout(Duration::from_millis(
+                        u64::from(SHUTDOWN_CHECK_INTERVAL_MILLISECONDS),
+                    )) {
+                        Some(message) => message,
+                        None => continue,
+                    },
+                };
+
+            let Some(message) = self.unless_expired(message) else {
+                continue;
+            };
+
+            let index_of_publisher_that_will_be_used = self
+                .publisher_selector
+                .select(&self.publishers, message.message_bytes.as_slice());
+
+            let Some(message) =
+                self.unless_unroutable(index_of_publisher_that_will_be_used, message)
+            else {
+                continue;
+            };
+
+            match (*self.publishers[index_of_publisher_that_will_be_used]).send(
+                Message::from(message.message_bytes.clone()),
+                ZEROMQ_ZERO_FLAG,
+            ) {
+                Ok(()) => {
+                    log::trace!("> {:?}", message.message_bytes);
+                    total_processed_messages_count += 1;
+                    self.record_published(
+                        index_of_publisher_that_will_be_used,
+                        message.message_bytes.as_slice(),
+                    );
+                    if drain_deadline.is_some() {
+                        summary.flushed_messages += 1;
+                    }
+                    mark_delivered(self.write_ahead_log.as_ref(), message.wal_sequence);
+                }
+                Err(error) => {
+                    log::error!("failed to send message because of: {}", error);
+                    self.stats.publish_failures.increment();
+                    if let Ok((kind, _)) =
+                        decode_message_kind_and_uuid(message.message_bytes.as_slice())
+                    {
+                        self.stats.kind(kind).publish_failures.increment();
+                    }
+                    self.retry_later(message);
+                }
+            }
+
+            self.stats
+                .publishing_queue_depth
+                .set(self.publishing_queue.len() as u64);
+            self.stats
+                .retry_buffer_depth
+                .set(self.errored_messages_buffer.len() as u64);
+
+            self.publishers[index_of_publisher_that_will_be_used].update_last_action_time();
+
+            if total_processed_messages_count
+                .is_multiple_of(self.requests_count_inside_one_group)
+            {
+                log::debug!(
+                    "{:?} | total processed {} messages, {:?}",
+                    SystemTime::now(),
+                    total_processed_messages_count,
+                    self.stats.snapshot()
+                );
+            }
+        }
+
+        log::debug!("sender loop has stopped, {}", summary);
+        summary
+    }
+
+    fn execute_control_commands(&mut self) {
+        while let Ok(command) = self.control_commands.try_recv() {
+            match command {
+                SenderControlCommand::PurgeRetryBuffer(reply_sender) => {
+                    let purged_messages_count = self.errored_messages_buffer.len() as u64;
+                    for message in self.errored_messages_buffer.drain(..) {
+                        mark_delivered(self.write_ahead_log.as_ref(), message.wal_sequence);
+                    }
+                    self.stats.retry_buffer_depth.set(0);
+
+                    log::warn!(
+                        "purged {} messages from retry buffer",
+                        purged_messages_count
+                    );
+                    let _ = reply_sender.send(purged_messages_count);
+                }
+            }
+        }
+    }
+
+    /// Publisher sockets report subscriptions of their subscribers as incoming messages.
+    fn receive_subscription_events(&self) {
+        for (publisher_index, publisher) in self.publishers.iter().enumerate() {
+            loop {
+                let mut event_message = Message::new();
+                match publisher.recv(&mut event_message, zmq::DONTWAIT) {
+                    Ok(()) => {
+                        let user_id = event_message.gets("User-Id").map(str::to_string);
+                        let event = event_message.to_vec();
+                        log::trace!("< [SUBSCRIPTION] {} {:?}", publisher_index, event);
+                        if !self.apply_subscription(publisher, user_id.as_deref(), &event) {
+                            continue;
+                        }
+                        if let Some(replication) = &self.replication {
+                            replication.subscription(publisher_index, event.as_slice());
+                        }
+                        self.control_state
+                            .apply_subscription_event(publisher_index, event);
+                    }
+                    Err(zmq::Error::EAGAIN) => break,
+                    Err(error) => {
+                        log::error!(
+                            "failed to receive subscription event because of: {}",
+                            error
+                        );
+                        break;
+                    }
+                }
+            }
+        }
+
+        for (gauge, count) in self
+            .stats
+            .publisher_subscriptions()
+            .iter()
+            .zip(self.control_state.subscriptions_counts())
+        {
+            gauge.set(count as u64);
+        }
+    }
+
+    /// Publisher sockets apply subscriptions themselves unless access control is enabled,
+    /// then only the allowed ones are applied and the others are never seen by
+    /// subscribers. Subscriptions are applied to the subscriber of the event which has
+    /// been received the last, so this is called right after receiving it.
+    fn apply_subscription(
+        &self,
+        publisher: &Socket,
+        user_id: Option<&str>,
+        event: &[u8],
+    ) -> bool {
+        let Some(access_control) = &self.access_control else {
+            return true;
+        };
+
+        match access_control.apply_subscription_event(publisher, user_id, event) {
+            Ok(true) => true,
+            Ok(false) => {
+                if let Some((1, prefix)) = event.split_first() {
+                    log::warn!(
+                        "denied subscription to prefix {:?} of client {}",
+                        prefix,
+                        user_id.unwrap_or("without user id")
+                    );
+                    self.stats.denied_subscriptions.increment();
+                }
+                false
+            }
+            Err(error) => {
+                log::error!("failed to apply subscription event because of: {}", error);
+                false
+            }
+        }
+    }
+
+    fn record_published(&self, publisher_index: usize, message_bytes: &[u8]) {
+        let message_length = message_bytes.len() as u64;
+        self.stats.published_messages.increment();
+        self.stats.published_bytes.add(message_length);
+        self.stats.publisher_sends()[publisher_index].increment();
+        capture(
+            self.capture_writer.as_ref(),
+            CaptureSource::Published(publisher_index),
+            message_bytes,
+        );
+
+        if let Ok((kind, _)) = decode_message_kind_and_uuid(message_bytes) {
+            let kind_stats = self.stats.kind(kind);
+            kind_stats.published_messages.increment();
+            kind_stats.published_bytes.add(message_length);
+        }
+
+        if let Some(timestamp) = decode_message_header(message_bytes)
+            .ok()
+            .and_then(|header| header.timestamp)
+        {
+            self.stats
+                .latency
+                .observe(milliseconds_since_unix_epoch().saturating_sub(timestamp));
+        }
+    }
+
+    /// Retried messages as well as the ones which have waited in the queue are not
+    /// published after nobody waits for them.
+    fn unless_expired(&self, message: PublishingMessage) -> Option<PublishingMessage> {
+        if !is_expired(message.message_bytes.as_slice()) {
+            return Some(message);
+        }
+
+        mark_delivered(self.write_ahead_log.as_ref(), message.wal_sequence);
+        expire(
+            &self.dead_letter_queue,
+            &self.stats,
+            self.expiry_policy,
+            message.message_bytes,
+        );
+        None
+    }
+
+    /// Message which no subscriber of the chosen publisher wants is not sent, unless the
+    /// policy is to publish it. Subscriptions are received right before judging, so the
+    /// ones made just before the message was published are not missed.
+    fn unless_unroutable(
+        &self,
+        publisher_index: usize,
+        message: PublishingMessage,
+    ) -> Option<PublishingMessage> {
+        if self.unroutable_policy == UnroutablePolicy::Publish || message.is_replayed {
+            return Some(message);
+        }
+
+        self.receive_subscription_events();
+        if self
+            .control_state
+            .is_wanted(publisher_index, message.message_bytes.as_slice())
+        {
+            return Some(message);
+        }
+
+        self.stats.unroutable_messages.increment();
+        if let Ok((kind, uuid)) =
+            decode_message_kind_and_uuid(message.message_bytes.as_slice())
+        {
+            self.stats.kind(kind).unroutable_messages.increment();
+            log::trace!("{:?} message {} has no subscribers", kind, uuid);
+        }
+
+        mark_delivered(self.write_ahead_log.as_ref(), message.wal_sequence);
+        if self.unroutable_policy == UnroutablePolicy::DeadLetter {
+            dead_letter(
+                &self.dead_letter_queue,
+                &self.stats,
+                DeadLetterReason::Unroutable,
+                message.message_bytes,
+                None,
+            );
+        }
+        None
+    }
+
+    /// Puts message which was not published into the retry buffer, or into the dead-letter
+    /// queue when its retry budget is exhausted.
+    fn retry_later(&mut self, message: PublishingMessage) {
+        let attempts = message.attempts + 1;
+        if attempts >= self.publish_retry_budget {
+            dead_letter(
+                &self.dead_letter_queue,
+                &self.stats,
+                DeadLetterReason::RetryBudgetExhausted(attempts),
+                message.message_bytes,
+                None,
+            );
+            mark_delivered(self.write_ahead_log.as_ref(), message.wal_sequence);
+            return;
+        }
+
+        if self.errored_messages_buffer.len() >= self.retry_buffer_capacity {
+            if let Some(oldest_message) = self.errored_messages_buffer.pop_front() {
+                self.stats.retry_buffer_overflows.increment();
+                dead_letter(
+                    &self.dead_letter_queue,
+                    &self.stats,
+                    DeadLetterReason::RetryBufferOverflow,
+                    oldest_message.message_bytes,
+                    None,
+                );
+                mark_delivered(self.write_ahead_log.as_ref(), oldest_message.wal_sequence);
+            }
+        }
+
+        if let (Some(replication), Some(wal_sequence)) =
+            (&self.replication, message.wal_sequence)
+        {
+            replication.retrying(wal_sequence);
+        }
+
+        self.errored_messages_buffer.push_back(PublishingMessage {
+            attempts,
+            ..message
+        });
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// MessagesRouter
+//-----------------------------------------------------------------------------------------
+
+/// State of the receiver thread which decides where every received message should go.
+struct MessagesRouter {
+    router_socket: Socket,
+    work_queue: WorkQueue,
+    work_queue_wal_sequences: HashMap<Uuid, u64>,
+    write_ahead_log: Option<DeadLockSafeMutex<WriteAheadLog>>,
+    dead_letter_queue: DeadLockSafeMutex<DeadLetterQueue>,
+    capture_writer: Option<DeadLockSafeMutex<CaptureWriter>>,
+    publishing_queue: BoundedQueue<PublishingMessage>,
+    kind_priorities: HashMap<ZeromqMessageKind, MessagePriority>,
+    stats: Arc<BusStats>,
+    expiry_policy: ExpiryPolicy,
+    control_state: BusControlState,
+    paused_messages: PausedMessages,
+    membership: Membership,
+    /// Clients which have sent nothing for this long are not listed by the control socket.
+    client_activity_ttl: Duration,
+    service_registry: ServiceRegistry,
+    federation: Option<Federation>,
+    scheduled_messages: ScheduledMessages,
+    dedup_window: Option<DeduplicationWindow<(ZeromqMessageKind, Uuid)>>,
+    rate_limiter: Option<RateLimiter>,
+    rate_limit_policy: RateLimitPolicy,
+    access_control: Option<Arc<AccessControl>>,
+    /// Set while messages of the previous run or of the taken over BUS are routed.
+    is_replaying: bool,
+}
+
+impl MessagesRouter {
+    /// Routes messages which previous BUS run has accepted but not delivered.
+    fn replay(&mut self, undelivered_records: Vec<WriteAheadLogRecord>) {
+        if !undelivered_records.is_empty() {
+            log::debug!(
+                "replaying {} messages from write-ahead log",
+                undelivered_records.len()
+            );
+        }
+
+        self.is_replaying = true;
+        for record in undelivered_records {
+            match decode_message_kind_and_uuid(record.message_bytes.as_slice()) {
+                Ok((message_kind, message_uuid)) => {
+                    if let Err(reason) = self.route(
+                        None,
+                        None,
+                        message_kind,
+                        message_uuid,
+                        record.message_bytes,
+                        Some(record.sequence),
+                    ) {
+                        log::error!(
+                            "failed to replay message {} because of: {}",
+                            message_uuid,
+                            reason
+                        );
+                    }
+                }
+                Err(error) => {
+                    log::error!(
+                        "dropped message {} from write-ahead log because of: {}",
+                        record.sequence,
+                        error
+                    );
+                    mark_delivered(self.write_ahead_log.as_ref(), Some(record.sequence));
+                }
+            }
+        }
+        self.is_replaying = false;
+    }
+
+    /// Receives messages until shutdown is requested. Socket is polled with timeout only
+    /// when it has nothing to receive, so shutdown request is noticed without slowing down
+    /// the busy BUS.
+    fn run(&mut self, shutdown_signal: &ShutdownSignal) {
+        log::debug!("running received loop");
+
+        let mut last_membership_check = Instant::now();
+
+        while !shutdown_signal.is_requested() {
+            self.release_resumed_messages();
+            self.release_due_messages();
+
+            if last_membership_check.elapsed() >= MEMBERSHIP_CHECK_INTERVAL {
+                self.expire_members();
+                self.control_state
+                    .forget_idle_clients(self.client_activity_ttl);
+                if let Some(rate_limiter) = &mut self.rate_limiter {
+                    rate_limiter.forget_idle(Instant::now());
+                }
+                last_membership_check = Instant::now();
+            }
+
+            match receive_with_user_id(&self.router_socket)
+                .map(|(frames, user_id)| (<[Vec<u8>; 2]>::try_from(frames), user_id))
+            {
+                Ok((Ok([identity_bytes, message_bytes]), user_id)) => {
+                    self.receive(identity_bytes, message_bytes, user_id.as_deref());
+                }
+                Ok((Err(frames), _)) => {
+                    log::error!(
+                        "failed to receive message because of: unexpected {} frames",
+                        frames.len()
+                    );
+                }
+                Err(zmq::Error::EAGAIN) => {
+                    if let Err(error) = self
+                        .router_socket
+                        .poll(zmq::POLLIN, i64::from(SHUTDOWN_CHECK_INTERVAL_MILLISECONDS))
+                    {
+                        log::error!("failed to poll BUS router socket because of: {}", error);
+                    }
+                }
+                Err(error) => {
+                    log::error!("failed to receive message because of: {}", error);
+                }
+            }
+        }
+
+        if !self.paused_messages.is_empty() {
+            log::warn!(
+                "{} messages of paused kinds were left undelivered",
+                self.paused_messages.len()
+            );
+        }
+        if !self.scheduled_messages.is_empty() {
+            log::warn!(
+                "{} scheduled messages were left undelivered",
+                self.scheduled_messages.len()
+            );
+        }
+
+        log::debug!("received loop has stopped");
+    }
+
+    fn receive(
+        &mut self,
+        identity_bytes: WorkerIdentity,
+        message_bytes: Vec<u8>,
+        user_id: Option<&str>,
+    ) {
+        log::trace!("< [IDENTITY] {:?}", identity_bytes);
+        log::trace!("< {:?}", message_bytes);
+        self.stats.received_messages.increment();
+        self.stats.received_bytes.add(message_bytes.len() as u64);
+        capture(
+            self.capture_writer.as_ref(),
+            CaptureSource::Received(identity_bytes.clone()),
+            message_bytes.as_slice(),
+        );
+
+        let (message_kind, message_uuid) = match validate_message(message_bytes.as_slice()) {
+            Ok(message_kind_and_uuid) => {
+                self.control_state
+                    .record_client_message(identity_bytes.clone());
+                self.membership
+                    .touch(identity_bytes.as_slice(), Instant::now());
+                let kind_stats = self.stats.kind(message_kind_and_uuid.0);
+                kind_stats.received_messages.increment();
+                kind_stats.received_bytes.add(message_bytes.len() as u64);
+                message_kind_and_uuid
+            }
+            Err(reason) => {
+                log::warn!("rejected invalid message because of: {}", reason);
+                self.confirm(
+                    identity_bytes,
+                    read_message_uuid(message_bytes.as_slice()),
+                    Some(reason.clone()),
+                );
+                dead_letter(
+                    &self.dead_letter_queue,
+                    &self.stats,
+                    DeadLetterReason::ValidationFailed(reason),
+                    message_bytes,
+                    user_id,
+                );
+                return;
+            }
+        };
+
+        // Heartbeats are neither persisted nor confirmed, they matter for the current
+        // connection only.
+        if message_kind == ZeromqMessageKind::Heartbeat {
+            self.receive_heartbeat(identity_bytes.as_slice(), message_bytes.as_slice());
+            return;
+        }
+
+        if !self.is_permitted(user_id, message_kind, message_bytes.as_slice()) {
+            self.deny(identity_bytes, user_id, message_kind, message_uuid);
+            return;
+        }
+
+        // Flood of one client must not starve the others, so messages above its limit are
+        // dropped before they cost anything more.
+        if !self.is_within_rate_limit(identity_bytes.as_slice(), user_id, message_kind) {
+            self.throttle(identity_bytes, user_id, message_kind, message_uuid);
+            return;
+        }
+
+        // Message which this BUS has forwarded to peers is already delivered here, peers
+        // which federate the same kind back must not make it loop.
+        if self
+            .federation
+            .as_ref()
+            .is_some_and(|federation| federation.has_forwarded(message_bytes.as_slice()))
+        {
+            log::trace!("dropped message {} which has looped back", message_uuid);
+            self.stats.federation_looped_messages.increment();
+            self.confirm(identity_bytes, message_uuid, None);
+            return;
+        }
+
+        // Sender resends message with the same uuid when it has not seen the outcome, the
+        // copy which comes inside the window is confirmed without being delivered again.
+        // Responses reuse uuids of their requests, so messages are told apart by kind too.
+        if self.is_duplicate(message_kind, message_uuid) {
+            log::trace!("dropped duplicate message {}", message_uuid);
+            self.stats.deduplicated_messages.increment();
+            self.stats
+                .kind(message_kind)
+                .deduplicated_messages
+                .increment();
+            self.confirm(identity_bytes, message_uuid, None);
+            return;
+        }
+
+        // Message is persisted before it is accepted by any further stage, so it can be
+        // replayed in case if BUS dies before the message is delivered.
+        let (wal_sequence, message_bytes) = match self.persist(message_kind, message_bytes) {
+            Ok(sequence_and_message_bytes) => sequence_and_message_bytes,
+            Err(error) => {
+                log::error!(
+                    "failed to append message to write-ahead log because of: {}",
+                    error
+                );
+                self.confirm(
+                    identity_bytes,
+                    message_uuid,
+                    Some(format!("failed to persist message: {}", error)),
+                );
+                return;
+            }
+        };
+
+        let rejection_reason = self
+            .route(
+                Some(identity_bytes.clone()),
+                user_id,
+                message_kind,
+                message_uuid,
+                message_bytes,
+                wal_sequence,
+            )
+            .err();
+        if rejection_reason.is_none() {
+            self.remember_accepted(message_kind, message_uuid);
+        }
+        self.confirm(identity_bytes, message_uuid, rejection_reason);
+    }
+
+    /// Messages which BUS consumes itself are exempt, except worker registration which
+    /// makes BUS give requests of its kinds to the worker, as if it subscribed to them.
+    fn is_permitted(
+        &self,
+        user_id: Option<&str>,
+        message_kind: ZeromqMessageKind,
+        message_bytes: &[u8],
+    ) -> bool {
+        let Some(access_control) = &self.access_control else {
+            return true;
+        };
+
+        match message_kind {
+            ZeromqMessageKind::WorkerReady => decode_payload::<WorkerReady>(message_bytes)
+                .map_or(true, |payload| {
+                    payload
+                        .kinds
+                        .iter()
+                        .filter_map(|kind| u32::try_from(*kind).ok())
+                        .filter_map(|kind| ZeromqMessageKind::try_from(kind).ok())
+                        .all(|kind| access_control.may_subscribe(user_id, kind))
+                }),
+            // These ones put messages into the dead-letter queue, take them out of it or
+            // cancel delivery of messages of other clients, so they are granted explicitly.
+            ZeromqMessageKind::MessageRejection
+            | ZeromqMessageKind::DeadLetterCommand
+            | ZeromqMessageKind::DeliveryCancellation => {
+                access_control.may_publish(user_id, message_kind)
+            }
+            message_kind if is_control_message(message_kind) => true,
+            message_kind => access_control.may_publish(user_id, message_kind),
+        }
+    }
+
+    fn deny(
+        &self,
+        identity_bytes: WorkerIdentity,
+        user_id: Option<&str>,
+        message_kind: ZeromqMessageKind,
+        message_uuid: Uuid,
+    ) {
+        log::warn!(
+            "denied message {} of kind {:?} to client {}",
+            message_uuid,
+            message_kind,
+            user_id.unwrap_or("without user id")
+        );
+        self.stats.denied_publications.increment();
+        self.confirm(
+            identity_bytes,
+            message_uuid,
+            Some(format!("not allowed to publish kind {:?}", message_kind)),
+        );
+    }
+
+    /// Heartbeats are never limited, so clients are able to keep their connections alive
+    /// while being throttled. Other messages which BUS consumes itself cost it work too, so
+    /// they are limited like published ones.
+    fn is_within_rate_limit(
+        &mut self,
+        identity_bytes: &[u8],
+        user_id: Option<&str>,
+        message_kind: ZeromqMessageKind,
+    ) -> bool {
+        message_kind == ZeromqMessageKind::Heartbeat
+            || self.rate_limiter.as_mut().is_none_or(|rate_limiter| {
+                rate_limiter.allow(
+                    &RateLimitedClient::new(user_id, identity_bytes),
+                    message_kind,
+                    Instant::now(),
+                )
+            })
+    }
+
+    /// Drops message above the rate limit, its sender is told so with the nack policy only.
+    fn throttle(
+        &self,
+        identity_bytes: WorkerIdentity,
+        user_id: Option<&str>,
+        message_kind: ZeromqMessageKind,
+        message_uuid: Uuid,
+    ) {
+        let client = RateLimitedClient::new(user_id, identity_bytes.as_slice());
+        log::debug!("throttled message {} of client {}", message_uuid, client);
+        self.stats.record_throttled(&client, message_kind);
+        if self.rate_limit_policy == RateLimitPolicy::Nack {
+            self.confirm(
+                identity_bytes,
+                message_uuid,
+                Some("rate limit exceeded".to_string()),
+            );
+        }
+    }
+
+    /// Whether the same message has been accepted inside the deduplication window.
+    fn is_duplicate(&mut self, message_kind: ZeromqMessageKind, message_uuid: Uuid) -> bool {
+        let now = Instant::now();
+        !is_control_message(message_kind)
+            && self.dedup_window.as_mut().is_some_and(|dedup_window| {
+                dedup_window
+                    .get(&(message_kind, message_uuid), now)
+                    .is_some()
+            })
+    }
+
+    /// Rejected message is not remembered, so its resent copy gets another chance.
+    fn remember_accepted(&mut self, message_kind: ZeromqMessageKind, message_uuid: Uuid) {
+        if is_control_message(message_kind) {
+            return;
+        }
+        if let Some(dedup_window) = &mut self.dedup_window {
+            dedup_window.insert((message_kind, message_uuid), (), Instant::now());
+        }
+    }
+
+    fn receive_heartbeat(&mut self, identity_bytes: &[u8], message_bytes: &[u8]) {
+        let heartbeat = match decode_payload::<Heartbeat>(message_bytes) {
+            Ok(heartbeat) => heartbeat,
+            Err(reason) => {
+                log::warn!("ignored invalid heartbeat because of: {}", reason);
+                return;
+            }
+        };
+        let heartbeat_interval_ms = u64::try_from(heartbeat.interval_ms).unwrap_or_default();
+
+        if !self
+            .membership
+            .heartbeat(identity_bytes, heartbeat_interval_ms, Instant::now())
+        {
+            return;
+        }
+
+        log::info!(
+            "client {:?} has joined with heartbeat interval {}ms",
+            identity_bytes,
+            heartbeat_interval_ms
+        );
+
+        // Worker which was considered dead gets requests again.
+        let dispatches = self.work_queue.resume_worker(identity_bytes);
+        self.send_work_queue_dispatches(dispatches);
+
+        self.publish_event(ServiceJoined {
+            identity: hex(identity_bytes),
+            heartbeat_interval_ms: heartbeat.interval_ms,
+        });
+    }
+
+    /// Stops routing work to members which have sent nothing during the grace time.
+    fn expire_members(&mut self) {
+        for member in self.membership.expire(Instant::now()) {
+            self.control_state.forget_client(member.identity.as_slice());
+
+            log::warn!(
+                "client {:?} has left after missing heartbeats, its in-flight requests are \
+                 given to other workers",
+                member.identity
+            );
+
+            // Requests which dead worker did not answer are given to other workers, the
+            // same as when worker disconnects.
+            let dispatches = self.work_queue.suspend_worker(member.identity.as_slice());
+            self.send_work_queue_dispatches(dispatches);
+
+            if let Some(service) = self.service_registry.unregister(member.identity.as_slice())
+            {
+                log::info!(
+                    "unregistered service {} instance {}",
+                    service.name,
+                    service.instance_id
+                );
+            }
+
+            self.publish_event(ServiceLeft {
+                identity: hex(member.identity.as_slice()),
+                last_seen_at: i64::try_from(member.last_seen_at).unwrap_or(i64::MAX),
+            });
+        }
+    }
+
+    /// Publishes message which BUS produces itself, it is not persisted.
+    fn publish_event<'de, P: ZeromqMessageTrait<'de>>(&self, payload: P) {
+        let result =
+            encode_message_with_header(Uuid::new_v4(), &MessageHeader::now(), payload)
+                .map_err(|error| error.to_string())
+                .and_then(|message_bytes| self.publish(message_bytes, None));
+
+        if let Err(reason) = result {
+            log::error!("failed to publish BUS event because of: {}", reason);
+        }
+    }
+
+    fn persist(
+        &self,
+        message_kind: ZeromqMessageKind,
+        message_bytes: Vec<u8>,
+    ) -> io::Result<(Option<u64>, Vec<u8>)> {
+        // Control messages are not persisted, because they are meaningful for the current
+        // connections only.
+        match &self.write_ahead_log {
+            Some(write_ahead_log) if !is_control_message(message_kind) => write_ahead_log
+                .lock(move |write_ahead_log| {
+                    let sequence = write_ahead_log.append(message_bytes.as_slice())?;
+                    Ok((Some(sequence), message_bytes))
+                }),
+            _ => Ok((None, message_bytes)),
+        }
+    }
+
+    /// Delivers message to its destination, returned error is the reason of rejection
+    /// which is reported to the sender.
+    fn route(
+        &mut self,
+        identity_bytes: Option<WorkerIdentity>,
+        user_id: Option<&str>,
+        message_kind: ZeromqMessageKind,
+        message_uuid: Uuid,
+        message_bytes: Vec<u8>,
+        wal_sequence: Option<u64>,
+    ) -> Result<(), String> {
+        match message_kind {
+            ZeromqMessageKind::WorkerReady => {
+                if let Some(identity_bytes) = identity_bytes {
+                    let dispatches = register_worker(
+                        &mut self.work_queue,
+                        identity_bytes,
+                        message_bytes.as_slice(),
+                    )?;
+                    self.send_work_queue_dispatches(dispatches);
+                }
+                return Ok(());
+            }
+            ZeromqMessageKind::MessageRejection => {
+                return self.dead_letter_rejected_message(
+                    identity_bytes,
+                    user_id,
+                    message_uuid,
+                    message_bytes.as_slice(),
+                );
+            }
+            ZeromqMessageKind::DeadLetterCommand => {
+                return self.execute_dead_letter_command(
+                    identity_bytes,
+                    message_uuid,
+                    message_bytes.as_slice(),
+                );
+            }
+            ZeromqMessageKind::ServiceRegistration => {
+                if let Some(identity_bytes) = identity_bytes {
+                    self.register_service(identity_bytes, message_bytes.as_slice())?;
+                }
+                return Ok(());
+            }
+            ZeromqMessageKind::ServiceQuery => {
+                return self.answer_service_query(
+                    identity_bytes,
+                    message_uuid,
+                    message_bytes.as_slice(),
+                );
+            }
+            ZeromqMessageKind::DeliveryCancellation => {
+                return self.cancel_delivery(message_bytes.as_slice());
+            }
+            ZeromqMessageKind::ServiceQueryReply => {
+                return Err("service query replies are sent by BUS only".to_string());
+            }
+            ZeromqMessageKind::ControlCommand
+            | ZeromqMessageKind::ControlReply
+            | ZeromqMessageKind::ControlStatsReply
+            | ZeromqMessageKind::ControlClientsReply
+            | ZeromqMessageKind::ControlSubscriptionsReply => {
+                return Err(
+                    "control messages are accepted on the control endpoint".to_string()
+                );
+            }
+            _ => {}
+        }
+
+        if let Some(deliver_at) = future_deliver_at(message_bytes.as_slice()) {
+            return self.schedule(ScheduledMessage {
+                kind: message_kind,
+                uuid: message_uuid,
+                deliver_at,
+                wal_sequence,
+                message_bytes,
+            });
+        }
+
+        let is_paused = self.control_state.is_paused(message_kind);
+
+        if self.work_queue.is_work_queue_kind(message_kind) {
+            if is_paused {
+                return self.hold(message_kind, message_uuid, message_bytes, wal_sequence);
+            }
+            if let Some(wal_sequence) = wal_sequence {
+                let _ = self
+                    .work_queue_wal_sequences
+                    .insert(message_uuid, wal_sequence);
+            }
+            if let Some(dispatch) =
+                self.dispatch_request(message_kind, message_uuid, message_bytes)?
+            {
+                self.send_work_queue_dispatches(vec![dispatch]);
+            }
+            return Ok(());
+        }
+
+        if let Some(identity_bytes) = identity_bytes {
+            // Message could be an answer on the request which was given to this worker.
+            let dispatches = self
+                .work_queue
+                .complete(identity_bytes.as_slice(), message_uuid);
+            self.send_work_queue_dispatches(dispatches);
+        }
+
+        if is_paused {
+            return self.hold(message_kind, message_uuid, message_bytes, wal_sequence);
+        }
+
+        if let Some(federation) = &self.federation {
+            federation.forward(message_kind, message_bytes.as_slice());
+        }
+
+        self.publish(message_bytes, wal_sequence)
+    }
+
+    /// Keeps accepted message of paused kind until the kind is resumed. Message stays in
+    /// write-ahead log, so it is replayed after restart as well.
+    fn hold(
+        &mut self,
+        message_kind: ZeromqMessageKind,
+        message_uuid: Uuid,
+        message_bytes: Vec<u8>,
+        wal_sequence: Option<u64>,
+    ) -> Result<(), String> {
+        log::trace!("holding message {} of paused kind", message_uuid);
+        let outcome = self.paused_messages.hold(PausedMessage {
+            kind: message_kind,
+            uuid: message_uuid,
+            wal_sequence,
+            message_bytes,
+        });
+        self.stats
+            .paused_messages
+            .set(self.paused_messages.len() as u64);
+
+        match outcome {
+            PausedMessageHoldOutcome::Held => Ok(()),
+            PausedMessageHoldOutcome::DroppedOldest(message)
+            | PausedMessageHoldOutcome::DroppedNewest(message) => {
+                log::warn!(
+                    "dropped held {:?} message {} because messages of paused kinds are at capacity",
+                    message.kind,
+                    message.uuid
+                );
+                mark_delivered(self.write_ahead_log.as_ref(), message.wal_sequence);
+                Ok(())
+            }
+            PausedMessageHoldOutcome::Refused(message) => {
+                mark_delivered(self.write_ahead_log.as_ref(), message.wal_sequence);
+                Err("messages of paused kinds are at capacity".to_string())
+            }
+        }
+    }
+
+    /// Holds message until its deliver-at time. Message stays in write-ahead log, so it is
+    /// scheduled again after restart.
+    fn schedule(&mut self, message: ScheduledMessage) -> Result<(), String> {
+        log::trace!(
+            "scheduling {:?} message {} at {}",
+            message.kind,
+            message.uuid,
+            message.deliver_at
+        );
+        let wal_sequence = message.wal_sequence;
+        match self
+            .scheduled_messages
+            .schedule(message, milliseconds_since_unix_epoch())
+        {
+            Ok(replaced_message) => {
+                if let Some(replaced_message) = replaced_message {
+                    mark_delivered(
+                        self.write_ahead_log.as_ref(),
+                        replaced_message.wal_sequence,
+                    );
+                }
+                self.stats
+                    .scheduled_messages
+                    .set(self.scheduled_messages.len() as u64);
+                Ok(())
+            }
+            Err(reason) => {
+                mark_delivered(self.write_ahead_log.as_ref(), wal_sequence);
+                Err(reason)
+            }
+        }
+    }
+
+    fn cancel_delivery(&mut self, message_bytes: &[u8]) -> Result<(), String> {
+        let cancellation = decode_payload::<DeliveryCancellation>(message_bytes)?;
+        let kind = u32::try_from(cancellation.kind)
+            .ok()
+            .and_then(|kind| ZeromqMessageKind::try_from(kind).ok())
+            .ok_or_else(|| format!("unknown message kind {}", cancellation.kind))?;
+        let uuid = Uuid::parse_str(cancellation.uuid.as_str())
+            .map_err(|error| format!("invalid uuid '{}': {}", cancellation.uuid, error))?;
+        let message = self
+            .scheduled_messages
+            .cancel(kind, uuid)
+            .ok_or_else(|| format!("{:?} message {} is not scheduled", kind, uuid))?;
+
+        log::debug!("cancelled delivery of {:?} message {}", kind, uuid);
+        mark_delivered(self.write_ahead_log.as_ref(), message.wal_sequence);
+        self.stats.cancelled_deliveries.increment();
+        self.stats
+            .scheduled_messages
+            .set(self.scheduled_messages.len() as u64);
+        Ok(())
+    }
+
+    fn release_due_messages(&mut self) {
+        if self.scheduled_messages.is_empty() {
+            return;
+        }
+
+        for message in self
+            .scheduled_messages
+            .take_due(milliseconds_since_unix_epoch())
+        {
+            if let Err(reason) = self.route(
+                None,
+                None,
+                message.kind,
+                message.uuid,
+                message.message_bytes,
+                message.wal_sequence,
+            ) {
+                log::error!(
+                    "failed to deliver scheduled message {} because of: {}",
+                    message.uuid,
+                    reason
+                );
+            }
+        }
+        self.stats
+            .scheduled_messages
+            .set(self.scheduled_messages.len() as u64);
+    }
+
+    /// Routes held messages of kinds which were resumed since the previous check.
+    fn release_resumed_messages(&mut self) {
+        if self.paused_messages.is_empty() {
+            return;
+        }
+
+        let control_state = &self.control_state;
+        let messages = self
+            .paused_messages
+            .take_resumed(|kind| control_state.is_paused(kind));
+        self.stats
+            .paused_messages
+            .set(self.paused_messages.len() as u64);
+        if messages.is_empty() {
+            return;
+        }
+        log::debug!(
+            "releasing {} held messages of resumed kinds",
+            messages.len()
+        );
+
+        for message in messages {
+            if let Err(reason) = self.route(
+                None,
+                None,
+                message.kind,
+                message.uuid,
+                message.message_bytes,
+                message.wal_sequence,
+            ) {
+                log::error!(
+                    "failed to route held message {} because of: {}",
+                    message.uuid,
+                    reason
+                );
+            }
+        }
+    }
+
+    /// Moves message which consumer was not able to process to the dead-letter queue.
+    fn dead_letter_rejected_message(
+        &mut self,
+        identity_bytes: Option<WorkerIdentity>,
+        user_id: Option<&str>,
+        message_uuid: Uuid,
+        message_bytes: &[u8],
+    ) -> Result<(), String> {
+        let payload = decode_payload::<MessageRejection>(message_bytes)?;
+        let rejected_message_bytes = payload
+            .message
+            .iter()
+            .map(|byte| u8::try_from(*byte))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|error| format!("rejected message is not a byte array: {}", error))?;
+
+        if let Some(identity_bytes) = identity_bytes {
+            // Rejected request will never be answered by the worker.
+            let dispatches = self
+                .work_queue
+                .complete(identity_bytes.as_slice(), message_uuid);
+            self.send_work_queue_dispatches(dispatches);
+        }
+
+        dead_letter(
+            &self.dead_letter_queue,
+            &self.stats,
+            DeadLetterReason::RejectedByConsumer(payload.reason),
+            rejected_message_bytes,
+            user_id,
+        );
+
+        Ok(())
+    }
+
+    /// Applies dead-letter command and answers with report of affected messages.
+    fn execute_dead_letter_command(
+        &mut self,
+        identity_bytes: Option<WorkerIdentity>,
+        message_uuid: Uuid,
+        message_bytes: &[u8],
+    ) -> Result<(), String> {
+        let Some(identity_bytes) = identity_bytes else {
+            return Ok(());
+        };
+
+        let command = decode_payload::<DeadLetterCommand>(message_bytes)?;
+        let id = command
+            .id
+            .map(u64::try_from)
+            .transpose()
+            .map_err(|error| format!("invalid dead-letter id: {}", error))?;
+
+        let entries = match command.action.as_str() {
+            "list" | "inspect" => self.dead_letter_queue.lock(move |dead_letter_queue| {
+                dead_letter_queue
+                    .entries()
+                    .filter(|entry| id.is_none_or(|id| entry.id == id))
+                    .cloned()
+                    .collect::<Vec<DeadLetter>>()
+            }),
+            "purge" => self
+                .dead_letter_queue
+                .lock(move |dead_letter_queue| dead_letter_queue.remove(id))
+                .map_err(|error| format!("failed to purge dead-letter queue: {}", error))?,
+            "reinject" => self.reinject_dead_letters(id)?,
+            action => return Err(format!("unknown dead-letter command action: {}", action)),
+        };
+
+        let is_inspect = command.action == "inspect";
+        let report_message_bytes = encode_message(
+            message_uuid,
+            DeadLetterReport {
+                entries: entries
+                    .iter()
+                    .map(|entry| entry.report_entry(is_inspect))
+                    .collect(),
+            },
+        )
+        .map_err(|error| format!("failed to encode dead-letter report: {}", error))?;
+
+        if let Err(error) = self
+            .router_socket
+            .send_multipart(vec![identity_bytes, report_message_bytes], zmq::DONTWAIT)
+        {
+            log::error!("failed to send dead-letter report because of: {}", error);
+        }
+
+        Ok(())
+    }
+
+    fn register_service(
+        &mut self,
+        identity_bytes: WorkerIdentity,
+        message_bytes: &[u8],
+    ) -> Result<(), String> {
+        let registration = decode_payload::<ServiceRegistration>(message_bytes)?;
+        let service = ServiceInstance::from_registration(identity_bytes, registration)?;
+
+        log::info!(
+            "registered service {} instance {} version {} handling {:?}",
+            service.name,
+            service.instance_id,
+            service.version,
+            service.handled_kinds
+        );
+
+        drop(self.service_registry.register(service));
+        Ok(())
+    }
+
+    /// Sends registered services which match the query directly to the asking client.
+    fn answer_service_query(
+        &self,
+        identity_bytes: Option<WorkerIdentity>,
+        message_uuid: Uuid,
+        message_bytes: &[u8],
+    ) -> Result<(), String> {
+        let Some(identity_bytes) = identity_bytes else {
+            return Ok(());
+        };
+
+        let query = decode_payload::<ServiceQuery>(message_bytes)?;
+        let kind = query
+            .kind
+            .map(|kind| {
+                u32::try_from(kind)
+                    .ok()
+                    .and_then(|kind| ZeromqMessageKind::try_from(kind).ok())
+                    .ok_or_else(|| format!("unknown message kind {}", kind))
+            })
+            .transpose()?;
+
+        let reply_message_bytes = encode_message(
+            message_uuid,
+            ServiceQueryReply {
+                providers: self
+                    .service_registry
+                    .query(kind, query.name.as_deref())
+                    .into_iter()
+                    .map(ServiceInstance::report_entry)
+                    .collect(),
+            },
+        )
+        .map_err(|error| format!("failed to encode service query reply: {}", error))?;
+
+        if let Err(error) = self
+            .router_socket
+            .send_multipart(vec![identity_bytes, reply_message_bytes], zmq::DONTWAIT)
+        {
+            log::error!("failed to send service query reply because of: {}", error);
+        }
+
+        Ok(())
+    }
+
+    /// Routes dead-lettered messages once again as if they were just received. Messages
+    /// which kind can't be decoded are left inside the queue.
+    fn reinject_dead_letters(&mut self, id: Option<u64>) -> Result<Vec<DeadLetter>, String> {
+        let access_control = self.access_control.clone();
+        let entries = self
+            .dead_letter_queue
+            .lock(move |dead_letter_queue| {
+                dead_letter_queue.remove_where(|entry| {
+                    id.is_none_or(|id| entry.id == id)
+                        && may_reinject(access_control.as_deref(), entry)
+                })
+            })
+            .map_err(|error| {
+                format!(
+                    "failed to remove messages from dead-letter queue: {}",
+                    error
+                )
+            })?;
+
+        for entry in &entries {
+            let (message_kind, message_uuid) = entry
+                .kind_and_uuid()
+                .expect("only decodable messages are reinjected");
+            let (wal_sequence, message_bytes) = self
+                .persist(message_kind, entry.message_bytes.clone())
+                .map_err(|error| format!("failed to persist reinjected message: {}", error))?;
+
+            log::debug!("reinjected dead-lettered message {}", entry.id);
+
+            if let Err(reason) = self.route(
+                None,
+                entry.sender_user_id.as_deref(),
+                message_kind,
+                message_uuid,
+                message_bytes,
+                wal_sequence,
+            ) {
+                log::error!(
+                    "failed to route reinjected message {} because of: {}",
+                    entry.id,
+                    reason
+                );
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Sends publish confirmation to the sender of message. Confirmations are never waited
+    /// for, so a client which does not read them is not able to block the BUS.
+    fn confirm(
+        &self,
+        identity_bytes: WorkerIdentity,
+        uuid: Uuid,
+        rejection_reason: Option<String>,
+    ) {
+        let confirmation_message_bytes = match encode_message(
+            uuid,
+            PublishConfirmation {
+                accepted: rejection_reason.is_none(),
+                reason: rejection_reason,
+            },
+        ) {
+            Ok(confirmation_message_bytes) => confirmation_message_bytes,
+            Err(error) => {
+                log::error!(
+                    "failed to encode publish confirmation because of: {}",
+                    error
+                );
+                return;
+            }
+        };
+
+        if let Err(error) = self.router_socket.send_multipart(
+            vec![identity_bytes, confirmation_message_bytes],
+            zmq::DONTWAIT,
+        ) {
+            log::trace!("failed to send publish confirmation because of: {}", error);
+        }
+    }
+
+    /// Hands message over to the sender thread, behaviour on full publishing queue depends
+    /// on its overflow policy.
+    fn publish(
+        &self,
+        message_bytes: Vec<u8>,
+        wal_sequence: Option<u64>,
+    ) -> Result<(), String> {
+        let priority = self.priority(message_bytes.as_slice());
+
+        match self.publishing_queue.push_to_lane(
+            priority as usize,
+            PublishingMessage {
+                wal_sequence,
+                attempts: 0,
+                is_replayed: self.is_replaying,
+                message_bytes,
+            },
+        ) {
+            BoundedQueuePushOutcome::Pushed => Ok(()),
+            BoundedQueuePushOutcome::PushedAfterBlocking => {
+                self.stats.publishing_queue_blocked_pushes.increment();
+                Ok(())
+            }
+            BoundedQueuePushOutcome::DroppedOldest(dropped_message) => {
+                log::trace!("publishing queue is full, dropped the oldest message");
+                self.stats
+                    .publishing_queue_dropped_oldest_messages
+                    .increment();
+                mark_delivered(self.write_ahead_log.as_ref(), dropped_message.wal_sequence);
+                Ok(())
+            }
+            BoundedQueuePushOutcome::Refused(refused_message) => {
+                mark_delivered(self.write_ahead_log.as_ref(), refused_message.wal_sequence);
+
+                if self.publishing_queue.policy() == OverflowPolicy::RejectWithNack {
+                    self.stats.publishing_queue_rejected_messages.increment();
+                    Err("publishing queue is full".to_string())
+                } else {
+                    log::trace!("publishing queue is full, dropped the newest message");
+                    self.stats
+                        .publishing_queue_dropped_newest_messages
+                        .increment();
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    /// Priority from the message header wins over priority of its kind.
+    fn priority(&self, message_bytes: &[u8]) -> MessagePriority {
+        decode_message_header(message_bytes)
+            .ok()
+            .and_then(|header| header.priority)
+            .or_else(|| {
+                decode_message_kind_and_uuid(message_bytes)
+                    .ok()
+                    .and_then(|(kind, _)| self.kind_priorities.get(&kind).copied())
+            })
+            .unwrap_or_default()
+    }
+
+    /// Gives request to the work queue, requests which it drops because of overflow are
+    /// not delivered anymore, returned error is the reason of rejection.
+    fn dispatch_request(
+        &mut self,
+        kind: ZeromqMessageKind,
+        uuid: Uuid,
+        message_bytes: Vec<u8>,
+    ) -> Result<Option<WorkQueueDispatch>, String> {
+        match self.work_queue.dispatch(kind, uuid, message_bytes) {
+            WorkQueueDispatchOutcome::Dispatched(dispatch) => Ok(Some(dispatch)),
+            WorkQueueDispatchOutcome::Held => Ok(None),
+            WorkQueueDispatchOutcome::DroppedOldest(dropped_uuid) => {
+                log::trace!("work queue is full, dropped the oldest request");
+                self.stats.work_queue_overflows.increment();
+                mark_delivered(
+                    self.write_ahead_log.as_ref(),
+                    self.work_queue_wal_sequences.remove(&dropped_uuid),
+                );
+                Ok(None)
+            }
+            WorkQueueDispatchOutcome::Refused => {
+                self.stats.work_queue_overflows.increment();
+                mark_delivered(
+                    self.write_ahead_log.as_ref(),
+                    self.work_queue_wal_sequences.remove(&uuid),
+                );
+
+                if self.work_queue.overflow_policy() == OverflowPolicy::DropNewest {
+                    log::trace!("work queue is full, dropped the newest request");
+                    Ok(None)
+                } else {
+                    Err("work queue is full".to_string())
+                }
+            }
+        }
+    }
+
+    fn send_work_queue_dispatches(&mut self, dispatches: Vec<WorkQueueDispatch>) {
+        let mut dispatches = VecDeque::from(dispatches);
+
+        while let Some(dispatch) = dispatches.pop_front() {
+            if is_expired(dispatch.message_bytes.as_slice()) {
+                mark_delivered(
+                    self.write_ahead_log.as_ref(),
+                    self.work_queue_wal_sequences.remove(&dispatch.uuid),
+                );
+                dispatches.extend(
+                    self.work_queue
+                        .complete(dispatch.worker_identity.as_slice(), dispatch.uuid),
+                );
+                expire(
+                    &self.dead_letter_queue,
+                    &self.stats,
+                    self.expiry_policy,
+                    dispatch.message_bytes,
+                );
+                continue;
+            }
+
+            // Worker which does not keep up must not stop the router loop.
+            match self.router_socket.send_multipart(
+                vec![
+                    dispatch.worker_identity.clone(),
+                    dispatch.message_bytes.clone(),
+                ],
+                zmq::DONTWAIT,
+            ) {
+                Ok(()) => {
+                    log::trace!(
+                        "> [WORKER] {:?} {:?}",
+                        dispatch.worker_identity,
+                        dispatch.message_bytes
+                    );
+                    mark_delivered(
+                        self.write_ahead_log.as_ref(),
+                        self.work_queue_wal_sequences.remove(&dispatch.uuid),
+                    );
+                }
+                Err(zmq::Error::EHOSTUNREACH) => {
+                    log::warn!(
+                        "failed to send request to worker {:?} because it has gone",
+                        dispatch.worker_identity
+                    );
+
+                    // Worker has gone, so the current request and the ones which it did not
+                    // answer are given to other workers.
+                    dispatches.extend(
+                        self.work_queue
+                            .unregister_worker(dispatch.worker_identity.as_slice()),
+                    );
+                }
+                Err(error) => {
+                    log::debug!(
+                        "failed to send request to worker {:?} because of: {}",
+                        dispatch.worker_identity,
+                        error
+                    );
+
+                    // Worker is busy, so request waits until one of workers is able to
+                    // take it.
+                    self.work_queue
+                        .hold_unsent(dispatch.worker_identity.as_slice(), dispatch.uuid);
+                }
+            }
+        }
+    }
+}
+
+/// Reads uuid of the message which kind can't be decoded, nil uuid is returned for
+/// messages which are too short.
+fn read_message_uuid(message_bytes: &[u8]) -> Uuid {
+    message_bytes
+        .get(4..MESSAGE_KIND_AND_UUID_LENGTH)
+        .and_then(|uuid_bytes| Uuid::from_slice(uuid_bytes).ok())
+        .unwrap_or_else(Uuid::nil)
+}
+
+fn mark_delivered(
+    write_ahead_log: Option<&DeadLockSafeMutex<WriteAheadLog>>,
+    wal_sequence: Option<u64>,
+) {
+    if let (Some(write_ahead_log), Some(wal_sequence)) = (write_ahead_log, wal_sequence) {
+        if let Err(error) = write_ahead_log
+            .lock(move |write_ahead_log| write_ahead_log.mark_delivered(wal_sequence))
+        {
+            log::error!(
+                "failed to mark message {} as delivered in write-ahead log because of: {}",
+                wal_sequence,
+                error
+            );
+        }
+    }
+}
+
+fn capture(
+    capture_writer: Option<&DeadLockSafeMutex<CaptureWriter>>,
+    source: CaptureSource,
+    message_bytes: &[u8],
+) {
+    if let Some(capture_writer) = capture_writer {
+        let record = CaptureRecord {
+            captured_at: milliseconds_since_unix_epoch(),
+            source,
+            message_bytes: message_bytes.to_vec(),
+        };
+        if let Err(error) =
+            capture_writer.lock(move |capture_writer| capture_writer.write(&record))
+        {
+            log::error!("failed to capture message because of: {}", error);
+        }
+    }
+}
+
+fn register_worker(
+    work_queue: &mut WorkQueue,
+    identity_bytes: WorkerIdentity,
+    message_bytes: &[u8],
+) -> Result<Vec<WorkQueueDispatch>, String> {
+    let payload = decode_payload::<WorkerReady>(message_bytes)?;
+
+    let kinds = payload
+        .kinds
+        .iter()
+        .filter_map(|kind| u32::try_from(*kind).ok())
+        .filter_map(|kind| ZeromqMessageKind::try_from(kind).ok())
+        .collect::<Vec<ZeromqMessageKind>>();
+    let credit = usize::try_from(payload.credit).unwrap_or(0);
+
+    log::debug!(
+        "registered worker {:?} for kinds {:?} with credit {}",
+        identity_bytes,
+        kinds,
+        credit
+    );
+
+    Ok(work_queue.register_worker(identity_bytes, kinds.as_slice(), credit))
+}
+
+/// Receives all frames of one message, user id which ZAP handler has given to the client
+/// is kept by the frames, so it is read before they are turned into bytes.
+fn receive_with_user_id(socket: &Socket) -> zmq::Result<(Vec<Vec<u8>>, Option<String>)> {
+    let mut frames = Vec::new();
+    let mut user_id = None;
+    loop {
+        let mut frame = Message::new();
+        socket.recv(&mut frame, zmq::DONTWAIT)?;
+        if user_id.is_none() {
+            user_id = frame.gets("User-Id").map(str::to_string);
+        }
+        let has_more_frames = frame.get_more();
+        frames.push(frame.to_vec());
+        if !has_more_frames {
+            return Ok((frames, user_id));
+        }
+    }
+}
+
+/// Checks that message has known kind and well-formed payload, so consumers never receive
+/// a message which nobody is able to decode.
+fn validate_message(message_bytes: &[u8]) -> Result<(ZeromqMessageKind, Uuid), String> {
+    let message_kind_and_uuid = decode_message_kind_and_uuid(message_bytes)
+        .map_err(|error| format!("failed to decode message kind: {}", error))?;
+
+    let payload_bytes = message_payload_bytes(message_bytes)
+        .map_err(|error| format!("failed to decode message header: {}", error))?;
+    let _ = serde_json::from_slice::<IgnoredAny>(payload_bytes)
+        .map_err(|error| format!("failed to decode message payload: {}", error))?;
+
+    Ok(message_kind_and_uuid)
+}
+
+/// Decodes payload of already validated message.
+fn decode_payload<P: for<'de> ZeromqMessageTrait<'de>>(
+    message_bytes: &[u8],
+) -> Result<P, String> {
+    message_payload_bytes(message_bytes)
+        .and_then(decode_message_payload::<'_, P>)
+        .map_err(|error| format!("failed to decode {:?} payload: {}", P::kind(), error))
+}
+
+/// Deliver-at time from the message header when it has not come yet.
+fn future_deliver_at(message_bytes: &[u8]) -> Option<u64> {
+    decode_message_header(message_bytes)
+        .ok()
+        .and_then(|header| header.deliver_at)
+        .filter(|deliver_at| *deliver_at > milliseconds_since_unix_epoch())
+}
+
+fn dead_letter(
+    dead_letter_queue: &DeadLockSafeMutex<DeadLetterQueue>,
+    stats: &BusStats,
+    reason: DeadLetterReason,
+    message_bytes: Vec<u8>,
+    sender_user_id: Option<&str>,
+) {
+    let reason_description = reason.to_string();
+    let sender_user_id = sender_user_id.map(str::to_string);
+    stats.dead_lettered_messages.increment();
+
+    match dead_letter_queue.lock(move |dead_letter_queue| {
+        dead_letter_queue.push_from_sender(reason, message_bytes, sender_user_id)
+    }) {
+        Ok(id) => log::warn!(
+            "message moved to dead-letter queue as {} because of: {}",
+            id,
+            reason_description
+        ),
+        Err(error) => log::error!(
+            "failed to move message to dead-letter queue because of: {}",
+            error
+        ),
+    }
+}
+
+/// Messages which clients have handed over as rejected or invalid ones have never been
+/// checked against access control of their kind, so they are reinjected only when their
+/// sender may publish them. The other ones were permitted when BUS received them.
+fn may_reinject(access_control: Option<&AccessControl>, entry: &DeadLetter) -> bool {
+    let Some((message_kind, _)) = entry.kind_and_uuid() else {
+        return false;
+    };
+
+    match (access_control, &entry.reason) {
+        (
+            Some(access_control),
+            DeadLetterReason::ValidationFailed(_) | DeadLetterReason::RejectedByConsumer(_),
+        ) if !access_control.may_publish(entry.sender_user_id.as_deref(), message_kind) => {
+            log::warn!(
+                "refused to reinject dead-lettered message {} which client {} may not publish",
+                entry.id,
+                entry.sender_user_id.as_deref().unwrap_or("without user id")
+            );
+            false
+        }
+        _ => true,
+    }
+}
+
+/// Whether deadline from the message header has passed.
+fn is_expired(message_bytes: &[u8]) -> bool {
+    decode_message_header(message_bytes)
+        .is_ok_and(|header| header.is_expired_at(milliseconds_since_unix_epoch()))
+}
+
+fn expire(
+    dead_letter_queue: &DeadLockSafeMutex<DeadLetterQueue>,
+    stats: &BusStats,
+    expiry_policy: ExpiryPolicy,
+    message_bytes: Vec<u8>,
+) {
+    stats.expired_messages.increment();
+    if let Ok((kind, uuid)) = decode_message_kind_and_uuid(message_bytes.as_slice()) {
+        stats.kind(kind).expired_messages.increment();
+        log::debug!("{:?} message {} has expired", kind, uuid);
+    }
+
+    match expiry_policy {
+        ExpiryPolicy::Drop => {}
+        ExpiryPolicy::DeadLetter => dead_letter(
+            dead_letter_queue,
+            stats,
+            DeadLetterReason::Expired,
+            message_bytes,
+            None,
+        ),
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// Tests
+//-----------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::bus::may_reinject;
+    use crate::bus::Bus;
+    use crate::bus::BusBuilder;
+    use crate::AccessControl;
+    use crate::AclRule;
+    use crate::BusClient;
+    use crate::BusClientError;
+    use crate::BusClientEvent;
+    use crate::BusTakeover;
+    use crate::Config;
+    use crate::DeadLetter;
+    use crate::DeadLetterReason;
+    use crate::ExpiryPolicy;
+    use crate::PublishConfirmationOutcome;
+    use crate::ShutdownSignal;
+    use crate::Standby;
+    use crate::UnroutablePolicy;
+    use crate::WriteAheadLogFsyncPolicy;
+    use std::collections::HashSet;
+    use std::env;
+    use std::fs;
+    use std::mem;
+    use std::time::Duration;
+    use std::time::Instant;
+    use uuid::Uuid;
+    use zeromq_messages::codec::decode_message_header;
+    use zeromq_messages::codec::decode_message_kind_and_uuid;
+    use zeromq_messages::codec::decode_message_payload;
+    use zeromq_messages::codec::encode_message;
+    use zeromq_messages::codec::message_payload_bytes;
+    use zeromq_messages::header::MessageHeader;
+    use zeromq_messages::kind::ZeromqMessageKind;
+    use zeromq_messages::messages::ControlClientsReply;
+    use zeromq_messages::messages::ControlCommand;
+    use zeromq_messages::messages::ControlReply;
+    use zeromq_messages::messages::ControlSubscriptionsReply;
+    use zeromq_messages::messages::ServiceJoined;
+    use zeromq_messages::messages::ServiceLeft;
+    use zeromq_messages::messages::ServiceQuery;
+    use zeromq_messages::messages::ServiceRegistration;
+    use zeromq_messages::messages::ValueMultiplicationRequest;
+    use zeromq_messages::messages::ValueMultiplicationResponse;
+    use zeromq_messages::messages::WorkerReady;
+    use zeromq_messages::template::ZeromqMessageTrait;
+    use zmq::Socket;
+    use zmq::SocketType;
+
+    fn execute_control_command<R: for<'de> ZeromqMessageTrait<'de>>(
+        control_socket: &Socket,
+        action: &str,
+        kind: Option<ZeromqMessageKind>,
+    ) -> R {
+        let command = ControlCommand {
+            action: action.to_string(),
+            kind: kind.map(|kind| i64::from(kind as u32)),
+            log_level: None,
+        };
+        control_socket
+            .send(encode_message(Uuid::new_v4(), command).unwrap(), 0)
+            .unwrap();
+        let reply_bytes = control_socket.recv_bytes(0).unwrap();
+        message_payload_bytes(reply_bytes.as_slice())
+            .and_then(decode_message_payload::<R>)
+            .unwrap()
+    }
+
+    #[test]
+    fn publishes_messages_over_inproc_endpoints() {
+        let bus = Bus::builder()
+            .router_endpoint("inproc://bus-test-router")
+            .publisher_endpoints(vec!["inproc://bus-test-publisher"])
+            .start()
+            .unwrap();
+
+        let subscriber = bus.context().socket(SocketType::SUB).unwrap();
+        subscriber.connect("inproc://bus-test-publisher").unwrap();
+        subscriber.set_subscribe(b"").unwrap();
+        let mut client =
+            BusClient::connect(bus.context(), "inproc://bus-test-router").unwrap();
+
+        // Subscription reaches the BUS asynchronously, so messages published before it are
+        // lost and publishing is repeated until subscriber gets one of them.
+        let mut received_message_bytes = None;
+        for _ in 0..50 {
+            let uuid = Uuid::new_v4();
+            client
+                .publish(uuid, ValueMultiplicationResponse { result: 42 })
+                .unwrap();
+            assert_eq!(
+                BusClientEvent::Confirmation {
+                    uuid,
+                    outcome: PublishConfirmationOutcome::Accepted,
+                },
+                client.receive(0).unwrap()
+            );
+
+            if subscriber.poll(zmq::POLLIN, 100).unwrap() > 0 {
+                received_message_bytes = Some(subscriber.recv_bytes(0).unwrap());
+                break;
+            }
+        }
+
+        let (message_kind, _) =
+            decode_message_kind_and_uuid(received_message_bytes.unwrap().as_slice()).unwrap();
+        assert_eq!(ZeromqMessageKind::ValueMultiplicationResponse, message_kind);
+        assert!(bus.stats().received_messages >= 1);
+
+        assert_eq!(0, bus.shutdown().unwrap().dropped_messages);
+    }
+
+    #[test]
+    fn pauses_kind_through_control_socket() {
+        let bus = Bus::builder()
+            .router_endpoint("inproc://bus-control-test-router")
+            .publisher_endpoints(vec!["inproc://bus-control-test-publisher"])
+            .control_endpoint("inproc://bus-control-test-control")
+            .start()
+            .unwrap();
+
+        let kind = ZeromqMessageKind::ValueMultiplicationResponse;
+        let subscriber = bus.context().socket(SocketType::SUB).unwrap();
+        subscriber
+            .connect("inproc://bus-control-test-publisher")
+            .unwrap();
+        subscriber
+            .set_subscribe(&(kind as u32).to_be_bytes())
+            .unwrap();
+        let control_socket = bus.context().socket(SocketType::REQ).unwrap();
+        control_socket
+            .connect("inproc://bus-control-test-control")
+            .unwrap();
+        let mut client =
+            BusClient::connect(bus.context(), "inproc://bus-control-test-router").unwrap();
+
+        // Subscription is listed once BUS has seen it, so nothing published after that
+        // is lost.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let reply: ControlSubscriptionsReply =
+                execute_control_command(&control_socket, "subscriptions", None);
+            if reply.subscriptions.iter().any(|subscription| {
+                subscription.kind == Some(i64::from(kind as u32))
+                    && subscription.publisher == 0
+            }) {
+                break;
+            }
+            assert!(Instant::now() < deadline, "subscription is not seen by BUS");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        let reply: ControlReply =
+            execute_control_command(&control_socket, "pause-kind", Some(kind));
+        assert_eq!(Some(vec![i64::from(kind as u32)]), reply.paused_kinds);
+
+        let uuid = Uuid::new_v4();
+        client
+            .publish(uuid, ValueMultiplicationResponse { result: 42 })
+            .unwrap();
+        assert_eq!(
+            BusClientEvent::Confirmation {
+                uuid,
+                outcome: PublishConfirmationOutcome::Accepted,
+            },
+            client.receive(0).unwrap()
+        );
+        assert_eq!(0, subscriber.poll(zmq::POLLIN, 300).unwrap());
+
+        let reply: ControlReply =
+            execute_control_command(&control_socket, "resume-kind", Some(kind));
+        assert_eq!(Some(Vec::new()), reply.paused_kinds);
+        assert!(subscriber.poll(zmq::POLLIN, 5_000).unwrap() > 0);
+        let (_, received_uuid) =
+            decode_message_kind_and_uuid(subscriber.recv_bytes(0).unwrap().as_slice())
+                .unwrap();
+        assert_eq!(uuid, received_uuid);
+
+        let reply: ControlClientsReply =
+            execute_control_command(&control_socket, "clients", None);
+        assert_eq!(1, reply.clients.len());
+        assert_eq!(1, reply.clients[0].received_messages);
+
+        let reply: ControlReply = execute_control_command(&control_socket, "pause-kind", None);
+        assert!(reply.error.is_some());
+
+        assert_eq!(0, bus.shutdown().unwrap().dropped_messages);
+    }
+
+    #[test]
+    fn publishes_membership_events_of_heartbeating_clients() {
+        let mut config = Config {
+            heartbeat_interval_ms: 50,
+            ..Config::default()
+        };
+        config.bus.heartbeat_grace_ms = 300;
+        let bus = BusBuilder::new(config)
+            .router_endpoint("inproc://bus-membership-test-router")
+            .publisher_endpoints(vec!["inproc://bus-membership-test-publisher"])
+            .start()
+            .unwrap();
+
+        let subscriber = bus.context().socket(SocketType::SUB).unwrap();
+        subscriber
+            .connect("inproc://bus-membership-test-publisher")
+            .unwrap();
+        for kind in &[
+            ZeromqMessageKind::ServiceJoined,
+            ZeromqMessageKind::ServiceLeft,
+        ] {
+            subscriber
+                .set_subscribe(&(*kind as u32).to_be_bytes())
+                .unwrap();
+        }
+
+        // Subscription reaches the BUS asynchronously, so client rejoins with a new
+        // identity until subscriber gets its joined event.
+        let mut joined_identity = None;
+        for _ in 0..50 {
+            let mut client =
+                BusClient::connect(bus.context(), "inproc://bus-membership-test-router")
+                    .unwrap();
+            client.set_heartbeat_interval(Duration::from_millis(50));
+            assert!(client.send_heartbeat_if_due().unwrap());
+            assert!(!client.send_heartbeat_if_due().unwrap());
+
+            if subscriber.poll(zmq::POLLIN, 100).unwrap() > 0 {
+                let message_bytes = subscriber.recv_bytes(0).unwrap();
+                let joined = message_payload_bytes(message_bytes.as_slice())
+                    .and_then(decode_message_payload::<ServiceJoined>)
+                    .unwrap();
+                assert_eq!(50, joined.heartbeat_interval_ms);
+                joined_identity = Some(joined.identity);
+                break;
+            }
+        }
+        let joined_identity = joined_identity.unwrap();
+
+        // Client has stopped sending heartbeats, so it leaves after the grace time.
+        let mut left_identity = None;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while left_identity.as_ref() != Some(&joined_identity) && Instant::now() < deadline {
+            if subscriber.poll(zmq::POLLIN, 100).unwrap() > 0 {
+                let message_bytes = subscriber.recv_bytes(0).unwrap();
+                if let Ok((ZeromqMessageKind::ServiceLeft, _)) =
+                    decode_message_kind_and_uuid(message_bytes.as_slice())
+                {
+                    let left = message_payload_bytes(message_bytes.as_slice())
+                        .and_then(decode_message_payload::<ServiceLeft>)
+                        .unwrap();
+                    left_identity = Some(left.identity);
+                }
+            }
+        }
+        assert_eq!(Some(joined_identity), left_identity);
+
+        assert_eq!(0, bus.shutdown().unwrap().dropped_messages);
+    }
+
+    #[test]
+    fn gives_requests_of_worker_which_missed_heartbeats_to_another_worker() {
+        let mut config = Config {
+            heartbeat_interval_ms: 50,
+            ..Config::default()
+        };
+        config.bus.heartbeat_grace_ms = 300;
+        let bus = BusBuilder::new(config)
+            .router_endpoint("inproc://bus-worker-expiry-test-router")
+            .publisher_endpoints(vec!["inproc://bus-worker-expiry-test-publisher"])
+            .start()
+            .unwrap();
+
+        let mut workers = (0..2)
+            .map(|_| {
+                let mut worker = BusClient::connect(
+                    bus.context(),
+                    "inproc://bus-worker-expiry-test-router",
+                )
+                .unwrap();
+                worker.set_heartbeat_interval(Duration::from_millis(50));
+                assert!(worker.send_heartbeat_if_due().unwrap());
+                worker
+                    .publish(
+                        Uuid::new_v4(),
+                        WorkerReady {
+                            kinds: vec![i64::from(
+                                ZeromqMessageKind::ValueMultiplicationRequest as u32,
+                            )],
+                            credit: 10,
+                        },
+                    )
+                    .unwrap();
+                worker
+            })
+            .collect::<Vec<BusClient>>();
+
+        let mut client =
+            BusClient::connect(bus.context(), "inproc://bus-worker-expiry-test-router")
+                .unwrap();
+        let uuid = Uuid::new_v4();
+        client
+            .publish(
+                uuid,
+                ValueMultiplicationRequest {
+                    value: 1,
+                    multiplier: 2,
+                },
+            )
+            .unwrap();
+
+        // Worker which gets the request first stops heartbeating without answering it, so
+        // the request has to reach the other one.
+        let mut receiving_worker_indexes = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while receiving_worker_indexes.len() < 2 {
+            assert!(
+                Instant::now() < deadline,
+                "request is not given to another worker"
+            );
+            for (index, worker) in workers.iter_mut().enumerate() {
+                if !receiving_worker_indexes.contains(&index) {
+                    let _ = worker.send_heartbeat_if_due().unwrap();
+                }
+                if worker.socket().poll(zmq::POLLIN, 10).unwrap() == 0 {
+                    continue;
+                }
+                if let BusClientEvent::Message(message_bytes) = worker.receive(0).unwrap() {
+                    let (_, received_uuid) =
+                        decode_message_kind_and_uuid(message_bytes.as_slice()).unwrap();
+                    assert_eq!(uuid, received_uuid);
+                    assert!(!receiving_worker_indexes.contains(&index));
+                    receiving_worker_indexes.push(index);
+                }
+            }
+        }
+
+        let _ = bus.shutdown().unwrap();
+    }
+
+    #[test]
+    fn answers_service_queries() {
+        let bus = Bus::builder()
+            .router_endpoint("inproc://bus-registry-test-router")
+            .publisher_endpoints(vec!["inproc://bus-registry-test-publisher"])
+            .start()
+            .unwrap();
+        let request_kind = ZeromqMessageKind::ValueMultiplicationRequest;
+        let timeout = Duration::from_secs(5);
+
+        let mut service =
+            BusClient::connect(bus.context(), "inproc://bus-registry-test-router").unwrap();
+        service
+            .register_service(ServiceRegistration {
+                name: "responder".to_string(),
+                instance_id: "responder-1".to_string(),
+                version: "1.0.0".to_string(),
+                handled_kinds: vec![i64::from(request_kind as u32)],
+                produced_kinds: Vec::new(),
+            })
+            .unwrap();
+        assert!(matches!(
+            service.receive(0).unwrap(),
+            BusClientEvent::Confirmation {
+                outcome: PublishConfirmationOutcome::Accepted,
+                ..
+            }
+        ));
+
+        let mut client =
+            BusClient::connect(bus.context(), "inproc://bus-registry-test-router").unwrap();
+        let providers = client
+            .query_services(
+                ServiceQuery {
+                    kind: Some(i64::from(request_kind as u32)),
+                    name: None,
+                },
+                timeout,
+            )
+            .unwrap();
+        assert_eq!(1, providers.len());
+        assert_eq!("responder-1", providers[0].instance_id);
+
+        client.require_providers(&[request_kind], timeout).unwrap();
+        assert!(matches!(
+            client.require_providers(&[ZeromqMessageKind::ServiceJoined], timeout),
+            Err(BusClientError::MissingProviders(kinds))
+                if kinds == vec![ZeromqMessageKind::ServiceJoined]
+        ));
+
+        assert_eq!(0, bus.shutdown().unwrap().dropped_messages);
+    }
+
+    fn federated_bus(instance_id: &str, port: u16, peer_port: u16) -> Bus {
+        let mut config = Config::default();
+        config.bus.instance_id = Some(instance_id.to_string());
+        config.bus.federation_peers = vec![format!("tcp://127.0.0.1:{}", peer_port)];
+        config.bus.federation_kinds =
+            vec![ZeromqMessageKind::ValueMultiplicationResponse as u32];
+        BusBuilder::new(config)
+            .router_endpoint(format!("tcp://127.0.0.1:{}", port))
+            .publisher_endpoints(vec![format!("tcp://127.0.0.1:{}", port + 1)])
+            .start()
+            .unwrap()
+    }
+
+    #[test]
+    fn forwards_federated_kinds_between_buses_without_loops() {
+        // Both BUSes federate the same kind to each other, which is the simplest loop.
+        let bus_a = federated_bus("bus-a", 58_731, 58_741);
+        let bus_b = federated_bus("bus-b", 58_741, 58_731);
+        let kind_prefix =
+            (ZeromqMessageKind::ValueMultiplicationResponse as u32).to_be_bytes();
+
+        let subscriber_a = bus_a.context().socket(SocketType::SUB).unwrap();
+        subscriber_a.connect("tcp://127.0.0.1:58732").unwrap();
+        subscriber_a.set_subscribe(&kind_prefix).unwrap();
+        let subscriber_b = bus_b.context().socket(SocketType::SUB).unwrap();
+        subscriber_b.connect("tcp://127.0.0.1:58742").unwrap();
+        subscriber_b.set_subscribe(&kind_prefix).unwrap();
+        let mut client = BusClient::connect(bus_a.context(), "tcp://127.0.0.1:58731").unwrap();
+
+        // Subscription reaches the peer asynchronously, so publishing is repeated until
+        // subscriber of the peer gets one of the messages.
+        let mut published_uuids = HashSet::new();
+        let mut forwarded_message_bytes = None;
+        for _ in 0..50 {
+            let uuid = Uuid::new_v4();
+            client
+                .publish(uuid, ValueMultiplicationResponse { result: 42 })
+                .unwrap();
+            let _ = published_uuids.insert(uuid);
+
+            if subscriber_b.poll(zmq::POLLIN, 100).unwrap() > 0 {
+                forwarded_message_bytes = Some(subscriber_b.recv_bytes(0).unwrap());
+                break;
+            }
+        }
+
+        let forwarded_message_bytes = forwarded_message_bytes.unwrap();
+        let (_, uuid) =
+            decode_message_kind_and_uuid(forwarded_message_bytes.as_slice()).unwrap();
+        assert!(published_uuids.contains(&uuid));
+        assert_eq!(
+            vec!["bus-a".to_string()],
+            decode_message_header(forwarded_message_bytes.as_slice())
+                .unwrap()
+                .hops
+        );
+
+        // Every message comes back from the peer once and is dropped there.
+        let published_count = published_uuids.len() as u64;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while bus_a.stats().federation_looped_messages < published_count
+            && Instant::now() < deadline
+        {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(published_count, bus_a.stats().federated_messages);
+        assert_eq!(published_count, bus_a.stats().federation_looped_messages);
+        assert_eq!(published_count, bus_b.stats().federated_messages);
+        assert_eq!(0, bus_b.stats().federation_looped_messages);
+
+        let mut uuids_received_by_a = HashSet::new();
+        while subscriber_a.poll(zmq::POLLIN, 100).unwrap() > 0 {
+            let message_bytes = subscriber_a.recv_bytes(0).unwrap();
+            let (_, uuid) = decode_message_kind_and_uuid(message_bytes.as_slice()).unwrap();
+            assert!(uuids_received_by_a.insert(uuid));
+        }
+
+        assert_eq!(0, bus_a.shutdown().unwrap().dropped_messages);
+        assert_eq!(0, bus_b.shutdown().unwrap().dropped_messages);
+    }
+
+    #[test]
+    fn standby_takes_over_undelivered_messages_of_stopped_bus() {
+        let primary_wal_directory =
+            env::temp_dir().join(format!("zeromq-bus-replication-{}", Uuid::new_v4()));
+        let standby_wal_directory =
+            env::temp_dir().join(format!("zeromq-bus-replication-{}", Uuid::new_v4()));
+        let kind = ZeromqMessageKind::ValueMultiplicationRequest;
+
+        let mut config = Config {
+            heartbeat_interval_ms: 50,
+            ..Config::default()
+        };
+        config.bus.wal_directory = Some(primary_wal_directory.clone());
+        config.bus.replication_endpoint =
+            Some("inproc://bus-replication-test-replication".to_string());
+        let primary = BusBuilder::new(config)
+            .router_endpoint("inproc://bus-replication-test-router")
+            .publisher_endpoints(vec!["inproc://bus-replication-test-publisher"])
+            .start()
+            .unwrap();
+        let context = primary.context().clone();
+
+        let standby =
+            Standby::connect(&context, "inproc://bus-replication-test-replication").unwrap();
+        let standby_thread = std::thread::spawn(move || {
+            standby.wait_for_takeover(Duration::from_millis(300), &ShutdownSignal::new())
+        });
+
+        // Nobody works on requests, so they stay undelivered in the log of the primary.
+        let mut client =
+            BusClient::connect(&context, "inproc://bus-replication-test-router").unwrap();
+        let mut uuids = HashSet::new();
+        for value in 0..3 {
+            let uuid = Uuid::new_v4();
+            client
+                .publish(
+                    uuid,
+                    ValueMultiplicationRequest {
+                        value,
+                        multiplier: 2,
+                    },
+                )
+                .unwrap();
+            assert_eq!(
+                BusClientEvent::Confirmation {
+                    uuid,
+                    outcome: PublishConfirmationOutcome::Accepted,
+                },
+                client.receive(0).unwrap()
+            );
+            let _ = uuids.insert(uuid);
+        }
+
+        // Replication events reach standby asynchronously.
+        std::thread::sleep(Duration::from_millis(300));
+        let _ = primary.shutdown().unwrap();
+
+        let takeover = standby_thread.join().unwrap().unwrap();
+        assert_eq!(
+            uuids,
+            takeover
+                .messages
+                .iter()
+                .map(|message_bytes| decode_message_kind_and_uuid(message_bytes).unwrap().1)
+                .collect::<HashSet<Uuid>>()
+        );
+
+        let mut config = Config::default();
+        config.bus.wal_directory = Some(standby_wal_directory.clone());
+        let standby = BusBuilder::new(config)
+            .context(context.clone())
+            .takeover(takeover)
+            .router_endpoint("inproc://bus-replication-test-router")
+            .publisher_endpoints(vec!["inproc://bus-replication-test-publisher"])
+            .start()
+            .unwrap();
+
+        let mut worker =
+            BusClient::connect(&context, "inproc://bus-replication-test-router").unwrap();
+        worker
+            .publish(
+                Uuid::new_v4(),
+                WorkerReady {
+                    kinds: vec![i64::from(kind as u32)],
+                    credit: 10,
+                },
+            )
+            .unwrap();
+
+        let mut received_uuids = HashSet::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while received_uuids.len() < uuids.len() {
+            assert!(Instant::now() < deadline, "requests are not taken over");
+            if worker.socket().poll(zmq::POLLIN, 100).unwrap() == 0 {
+                continue;
+            }
+            if let BusClientEvent::Message(message_bytes) = worker.receive(0).unwrap() {
+                let (_, uuid) =
+                    decode_message_kind_and_uuid(message_bytes.as_slice()).unwrap();
+                let _ = received_uuids.insert(uuid);
+            }
+        }
+        assert_eq!(uuids, received_uuids);
+
+        let _ = standby.shutdown().unwrap();
+        fs::remove_dir_all(primary_wal_directory).unwrap();
+        fs::remove_dir_all(standby_wal_directory).unwrap();
+    }
+
+    #[test]
+    fn redelivers_undelivered_messages_once_after_restart() {
+        let wal_directory =
+            env::temp_dir().join(format!("zeromq-bus-restart-{}", Uuid::new_v4()));
+        let start = |context: &zmq::Context| {
+            let mut config = Config::default();
+            config.bus.wal_directory = Some(wal_directory.clone());
+            config.bus.wal_segment_max_bytes = 256;
+            config.bus.wal_fsync_policy = WriteAheadLogFsyncPolicy::Always;
+            BusBuilder::new(config)
+                .context(context.clone())
+                .router_endpoint("inproc://bus-restart-test-router")
+                .publisher_endpoints(vec!["inproc://bus-restart-test-publisher"])
+                .start()
+                .unwrap()
+        };
+        let receive_requests = |context: &zmq::Context| {
+            let mut worker =
+                BusClient::connect(context, "inproc://bus-restart-test-router").unwrap();
+            worker
+                .publish(
+                    Uuid::new_v4(),
+                    WorkerReady {
+                        kinds: vec![i64::from(
+                            ZeromqMessageKind::ValueMultiplicationRequest as u32,
+                        )],
+                        credit: 10,
+                    },
+                )
+                .unwrap();
+
+            // Requests come right after registration, the quiet time after them shows
+            // that none of them comes twice.
+            let mut received_uuids = Vec::new();
+            while worker.socket().poll(zmq::POLLIN, 500).unwrap() > 0 {
+                if let BusClientEvent::Message(message_bytes) = worker.receive(0).unwrap() {
+                    let (_, uuid) =
+                        decode_message_kind_and_uuid(message_bytes.as_slice()).unwrap();
+                    received_uuids.push(uuid);
+                }
+            }
+            received_uuids
+        };
+
+        // Nobody works on requests, so they stay undelivered when BUS crashes. Inproc
+        // endpoints belong to their context, so the crashed BUS gets its own one.
+        let crashed_context = zmq::Context::new();
+        let bus = start(&crashed_context);
+        let mut client =
+            BusClient::connect(&crashed_context, "inproc://bus-restart-test-router").unwrap();
+        let mut uuids = Vec::new();
+        for value in 0..3 {
+            let uuid = Uuid::new_v4();
+            client
+                .publish(
+                    uuid,
+                    ValueMultiplicationRequest {
+                        value,
+                        multiplier: 2,
+                    },
+                )
+                .unwrap();
+            assert_eq!(
+                BusClientEvent::Confirmation {
+                    uuid,
+                    outcome: PublishConfirmationOutcome::Accepted,
+                },
+                client.receive(0).unwrap()
+            );
+            uuids.push(uuid);
+        }
+        // Forgotten handle never runs the shutdown path, so nothing is drained or
+        // flushed, the same as when the process is killed.
+        drop(client);
+        mem::forget(bus);
+
+        let context = zmq::Context::new();
+        let bus = start(&context);
+        assert_eq!(uuids, receive_requests(&context));
+        let _ = bus.shutdown().unwrap();
+
+        // Delivery is remembered too, so the next run has nothing to replay.
+        let bus = start(&context);
+        assert_eq!(Vec::<Uuid>::new(), receive_requests(&context));
+        let _ = bus.shutdown().unwrap();
+
+        fs::remove_dir_all(wal_directory).unwrap();
+    }
+
+    #[test]
+    fn discards_expired_messages_before_publishing() {
+        let mut config = Config::default();
+        config.bus.expiry_policy = ExpiryPolicy::DeadLetter;
+        let bus = BusBuilder::new(config)
+            .router_endpoint("inproc://bus-expiry-test-router")
+            .publisher_endpoints(vec!["inproc://bus-expiry-test-publisher"])
+            .start()
+            .unwrap();
+        let mut client =
+            BusClient::connect(bus.context(), "inproc://bus-expiry-test-router").unwrap();
+
+        for ttl in &[Duration::from_secs(0), Duration::from_secs(30)] {
+            let uuid = Uuid::new_v4();
+            client
+                .publish_with_header(
+                    uuid,
+                    &MessageHeader::with_ttl(*ttl),
+                    ValueMultiplicationResponse { result: 42 },
+                )
+                .unwrap();
+            assert_eq!(
+                BusClientEvent::Confirmation {
+                    uuid,
+                    outcome: PublishConfirmationOutcome::Accepted,
+                },
+                client.receive(0).unwrap()
+            );
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while bus.stats().expired_messages + bus.stats().published_messages < 2 {
+            assert!(Instant::now() < deadline, "messages are not processed");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let stats = bus.stats();
+        assert_eq!(1, stats.expired_messages);
+        assert_eq!(1, stats.published_messages);
+        assert_eq!(1, stats.dead_lettered_messages);
+
+        assert_eq!(0, bus.shutdown().unwrap().dropped_messages);
+    }
+
+    #[test]
+    fn delivers_scheduled_messages_when_due() {
+        let bus = Bus::builder()
+            .router_endpoint("inproc://bus-schedule-test-router")
+            .publisher_endpoints(vec!["inproc://bus-schedule-test-publisher"])
+            .start()
+            .unwrap();
+        let mut client =
+            BusClient::connect(bus.context(), "inproc://bus-schedule-test-router").unwrap();
+
+        let confirm = |client: &mut BusClient, uuid| {
+            assert_eq!(
+                BusClientEvent::Confirmation {
+                    uuid,
+                    outcome: PublishConfirmationOutcome::Accepted,
+                },
+                client.receive(0).unwrap()
+            );
+        };
+
+        let (delivered_uuid, cancelled_uuid) = (Uuid::new_v4(), Uuid::new_v4());
+        for uuid in &[delivered_uuid, cancelled_uuid] {
+            client
+                .publish_with_header(
+                    *uuid,
+                    &MessageHeader::with_delay(Duration::from_millis(500)),
+                    ValueMultiplicationResponse { result: 42 },
+                )
+                .unwrap();
+            confirm(&mut client, *uuid);
+        }
+        assert_eq!(2, bus.stats().scheduled_messages);
+
+        let kind = ZeromqMessageKind::ValueMultiplicationResponse;
+        let cancellation_uuid = client
+            .cancel_delivery(
+                ZeromqMessageKind::ValueMultiplicationRequest,
+                cancelled_uuid,
+            )
+            .unwrap();
+        assert!(matches!(
+            client.receive(0).unwrap(),
+            BusClientEvent::Confirmation {
+                uuid,
+                outcome: PublishConfirmationOutcome::Rejected(_),
+            } if uuid == cancellation_uuid
+        ));
+        let cancellation_uuid = client.cancel_delivery(kind, cancelled_uuid).unwrap();
+        confirm(&mut client, cancellation_uuid);
+        let cancellation_uuid = client.cancel_delivery(kind, cancelled_uuid).unwrap();
+        assert!(matches!(
+            client.receive(0).unwrap(),
+            BusClientEvent::Confirmation {
+                uuid,
+                outcome: PublishConfirmationOutcome::Rejected(_),
+            } if uuid == cancellation_uuid
+        ));
+        assert_eq!(1, bus.stats().scheduled_messages);
+        assert_eq!(0, bus.stats().published_messages);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while bus.stats().published_messages == 0 {
+            assert!(
+                Instant::now() < deadline,
+                "scheduled message is not delivered"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let stats = bus.stats();
+        assert_eq!(1, stats.published_messages);
+        assert_eq!(1, stats.cancelled_deliveries);
+        assert_eq!(0, stats.scheduled_messages);
+
+        assert_eq!(0, bus.shutdown().unwrap().dropped_messages);
+    }
+
+    #[test]
+    fn refuses_scheduled_messages_beyond_limits() {
+        let mut config = Config::default();
+        config.bus.scheduled_messages_capacity = 1;
+        config.bus.schedule_max_delay_ms = 60_000;
+        let bus = BusBuilder::new(config)
+            .router_endpoint("inproc://bus-schedule-limits-test-router")
+            .publisher_endpoints(vec!["inproc://bus-schedule-limits-test-publisher"])
+            .start()
+            .unwrap();
+        let mut client =
+            BusClient::connect(bus.context(), "inproc://bus-schedule-limits-test-router")
+                .unwrap();
+
+        let outcomes = [3_600, 30, 30]
+            .iter()
+            .map(|delay_seconds| {
+                client
+                    .publish_with_header(
+                        Uuid::new_v4(),
+                        &MessageHeader::with_delay(Duration::from_secs(*delay_seconds)),
+                        ValueMultiplicationResponse { result: 42 },
+                    )
+                    .unwrap();
+                match client.receive(0).unwrap() {
+                    BusClientEvent::Confirmation { outcome, .. } => outcome,
+                    BusClientEvent::Message(_) => panic!("unexpected message"),
+                }
+            })
+            .collect::<Vec<PublishConfirmationOutcome>>();
+        assert_eq!(
+            vec![
+                PublishConfirmationOutcome::Rejected(
+                    "deliver-at time is more than 60000 ms ahead".to_string()
+                ),
+                PublishConfirmationOutcome::Accepted,
+                PublishConfirmationOutcome::Rejected(
+                    "scheduled messages are at capacity of 1".to_string()
+                ),
+            ],
+            outcomes
+        );
+        assert_eq!(1, bus.stats().scheduled_messages);
+
+        let _ = bus.shutdown().unwrap();
+    }
+
+    #[test]
+    fn drops_duplicate_messages_inside_dedup_window() {
+        let mut config = Config::default();
+        config.bus.dedup_window_ms = Some(30_000);
+        let bus = BusBuilder::new(config)
+            .router_endpoint("inproc://bus-dedup-test-router")
+            .publisher_endpoints(vec!["inproc://bus-dedup-test-publisher"])
+            .start()
+            .unwrap();
+        let mut client =
+            BusClient::connect(bus.context(), "inproc://bus-dedup-test-router").unwrap();
+
+        let (duplicated_uuid, other_uuid) = (Uuid::new_v4(), Uuid::new_v4());
+        for uuid in &[duplicated_uuid, duplicated_uuid, other_uuid] {
+            client
+                .publish(*uuid, ValueMultiplicationResponse { result: 42 })
+                .unwrap();
+            // Duplicate is confirmed as well, so sender stops resending it.
+            assert_eq!(
+                BusClientEvent::Confirmation {
+                    uuid: *uuid,
+                    outcome: PublishConfirmationOutcome::Accepted,
+                },
+                client.receive(0).unwrap()
+            );
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while bus.stats().published_messages < 2 {
+            assert!(Instant::now() < deadline, "messages are not published");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let stats = bus.stats();
+        assert_eq!(3, stats.received_messages);
+        assert_eq!(1, stats.deduplicated_messages);
+        assert_eq!(2, stats.published_messages);
+
+        assert_eq!(0, bus.shutdown().unwrap().dropped_messages);
+    }
+
+    #[test]
+    fn refuses_messages_above_rate_limit_of_client() {
+        let mut config = Config::default();
+        config.bus.rate_limit_per_second = Some(1);
+        config.bus.rate_limit_burst = Some(2);
+        let bus = BusBuilder::new(config)
+            .router_endpoint("inproc://bus-rate-limit-test-router")
+            .publisher_endpoints(vec!["inproc://bus-rate-limit-test-publisher"])
+            .start()
+            .unwrap();
+        let mut flooding_client =
+            BusClient::connect(bus.context(), "inproc://bus-rate-limit-test-router").unwrap();
+        let mut quiet_client =
+            BusClient::connect(bus.context(), "inproc://bus-rate-limit-test-router").unwrap();
+
+        let outcomes = (0..3)
+            .map(|_| {
+                let uuid = Uuid::new_v4();
+                flooding_client
+                    .publish(uuid, ValueMultiplicationResponse { result: 42 })
+                    .unwrap();
+                match flooding_client.receive(0).unwrap() {
+                    BusClientEvent::Confirmation { outcome, .. } => outcome,
+                    BusClientEvent::Message(_) => panic!("unexpected message"),
+                }
+            })
+            .collect::<Vec<PublishConfirmationOutcome>>();
+        assert_eq!(
+            vec![
+                PublishConfirmationOutcome::Accepted,
+                PublishConfirmationOutcome::Accepted,
+                PublishConfirmationOutcome::Rejected("rate limit exceeded".to_string()),
+            ],
+            outcomes
+        );
+
+        // Limit of one client does not hold back the others.
+        let uuid = Uuid::new_v4();
+        quiet_client
+            .publish(uuid, ValueMultiplicationResponse { result: 42 })
+            .unwrap();
+        assert_eq!(
+            BusClientEvent::Confirmation {
+                uuid,
+                outcome: PublishConfirmationOutcome::Accepted,
+            },
+            quiet_client.receive(0).unwrap()
+        );
+
+        assert_eq!(1, bus.stats().throttled_messages);
+
+        assert_eq!(0, bus.shutdown().unwrap().dropped_messages);
+    }
+
+    #[test]
+    fn refuses_flood_of_message_rejections() {
+        let mut config = Config::default();
+        config.bus.rate_limit_per_second = Some(1);
+        config.bus.rate_limit_burst = Some(2);
+        let bus = BusBuilder::new(config)
+            .router_endpoint("inproc://bus-rejection-flood-test-router")
+            .publisher_endpoints(vec!["inproc://bus-rejection-flood-test-publisher"])
+            .start()
+            .unwrap();
+        let mut client =
+            BusClient::connect(bus.context(), "inproc://bus-rejection-flood-test-router")
+                .unwrap();
+
+        let outcomes = (0..3)
+            .map(|_| {
+                let message_bytes =
+                    encode_message(Uuid::new_v4(), ValueMultiplicationResponse { result: 42 })
+                        .unwrap();
+                client
+                    .reject(message_bytes.as_slice(), "poison".to_string())
+                    .unwrap();
+                match client.receive(0).unwrap() {
+                    BusClientEvent::Confirmation { outcome, .. } => outcome,
+                    BusClientEvent::Message(_) => panic!("unexpected message"),
+                }
+            })
+            .collect::<Vec<PublishConfirmationOutcome>>();
+        assert_eq!(
+            vec![
+                PublishConfirmationOutcome::Accepted,
+                PublishConfirmationOutcome::Accepted,
+                PublishConfirmationOutcome::Rejected("rate limit exceeded".to_string()),
+            ],
+            outcomes
+        );
+
+        let stats = bus.stats();
+        assert_eq!(1, stats.throttled_messages);
+        assert_eq!(2, stats.dead_lettered_messages);
+
+        assert_eq!(0, bus.shutdown().unwrap().dropped_messages);
+    }
+
+    #[test]
+    fn dead_letters_messages_which_no_subscriber_wants() {
+        let mut config = Config::default();
+        config.bus.unroutable_policy = UnroutablePolicy::DeadLetter;
+        let bus = BusBuilder::new(config)
+            .router_endpoint("inproc://bus-unroutable-test-router")
+            .publisher_endpoints(vec!["inproc://bus-unroutable-test-publisher"])
+            .start()
+            .unwrap();
+        let mut client =
+            BusClient::connect(bus.context(), "inproc://bus-unroutable-test-router").unwrap();
+        let mut publish = || {
+            let uuid = Uuid::new_v4();
+            client
+                .publish(uuid, ValueMultiplicationResponse { result: 42 })
+                .unwrap();
+            assert_eq!(
+                BusClientEvent::Confirmation {
+                    uuid,
+                    outcome: PublishConfirmationOutcome::Accepted,
+                },
+                client.receive(0).unwrap()
+            );
+        };
+
+        publish();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while bus.stats().unroutable_messages < 1 {
+            assert!(Instant::now() < deadline, "message is not processed");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(1, bus.stats().dead_lettered_messages);
+        assert_eq!(0, bus.stats().published_messages);
+
+        let subscriber = bus.context().socket(SocketType::SUB).unwrap();
+        subscriber
+            .connect("inproc://bus-unroutable-test-publisher")
+            .unwrap();
+        subscriber
+            .set_subscribe(
+                &(ZeromqMessageKind::ValueMultiplicationResponse as u32).to_be_bytes(),
+            )
+            .unwrap();
+
+        // Subscriptions are received right before message is judged, so message which is
+        // published right after subscribing is not taken as unroutable.
+        publish();
+        assert!(subscriber.poll(zmq::POLLIN, 5_000).unwrap() > 0);
+        let (message_kind, _) =
+            decode_message_kind_and_uuid(subscriber.recv_bytes(0).unwrap().as_slice())
+                .unwrap();
+        assert_eq!(ZeromqMessageKind::ValueMultiplicationResponse, message_kind);
+        assert_eq!(1, bus.stats().unroutable_messages);
+
+        assert_eq!(0, bus.shutdown().unwrap().dropped_messages);
+    }
+
+    #[test]
+    fn reinjects_client_supplied_messages_only_when_sender_may_publish_them() {
+        let access_control = AccessControl::new(&[AclRule {
+            identity: "publisher".to_string(),
+            publish_kinds: vec![ZeromqMessageKind::ValueMultiplicationRequest as u32],
+            subscribe_kinds: vec![],
+        }]);
+        let entry = |reason: DeadLetterReason, sender_user_id: Option<&str>| DeadLetter {
+            id: 0,
+            reason,
+            dead_lettered_at: 0,
+            message_bytes: encode_message(
+                Uuid::new_v4(),
+                ValueMultiplicationRequest {
+                    value: 2,
+                    multiplier: 3,
+                },
+            )
+            .unwrap(),
+            sender_user_id: sender_user_id.map(str::to_string),
+        };
+        let rejected = DeadLetterReason::RejectedByConsumer("poison".to_string());
+
+        assert!(may_reinject(
+            Some(&access_control),
+            &entry(rejected.clone(), Some("publisher"))
+        ));
+        assert!(!may_reinject(
+            Some(&access_control),
+            &entry(rejected.clone(), Some("consumer"))
+        ));
+        assert!(!may_reinject(
+            Some(&access_control),
+            &entry(DeadLetterReason::ValidationFailed("bad".to_string()), None)
+        ));
+        assert!(may_reinject(
+            Some(&access_control),
+            &entry(DeadLetterReason::Expired, None)
+        ));
+        assert!(may_reinject(None, &entry(rejected, Some("consumer"))));
+    }
+
+    #[test]
+    fn publishes_replayed_messages_before_subscribers_reconnect() {
+        let wal_directory =
+            env::temp_dir().join(format!("zeromq-bus-unroutable-replay-{}", Uuid::new_v4()));
+        let mut config = Config::default();
+        config.bus.wal_directory = Some(wal_directory.clone());
+        config.bus.unroutable_policy = UnroutablePolicy::DeadLetter;
+        let bus = BusBuilder::new(config)
+            .takeover(BusTakeover {
+                messages: vec![encode_message(
+                    Uuid::new_v4(),
+                    ValueMultiplicationResponse { result: 42 },
+                )
+                .unwrap()],
+                subscriptions: Vec::new(),
+            })
+            .router_endpoint("inproc://bus-unroutable-replay-test-router")
+            .publisher_endpoints(vec!["inproc://bus-unroutable-replay-test-publisher"])
+            .start()
+            .unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while bus.stats().published_messages < 1 {
+            assert!(Instant::now() < deadline, "message is not replayed");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(0, bus.stats().unroutable_messages);
+        assert_eq!(0, bus.stats().dead_lettered_messages);
+
+        assert_eq!(0, bus.shutdown().unwrap().dropped_messages);
+        fs::remove_dir_all(wal_directory).unwrap();
+    }
+}
@@ -0,0 +1,286 @@
+use crate::helpers::checksum;
+use crate::WorkerIdentity;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Take;
+use std::io::Write;
+use std::iter::Iterator;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const RECEIVED_RECORD_TYPE: u8 = 1;
+const PUBLISHED_RECORD_TYPE: u8 = 2;
+
+//-----------------------------------------------------------------------------------------
+// CaptureSource
+//-----------------------------------------------------------------------------------------
+
+/// Side of the BUS where captured frame was seen.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum CaptureSource {
+    /// Frame was received on the router socket from the client with the given identity.
+    Received(WorkerIdentity),
+    /// Frame was published through the publisher socket with the given index.
+    Published(usize),
+}
+
+//-----------------------------------------------------------------------------------------
+// CaptureRecord
+//-----------------------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CaptureRecord {
+    /// Milliseconds since Unix epoch.
+    pub captured_at: u64,
+    pub source: CaptureSource,
+    pub message_bytes: Vec<u8>,
+}
+
+impl CaptureRecord {
+    /// Time which should pass since the first replayed record before this one is sent,
+    /// speed above 1 makes replay faster than the original traffic.
+    #[must_use]
+    pub fn replay_offset(&self, first_captured_at: u64, speed: f64) -> Duration {
+        let offset = Duration::from_millis(self.captured_at.saturating_sub(first_captured_at));
+        if speed > 0.0 {
+            offset.div_f64(speed)
+        } else {
+            Duration::from_millis(0)
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// CaptureWriter
+//-----------------------------------------------------------------------------------------
+
+/// Appends every frame which BUS receives or publishes to the capture file. Records are
+/// flushed one by one, so capture survives crash of the process which it should explain.
+#[derive(Debug)]
+pub struct CaptureWriter {
+    path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl CaptureWriter {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+
+        Ok(Self {
+            path,
+            writer: BufWriter::new(file),
+        })
+    }
+
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    pub fn write(&mut self, record: &CaptureRecord) -> io::Result<()> {
+        self.writer.write_all(encode_record(record).as_slice())?;
+        self.writer.flush()
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// CaptureReader
+//-----------------------------------------------------------------------------------------
+
+/// Reads records of the capture file in order of their writing. Reading stops on the
+/// first damaged record, which is usually the one partially written during crash.
+/// Records appended after the reader was opened are not read, so capture can be replayed
+/// into the BUS which is still writing it.
+#[derive(Debug)]
+pub struct CaptureReader {
+    path: PathBuf,
+    reader: BufReader<Take<File>>,
+}
+
+impl CaptureReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::open(&path)?;
+        let length = file.metadata()?.len();
+        let reader = BufReader::new(file.take(length));
+        Ok(Self { path, reader })
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<CaptureRecord>> {
+        let mut record_type = [0_u8; 1];
+        if self.reader.read(&mut record_type)? == 0 {
+            return Ok(None);
+        }
+
+        let mut record_bytes = record_type.to_vec();
+        let captured_at = u64::from_be_bytes(self.read_array::<8>(&mut record_bytes)?);
+        let source = match record_type[0] {
+            RECEIVED_RECORD_TYPE => {
+                let [identity_length] = self.read_array::<1>(&mut record_bytes)?;
+                CaptureSource::Received(
+                    self.read_vec(usize::from(identity_length), &mut record_bytes)?,
+                )
+            }
+            PUBLISHED_RECORD_TYPE => CaptureSource::Published(usize::from(
+                u16::from_be_bytes(self.read_array::<2>(&mut record_bytes)?),
+            )),
+            record_type => {
+                return Err(damaged(format!("unknown record type {}", record_type)))
+            }
+        };
+        let message_length = u32::from_be_bytes(self.read_array::<4>(&mut record_bytes)?);
+        let message_bytes = self.read_vec(
+            usize::try_from(message_length).map_err(|error| damaged(error.to_string()))?,
+            &mut record_bytes,
+        )?;
+
+        let expected_checksum = u32::from_be_bytes(self.read_array::<4>(&mut Vec::new())?);
+        if checksum(record_bytes.as_slice()) != expected_checksum {
+            return Err(damaged("checksum mismatch".to_string()));
+        }
+
+        Ok(Some(CaptureRecord {
+            captured_at,
+            source,
+            message_bytes,
+        }))
+    }
+
+    fn read_array<const N: usize>(
+        &mut self,
+        record_bytes: &mut Vec<u8>,
+    ) -> io::Result<[u8; N]> {
+        let mut bytes = [0_u8; N];
+        self.reader.read_exact(&mut bytes)?;
+        record_bytes.extend_from_slice(&bytes);
+        Ok(bytes)
+    }
+
+    fn read_vec(&mut self, length: usize, record_bytes: &mut Vec<u8>) -> io::Result<Vec<u8>> {
+        let mut bytes = vec![0_u8; length];
+        self.reader.read_exact(bytes.as_mut_slice())?;
+        record_bytes.extend_from_slice(bytes.as_slice());
+        Ok(bytes)
+    }
+}
+
+impl Iterator for CaptureReader {
+    type Item = CaptureRecord;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.read_record() {
+            Ok(record) => record,
+            Err(error) => {
+                log::warn!(
+                    "stopped reading capture {} because of: {}",
+                    self.path.display(),
+                    error
+                );
+                None
+            }
+        }
+    }
+}
+
+fn damaged(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+// Record layout: type (1 byte), capture time (8 bytes), source, message length (4 bytes),
+// message and checksum (4 bytes) of all previous fields. Source is identity length (1 byte)
+// and identity for received frames, or publisher index (2 bytes) for published ones.
+fn encode_record(record: &CaptureRecord) -> Vec<u8> {
+    let message_length =
+        u32::try_from(record.message_bytes.len()).expect("captured message is too large");
+    let mut record_bytes = Vec::with_capacity(record.message_bytes.len() + 275);
+
+    match &record.source {
+        CaptureSource::Received(identity) => {
+            let identity_length =
+                u8::try_from(identity.len()).expect("client identity is at most 255 bytes");
+            record_bytes.push(RECEIVED_RECORD_TYPE);
+            record_bytes.extend_from_slice(&record.captured_at.to_be_bytes());
+            record_bytes.push(identity_length);
+            record_bytes.extend_from_slice(identity.as_slice());
+        }
+        CaptureSource::Published(publisher_index) => {
+            let publisher_index =
+                u16::try_from(*publisher_index).expect("publisher index is too large");
+            record_bytes.push(PUBLISHED_RECORD_TYPE);
+            record_bytes.extend_from_slice(&record.captured_at.to_be_bytes());
+            record_bytes.extend_from_slice(&publisher_index.to_be_bytes());
+        }
+    }
+
+    record_bytes.extend_from_slice(&message_length.to_be_bytes());
+    record_bytes.extend_from_slice(record.message_bytes.as_slice());
+    let checksum = checksum(record_bytes.as_slice());
+    record_bytes.extend_from_slice(&checksum.to_be_bytes());
+    record_bytes
+}
+
+//-----------------------------------------------------------------------------------------
+// Tests
+//-----------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::capture::CaptureReader;
+    use crate::capture::CaptureRecord;
+    use crate::capture::CaptureSource;
+    use crate::capture::CaptureWriter;
+    use std::env;
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    #[test]
+    fn reads_written_records_until_damaged_tail() {
+        let path = env::temp_dir().join(format!("zeromq-bus-capture-{}", Uuid::new_v4()));
+        let records = vec![
+            CaptureRecord {
+                captured_at: 1_000,
+                source: CaptureSource::Received(b"client".to_vec()),
+                message_bytes: b"first".to_vec(),
+            },
+            CaptureRecord {
+                captured_at: 1_500,
+                source: CaptureSource::Published(3),
+                message_bytes: b"second".to_vec(),
+            },
+        ];
+
+        {
+            let mut writer = CaptureWriter::open(&path).unwrap();
+            for record in &records {
+                writer.write(record).unwrap();
+            }
+        }
+
+        // Partially written record, as it happens when process is killed during write.
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[1, 0, 0, 0]).unwrap();
+
+        assert_eq!(
+            records,
+            CaptureReader::open(&path)
+                .unwrap()
+                .collect::<Vec<CaptureRecord>>()
+        );
+        assert_eq!(
+            Duration::from_millis(250),
+            records[1].replay_offset(records[0].captured_at, 2.0)
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+}
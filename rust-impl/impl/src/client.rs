@@ -0,0 +1,468 @@
+use crate::security::secure_client_socket;
+use crate::DeduplicationWindow;
+use crate::EndpointsConfig;
+use crate::ZEROMQ_ZERO_FLAG;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::iter::Iterator;
+use std::time::Duration;
+use std::time::Instant;
+use uuid::Uuid;
+use zeromq_messages::codec::decode_message_kind_and_uuid;
+use zeromq_messages::codec::decode_message_payload;
+use zeromq_messages::codec::encode_message;
+use zeromq_messages::codec::encode_message_with_header;
+use zeromq_messages::codec::message_payload_bytes;
+use zeromq_messages::codec::MessageDecodeError;
+use zeromq_messages::codec::MessageEncodeError;
+use zeromq_messages::header::MessageHeader;
+use zeromq_messages::kind::ZeromqMessageKind;
+use zeromq_messages::messages::DeliveryCancellation;
+use zeromq_messages::messages::Heartbeat;
+use zeromq_messages::messages::MessageRejection;
+use zeromq_messages::messages::PublishConfirmation;
+use zeromq_messages::messages::ServiceQuery;
+use zeromq_messages::messages::ServiceQueryReply;
+use zeromq_messages::messages::ServiceQueryReplyItemProviders;
+use zeromq_messages::messages::ServiceRegistration;
+use zeromq_messages::template::ZeromqMessageTrait;
+use zmq::Context;
+use zmq::Message;
+use zmq::Socket;
+use zmq::SocketType;
+
+//-----------------------------------------------------------------------------------------
+// Errors
+//-----------------------------------------------------------------------------------------
+
+#[derive(Debug, thiserror::Error)]
+pub enum BusClientError {
+    #[error("ZeroMQ operation failed")]
+    Zmq(#[from] zmq::Error),
+
+    #[error("Failed to encode message")]
+    Encode(#[from] MessageEncodeError),
+
+    #[error("Failed to decode message")]
+    Decode(#[from] MessageDecodeError),
+
+    #[error("BUS has not answered in time")]
+    Timeout,
+
+    #[error("BUS has rejected message: {0}")]
+    Rejected(String),
+
+    #[error("No registered service handles {0:?}")]
+    MissingProviders(Vec<ZeromqMessageKind>),
+}
+
+//-----------------------------------------------------------------------------------------
+// PublishConfirmationOutcome
+//-----------------------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum PublishConfirmationOutcome {
+    /// BUS has accepted the message and is responsible for its delivery.
+    Accepted,
+    /// BUS has refused the message with the given reason.
+    Rejected(String),
+}
+
+//-----------------------------------------------------------------------------------------
+// BusClientEvent
+//-----------------------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum BusClientEvent {
+    /// BUS answer on one of published messages.
+    Confirmation {
+        uuid: Uuid,
+        outcome: PublishConfirmationOutcome,
+    },
+    /// Any other message which BUS has sent directly to this client, for example
+    /// work-queue request.
+    Message(Vec<u8>),
+}
+
+//-----------------------------------------------------------------------------------------
+// BusClient
+//-----------------------------------------------------------------------------------------
+
+/// Producer side of the BUS connection. Keeps track of published messages which were not
+/// confirmed by the BUS yet, so sender is able to distinguish lost messages from messages
+/// which nobody has answered.
+pub struct BusClient {
+    socket: Socket,
+    unconfirmed_messages: HashMap<Uuid, Instant>,
+    heartbeat_interval: Option<Duration>,
+    last_heartbeat_time: Option<Instant>,
+}
+
+impl BusClient {
+    pub fn connect(context: &Context, router_endpoint: &str) -> Result<Self, BusClientError> {
+        Self::connect_any(context, &[router_endpoint])
+    }
+
+    /// Connects to every given router endpoint, messages are sent only to the endpoints
+    /// which are actually bound, so a standby BUS receives them once it has taken over.
+    pub fn connect_any<E: AsRef<str>>(
+        context: &Context,
+        router_endpoints: &[E],
+    ) -> Result<Self, BusClientError> {
+        Self::connect_socket(context.socket(SocketType::DEALER)?, router_endpoints)
+    }
+
+    /// Connects to router endpoints of the configuration, with CURVE encryption when the
+    /// client keys are configured.
+    pub fn connect_endpoints(
+        context: &Context,
+        endpoints: &EndpointsConfig,
+    ) -> Result<Self, BusClientError> {
+        let socket = context.socket(SocketType::DEALER)?;
+        secure_client_socket(&socket, endpoints)?;
+        Self::connect_socket(socket, endpoints.client_routers().as_slice())
+    }
+
+    fn connect_socket<E: AsRef<str>>(
+        socket: Socket,
+        router_endpoints: &[E],
+    ) -> Result<Self, BusClientError> {
+        if router_endpoints.len() > 1 {
+            socket.set_immediate(true)?;
+        }
+        for router_endpoint in router_endpoints {
+            socket.connect(router_endpoint.as_ref())?;
+        }
+
+        Ok(Self {
+            socket,
+            unconfirmed_messages: HashMap::new(),
+            heartbeat_interval: None,
+            last_heartbeat_time: None,
+        })
+    }
+
+    /// Makes `send_heartbeat_if_due` announce liveness of this client to the BUS once per
+    /// the interval. Clients which never send heartbeats are not tracked by the BUS.
+    pub fn set_heartbeat_interval(&mut self, interval: Duration) {
+        self.heartbeat_interval = Some(interval);
+    }
+
+    /// Sends heartbeat when the interval has passed since the previous one, so it should
+    /// be called from the client loop more often than the interval. Heartbeats are not
+    /// confirmed by the BUS. Returns whether heartbeat was sent.
+    pub fn send_heartbeat_if_due(&mut self) -> Result<bool, BusClientError> {
+        let Some(heartbeat_interval) = self.heartbeat_interval else {
+            return Ok(false);
+        };
+        if self.last_heartbeat_time.is_some_and(|last_heartbeat_time| {
+            last_heartbeat_time.elapsed() < heartbeat_interval
+        }) {
+            return Ok(false);
+        }
+
+        let message_bytes = encode_message(
+            Uuid::new_v4(),
+            Heartbeat {
+                interval_ms: i64::try_from(heartbeat_interval.as_millis()).unwrap_or(i64::MAX),
+            },
+        )?;
+        self.socket
+            .send(Message::from(message_bytes), ZEROMQ_ZERO_FLAG)?;
+        self.last_heartbeat_time = Some(Instant::now());
+
+        Ok(true)
+    }
+
+    /// Underlying DEALER socket, which can be used for polling together with other sockets.
+    #[must_use]
+    pub fn socket(&self) -> &Socket {
+        &self.socket
+    }
+
+    pub fn publish<'de, P: ZeromqMessageTrait<'de>>(
+        &mut self,
+        uuid: Uuid,
+        payload: P,
+    ) -> Result<(), BusClientError> {
+        let message_bytes = encode_message(uuid, payload)?;
+        self.publish_bytes(uuid, message_bytes)
+    }
+
+    pub fn publish_with_header<'de, P: ZeromqMessageTrait<'de>>(
+        &mut self,
+        uuid: Uuid,
+        header: &MessageHeader,
+        payload: P,
+    ) -> Result<(), BusClientError> {
+        let message_bytes = encode_message_with_header(uuid, header, payload)?;
+        self.publish_bytes(uuid, message_bytes)
+    }
+
+    /// Sends already encoded message, uuid should be the one which message contains.
+    pub fn publish_bytes(
+        &mut self,
+        uuid: Uuid,
+        message_bytes: Vec<u8>,
+    ) -> Result<(), BusClientError> {
+        self.socket
+            .send(Message::from(message_bytes), ZEROMQ_ZERO_FLAG)?;
+        let _ = self.unconfirmed_messages.insert(uuid, Instant::now());
+        Ok(())
+    }
+
+    /// Announces the service behind this connection, so other services are able to find
+    /// it with `query_services`.
+    pub fn register_service(
+        &mut self,
+        registration: ServiceRegistration,
+    ) -> Result<(), BusClientError> {
+        self.publish(Uuid::new_v4(), registration)
+    }
+
+    /// Cancels delivery of the message of the given kind which was published with deliver-at
+    /// header. Outcome is reported by confirmation of the returned uuid.
+    pub fn cancel_delivery(
+        &mut self,
+        scheduled_kind: ZeromqMessageKind,
+        scheduled_uuid: Uuid,
+    ) -> Result<Uuid, BusClientError> {
+        let uuid = Uuid::new_v4();
+        self.publish(
+            uuid,
+            DeliveryCancellation {
+                kind: i64::from(scheduled_kind as u32),
+                uuid: scheduled_uuid.to_string(),
+            },
+        )?;
+        Ok(uuid)
+    }
+
+    /// Asks the BUS for registered services and waits for the answer. Meant for startup
+    /// checks, direct messages received meanwhile are dropped.
+    pub fn query_services(
+        &mut self,
+        query: ServiceQuery,
+        timeout: Duration,
+    ) -> Result<Vec<ServiceQueryReplyItemProviders>, BusClientError> {
+        let uuid = Uuid::new_v4();
+        self.publish(uuid, query)?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(BusClientError::Timeout);
+            }
+
+            let poll_timeout = i64::try_from((deadline - now).as_millis()).unwrap_or(i64::MAX);
+            if self.socket.poll(zmq::POLLIN, poll_timeout)? == 0 {
+                continue;
+            }
+
+            match self.receive(zmq::DONTWAIT)? {
+                BusClientEvent::Message(message_bytes) => {
+                    if decode_message_kind_and_uuid(message_bytes.as_slice())?
+                        == (ZeromqMessageKind::ServiceQueryReply, uuid)
+                    {
+                        let reply = decode_message_payload::<'_, ServiceQueryReply>(
+                            message_payload_bytes(message_bytes.as_slice())?,
+                        )?;
+                        return Ok(reply.providers);
+                    }
+                }
+                BusClientEvent::Confirmation {
+                    uuid: confirmed_uuid,
+                    outcome: PublishConfirmationOutcome::Rejected(reason),
+                } if confirmed_uuid == uuid => return Err(BusClientError::Rejected(reason)),
+                BusClientEvent::Confirmation { .. } => {}
+            }
+        }
+    }
+
+    /// Checks that every kind is handled by at least one registered service, so
+    /// deployment is able to fail fast instead of sending requests nobody answers.
+    pub fn require_providers(
+        &mut self,
+        kinds: &[ZeromqMessageKind],
+        timeout: Duration,
+    ) -> Result<(), BusClientError> {
+        let providers = self.query_services(
+            ServiceQuery {
+                kind: None,
+                name: None,
+            },
+            timeout,
+        )?;
+
+        let missing_kinds = kinds
+            .iter()
+            .copied()
+            .filter(|kind| {
+                !providers
+                    .iter()
+                    .any(|provider| provider.handled_kinds.contains(&i64::from(*kind as u32)))
+            })
+            .collect::<Vec<ZeromqMessageKind>>();
+
+        if missing_kinds.is_empty() {
+            Ok(())
+        } else {
+            Err(BusClientError::MissingProviders(missing_kinds))
+        }
+    }
+
+    /// Reports to the BUS that received message can't be processed by this client, so BUS
+    /// moves it to the dead-letter queue.
+    pub fn reject(
+        &mut self,
+        message_bytes: &[u8],
+        reason: String,
+    ) -> Result<(), BusClientError> {
+        let (_, uuid) = decode_message_kind_and_uuid(message_bytes)?;
+
+        self.publish(
+            uuid,
+            MessageRejection {
+                reason,
+                message: message_bytes.iter().map(|byte| i64::from(*byte)).collect(),
+            },
+        )
+    }
+
+    /// Receives next message sent by BUS to this client. Pass `zmq::DONTWAIT` to get
+    /// `zmq::Error::EAGAIN` instead of blocking when nothing is received.
+    pub fn receive(&mut self, flags: i32) -> Result<BusClientEvent, BusClientError> {
+        let message_bytes = self.socket.recv_bytes(flags)?;
+
+        match decode_message_kind_and_uuid(message_bytes.as_slice()) {
+            Ok((ZeromqMessageKind::PublishConfirmation, uuid)) => {
+                let payload = decode_message_payload::<'_, PublishConfirmation>(
+                    message_payload_bytes(message_bytes.as_slice())?,
+                )?;
+
+                let _ = self.unconfirmed_messages.remove(&uuid);
+
+                let outcome = if payload.accepted {
+                    PublishConfirmationOutcome::Accepted
+                } else {
+                    PublishConfirmationOutcome::Rejected(payload.reason.unwrap_or_default())
+                };
+
+                Ok(BusClientEvent::Confirmation { uuid, outcome })
+            }
+            _ => Ok(BusClientEvent::Message(message_bytes)),
+        }
+    }
+
+    /// Waits until BUS confirms all published messages or the deadline passes, direct
+    /// messages received meanwhile are dropped. Used on shutdown, returns count of
+    /// messages which are still not confirmed.
+    pub fn flush(&mut self, deadline: Instant) -> usize {
+        while !self.unconfirmed_messages.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+
+            let timeout = i64::try_from((deadline - now).as_millis()).unwrap_or(i64::MAX);
+            match self.socket.poll(zmq::POLLIN, timeout) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(error) => {
+                    log::error!("failed to poll BUS client socket because of: {}", error);
+                    break;
+                }
+            }
+
+            match self.receive(zmq::DONTWAIT) {
+                Ok(BusClientEvent::Confirmation {
+                    uuid,
+                    outcome: PublishConfirmationOutcome::Rejected(reason),
+                }) => log::error!("BUS rejected message {} because of: {}", uuid, reason),
+                Ok(_) | Err(BusClientError::Zmq(zmq::Error::EAGAIN)) => {}
+                Err(error) => {
+                    log::error!("failed to receive confirmation because of: {}", error);
+                    break;
+                }
+            }
+        }
+
+        self.unconfirmed_messages.len()
+    }
+
+    #[must_use]
+    pub fn is_confirmed(&self, uuid: &Uuid) -> bool {
+        !self.unconfirmed_messages.contains_key(uuid)
+    }
+
+    #[must_use]
+    pub fn unconfirmed_messages_count(&self) -> usize {
+        self.unconfirmed_messages.len()
+    }
+
+    /// Returns uuids of messages which were not confirmed during the timeout, most likely
+    /// BUS never got them. Returned messages are not tracked anymore.
+    pub fn take_unconfirmed_messages(&mut self, timeout: Duration) -> Vec<Uuid> {
+        let now = Instant::now();
+        let expired_uuids = self
+            .unconfirmed_messages
+            .iter()
+            .filter(|(_, publish_time)| now.duration_since(**publish_time) > timeout)
+            .map(|(uuid, _)| *uuid)
+            .collect::<Vec<Uuid>>();
+
+        for uuid in &expired_uuids {
+            let _ = self.unconfirmed_messages.remove(uuid);
+        }
+
+        expired_uuids
+    }
+}
+
+impl fmt::Debug for BusClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BusClient")
+            .field("socket", &self.socket.get_socket_type())
+            .field("unconfirmed_messages", &self.unconfirmed_messages.len())
+            .field("heartbeat_interval", &self.heartbeat_interval)
+            .field("last_heartbeat_time", &self.last_heartbeat_time)
+            .finish()
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// ProcessedRequests
+//-----------------------------------------------------------------------------------------
+
+/// Responses of requests which responder has recently processed. Sender resends request
+/// with the same uuid when response does not come in time, so resent request is answered
+/// with the remembered response instead of being processed once more.
+#[derive(Debug)]
+pub struct ProcessedRequests {
+    responses: DeduplicationWindow<Uuid, Vec<u8>>,
+}
+
+impl ProcessedRequests {
+    /// Responses are remembered for the max age, and at most for the capacity of the most
+    /// recent requests.
+    #[must_use]
+    pub fn new(max_age: Duration, capacity: usize) -> Self {
+        Self {
+            responses: DeduplicationWindow::new(max_age, capacity),
+        }
+    }
+
+    /// Encoded response of the request, when the request has already been processed.
+    pub fn response(&mut self, request_uuid: &Uuid) -> Option<&[u8]> {
+        self.responses
+            .get(request_uuid, Instant::now())
+            .map(Vec::as_slice)
+    }
+
+    pub fn remember(&mut self, request_uuid: Uuid, response_bytes: Vec<u8>) {
+        self.responses
+            .insert(request_uuid, response_bytes, Instant::now());
+    }
+}
@@ -0,0 +1,1721 @@
+use crate::security::decode_curve_key;
+use crate::AclRule;
+use crate::ExpiryPolicy;
+use crate::OverflowPolicy;
+use crate::PublisherSelectionStrategy;
+use crate::RateLimit;
+use crate::RateLimitPolicy;
+use crate::UnroutablePolicy;
+use crate::WorkerSelectionStrategy;
+use crate::WriteAheadLogFsyncPolicy;
+use crate::BUS_CLIENT_ACTIVITY_TTL_MS;
+use crate::BUS_DEAD_LETTER_QUEUE_CAPACITY;
+use crate::BUS_DEDUP_WINDOW_CAPACITY;
+use crate::BUS_EXPIRY_POLICY;
+use crate::BUS_HEARTBEAT_GRACE_MS;
+use crate::BUS_PAUSED_MESSAGES_CAPACITY;
+use crate::BUS_PAUSED_MESSAGES_OVERFLOW_POLICY;
+use crate::BUS_PRIORITY_WEIGHTS;
+use crate::BUS_PUBLISHERS_HIGH_WATER_MARK;
+use crate::BUS_PUBLISHERS_SOCKET_ADDRS;
+use crate::BUS_PUBLISHER_SELECTION_STRATEGY;
+use crate::BUS_PUBLISHING_QUEUE_CAPACITY;
+use crate::BUS_PUBLISHING_QUEUE_OVERFLOW_POLICY;
+use crate::BUS_PUBLISH_RETRY_BUDGET;
+use crate::BUS_RATE_LIMIT_POLICY;
+use crate::BUS_RETRY_BUFFER_CAPACITY;
+use crate::BUS_ROUTER_HIGH_WATER_MARK;
+use crate::BUS_ROUTER_SOCKET_ADDR;
+use crate::BUS_SCHEDULED_MESSAGES_CAPACITY;
+use crate::BUS_SCHEDULE_MAX_DELAY_MS;
+use crate::BUS_UNROUTABLE_POLICY;
+use crate::BUS_WAL_FSYNC_POLICY;
+use crate::BUS_WAL_SEGMENT_MAX_BYTES;
+use crate::BUS_WORK_QUEUE_CAPACITY;
+use crate::BUS_WORK_QUEUE_KINDS;
+use crate::BUS_WORK_QUEUE_OVERFLOW_POLICY;
+use crate::BUS_WORK_QUEUE_STRATEGY;
+use crate::HEARTBEAT_INTERVAL_MS;
+use crate::LOG_LEVEL;
+use crate::REQUESTS_COUNT_INSIDE_ONE_GROUP;
+use crate::SHUTDOWN_DEADLINE_MS;
+use log::LevelFilter;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::iter::Iterator;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+use structopt::StructOpt;
+use zeromq_messages::header::MessagePriority;
+use zeromq_messages::kind::ZeromqMessageKind;
+
+const LOG_LEVELS: &[&str] = &["off", "error", "warn", "info", "debug", "trace"];
+const REDACTED_VALUE: &str = "<redacted>";
+
+//-----------------------------------------------------------------------------------------
+// Errors
+//-----------------------------------------------------------------------------------------
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("Failed to read configuration file {path:?}: {source}")]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("Failed to parse configuration file {path:?}: {source}")]
+    Parse {
+        path: PathBuf,
+        #[source]
+        source: toml::de::Error,
+    },
+
+    #[error("Invalid endpoint '{endpoint}': {reason}")]
+    InvalidEndpoint { endpoint: String, reason: String },
+
+    #[error("Invalid value of '{key}': {reason}")]
+    InvalidValue { key: &'static str, reason: String },
+}
+
+//-----------------------------------------------------------------------------------------
+// ConfigOptions
+//-----------------------------------------------------------------------------------------
+
+/// Command line flags which are shared by BUS and services. Every flag can be given
+/// through the environment variable as well, flags win over environment variables and
+/// both win over the configuration file.
+#[derive(Debug, Clone, Default, StructOpt)]
+pub struct ConfigOptions {
+    /// TOML configuration file, built-in defaults are used when absent
+    #[structopt(long, env = "BUS_CONFIG", parse(from_os_str))]
+    pub config: Option<PathBuf>,
+
+    /// Prints effective configuration and exits
+    #[structopt(long)]
+    pub print_config: bool,
+
+    #[structopt(long, env = "BUS_LOG_LEVEL")]
+    pub log_level: Option<String>,
+
+    #[structopt(long, env = "BUS_REQUESTS_COUNT_INSIDE_ONE_GROUP")]
+    pub requests_count_inside_one_group: Option<usize>,
+
+    /// Time given on SIGTERM or SIGINT to flush in-flight messages before exit
+    #[structopt(long, env = "BUS_SHUTDOWN_DEADLINE_MS")]
+    pub shutdown_deadline_ms: Option<u64>,
+
+    /// How often services send heartbeats to BUS
+    #[structopt(long, env = "BUS_HEARTBEAT_INTERVAL_MS")]
+    pub heartbeat_interval_ms: Option<u64>,
+
+    #[structopt(long, env = "BUS_ROUTER_ENDPOINT")]
+    pub router_endpoint: Option<String>,
+
+    /// Can be repeated, environment variable takes comma separated list
+    #[structopt(
+        long = "publisher-endpoint",
+        env = "BUS_PUBLISHER_ENDPOINTS",
+        use_delimiter = true
+    )]
+    pub publisher_endpoints: Vec<String>,
+
+    /// Endpoint of BUS administrative commands, disabled when absent
+    #[structopt(long, env = "BUS_CONTROL_ENDPOINT")]
+    pub control_endpoint: Option<String>,
+
+    /// Router endpoint of the standby BUS which services use when the active one is gone,
+    /// can be repeated, environment variable takes comma separated list
+    #[structopt(
+        long = "failover-router-endpoint",
+        env = "BUS_FAILOVER_ROUTER_ENDPOINTS",
+        use_delimiter = true
+    )]
+    pub failover_router_endpoints: Vec<String>,
+
+    /// Publisher endpoint of the standby BUS, can be repeated, environment variable takes
+    /// comma separated list
+    #[structopt(
+        long = "failover-publisher-endpoint",
+        env = "BUS_FAILOVER_PUBLISHER_ENDPOINTS",
+        use_delimiter = true
+    )]
+    pub failover_publisher_endpoints: Vec<String>,
+
+    /// Public CURVE key of BUS in Z85 form, services use it to verify the BUS
+    #[structopt(long, env = "BUS_CURVE_SERVER_PUBLIC_KEY")]
+    pub curve_server_public_key: Option<String>,
+
+    /// Secret CURVE key of BUS in Z85 form, BUS sockets are encrypted when present
+    #[structopt(long, env = "BUS_CURVE_SERVER_SECRET_KEY", hide_env_values = true)]
+    pub curve_server_secret_key: Option<String>,
+
+    /// Public CURVE key of the service in Z85 form
+    #[structopt(long, env = "BUS_CURVE_CLIENT_PUBLIC_KEY")]
+    pub curve_client_public_key: Option<String>,
+
+    /// Secret CURVE key of the service in Z85 form
+    #[structopt(long, env = "BUS_CURVE_CLIENT_SECRET_KEY", hide_env_values = true)]
+    pub curve_client_secret_key: Option<String>,
+
+    /// File with public CURVE keys of services which may connect to BUS, one per line
+    #[structopt(long, env = "BUS_CURVE_ALLOWED_CLIENTS_FILE", parse(from_os_str))]
+    pub curve_allowed_clients_file: Option<PathBuf>,
+
+    #[structopt(long, env = "BUS_WAL_DIRECTORY", parse(from_os_str))]
+    pub wal_directory: Option<PathBuf>,
+
+    /// Size after which write-ahead log continues in a new segment file
+    #[structopt(long, env = "BUS_WAL_SEGMENT_MAX_BYTES")]
+    pub wal_segment_max_bytes: Option<u64>,
+
+    /// How often write-ahead log is synchronized to the disk, one of: always, never,
+    /// every-records:<count>
+    #[structopt(long, env = "BUS_WAL_FSYNC_POLICY")]
+    pub wal_fsync_policy: Option<WriteAheadLogFsyncPolicy>,
+
+    #[structopt(long, env = "BUS_DEAD_LETTER_FILE", parse(from_os_str))]
+    pub dead_letter_file: Option<PathBuf>,
+
+    #[structopt(long, env = "BUS_DEAD_LETTER_QUEUE_CAPACITY")]
+    pub dead_letter_queue_capacity: Option<usize>,
+
+    /// What happens with messages whose deadline has passed, one of: drop, dead-letter
+    #[structopt(long, env = "BUS_EXPIRY_POLICY")]
+    pub expiry_policy: Option<ExpiryPolicy>,
+
+    /// Time during which messages with the same kind and uuid as an already received one
+    /// are dropped, disabled when absent
+    #[structopt(long, env = "BUS_DEDUP_WINDOW_MS")]
+    pub dedup_window_ms: Option<u64>,
+
+    /// Maximum count of recent messages which are remembered for deduplication
+    #[structopt(long, env = "BUS_DEDUP_WINDOW_CAPACITY")]
+    pub dedup_window_capacity: Option<usize>,
+
+    /// What happens with messages which no subscriber wants, one of: publish, drop,
+    /// dead-letter
+    #[structopt(long, env = "BUS_UNROUTABLE_POLICY")]
+    pub unroutable_policy: Option<UnroutablePolicy>,
+
+    #[structopt(long, env = "BUS_PUBLISH_RETRY_BUDGET")]
+    pub publish_retry_budget: Option<usize>,
+
+    #[structopt(long, env = "BUS_PUBLISHING_QUEUE_CAPACITY")]
+    pub publishing_queue_capacity: Option<usize>,
+
+    /// One of: block, drop-oldest, drop-newest, reject-with-nack
+    #[structopt(long, env = "BUS_PUBLISHING_QUEUE_OVERFLOW_POLICY")]
+    pub publishing_queue_overflow_policy: Option<OverflowPolicy>,
+
+    #[structopt(long, env = "BUS_RETRY_BUFFER_CAPACITY")]
+    pub retry_buffer_capacity: Option<usize>,
+
+    /// Maximum count of messages which wait for their deliver-at time
+    #[structopt(long, env = "BUS_SCHEDULED_MESSAGES_CAPACITY")]
+    pub scheduled_messages_capacity: Option<usize>,
+
+    /// Longest time ahead which deliver-at time of a message may be at
+    #[structopt(long, env = "BUS_SCHEDULE_MAX_DELAY_MS")]
+    pub schedule_max_delay_ms: Option<u64>,
+
+    /// Maximum count of messages of paused kinds which wait until the kinds are resumed
+    #[structopt(long, env = "BUS_PAUSED_MESSAGES_CAPACITY")]
+    pub paused_messages_capacity: Option<usize>,
+
+    /// One of: drop-oldest, drop-newest, reject-with-nack
+    #[structopt(long, env = "BUS_PAUSED_MESSAGES_OVERFLOW_POLICY")]
+    pub paused_messages_overflow_policy: Option<OverflowPolicy>,
+
+    #[structopt(long, env = "BUS_ROUTER_HIGH_WATER_MARK")]
+    pub router_high_water_mark: Option<i32>,
+
+    #[structopt(long, env = "BUS_PUBLISHERS_HIGH_WATER_MARK")]
+    pub publishers_high_water_mark: Option<i32>,
+
+    /// One of: round-robin, least-recently-used, hash-by-kind, hash-by-uuid
+    #[structopt(long, env = "BUS_PUBLISHER_SELECTION_STRATEGY")]
+    pub publisher_selection_strategy: Option<PublisherSelectionStrategy>,
+
+    /// One of: round-robin, least-loaded, credit-based
+    #[structopt(long, env = "BUS_WORK_QUEUE_STRATEGY")]
+    pub work_queue_strategy: Option<WorkerSelectionStrategy>,
+
+    /// Maximum count of work-queue requests which are held while no worker accepts them
+    #[structopt(long, env = "BUS_WORK_QUEUE_CAPACITY")]
+    pub work_queue_capacity: Option<usize>,
+
+    /// One of: drop-oldest, drop-newest, reject-with-nack
+    #[structopt(long, env = "BUS_WORK_QUEUE_OVERFLOW_POLICY")]
+    pub work_queue_overflow_policy: Option<OverflowPolicy>,
+
+    /// Address like 127.0.0.1:9100 where metrics are served over HTTP
+    #[structopt(long, env = "BUS_METRICS_ADDRESS")]
+    pub metrics_address: Option<String>,
+
+    /// File where every received and published frame is recorded for later replay
+    #[structopt(long, env = "BUS_CAPTURE_FILE", parse(from_os_str))]
+    pub capture_file: Option<PathBuf>,
+
+    /// Time without any frame from the heartbeating service after which BUS considers it
+    /// dead
+    #[structopt(long, env = "BUS_HEARTBEAT_GRACE_MS")]
+    pub heartbeat_grace_ms: Option<u64>,
+
+    /// Time without any message from the client after which control socket doesn't list it
+    #[structopt(long, env = "BUS_CLIENT_ACTIVITY_TTL_MS")]
+    pub client_activity_ttl_ms: Option<u64>,
+
+    /// Identifier of this BUS among federated ones, random one is used when absent
+    #[structopt(long, env = "BUS_INSTANCE_ID")]
+    pub instance_id: Option<String>,
+
+    /// Router endpoint of the peer BUS which federated kinds are forwarded to, can be
+    /// repeated, environment variable takes comma separated list
+    #[structopt(
+        long = "federation-peer",
+        env = "BUS_FEDERATION_PEERS",
+        use_delimiter = true
+    )]
+    pub federation_peers: Vec<String>,
+
+    /// Kind which is forwarded to peers, can be repeated, environment variable takes
+    /// comma separated list
+    #[structopt(
+        long = "federation-kind",
+        env = "BUS_FEDERATION_KINDS",
+        use_delimiter = true
+    )]
+    pub federation_kinds: Vec<u32>,
+
+    /// Kind which overtakes queued messages of normal and low priority, can be repeated,
+    /// environment variable takes comma separated list
+    #[structopt(
+        long = "high-priority-kind",
+        env = "BUS_HIGH_PRIORITY_KINDS",
+        use_delimiter = true
+    )]
+    pub high_priority_kinds: Vec<u32>,
+
+    /// Kind which gives way to messages of normal and high priority, can be repeated,
+    /// environment variable takes comma separated list
+    #[structopt(
+        long = "low-priority-kind",
+        env = "BUS_LOW_PRIORITY_KINDS",
+        use_delimiter = true
+    )]
+    pub low_priority_kinds: Vec<u32>,
+
+    /// Shares of publishing which high, normal and low priority messages get when all of
+    /// them are queued, comma separated
+    #[structopt(long, env = "BUS_PRIORITY_WEIGHTS", use_delimiter = true)]
+    pub priority_weights: Vec<u32>,
+
+    /// Messages per second which every client may send, not limited when absent
+    #[structopt(long, env = "BUS_RATE_LIMIT_PER_SECOND")]
+    pub rate_limit_per_second: Option<u32>,
+
+    /// Messages which client may send at once above its rate, equal to the rate when
+    /// absent
+    #[structopt(long, env = "BUS_RATE_LIMIT_BURST")]
+    pub rate_limit_burst: Option<u32>,
+
+    /// Kind which is limited separately from other kinds, can be repeated, environment
+    /// variable takes comma separated list
+    #[structopt(
+        long = "rate-limited-kind",
+        env = "BUS_RATE_LIMITED_KINDS",
+        use_delimiter = true
+    )]
+    pub rate_limited_kinds: Vec<u32>,
+
+    /// What happens with messages above the rate limit, one of: drop, nack
+    #[structopt(long, env = "BUS_RATE_LIMIT_POLICY")]
+    pub rate_limit_policy: Option<RateLimitPolicy>,
+
+    /// Endpoint where active BUS streams its state to standby BUSes
+    #[structopt(long, env = "BUS_REPLICATION_ENDPOINT")]
+    pub replication_endpoint: Option<String>,
+
+    /// Replication endpoint of the active BUS, this BUS stands by until it stops
+    #[structopt(long, env = "BUS_STANDBY_OF")]
+    pub standby_of: Option<String>,
+}
+
+//-----------------------------------------------------------------------------------------
+// EndpointsConfig
+//-----------------------------------------------------------------------------------------
+
+/// Endpoints which are bound by BUS and connected by services.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EndpointsConfig {
+    pub router: String,
+    pub publishers: Vec<String>,
+    /// Endpoint of the control socket which accepts administrative commands, the socket
+    /// is not bound when absent.
+    pub control: Option<String>,
+    /// Router endpoints of standby BUSes, services connect to them as well and use the
+    /// one which is bound at the moment.
+    pub failover_routers: Vec<String>,
+    /// Publisher endpoints of standby BUSes.
+    pub failover_publishers: Vec<String>,
+    /// Public CURVE key of BUS in Z85 form, services which have their own keys connect
+    /// with encryption and check that they talk to the BUS which holds the secret key.
+    pub curve_server_public_key: Option<String>,
+    /// Secret CURVE key of BUS in Z85 form. Router, publisher and control sockets accept
+    /// encrypted connections only when it is present.
+    pub curve_server_secret_key: Option<String>,
+    pub curve_client_public_key: Option<String>,
+    pub curve_client_secret_key: Option<String>,
+    /// File with public keys of services which BUS accepts, one Z85 key per line. Any
+    /// service with a key pair is accepted when absent.
+    pub curve_allowed_clients_file: Option<PathBuf>,
+}
+
+impl EndpointsConfig {
+    /// Router endpoints which services connect to, the active one goes first.
+    #[must_use]
+    pub fn client_routers(&self) -> Vec<String> {
+        std::iter::once(&self.router)
+            .chain(self.failover_routers.iter())
+            .cloned()
+            .collect()
+    }
+
+    /// Publisher endpoints which services subscribe to, the active ones go first.
+    #[must_use]
+    pub fn client_publishers(&self) -> Vec<String> {
+        self.publishers
+            .iter()
+            .chain(self.failover_publishers.iter())
+            .cloned()
+            .collect()
+    }
+
+    fn apply_curve_options(&mut self, options: &ConfigOptions) {
+        for (value, override_value) in [
+            (
+                &mut self.curve_server_public_key,
+                &options.curve_server_public_key,
+            ),
+            (
+                &mut self.curve_server_secret_key,
+                &options.curve_server_secret_key,
+            ),
+            (
+                &mut self.curve_client_public_key,
+                &options.curve_client_public_key,
+            ),
+            (
+                &mut self.curve_client_secret_key,
+                &options.curve_client_secret_key,
+            ),
+        ] {
+            if override_value.is_some() {
+                value.clone_from(override_value);
+            }
+        }
+        if options.curve_allowed_clients_file.is_some() {
+            self.curve_allowed_clients_file
+                .clone_from(&options.curve_allowed_clients_file);
+        }
+    }
+
+    /// Keys which are given should be well-formed, client keys go in pairs and need the
+    /// key of the server.
+    fn validate_curve_keys(&self) -> Result<(), ConfigError> {
+        for (key, value) in [
+            (
+                "endpoints.curve_server_public_key",
+                &self.curve_server_public_key,
+            ),
+            (
+                "endpoints.curve_server_secret_key",
+                &self.curve_server_secret_key,
+            ),
+            (
+                "endpoints.curve_client_public_key",
+                &self.curve_client_public_key,
+            ),
+            (
+                "endpoints.curve_client_secret_key",
+                &self.curve_client_secret_key,
+            ),
+        ] {
+            if let Some(value) = value {
+                if let Err(reason) = decode_curve_key(value.as_str()) {
+                    return Err(ConfigError::InvalidValue { key, reason });
+                }
+            }
+        }
+
+        if self.curve_client_public_key.is_some() != self.curve_client_secret_key.is_some() {
+            return Err(ConfigError::InvalidValue {
+                key: "endpoints.curve_client_secret_key",
+                reason: "client public and secret keys should be given together".to_string(),
+            });
+        }
+
+        if self.curve_client_public_key.is_some() && self.curve_server_public_key.is_none() {
+            return Err(ConfigError::InvalidValue {
+                key: "endpoints.curve_server_public_key",
+                reason: "client keys require the public key of BUS".to_string(),
+            });
+        }
+
+        if self.curve_allowed_clients_file.is_some() && self.curve_server_secret_key.is_none()
+        {
+            return Err(ConfigError::InvalidValue {
+                key: "endpoints.curve_allowed_clients_file",
+                reason: "allow-list requires endpoints.curve_server_secret_key".to_string(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for EndpointsConfig {
+    fn default() -> Self {
+        Self {
+            router: BUS_ROUTER_SOCKET_ADDR.clone(),
+            publishers: BUS_PUBLISHERS_SOCKET_ADDRS.clone(),
+            control: None,
+            failover_routers: Vec::new(),
+            failover_publishers: Vec::new(),
+            curve_server_public_key: None,
+            curve_server_secret_key: None,
+            curve_client_public_key: None,
+            curve_client_secret_key: None,
+            curve_allowed_clients_file: None,
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// BusConfig
+//-----------------------------------------------------------------------------------------
+
+/// Settings which are used by BUS only.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BusConfig {
+    pub wal_directory: Option<PathBuf>,
+    pub wal_segment_max_bytes: u64,
+    pub wal_fsync_policy: WriteAheadLogFsyncPolicy,
+    pub dead_letter_file: Option<PathBuf>,
+    pub dead_letter_queue_capacity: usize,
+    pub expiry_policy: ExpiryPolicy,
+    /// Time during which received message is dropped when another one with the same kind
+    /// and uuid has already been received, messages are not deduplicated when absent.
+    pub dedup_window_ms: Option<u64>,
+    /// Maximum count of recent messages which are remembered for deduplication, the
+    /// oldest ones are forgotten before their time when there are more of them.
+    pub dedup_window_capacity: usize,
+    /// Messages which no subscriber wants are not sent unless the policy is to publish
+    /// them, subscriptions are known from events of publisher sockets.
+    pub unroutable_policy: UnroutablePolicy,
+    pub publish_retry_budget: usize,
+    pub publishing_queue_capacity: usize,
+    pub publishing_queue_overflow_policy: OverflowPolicy,
+    pub retry_buffer_capacity: usize,
+    /// Maximum count of messages which wait for their deliver-at time, messages which
+    /// come above it are refused.
+    pub scheduled_messages_capacity: usize,
+    /// Messages whose deliver-at time is further ahead than this are refused.
+    pub schedule_max_delay_ms: u64,
+    /// Maximum count of messages of paused kinds which are held until the kinds are
+    /// resumed.
+    pub paused_messages_capacity: usize,
+    /// What happens with message of paused kind which comes when held messages are at
+    /// capacity, BUS can't wait for kinds to be resumed, so blocking policy is not allowed.
+    pub paused_messages_overflow_policy: OverflowPolicy,
+    pub router_high_water_mark: i32,
+    pub publishers_high_water_mark: i32,
+    /// How publisher socket is chosen for every published message, hash strategies keep
+    /// order of messages with the same kind or uuid.
+    pub publisher_selection_strategy: PublisherSelectionStrategy,
+    pub work_queue_kinds: Vec<u32>,
+    pub work_queue_strategy: WorkerSelectionStrategy,
+    /// Maximum count of requests which are held while no worker is able to accept them.
+    pub work_queue_capacity: usize,
+    /// What happens with request which comes when held requests are at capacity, BUS
+    /// can't wait for workers, so blocking policy is not allowed.
+    pub work_queue_overflow_policy: OverflowPolicy,
+    /// Address of HTTP `/metrics` endpoint, metrics are not served when absent.
+    pub metrics_address: Option<String>,
+    /// File where traffic is captured, nothing is captured when absent.
+    pub capture_file: Option<PathBuf>,
+    /// Time without any frame from the heartbeating client after which it is considered
+    /// dead, should be greater than the heartbeat interval.
+    pub heartbeat_grace_ms: u64,
+    /// Time without any message from the client after which control socket doesn't list
+    /// it, should be greater than the heartbeat grace.
+    pub client_activity_ttl_ms: u64,
+    /// Identifier of this BUS in hop lists of federated messages, random one is generated
+    /// on start when absent.
+    pub instance_id: Option<String>,
+    /// Router endpoints of peer BUSes, nothing is forwarded when empty.
+    pub federation_peers: Vec<String>,
+    /// Kinds of published messages which are forwarded to peers, work-queue requests are
+    /// dispatched to local workers only.
+    pub federation_kinds: Vec<u32>,
+    /// Kinds which are published before queued messages of other kinds, unless message
+    /// header tells another priority.
+    pub high_priority_kinds: Vec<u32>,
+    /// Kinds which are published after queued messages of other kinds, unless message
+    /// header tells another priority.
+    pub low_priority_kinds: Vec<u32>,
+    /// Weights of high, normal and low priority lanes of the publishing queue.
+    pub priority_weights: Vec<u32>,
+    /// Messages per second which every client connected to the router socket may send,
+    /// messages are not limited when absent.
+    pub rate_limit_per_second: Option<u32>,
+    /// Messages which client may send at once after being quiet, the rate is used when
+    /// absent.
+    pub rate_limit_burst: Option<u32>,
+    /// Kinds which have limits of their own for every client, messages of other kinds are
+    /// not limited. All messages of the client share one limit when empty.
+    pub rate_limited_kinds: Vec<u32>,
+    pub rate_limit_policy: RateLimitPolicy,
+    /// Kinds which authenticated clients may publish and subscribe to, every client may do
+    /// everything when empty. Requires CURVE, clients are told apart by their public keys.
+    /// Control socket executes commands only for clients which may publish `ControlCommand`.
+    pub acl: Vec<AclRule>,
+    /// Endpoint where BUS streams its state to standby BUSes, requires write-ahead log.
+    pub replication_endpoint: Option<String>,
+    /// Replication endpoint of the active BUS. BUS mirrors its state and binds own
+    /// endpoints only when the active one misses heartbeats during the grace time.
+    pub standby_of: Option<String>,
+}
+
+impl BusConfig {
+    /// Kinds of work-queue requests, configuration is validated so all of them are known.
+    #[must_use]
+    pub fn work_queue_kinds(&self) -> Vec<ZeromqMessageKind> {
+        self.work_queue_kinds
+            .iter()
+            .filter_map(|kind| ZeromqMessageKind::try_from(*kind).ok())
+            .collect()
+    }
+
+    /// Kinds which are forwarded to peers, configuration is validated so all of them are
+    /// known.
+    #[must_use]
+    pub fn federation_kinds(&self) -> Vec<ZeromqMessageKind> {
+        self.federation_kinds
+            .iter()
+            .filter_map(|kind| ZeromqMessageKind::try_from(*kind).ok())
+            .collect()
+    }
+
+    /// Priorities of kinds which are not normal, configuration is validated so all of
+    /// them are known.
+    #[must_use]
+    pub fn kind_priorities(&self) -> HashMap<ZeromqMessageKind, MessagePriority> {
+        let high_priority_kinds = self
+            .high_priority_kinds
+            .iter()
+            .map(|kind| (*kind, MessagePriority::High));
+        let low_priority_kinds = self
+            .low_priority_kinds
+            .iter()
+            .map(|kind| (*kind, MessagePriority::Low));
+
+        high_priority_kinds
+            .chain(low_priority_kinds)
+            .filter_map(|(kind, priority)| {
+                ZeromqMessageKind::try_from(kind)
+                    .ok()
+                    .map(|kind| (kind, priority))
+            })
+            .collect()
+    }
+
+    /// Limit of every client, clients are not limited when absent.
+    #[must_use]
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        self.rate_limit_per_second
+            .map(|messages_per_second| RateLimit {
+                messages_per_second,
+                burst: self.rate_limit_burst.unwrap_or(messages_per_second),
+            })
+    }
+
+    /// Kinds which are limited separately, configuration is validated so all of them are
+    /// known.
+    #[must_use]
+    pub fn rate_limited_kinds(&self) -> Vec<ZeromqMessageKind> {
+        self.rate_limited_kinds
+            .iter()
+            .filter_map(|kind| ZeromqMessageKind::try_from(*kind).ok())
+            .collect()
+    }
+}
+
+impl Default for BusConfig {
+    fn default() -> Self {
+        Self {
+            wal_directory: None,
+            wal_segment_max_bytes: BUS_WAL_SEGMENT_MAX_BYTES,
+            wal_fsync_policy: BUS_WAL_FSYNC_POLICY,
+            dead_letter_file: None,
+            dead_letter_queue_capacity: BUS_DEAD_LETTER_QUEUE_CAPACITY,
+            expiry_policy: BUS_EXPIRY_POLICY,
+            dedup_window_ms: None,
+            dedup_window_capacity: BUS_DEDUP_WINDOW_CAPACITY,
+            unroutable_policy: BUS_UNROUTABLE_POLICY,
+            publish_retry_budget: BUS_PUBLISH_RETRY_BUDGET,
+            publishing_queue_capacity: BUS_PUBLISHING_QUEUE_CAPACITY,
+            publishing_queue_overflow_policy: BUS_PUBLISHING_QUEUE_OVERFLOW_POLICY,
+            retry_buffer_capacity: BUS_RETRY_BUFFER_CAPACITY,
+            scheduled_messages_capacity: BUS_SCHEDULED_MESSAGES_CAPACITY,
+            schedule_max_delay_ms: BUS_SCHEDULE_MAX_DELAY_MS,
+            paused_messages_capacity: BUS_PAUSED_MESSAGES_CAPACITY,
+            paused_messages_overflow_policy: BUS_PAUSED_MESSAGES_OVERFLOW_POLICY,
+            router_high_water_mark: BUS_ROUTER_HIGH_WATER_MARK,
+            publishers_high_water_mark: BUS_PUBLISHERS_HIGH_WATER_MARK,
+            publisher_selection_strategy: BUS_PUBLISHER_SELECTION_STRATEGY,
+            work_queue_kinds: BUS_WORK_QUEUE_KINDS
+                .iter()
+                .map(|kind| *kind as u32)
+                .collect(),
+            work_queue_strategy: BUS_WORK_QUEUE_STRATEGY,
+            work_queue_capacity: BUS_WORK_QUEUE_CAPACITY,
+            work_queue_overflow_policy: BUS_WORK_QUEUE_OVERFLOW_POLICY,
+            metrics_address: None,
+            capture_file: None,
+            heartbeat_grace_ms: BUS_HEARTBEAT_GRACE_MS,
+            client_activity_ttl_ms: BUS_CLIENT_ACTIVITY_TTL_MS,
+            instance_id: None,
+            federation_peers: Vec::new(),
+            federation_kinds: Vec::new(),
+            high_priority_kinds: Vec::new(),
+            low_priority_kinds: Vec::new(),
+            priority_weights: BUS_PRIORITY_WEIGHTS.to_vec(),
+            rate_limit_per_second: None,
+            rate_limit_burst: None,
+            rate_limited_kinds: Vec::new(),
+            rate_limit_policy: BUS_RATE_LIMIT_POLICY,
+            acl: Vec::new(),
+            replication_endpoint: None,
+            standby_of: None,
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// Config
+//-----------------------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub log_level: String,
+    pub requests_count_inside_one_group: usize,
+    /// Time given on shutdown to flush in-flight messages, also used as socket linger.
+    pub shutdown_deadline_ms: u64,
+    /// Interval of heartbeats which services send to BUS.
+    pub heartbeat_interval_ms: u64,
+    pub endpoints: EndpointsConfig,
+    pub bus: BusConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            log_level: LOG_LEVEL.to_string(),
+            requests_count_inside_one_group: REQUESTS_COUNT_INSIDE_ONE_GROUP,
+            shutdown_deadline_ms: SHUTDOWN_DEADLINE_MS,
+            heartbeat_interval_ms: HEARTBEAT_INTERVAL_MS,
+            endpoints: EndpointsConfig::default(),
+            bus: BusConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Builds effective configuration from the file, environment variables and flags.
+    pub fn load(options: &ConfigOptions) -> Result<Self, ConfigError> {
+        let mut config = match &options.config {
+            Some(path) => {
+                let content =
+                    fs::read_to_string(path).map_err(|source| ConfigError::Read {
+                        path: path.clone(),
+                        source,
+                    })?;
+                Self::from_toml(content.as_str()).map_err(|source| ConfigError::Parse {
+                    path: path.clone(),
+                    source,
+                })?
+            }
+            None => Self::default(),
+        };
+
+        config.apply_options(options);
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn from_toml(content: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(content)
+    }
+
+    #[must_use]
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).expect("configuration is always serializable")
+    }
+
+    /// Serializes configuration the same as `to_toml`, but with secret keys masked, so
+    /// printed configuration can be shared safely.
+    #[must_use]
+    pub fn to_redacted_toml(&self) -> String {
+        let mut config = self.clone();
+        for secret_key in &mut [
+            &mut config.endpoints.curve_server_secret_key,
+            &mut config.endpoints.curve_client_secret_key,
+        ] {
+            if secret_key.is_some() {
+                **secret_key = Some(REDACTED_VALUE.to_string());
+            }
+        }
+        config.to_toml()
+    }
+
+    #[must_use]
+    pub fn shutdown_deadline(&self) -> Duration {
+        Duration::from_millis(self.shutdown_deadline_ms)
+    }
+
+    #[must_use]
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_millis(self.heartbeat_interval_ms)
+    }
+
+    /// Time during which duplicate messages are dropped, deduplication is disabled when
+    /// absent.
+    #[must_use]
+    pub fn dedup_window(&self) -> Option<Duration> {
+        self.bus.dedup_window_ms.map(Duration::from_millis)
+    }
+
+    #[must_use]
+    pub fn heartbeat_grace(&self) -> Duration {
+        Duration::from_millis(self.bus.heartbeat_grace_ms)
+    }
+
+    #[must_use]
+    pub fn client_activity_ttl(&self) -> Duration {
+        Duration::from_millis(self.bus.client_activity_ttl_ms)
+    }
+
+    /// Linger of BUS and service sockets, so context termination never waits longer
+    /// than the shutdown deadline.
+    #[must_use]
+    pub fn shutdown_linger(&self) -> i32 {
+        i32::try_from(self.shutdown_deadline_ms).unwrap_or(i32::MAX)
+    }
+
+    /// Validated log level, BUS is able to change it at runtime through `log::set_max_level`.
+    #[must_use]
+    pub fn log_level_filter(&self) -> LevelFilter {
+        self.log_level.parse().unwrap_or(LevelFilter::Debug)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if !LOG_LEVELS.contains(&self.log_level.as_str()) {
+            return Err(ConfigError::InvalidValue {
+                key: "log_level",
+                reason: format!("expected one of {}", LOG_LEVELS.join(", ")),
+            });
+        }
+
+        if self.requests_count_inside_one_group == 0 {
+            return Err(ConfigError::InvalidValue {
+                key: "requests_count_inside_one_group",
+                reason: "should be greater than zero".to_string(),
+            });
+        }
+
+        self.validate_endpoints()?;
+
+        if self.bus.publish_retry_budget == 0 {
+            return Err(ConfigError::InvalidValue {
+                key: "bus.publish_retry_budget",
+                reason: "should be greater than zero".to_string(),
+            });
+        }
+
+        validate_kinds("bus.work_queue_kinds", &self.bus.work_queue_kinds)?;
+
+        if self.bus.work_queue_overflow_policy == OverflowPolicy::Block {
+            return Err(ConfigError::InvalidValue {
+                key: "bus.work_queue_overflow_policy",
+                reason: "BUS can't wait for workers, expected one of drop-oldest, \
+                         drop-newest, reject-with-nack"
+                    .to_string(),
+            });
+        }
+
+        if self.bus.paused_messages_overflow_policy == OverflowPolicy::Block {
+            return Err(ConfigError::InvalidValue {
+                key: "bus.paused_messages_overflow_policy",
+                reason: "BUS can't wait for kinds to be resumed, expected one of \
+                         drop-oldest, drop-newest, reject-with-nack"
+                    .to_string(),
+            });
+        }
+
+        validate_kinds("bus.federation_kinds", &self.bus.federation_kinds)?;
+        validate_kinds("bus.high_priority_kinds", &self.bus.high_priority_kinds)?;
+        validate_kinds("bus.low_priority_kinds", &self.bus.low_priority_kinds)?;
+
+        if let Some(kind) = self
+            .bus
+            .high_priority_kinds
+            .iter()
+            .find(|kind| self.bus.low_priority_kinds.contains(kind))
+        {
+            return Err(ConfigError::InvalidValue {
+                key: "bus.low_priority_kinds",
+                reason: format!("message kind {} is high priority as well", kind),
+            });
+        }
+
+        if self.bus.priority_weights.len() != MessagePriority::ALL.len()
+            || self.bus.priority_weights.contains(&0)
+        {
+            return Err(ConfigError::InvalidValue {
+                key: "bus.priority_weights",
+                reason: "expected positive weights of high, normal and low priority"
+                    .to_string(),
+            });
+        }
+
+        for peer in &self.bus.federation_peers {
+            validate_endpoint(peer.as_str())?;
+
+            if peer == &self.endpoints.router {
+                return Err(ConfigError::InvalidEndpoint {
+                    endpoint: peer.clone(),
+                    reason: "BUS can't be federated with itself".to_string(),
+                });
+            }
+        }
+
+        if self.bus.instance_id.as_deref() == Some("") {
+            return Err(ConfigError::InvalidValue {
+                key: "bus.instance_id",
+                reason: "should not be empty".to_string(),
+            });
+        }
+
+        self.validate_heartbeats()?;
+        self.validate_limits()?;
+        self.validate_acl()?;
+
+        if let Some(metrics_address) = &self.bus.metrics_address {
+            if let Err(error) = metrics_address.parse::<SocketAddr>() {
+                return Err(ConfigError::InvalidValue {
+                    key: "bus.metrics_address",
+                    reason: error.to_string(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Heartbeat grace should cover a few heartbeats, and clients shouldn't vanish from the
+    /// control socket while they are still considered alive.
+    fn validate_heartbeats(&self) -> Result<(), ConfigError> {
+        if self.heartbeat_interval_ms == 0 {
+            return Err(ConfigError::InvalidValue {
+                key: "heartbeat_interval_ms",
+                reason: "should be greater than zero".to_string(),
+            });
+        }
+
+        if self.bus.heartbeat_grace_ms <= self.heartbeat_interval_ms {
+            return Err(ConfigError::InvalidValue {
+                key: "bus.heartbeat_grace_ms",
+                reason: "should be greater than heartbeat_interval_ms".to_string(),
+            });
+        }
+
+        if self.bus.client_activity_ttl_ms <= self.bus.heartbeat_grace_ms {
+            return Err(ConfigError::InvalidValue {
+                key: "bus.client_activity_ttl_ms",
+                reason: "should be greater than bus.heartbeat_grace_ms".to_string(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Deduplication and rate limits which are given should be positive.
+    fn validate_limits(&self) -> Result<(), ConfigError> {
+        for (key, value) in &[
+            (
+                "bus.publishing_queue_capacity",
+                self.bus.publishing_queue_capacity,
+            ),
+            ("bus.retry_buffer_capacity", self.bus.retry_buffer_capacity),
+        ] {
+            if *value == 0 {
+                return Err(ConfigError::InvalidValue {
+                    key,
+                    reason: "should be greater than zero".to_string(),
+                });
+            }
+        }
+
+        if self.bus.paused_messages_capacity == 0 {
+            return Err(ConfigError::InvalidValue {
+                key: "bus.paused_messages_capacity",
+                reason: "should be greater than zero".to_string(),
+            });
+        }
+
+        if self.bus.scheduled_messages_capacity == 0 {
+            return Err(ConfigError::InvalidValue {
+                key: "bus.scheduled_messages_capacity",
+                reason: "should be greater than zero".to_string(),
+            });
+        }
+
+        if self.bus.schedule_max_delay_ms == 0 {
+            return Err(ConfigError::InvalidValue {
+                key: "bus.schedule_max_delay_ms",
+                reason: "should be greater than zero".to_string(),
+            });
+        }
+
+        if self.bus.wal_segment_max_bytes == 0 {
+            return Err(ConfigError::InvalidValue {
+                key: "bus.wal_segment_max_bytes",
+                reason: "should be greater than zero".to_string(),
+            });
+        }
+
+        if self.bus.wal_fsync_policy == WriteAheadLogFsyncPolicy::EveryRecords(0) {
+            return Err(ConfigError::InvalidValue {
+                key: "bus.wal_fsync_policy",
+                reason: "records count should be greater than zero".to_string(),
+            });
+        }
+
+        if self.bus.dedup_window_ms == Some(0) {
+            return Err(ConfigError::InvalidValue {
+                key: "bus.dedup_window_ms",
+                reason: "should be greater than zero".to_string(),
+            });
+        }
+
+        if self.bus.dedup_window_capacity == 0 {
+            return Err(ConfigError::InvalidValue {
+                key: "bus.dedup_window_capacity",
+                reason: "should be greater than zero".to_string(),
+            });
+        }
+
+        for (key, value) in &[
+            ("bus.rate_limit_per_second", self.bus.rate_limit_per_second),
+            ("bus.rate_limit_burst", self.bus.rate_limit_burst),
+        ] {
+            if *value == Some(0) {
+                return Err(ConfigError::InvalidValue {
+                    key,
+                    reason: "should be greater than zero".to_string(),
+                });
+            }
+        }
+
+        validate_kinds("bus.rate_limited_kinds", &self.bus.rate_limited_kinds)?;
+
+        if self.bus.rate_limit_per_second.is_none()
+            && (self.bus.rate_limit_burst.is_some() || !self.bus.rate_limited_kinds.is_empty())
+        {
+            return Err(ConfigError::InvalidValue {
+                key: "bus.rate_limit_per_second",
+                reason: "rate limit is required by bus.rate_limit_burst and \
+                         bus.rate_limited_kinds"
+                    .to_string(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Rules need authenticated clients, so they are checked against CURVE settings.
+    fn validate_acl(&self) -> Result<(), ConfigError> {
+        if self.bus.acl.is_empty() {
+            return Ok(());
+        }
+
+        if self.endpoints.curve_server_secret_key.is_none() {
+            return Err(ConfigError::InvalidValue {
+                key: "bus.acl",
+                reason: "access control requires endpoints.curve_server_secret_key"
+                    .to_string(),
+            });
+        }
+
+        let mut identities = HashSet::new();
+        for rule in &self.bus.acl {
+            if let Err(reason) = decode_curve_key(rule.identity.as_str()) {
+                return Err(ConfigError::InvalidValue {
+                    key: "bus.acl.identity",
+                    reason,
+                });
+            }
+
+            if !identities.insert(rule.identity.as_str()) {
+                return Err(ConfigError::InvalidValue {
+                    key: "bus.acl.identity",
+                    reason: format!("identity '{}' has more than one rule", rule.identity),
+                });
+            }
+
+            validate_kinds("bus.acl.publish_kinds", &rule.publish_kinds)?;
+            validate_kinds("bus.acl.subscribe_kinds", &rule.subscribe_kinds)?;
+        }
+
+        Ok(())
+    }
+
+    /// Endpoints which are bound should be distinct, the ones which are only connected to
+    /// are just checked to be well-formed.
+    fn validate_endpoints(&self) -> Result<(), ConfigError> {
+        if self.endpoints.publishers.is_empty() {
+            return Err(ConfigError::InvalidValue {
+                key: "endpoints.publishers",
+                reason: "at least one publisher endpoint is required".to_string(),
+            });
+        }
+
+        self.endpoints.validate_curve_keys()?;
+
+        let mut unique_endpoints = HashSet::new();
+        for endpoint in std::iter::once(&self.endpoints.router)
+            .chain(self.endpoints.publishers.iter())
+            .chain(self.endpoints.control.iter())
+            .chain(self.bus.replication_endpoint.iter())
+        {
+            validate_endpoint(endpoint.as_str())?;
+
+            if !unique_endpoints.insert(endpoint.as_str()) {
+                return Err(ConfigError::InvalidEndpoint {
+                    endpoint: endpoint.clone(),
+                    reason: "endpoint is used more than once".to_string(),
+                });
+            }
+        }
+
+        for endpoint in self
+            .endpoints
+            .failover_routers
+            .iter()
+            .chain(self.endpoints.failover_publishers.iter())
+            .chain(self.bus.standby_of.iter())
+        {
+            validate_endpoint(endpoint.as_str())?;
+        }
+
+        if self.bus.wal_directory.is_none() {
+            if self.bus.replication_endpoint.is_some() {
+                return Err(ConfigError::InvalidValue {
+                    key: "bus.replication_endpoint",
+                    reason: "replication requires bus.wal_directory".to_string(),
+                });
+            }
+            if self.bus.standby_of.is_some() {
+                return Err(ConfigError::InvalidValue {
+                    key: "bus.standby_of",
+                    reason: "standby requires bus.wal_directory".to_string(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn apply_options(&mut self, options: &ConfigOptions) {
+        let bus = &mut self.bus;
+
+        override_value(&mut self.log_level, options.log_level.clone());
+        override_value(
+            &mut self.requests_count_inside_one_group,
+            options.requests_count_inside_one_group,
+        );
+        override_value(&mut self.shutdown_deadline_ms, options.shutdown_deadline_ms);
+        override_value(
+            &mut self.heartbeat_interval_ms,
+            options.heartbeat_interval_ms,
+        );
+        override_value(&mut self.endpoints.router, options.router_endpoint.clone());
+        if !options.publisher_endpoints.is_empty() {
+            self.endpoints
+                .publishers
+                .clone_from(&options.publisher_endpoints);
+        }
+        if options.control_endpoint.is_some() {
+            self.endpoints.control.clone_from(&options.control_endpoint);
+        }
+        if !options.failover_router_endpoints.is_empty() {
+            self.endpoints
+                .failover_routers
+                .clone_from(&options.failover_router_endpoints);
+        }
+        if !options.failover_publisher_endpoints.is_empty() {
+            self.endpoints
+                .failover_publishers
+                .clone_from(&options.failover_publisher_endpoints);
+        }
+        self.endpoints.apply_curve_options(options);
+        if options.wal_directory.is_some() {
+            bus.wal_directory.clone_from(&options.wal_directory);
+        }
+        override_value(
+            &mut bus.wal_segment_max_bytes,
+            options.wal_segment_max_bytes,
+        );
+        override_value(&mut bus.wal_fsync_policy, options.wal_fsync_policy);
+        if options.dead_letter_file.is_some() {
+            bus.dead_letter_file.clone_from(&options.dead_letter_file);
+        }
+        override_value(
+            &mut bus.dead_letter_queue_capacity,
+            options.dead_letter_queue_capacity,
+        );
+        override_value(&mut bus.expiry_policy, options.expiry_policy);
+        if options.dedup_window_ms.is_some() {
+            bus.dedup_window_ms = options.dedup_window_ms;
+        }
+        override_value(
+            &mut bus.dedup_window_capacity,
+            options.dedup_window_capacity,
+        );
+        override_value(&mut bus.unroutable_policy, options.unroutable_policy);
+        override_value(&mut bus.publish_retry_budget, options.publish_retry_budget);
+        override_value(
+            &mut bus.publishing_queue_capacity,
+            options.publishing_queue_capacity,
+        );
+        override_value(
+            &mut bus.publishing_queue_overflow_policy,
+            options.publishing_queue_overflow_policy,
+        );
+        override_value(
+            &mut bus.retry_buffer_capacity,
+            options.retry_buffer_capacity,
+        );
+        override_value(
+            &mut bus.scheduled_messages_capacity,
+            options.scheduled_messages_capacity,
+        );
+        override_value(
+            &mut bus.schedule_max_delay_ms,
+            options.schedule_max_delay_ms,
+        );
+        override_value(
+            &mut bus.paused_messages_capacity,
+            options.paused_messages_capacity,
+        );
+        override_value(
+            &mut bus.paused_messages_overflow_policy,
+            options.paused_messages_overflow_policy,
+        );
+        override_value(
+            &mut bus.router_high_water_mark,
+            options.router_high_water_mark,
+        );
+        override_value(
+            &mut bus.publishers_high_water_mark,
+            options.publishers_high_water_mark,
+        );
+        override_value(
+            &mut bus.publisher_selection_strategy,
+            options.publisher_selection_strategy,
+        );
+        override_value(&mut bus.work_queue_strategy, options.work_queue_strategy);
+        override_value(&mut bus.work_queue_capacity, options.work_queue_capacity);
+        override_value(
+            &mut bus.work_queue_overflow_policy,
+            options.work_queue_overflow_policy,
+        );
+        if options.metrics_address.is_some() {
+            bus.metrics_address.clone_from(&options.metrics_address);
+        }
+        if options.capture_file.is_some() {
+            bus.capture_file.clone_from(&options.capture_file);
+        }
+        override_value(&mut bus.heartbeat_grace_ms, options.heartbeat_grace_ms);
+        override_value(
+            &mut bus.client_activity_ttl_ms,
+            options.client_activity_ttl_ms,
+        );
+        if options.instance_id.is_some() {
+            bus.instance_id.clone_from(&options.instance_id);
+        }
+        if !options.federation_peers.is_empty() {
+            bus.federation_peers.clone_from(&options.federation_peers);
+        }
+        if !options.federation_kinds.is_empty() {
+            bus.federation_kinds.clone_from(&options.federation_kinds);
+        }
+        if !options.high_priority_kinds.is_empty() {
+            bus.high_priority_kinds
+                .clone_from(&options.high_priority_kinds);
+        }
+        if !options.low_priority_kinds.is_empty() {
+            bus.low_priority_kinds
+                .clone_from(&options.low_priority_kinds);
+        }
+        if !options.priority_weights.is_empty() {
+            bus.priority_weights.clone_from(&options.priority_weights);
+        }
+        if options.rate_limit_per_second.is_some() {
+            bus.rate_limit_per_second = options.rate_limit_per_second;
+        }
+        if options.rate_limit_burst.is_some() {
+            bus.rate_limit_burst = options.rate_limit_burst;
+        }
+        if !options.rate_limited_kinds.is_empty() {
+            bus.rate_limited_kinds
+                .clone_from(&options.rate_limited_kinds);
+        }
+        override_value(&mut bus.rate_limit_policy, options.rate_limit_policy);
+        if options.replication_endpoint.is_some() {
+            bus.replication_endpoint
+                .clone_from(&options.replication_endpoint);
+        }
+        if options.standby_of.is_some() {
+            bus.standby_of.clone_from(&options.standby_of);
+        }
+    }
+}
+
+/// Loads configuration of a binary from its flags, environment and configuration file.
+/// Process exits after printing effective configuration when `--print-config` is given,
+/// and with error when configuration is invalid.
+#[must_use]
+pub fn load_config_from_args() -> Config {
+    let options = ConfigOptions::from_args();
+
+    let config = Config::load(&options).unwrap_or_else(|error| {
+        eprintln!("invalid configuration: {}", error);
+        process::exit(1);
+    });
+
+    if options.print_config {
+        print!("{}", config.to_redacted_toml());
+        process::exit(0);
+    }
+
+    config
+}
+
+fn validate_kinds(key: &'static str, kinds: &[u32]) -> Result<(), ConfigError> {
+    match kinds
+        .iter()
+        .find(|kind| ZeromqMessageKind::try_from(**kind).is_err())
+    {
+        Some(kind) => Err(ConfigError::InvalidValue {
+            key,
+            reason: format!("unknown message kind {}", kind),
+        }),
+        None => Ok(()),
+    }
+}
+
+fn override_value<T>(value: &mut T, override_value: Option<T>) {
+    if let Some(override_value) = override_value {
+        *value = override_value;
+    }
+}
+
+/// Checks endpoint syntax for transports which BUS is able to use.
+pub fn validate_endpoint(endpoint: &str) -> Result<(), ConfigError> {
+    let invalid = |reason: &str| ConfigError::InvalidEndpoint {
+        endpoint: endpoint.to_string(),
+        reason: reason.to_string(),
+    };
+
+    let (transport, address) = endpoint
+        .split_once("://")
+        .ok_or_else(|| invalid("expected transport://address"))?;
+
+    match transport {
+        "tcp" => {
+            let (host, port) = address
+                .rsplit_once(':')
+                .ok_or_else(|| invalid("expected tcp://host:port"))?;
+            if host.is_empty() {
+                return Err(invalid("host is empty"));
+            }
+            if port != "*" && port.parse::<u16>().map_or(true, |port| port == 0) {
+                return Err(invalid("port should be a number from 1 to 65535 or '*'"));
+            }
+            Ok(())
+        }
+        "ipc" | "inproc" if address.is_empty() => Err(invalid("address is empty")),
+        "ipc" | "inproc" => Ok(()),
+        _ => Err(invalid("supported transports are tcp, ipc and inproc")),
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// Tests
+//-----------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::config::validate_endpoint;
+    use crate::config::Config;
+    use crate::config::ConfigOptions;
+    use crate::config::REDACTED_VALUE;
+    use crate::OverflowPolicy;
+    use crate::RateLimit;
+    use crate::WriteAheadLogFsyncPolicy;
+    use std::time::Duration;
+    use structopt::StructOpt;
+    use zeromq_messages::header::MessagePriority;
+    use zeromq_messages::kind::ZeromqMessageKind;
+
+    #[test]
+    fn partial_file_uses_defaults() {
+        let config = Config::from_toml(
+            r#"
+            log_level = "info"
+
+            [bus]
+            publishing_queue_overflow_policy = "drop-oldest"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!("info", config.log_level);
+        assert_eq!(
+            OverflowPolicy::DropOldest,
+            config.bus.publishing_queue_overflow_policy
+        );
+        assert_eq!(Config::default().endpoints, config.endpoints);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn kind_priorities() {
+        let mut config = Config::from_toml(
+            r"
+            [bus]
+            high_priority_kinds = [14]
+            low_priority_kinds = [2]
+            priority_weights = [16, 4, 1]
+            ",
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            Some(&MessagePriority::High),
+            config
+                .bus
+                .kind_priorities()
+                .get(&ZeromqMessageKind::ServiceJoined)
+        );
+        assert_eq!(
+            Some(&MessagePriority::Low),
+            config
+                .bus
+                .kind_priorities()
+                .get(&ZeromqMessageKind::ValueMultiplicationResponse)
+        );
+
+        config.bus.low_priority_kinds.push(14);
+        assert!(config.validate().is_err());
+
+        config.bus.low_priority_kinds = Vec::new();
+        config.bus.priority_weights = vec![1, 0, 1];
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn rate_limit() {
+        let mut config = Config::from_toml(
+            r"
+            [bus]
+            rate_limit_per_second = 100
+            rate_limited_kinds = [2]
+            ",
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            Some(RateLimit {
+                messages_per_second: 100,
+                burst: 100,
+            }),
+            config.bus.rate_limit()
+        );
+        assert_eq!(
+            vec![ZeromqMessageKind::ValueMultiplicationResponse],
+            config.bus.rate_limited_kinds()
+        );
+
+        config.bus.rate_limit_burst = Some(0);
+        assert!(config.validate().is_err());
+
+        config.bus.rate_limit_burst = Some(500);
+        config.bus.rate_limit_per_second = None;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn work_queue_overflow_policy() {
+        let mut config = Config::from_toml(
+            r#"
+            [bus]
+            work_queue_capacity = 10
+            work_queue_overflow_policy = "drop-oldest"
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(10, config.bus.work_queue_capacity);
+
+        config.bus.work_queue_overflow_policy = OverflowPolicy::Block;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn paused_messages_overflow_policy() {
+        let mut config = Config::from_toml(
+            r#"
+            [bus]
+            paused_messages_capacity = 10
+            paused_messages_overflow_policy = "drop-newest"
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(10, config.bus.paused_messages_capacity);
+
+        config.bus.paused_messages_overflow_policy = OverflowPolicy::Block;
+        assert!(config.validate().is_err());
+
+        config.bus.paused_messages_overflow_policy = OverflowPolicy::DropOldest;
+        config.bus.paused_messages_capacity = 0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn queue_capacities() {
+        let mut config = Config::default();
+        config.bus.publishing_queue_capacity = 0;
+        assert!(config.validate().is_err());
+
+        config = Config::default();
+        config.bus.retry_buffer_capacity = 0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn client_activity_ttl() {
+        let mut config = Config::from_toml(
+            r"
+            [bus]
+            heartbeat_grace_ms = 3000
+            client_activity_ttl_ms = 90000
+            ",
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(Duration::from_secs(90), config.client_activity_ttl());
+
+        config.bus.client_activity_ttl_ms = 3000;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn write_ahead_log() {
+        let mut config = Config::from_toml(
+            r#"
+            [bus]
+            wal_directory = "/var/lib/bus/wal"
+            wal_segment_max_bytes = 1048576
+            wal_fsync_policy = "every-records:10"
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(1_048_576, config.bus.wal_segment_max_bytes);
+        assert_eq!(
+            WriteAheadLogFsyncPolicy::EveryRecords(10),
+            config.bus.wal_fsync_policy
+        );
+
+        assert!(Config::from_toml("[bus]\nwal_fsync_policy = \"every-records:0\"").is_err());
+        assert!(Config::from_toml("[bus]\nwal_fsync_policy = \"sometimes\"").is_err());
+
+        config.bus.wal_segment_max_bytes = 0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn curve_keys() {
+        let mut config = Config::from_toml(
+            r#"
+            [endpoints]
+            curve_server_public_key = "rq:rM>}U?@Lns47E1%kR.o@n%FcmmsL/@{H8]yf7"
+            curve_client_public_key = "Yne@$w-vo<fVvi]a<NY6T1ed:M$fCG*[IaLV{hID"
+            curve_client_secret_key = "D:)Q[IlAW!ahhC2ac:9*A}h:p?([4%wOTJ%JR%cs"
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+
+        config.endpoints.curve_server_public_key = None;
+        assert!(config.validate().is_err());
+
+        config.endpoints.curve_server_public_key = Some("too short".to_string());
+        assert!(config.validate().is_err());
+
+        config.endpoints.curve_server_public_key = None;
+        config.endpoints.curve_client_public_key = None;
+        config.endpoints.curve_client_secret_key = None;
+        config.endpoints.curve_allowed_clients_file = Some("clients.txt".into());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn acl() {
+        let mut config = Config::from_toml(
+            r#"
+            [endpoints]
+            curve_server_secret_key = "JTKVSB%%)wK0E.X)V>+}o?pNmC{O&4W4b!Ni{Lh6"
+
+            [[bus.acl]]
+            identity = "Yne@$w-vo<fVvi]a<NY6T1ed:M$fCG*[IaLV{hID"
+            publish_kinds = [1]
+            subscribe_kinds = [2]
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(vec![1], config.bus.acl[0].publish_kinds);
+
+        config.bus.acl.push(config.bus.acl[0].clone());
+        assert!(config.validate().is_err());
+
+        let _ = config.bus.acl.pop();
+        config.bus.acl[0].subscribe_kinds.push(u32::MAX);
+        assert!(config.validate().is_err());
+
+        config.bus.acl[0].subscribe_kinds = Vec::new();
+        config.endpoints.curve_server_secret_key = None;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(Config::from_toml("router_endpoint = \"tcp://127.0.0.1:1\"").is_err());
+    }
+
+    #[test]
+    fn flags_override_file() {
+        let options = ConfigOptions::from_iter(&[
+            "bus",
+            "--router-endpoint",
+            "tcp://127.0.0.1:7000",
+            "--publisher-endpoint",
+            "tcp://127.0.0.1:7001",
+            "--publisher-endpoint",
+            "inproc://publisher",
+            "--publishing-queue-overflow-policy",
+            "reject-with-nack",
+        ]);
+
+        let config = Config::load(&options).unwrap();
+        assert_eq!("tcp://127.0.0.1:7000", config.endpoints.router);
+        assert_eq!(
+            vec![
+                "tcp://127.0.0.1:7001".to_string(),
+                "inproc://publisher".to_string()
+            ],
+            config.endpoints.publishers
+        );
+        assert_eq!(
+            OverflowPolicy::RejectWithNack,
+            config.bus.publishing_queue_overflow_policy
+        );
+    }
+
+    #[test]
+    fn printed_config_is_loadable() {
+        let config = Config::default();
+        assert_eq!(
+            config,
+            Config::from_toml(config.to_toml().as_str()).unwrap()
+        );
+    }
+
+    #[test]
+    fn printed_config_has_no_secret_keys() {
+        let mut config = Config::default();
+        config.endpoints.curve_server_secret_key =
+            Some("JTKVSB%%)wK0E.X)V>+}o?pNmC{O&4W4b!Ni{Lh6".to_string());
+        config.endpoints.curve_client_secret_key =
+            Some("D:)Q[IlAW!ahhC2ac:9*A}h:p?([4%wOTJ%JR%cs".to_string());
+
+        let printed_config = config.to_redacted_toml();
+        assert!(!printed_config.contains("JTKVSB"));
+        assert!(!printed_config.contains("D:)Q[IlAW"));
+        assert_eq!(
+            Some(REDACTED_VALUE.to_string()),
+            Config::from_toml(printed_config.as_str())
+                .unwrap()
+                .endpoints
+                .curve_server_secret_key
+        );
+    }
+
+    #[test]
+    fn endpoints_validation() {
+        assert!(validate_endpoint("tcp://0.0.0.0:56731").is_ok());
+        assert!(validate_endpoint("tcp://*:*").is_ok());
+        assert!(validate_endpoint("ipc:///tmp/bus.ipc").is_ok());
+        assert!(validate_endpoint("inproc://bus").is_ok());
+        assert!(validate_endpoint("0.0.0.0:56731").is_err());
+        assert!(validate_endpoint("tcp://0.0.0.0").is_err());
+        assert!(validate_endpoint("tcp://0.0.0.0:70000").is_err());
+        assert!(validate_endpoint("udp://0.0.0.0:1").is_err());
+        assert!(validate_endpoint("inproc://").is_err());
+
+        let mut config = Config::default();
+        config
+            .endpoints
+            .publishers
+            .push(config.endpoints.router.clone());
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.bus.federation_peers = vec!["tcp://127.0.0.1:56831".to_string()];
+        assert!(config.validate().is_ok());
+        config
+            .bus
+            .federation_peers
+            .push(config.endpoints.router.clone());
+        assert!(config.validate().is_err());
+    }
+}
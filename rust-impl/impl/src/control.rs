@@ -0,0 +1,600 @@
+use crate::helpers::hex;
+use crate::helpers::is_control_message;
+use crate::AccessControl;
+use crate::BusStats;
+use crate::DeadLetterQueue;
+use crate::DeadLockSafeMutex;
+use crate::DeadLockSafeRwLock;
+use crate::ShutdownSignal;
+use crate::WorkerIdentity;
+use crate::SHUTDOWN_CHECK_INTERVAL_MILLISECONDS;
+use log::LevelFilter;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::iter::Iterator;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+use zeromq_messages::codec::decode_message_kind_and_uuid;
+use zeromq_messages::codec::decode_message_payload;
+use zeromq_messages::codec::encode_message;
+use zeromq_messages::codec::message_payload_bytes;
+use zeromq_messages::codec::MESSAGE_KIND_AND_UUID_LENGTH;
+use zeromq_messages::header::milliseconds_since_unix_epoch;
+use zeromq_messages::kind::ZeromqMessageKind;
+use zeromq_messages::messages::ControlClientsReply;
+use zeromq_messages::messages::ControlClientsReplyItemClients;
+use zeromq_messages::messages::ControlCommand;
+use zeromq_messages::messages::ControlReply;
+use zeromq_messages::messages::ControlStatsReply;
+use zeromq_messages::messages::ControlSubscriptionsReply;
+use zeromq_messages::messages::ControlSubscriptionsReplyItemSubscriptions;
+use zeromq_messages::messages::DeadLetterReport;
+use zmq::Message;
+use zmq::Socket;
+
+/// Time which control command waits for the sender thread, it is busy with a single
+/// message at most, so only a stuck socket is able to exceed it.
+const SENDER_REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const XPUB_SUBSCRIBE_EVENT: u8 = 1;
+const XPUB_UNSUBSCRIBE_EVENT: u8 = 0;
+
+//-----------------------------------------------------------------------------------------
+// BusClientActivity
+//-----------------------------------------------------------------------------------------
+
+/// What BUS knows about a client connected to its router socket.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct BusClientActivity {
+    pub received_messages: u64,
+    /// Milliseconds since unix epoch.
+    pub last_seen_at: u64,
+}
+
+//-----------------------------------------------------------------------------------------
+// BusControlState
+//-----------------------------------------------------------------------------------------
+
+/// State which BUS threads share with the control socket, cloned handles share it too.
+#[derive(Debug, Clone, Default)]
+pub struct BusControlState {
+    paused_kinds: DeadLockSafeRwLock<HashSet<ZeromqMessageKind>>,
+    clients: DeadLockSafeMutex<BTreeMap<WorkerIdentity, BusClientActivity>>,
+    /// Subscribed prefixes of every publisher socket with count of their subscribers.
+    /// Publisher sockets report only the first subscription and the last unsubscription of
+    /// a prefix, unless they leave subscriptions to BUS, then they report all of them.
+    subscriptions: DeadLockSafeRwLock<Vec<BTreeMap<Vec<u8>, usize>>>,
+}
+
+impl BusControlState {
+    #[must_use]
+    pub fn new(publishers_count: usize) -> Self {
+        Self {
+            subscriptions: DeadLockSafeRwLock::new(vec![BTreeMap::new(); publishers_count]),
+            ..Self::default()
+        }
+    }
+
+    #[must_use]
+    pub fn is_paused(&self, kind: ZeromqMessageKind) -> bool {
+        self.paused_kinds
+            .read(move |paused_kinds| paused_kinds.contains(&kind))
+    }
+
+    /// Returns whether the kind was not paused before.
+    #[must_use]
+    pub fn pause(&self, kind: ZeromqMessageKind) -> bool {
+        self.paused_kinds
+            .write(move |paused_kinds| paused_kinds.insert(kind))
+    }
+
+    /// Returns whether the kind was paused before.
+    #[must_use]
+    pub fn resume(&self, kind: ZeromqMessageKind) -> bool {
+        self.paused_kinds
+            .write(move |paused_kinds| paused_kinds.remove(&kind))
+    }
+
+    /// Paused kinds ordered by their numbers.
+    #[must_use]
+    pub fn paused_kinds(&self) -> Vec<ZeromqMessageKind> {
+        let mut paused_kinds = self
+            .paused_kinds
+            .read(|paused_kinds| paused_kinds.iter().copied().collect::<Vec<_>>());
+        paused_kinds.sort_by_key(|kind| *kind as u32);
+        paused_kinds
+    }
+
+    pub fn record_client_message(&self, identity: WorkerIdentity) {
+        let now = milliseconds_since_unix_epoch();
+        self.clients.lock(move |clients| {
+            let activity = clients.entry(identity).or_default();
+            activity.received_messages += 1;
+            activity.last_seen_at = now;
+        });
+    }
+
+    /// Forgets the client which has left, so identities of gone clients do not pile up.
+    pub fn forget_client(&self, identity: &[u8]) {
+        let identity = identity.to_vec();
+        self.clients.lock(move |clients| {
+            let _ = clients.remove(&identity);
+        });
+    }
+
+    /// Forgets clients which have sent nothing during the given time, clients which do
+    /// not send heartbeats never leave otherwise.
+    pub fn forget_idle_clients(&self, max_idle: Duration) {
+        let idle_since = milliseconds_since_unix_epoch()
+            .saturating_sub(u64::try_from(max_idle.as_millis()).unwrap_or(u64::MAX));
+        self.clients.lock(move |clients| {
+            clients.retain(|_, activity| activity.last_seen_at >= idle_since);
+        });
+    }
+
+    #[must_use]
+    pub fn clients(&self) -> Vec<(WorkerIdentity, BusClientActivity)> {
+        self.clients.lock(|clients| {
+            clients
+                .iter()
+                .map(|(identity, activity)| (identity.clone(), *activity))
+                .collect()
+        })
+    }
+
+    /// Applies subscription event which was received from the publisher socket, events
+    /// in other format are ignored.
+    pub fn apply_subscription_event(&self, publisher_index: usize, event: Vec<u8>) {
+        self.subscriptions.write(move |subscriptions| {
+            let Some(prefixes) = subscriptions.get_mut(publisher_index) else {
+                return;
+            };
+            match event.split_first() {
+                Some((&XPUB_SUBSCRIBE_EVENT, prefix)) => {
+                    *prefixes.entry(prefix.to_vec()).or_default() += 1;
+                }
+                Some((&XPUB_UNSUBSCRIBE_EVENT, prefix)) => {
+                    if let Some(subscribers_count) = prefixes.get_mut(prefix) {
+                        *subscribers_count -= 1;
+                        if *subscribers_count == 0 {
+                            let _ = prefixes.remove(prefix);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        });
+    }
+
+    /// Whether subscriber of the publisher socket is subscribed to a prefix of message.
+    /// Only kind and uuid of message are compared, longer prefixes are taken as matching
+    /// when they start with them, so wanted message is never taken as unwanted.
+    #[must_use]
+    pub fn is_wanted(&self, publisher_index: usize, message_bytes: &[u8]) -> bool {
+        let message_head = message_bytes
+            .get(..MESSAGE_KIND_AND_UUID_LENGTH)
+            .unwrap_or(message_bytes)
+            .to_vec();
+        let is_whole_message = message_head.len() == message_bytes.len();
+
+        self.subscriptions.read(move |subscriptions| {
+            subscriptions
+                .get(publisher_index)
+                .into_iter()
+                .flat_map(BTreeMap::keys)
+                .any(|prefix| {
+                    if prefix.len() <= message_head.len() {
+                        message_head.starts_with(prefix.as_slice())
+                    } else {
+                        !is_whole_message && prefix.starts_with(message_head.as_slice())
+                    }
+                })
+        })
+    }
+
+    /// Count of prefixes which subscribers of every publisher socket are subscribed to.
+    #[must_use]
+    pub fn subscriptions_counts(&self) -> Vec<usize> {
+        self.subscriptions
+            .read(|subscriptions| subscriptions.iter().map(BTreeMap::len).collect())
+    }
+
+    /// Subscribed prefixes with indexes of publishers which they were seen on.
+    #[must_use]
+    pub fn subscriptions(&self) -> Vec<(usize, Vec<u8>)> {
+        self.subscriptions.read(|subscriptions| {
+            subscriptions
+                .iter()
+                .enumerate()
+                .flat_map(|(publisher_index, prefixes)| {
+                    prefixes
+                        .keys()
+                        .map(move |prefix| (publisher_index, prefix.clone()))
+                })
+                .collect()
+        })
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// SenderControlCommand
+//-----------------------------------------------------------------------------------------
+
+/// Command which only the sender thread is able to execute, because it owns the state.
+#[derive(Debug)]
+pub enum SenderControlCommand {
+    /// Drops messages waiting for publishing retry, their count is sent back.
+    PurgeRetryBuffer(mpsc::Sender<u64>),
+}
+
+//-----------------------------------------------------------------------------------------
+// BusControl
+//-----------------------------------------------------------------------------------------
+
+/// State of the control thread which answers administrative commands on a REP socket.
+pub struct BusControl {
+    pub socket: Socket,
+    pub state: BusControlState,
+    pub stats: Arc<BusStats>,
+    pub dead_letter_queue: DeadLockSafeMutex<DeadLetterQueue>,
+    pub sender_commands: mpsc::Sender<SenderControlCommand>,
+    /// Commands are executed only for clients which may publish `ControlCommand`
+    /// messages, every client may execute them when there is no access control.
+    pub access_control: Option<Arc<AccessControl>>,
+}
+
+impl BusControl {
+    /// Answers commands until shutdown is requested.
+    pub fn run(&mut self, shutdown_signal: &ShutdownSignal) {
+        log::debug!("running control loop");
+
+        while !shutdown_signal.is_requested() {
+            match self
+                .socket
+                .poll(zmq::POLLIN, i64::from(SHUTDOWN_CHECK_INTERVAL_MILLISECONDS))
+            {
+                Ok(0) => continue,
+                Ok(_) => {}
+                Err(error) => {
+                    log::error!("failed to poll BUS control socket because of: {}", error);
+                    continue;
+                }
+            }
+
+            // User id which ZAP handler has given to the client is kept by the frame.
+            let mut command = Message::new();
+            if let Err(error) = self.socket.recv(&mut command, zmq::DONTWAIT) {
+                log::error!("failed to receive control command because of: {}", error);
+                continue;
+            }
+            let user_id = command.gets("User-Id").map(str::to_string);
+
+            // REP socket has to answer every request, otherwise it is not able to receive
+            // the next one.
+            let reply_bytes = self.execute(&command, user_id.as_deref());
+            if let Err(error) = self.socket.send(reply_bytes, zmq::DONTWAIT) {
+                log::error!("failed to send control reply because of: {}", error);
+            }
+        }
+
+        log::debug!("control loop has stopped");
+    }
+
+    fn execute(&self, command_bytes: &[u8], user_id: Option<&str>) -> Vec<u8> {
+        let uuid = decode_message_kind_and_uuid(command_bytes)
+            .map_or_else(|_| Uuid::nil(), |(_, uuid)| uuid);
+
+        let error = match self.execute_command(uuid, command_bytes, user_id) {
+            Ok(reply_bytes) => return reply_bytes,
+            Err(error) => error,
+        };
+
+        log::warn!("failed to execute control command because of: {}", error);
+        encode_message(
+            uuid,
+            ControlReply {
+                error: Some(error),
+                ..empty_reply()
+            },
+        )
+        .unwrap_or_else(|error| {
+            log::error!("failed to encode control reply because of: {}", error);
+            Vec::new()
+        })
+    }
+
+    fn execute_command(
+        &self,
+        uuid: Uuid,
+        command_bytes: &[u8],
+        user_id: Option<&str>,
+    ) -> Result<Vec<u8>, String> {
+        let (kind, _) = decode_message_kind_and_uuid(command_bytes)
+            .map_err(|error| format!("failed to decode message kind: {}", error))?;
+        if kind != ZeromqMessageKind::ControlCommand {
+            return Err(format!("expected ControlCommand message, got {:?}", kind));
+        }
+
+        self.authorize(user_id)?;
+
+        let command = message_payload_bytes(command_bytes)
+            .and_then(decode_message_payload::<'_, ControlCommand>)
+            .map_err(|error| format!("failed to decode control command: {}", error))?;
+
+        log::info!("executing control command {}", command.action);
+
+        let reply_bytes = match command.action.as_str() {
+            "stats" => encode_message(uuid, self.stats_reply()),
+            "clients" => encode_message(
+                uuid,
+                ControlClientsReply {
+                    clients: self
+                        .state
+                        .clients()
+                        .into_iter()
+                        .map(|(identity, activity)| ControlClientsReplyItemClients {
+                            identity: hex(identity.as_slice()),
+                            received_messages: integer(activity.received_messages),
+                            last_seen_at: integer(activity.last_seen_at),
+                        })
+                        .collect(),
+                },
+            ),
+            "subscriptions" => encode_message(
+                uuid,
+                ControlSubscriptionsReply {
+                    subscriptions: self
+                        .state
+                        .subscriptions()
+                        .into_iter()
+                        .map(|(publisher_index, prefix)| {
+                            ControlSubscriptionsReplyItemSubscriptions {
+                                publisher: integer(publisher_index as u64),
+                                kind: prefix_kind(prefix.as_slice())
+                                    .map(|kind| i64::from(kind as u32)),
+                                prefix: hex(prefix.as_slice()),
+                            }
+                        })
+                        .collect(),
+                },
+            ),
+            "pause-kind" => {
+                let kind = pausable_kind(command.kind)?;
+                if self.state.pause(kind) {
+                    log::warn!("paused delivery of {:?} messages", kind);
+                }
+                encode_message(uuid, self.paused_kinds_reply())
+            }
+            "resume-kind" => {
+                let kind = pausable_kind(command.kind)?;
+                if self.state.resume(kind) {
+                    log::warn!("resumed delivery of {:?} messages", kind);
+                }
+                encode_message(uuid, self.paused_kinds_reply())
+            }
+            "purge-retry-buffer" => encode_message(
+                uuid,
+                ControlReply {
+                    purged_messages: Some(integer(self.purge_retry_buffer()?)),
+                    ..empty_reply()
+                },
+            ),
+            "dump-dead-letters" => {
+                let entries = self.dead_letter_queue.lock(|dead_letter_queue| {
+                    dead_letter_queue
+                        .entries()
+                        .map(|entry| entry.report_entry(true))
+                        .collect()
+                });
+                encode_message(uuid, DeadLetterReport { entries })
+            }
+            "set-log-level" => {
+                let log_level = command
+                    .log_level
+                    .ok_or_else(|| "log level is required".to_string())?
+                    .parse::<LevelFilter>()
+                    .map_err(|error| format!("invalid log level: {}", error))?;
+                log::set_max_level(log_level);
+                log::warn!("log level is changed to {}", log_level);
+                encode_message(
+                    uuid,
+                    ControlReply {
+                        log_level: Some(current_log_level()),
+                        ..empty_reply()
+                    },
+                )
+            }
+            action => return Err(format!("unknown control command action: {}", action)),
+        };
+
+        reply_bytes.map_err(|error| format!("failed to encode control reply: {}", error))
+    }
+
+    fn authorize(&self, user_id: Option<&str>) -> Result<(), String> {
+        match &self.access_control {
+            Some(access_control)
+                if !access_control.may_publish(user_id, ZeromqMessageKind::ControlCommand) =>
+            {
+                Err(format!(
+                    "client {:?} is not permitted to execute control commands",
+                    user_id
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn stats_reply(&self) -> ControlStatsReply {
+        let snapshot = self.stats.snapshot();
+
+        ControlStatsReply {
+            received_messages: integer(snapshot.received_messages),
+            received_bytes: integer(snapshot.received_bytes),
+            published_messages: integer(snapshot.published_messages),
+            published_bytes: integer(snapshot.published_bytes),
+            publish_failures: integer(snapshot.publish_failures),
+            dead_lettered_messages: integer(snapshot.dead_lettered_messages),
+            publishing_queue_depth: integer(snapshot.publishing_queue_depth),
+            retry_buffer_depth: integer(snapshot.retry_buffer_depth),
+            paused_kinds: self.paused_kinds(),
+            log_level: current_log_level(),
+        }
+    }
+
+    fn paused_kinds(&self) -> Vec<i64> {
+        self.state
+            .paused_kinds()
+            .into_iter()
+            .map(|kind| i64::from(kind as u32))
+            .collect()
+    }
+
+    fn paused_kinds_reply(&self) -> ControlReply {
+        ControlReply {
+            paused_kinds: Some(self.paused_kinds()),
+            ..empty_reply()
+        }
+    }
+
+    fn purge_retry_buffer(&self) -> Result<u64, String> {
+        let (reply_sender, reply_receiver) = mpsc::channel();
+        self.sender_commands
+            .send(SenderControlCommand::PurgeRetryBuffer(reply_sender))
+            .map_err(|_| "sender thread has stopped".to_string())?;
+        reply_receiver
+            .recv_timeout(SENDER_REPLY_TIMEOUT)
+            .map_err(|error| format!("sender thread has not answered: {}", error))
+    }
+}
+
+impl fmt::Debug for BusControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BusControl")
+            .field("state", &self.state)
+            .field("stats", &self.stats.snapshot())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Kinds which BUS consumes itself are never delivered, so they can't be paused.
+fn pausable_kind(kind: Option<i64>) -> Result<ZeromqMessageKind, String> {
+    let kind = kind.ok_or_else(|| "kind is required".to_string())?;
+    let kind = u32::try_from(kind)
+        .ok()
+        .and_then(|kind| ZeromqMessageKind::try_from(kind).ok())
+        .ok_or_else(|| format!("unknown message kind {}", kind))?;
+
+    if is_control_message(kind) {
+        return Err(format!("{:?} messages can't be paused", kind));
+    }
+    Ok(kind)
+}
+
+/// Subscription prefix starts with big-endian message kind unless it is too short.
+fn prefix_kind(prefix: &[u8]) -> Option<ZeromqMessageKind> {
+    let kind_bytes = <[u8; 4]>::try_from(prefix.get(..4)?).ok()?;
+    ZeromqMessageKind::try_from(u32::from_be_bytes(kind_bytes)).ok()
+}
+
+fn empty_reply() -> ControlReply {
+    ControlReply {
+        error: None,
+        paused_kinds: None,
+        purged_messages: None,
+        log_level: None,
+    }
+}
+
+fn current_log_level() -> String {
+    log::max_level().to_string().to_lowercase()
+}
+
+fn integer(value: u64) -> i64 {
+    i64::try_from(value).unwrap_or(i64::MAX)
+}
+
+//-----------------------------------------------------------------------------------------
+// Tests
+//-----------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::control::BusControl;
+    use crate::AccessControl;
+    use crate::AclRule;
+    use crate::BusControlState;
+    use crate::BusStats;
+    use crate::DeadLetterQueue;
+    use crate::DeadLetterQueueOptions;
+    use crate::DeadLockSafeMutex;
+    use std::sync::mpsc;
+    use std::sync::Arc;
+    use uuid::Uuid;
+    use zeromq_messages::codec::decode_message_payload;
+    use zeromq_messages::codec::encode_message;
+    use zeromq_messages::codec::message_payload_bytes;
+    use zeromq_messages::kind::ZeromqMessageKind;
+    use zeromq_messages::messages::ControlCommand;
+    use zeromq_messages::messages::ControlReply;
+    use zmq::Context;
+    use zmq::SocketType;
+
+    const IDENTITY: &str = "Yne@$w-vo<fVvi]a<NY6T1ed:M$fCG*[IaLV{hID";
+
+    #[test]
+    fn refuses_commands_of_clients_without_grant() {
+        let access_control = AccessControl::new(&[AclRule {
+            identity: IDENTITY.to_string(),
+            publish_kinds: vec![ZeromqMessageKind::ControlCommand as u32],
+            subscribe_kinds: vec![],
+        }]);
+        let (sender_commands, _) = mpsc::channel();
+        let control = BusControl {
+            socket: Context::new().socket(SocketType::REP).unwrap(),
+            state: BusControlState::new(1),
+            stats: Arc::new(BusStats::new(1)),
+            dead_letter_queue: DeadLockSafeMutex::new(
+                DeadLetterQueue::open(DeadLetterQueueOptions::new(1)).unwrap(),
+            ),
+            sender_commands,
+            access_control: Some(Arc::new(access_control)),
+        };
+
+        let command_bytes = encode_message(
+            Uuid::new_v4(),
+            ControlCommand {
+                action: "pause-kind".to_string(),
+                kind: Some(i64::from(
+                    ZeromqMessageKind::ValueMultiplicationResponse as u32,
+                )),
+                log_level: None,
+            },
+        )
+        .unwrap();
+        let execute = |user_id| {
+            let reply_bytes = control.execute(command_bytes.as_slice(), user_id);
+            message_payload_bytes(reply_bytes.as_slice())
+                .and_then(decode_message_payload::<ControlReply>)
+                .unwrap()
+        };
+
+        for user_id in &[None, Some("Stranger")] {
+            let reply = execute(*user_id);
+            assert!(reply.error.is_some());
+            assert_eq!(None, reply.paused_kinds);
+        }
+        assert!(!control
+            .state
+            .is_paused(ZeromqMessageKind::ValueMultiplicationResponse));
+
+        let reply = execute(Some(IDENTITY));
+        assert_eq!(None, reply.error);
+        assert!(control
+            .state
+            .is_paused(ZeromqMessageKind::ValueMultiplicationResponse));
+    }
+}
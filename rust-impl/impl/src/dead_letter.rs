@@ -0,0 +1,477 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write;
+use std::iter::Iterator;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use uuid::Uuid;
+use zeromq_messages::codec::decode_message_kind_and_uuid;
+use zeromq_messages::kind::ZeromqMessageKind;
+use zeromq_messages::messages::DeadLetterReportItemEntries;
+
+//-----------------------------------------------------------------------------------------
+// DeadLetterReason
+//-----------------------------------------------------------------------------------------
+
+/// Explains why message has left the normal delivery path.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum DeadLetterReason {
+    /// BUS has failed to publish message the given count of times.
+    RetryBudgetExhausted(usize),
+    /// Message was waiting for publishing retry while retry buffer was full.
+    RetryBufferOverflow,
+    /// Message was refused on the BUS ingress.
+    ValidationFailed(String),
+    /// One of consumers was not able to process message.
+    RejectedByConsumer(String),
+    /// Deadline from the message header has passed before delivery.
+    Expired,
+    /// No subscriber of any publisher socket wanted message.
+    Unroutable,
+}
+
+impl fmt::Display for DeadLetterReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RetryBudgetExhausted(attempts) => {
+                write!(f, "retry budget exhausted after {} attempts", attempts)
+            }
+            Self::RetryBufferOverflow => write!(f, "retry buffer overflow"),
+            Self::ValidationFailed(reason) => write!(f, "validation failed: {}", reason),
+            Self::RejectedByConsumer(reason) => write!(f, "rejected by consumer: {}", reason),
+            Self::Expired => write!(f, "deadline has passed"),
+            Self::Unroutable => write!(f, "no subscriber wanted it"),
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// ExpiryPolicy
+//-----------------------------------------------------------------------------------------
+
+/// Defines what happens with a message whose deadline has passed before delivery.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExpiryPolicy {
+    /// Message is discarded, only counters tell about it.
+    Drop,
+    /// Message is moved to the dead-letter queue.
+    DeadLetter,
+}
+
+impl FromStr for ExpiryPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "drop" => Ok(Self::Drop),
+            "dead-letter" => Ok(Self::DeadLetter),
+            _ => Err(format!("unknown expiry policy '{}'", value)),
+        }
+    }
+}
+
+impl fmt::Display for ExpiryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Drop => "drop",
+            Self::DeadLetter => "dead-letter",
+        })
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// UnroutablePolicy
+//-----------------------------------------------------------------------------------------
+
+/// Defines what happens with a message which no subscriber wants. Messages replayed after
+/// restart or takeover are always published, their subscribers may not have reconnected
+/// yet when they are sent.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnroutablePolicy {
+    /// Message is published anyway, publisher socket discards it.
+    Publish,
+    /// Message is discarded without being sent, only counters tell about it.
+    Drop,
+    /// Message is moved to the dead-letter queue without being sent.
+    DeadLetter,
+}
+
+impl FromStr for UnroutablePolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "publish" => Ok(Self::Publish),
+            "drop" => Ok(Self::Drop),
+            "dead-letter" => Ok(Self::DeadLetter),
+            _ => Err(format!("unknown unroutable policy '{}'", value)),
+        }
+    }
+}
+
+impl fmt::Display for UnroutablePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Publish => "publish",
+            Self::Drop => "drop",
+            Self::DeadLetter => "dead-letter",
+        })
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// DeadLetter
+//-----------------------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub id: u64,
+    pub reason: DeadLetterReason,
+    /// Milliseconds since unix epoch.
+    pub dead_lettered_at: u64,
+    pub message_bytes: Vec<u8>,
+    /// User id of the client which has handed message bytes over as rejected or invalid
+    /// ones, known only when clients are authenticated.
+    #[serde(default)]
+    pub sender_user_id: Option<String>,
+}
+
+impl DeadLetter {
+    /// Kind and uuid of the message, absent for messages which failed validation because
+    /// of their header.
+    #[must_use]
+    pub fn kind_and_uuid(&self) -> Option<(ZeromqMessageKind, Uuid)> {
+        decode_message_kind_and_uuid(self.message_bytes.as_slice()).ok()
+    }
+
+    /// Entry of dead-letter report, message bytes are included only on request.
+    #[must_use]
+    pub fn report_entry(&self, with_message: bool) -> DeadLetterReportItemEntries {
+        let kind_and_uuid = self.kind_and_uuid();
+
+        DeadLetterReportItemEntries {
+            id: i64::try_from(self.id).unwrap_or(i64::MAX),
+            kind: kind_and_uuid.map(|(kind, _)| i64::from(kind as u32)),
+            uuid: kind_and_uuid.map(|(_, uuid)| uuid.to_string()),
+            reason: self.reason.to_string(),
+            dead_lettered_at: i64::try_from(self.dead_lettered_at).unwrap_or(i64::MAX),
+            message: if with_message {
+                Some(
+                    self.message_bytes
+                        .iter()
+                        .map(|byte| i64::from(*byte))
+                        .collect(),
+                )
+            } else {
+                None
+            },
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// DeadLetterQueueOptions
+//-----------------------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DeadLetterQueueOptions {
+    /// Maximum count of kept messages, the oldest message is dropped on overflow.
+    pub capacity: usize,
+    /// File which keeps messages between BUS runs, queue is in memory only when absent.
+    pub file: Option<PathBuf>,
+}
+
+impl DeadLetterQueueOptions {
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            file: None,
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// DeadLetterQueue
+//-----------------------------------------------------------------------------------------
+
+/// Bounded storage of messages which BUS was not able to deliver or which consumers were
+/// not able to process. Messages stay here until they are re-injected or purged.
+#[derive(Debug)]
+pub struct DeadLetterQueue {
+    options: DeadLetterQueueOptions,
+    entries: VecDeque<DeadLetter>,
+    next_id: u64,
+}
+
+impl DeadLetterQueue {
+    /// Creates queue and loads messages which were kept in the file by previous run.
+    pub fn open(options: DeadLetterQueueOptions) -> io::Result<Self> {
+        let mut entries = VecDeque::new();
+
+        if let Some(path) = &options.file {
+            if path.exists() {
+                for line in BufReader::new(File::open(path)?).lines() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    entries.push_back(serde_json::from_str::<DeadLetter>(line.as_str())?);
+                }
+            }
+        }
+
+        let next_id = entries.iter().map(|entry| entry.id + 1).max().unwrap_or(0);
+        let mut dead_letter_queue = Self {
+            options,
+            entries,
+            next_id,
+        };
+
+        if dead_letter_queue.entries.len() > dead_letter_queue.options.capacity {
+            while dead_letter_queue.entries.len() > dead_letter_queue.options.capacity {
+                let _ = dead_letter_queue.entries.pop_front();
+            }
+            dead_letter_queue.rewrite_file()?;
+        }
+
+        Ok(dead_letter_queue)
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Messages from the oldest to the newest one.
+    pub fn entries(&self) -> impl Iterator<Item = &DeadLetter> {
+        self.entries.iter()
+    }
+
+    #[must_use]
+    pub fn get(&self, id: u64) -> Option<&DeadLetter> {
+        self.entries.iter().find(|entry| entry.id == id)
+    }
+
+    /// Keeps message and returns its id.
+    pub fn push(
+        &mut self,
+        reason: DeadLetterReason,
+        message_bytes: Vec<u8>,
+    ) -> io::Result<u64> {
+        self.push_from_sender(reason, message_bytes, None)
+    }
+
+    /// Keeps message whose bytes have come from the client with the given user id and
+    /// returns its id.
+    pub fn push_from_sender(
+        &mut self,
+        reason: DeadLetterReason,
+        message_bytes: Vec<u8>,
+        sender_user_id: Option<String>,
+    ) -> io::Result<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let entry = DeadLetter {
+            id,
+            reason,
+            dead_lettered_at: milliseconds_since_unix_epoch(),
+            message_bytes,
+            sender_user_id,
+        };
+
+        if self.entries.len() >= self.options.capacity {
+            if let Some(dropped_entry) = self.entries.pop_front() {
+                log::warn!(
+                    "dead-letter queue is full, dropped message {}",
+                    dropped_entry.id
+                );
+            }
+            self.entries.push_back(entry);
+            self.rewrite_file()?;
+        } else {
+            if let Some(path) = &self.options.file {
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+            }
+            self.entries.push_back(entry);
+        }
+
+        Ok(id)
+    }
+
+    /// Removes message with the given id or all messages when id is absent.
+    pub fn remove(&mut self, id: Option<u64>) -> io::Result<Vec<DeadLetter>> {
+        self.remove_where(move |entry| id.is_none_or(|id| entry.id == id))
+    }
+
+    /// Removes all messages which satisfy the predicate and returns them.
+    pub fn remove_where<F: FnMut(&DeadLetter) -> bool>(
+        &mut self,
+        mut predicate: F,
+    ) -> io::Result<Vec<DeadLetter>> {
+        let (removed_entries, kept_entries) = self
+            .entries
+            .drain(..)
+            .partition::<Vec<DeadLetter>, _>(|entry| predicate(entry));
+        self.entries = kept_entries.into_iter().collect();
+
+        if !removed_entries.is_empty() {
+            self.rewrite_file()?;
+        }
+
+        Ok(removed_entries)
+    }
+
+    fn rewrite_file(&self) -> io::Result<()> {
+        let Some(path) = &self.options.file else {
+            return Ok(());
+        };
+
+        // Temporary file is renamed over the old one, so a crash never leaves half of the
+        // queue on disk.
+        let temporary_path = path.with_extension("tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&temporary_path)?);
+            for entry in &self.entries {
+                writeln!(writer, "{}", serde_json::to_string(entry)?)?;
+            }
+            writer.flush()?;
+        }
+        fs::rename(temporary_path, path)
+    }
+}
+
+fn milliseconds_since_unix_epoch() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| {
+            u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+        })
+}
+
+//-----------------------------------------------------------------------------------------
+// Tests
+//-----------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::dead_letter::DeadLetterQueue;
+    use crate::dead_letter::DeadLetterQueueOptions;
+    use crate::dead_letter::DeadLetterReason;
+    use std::env;
+    use uuid::Uuid;
+
+    #[test]
+    fn drops_oldest_message_on_overflow() {
+        let mut dead_letter_queue =
+            DeadLetterQueue::open(DeadLetterQueueOptions::new(2)).unwrap();
+
+        for message in &["first", "second", "third"] {
+            let _ = dead_letter_queue
+                .push(
+                    DeadLetterReason::RetryBudgetExhausted(3),
+                    message.as_bytes().to_vec(),
+                )
+                .unwrap();
+        }
+
+        assert_eq!(
+            vec![1, 2],
+            dead_letter_queue
+                .entries()
+                .map(|entry| entry.id)
+                .collect::<Vec<u64>>()
+        );
+        assert!(dead_letter_queue.get(0).is_none());
+        assert_eq!(
+            b"third".to_vec(),
+            dead_letter_queue.get(2).unwrap().message_bytes
+        );
+    }
+
+    #[test]
+    fn removes_messages() {
+        let mut dead_letter_queue =
+            DeadLetterQueue::open(DeadLetterQueueOptions::new(10)).unwrap();
+        let first_id = dead_letter_queue
+            .push(
+                DeadLetterReason::ValidationFailed("bad".to_string()),
+                b"first".to_vec(),
+            )
+            .unwrap();
+        let _ = dead_letter_queue
+            .push(
+                DeadLetterReason::ValidationFailed("bad".to_string()),
+                b"second".to_vec(),
+            )
+            .unwrap();
+
+        assert_eq!(1, dead_letter_queue.remove(Some(first_id)).unwrap().len());
+        assert!(dead_letter_queue.remove(Some(first_id)).unwrap().is_empty());
+        assert_eq!(1, dead_letter_queue.remove(None).unwrap().len());
+        assert!(dead_letter_queue.is_empty());
+    }
+
+    #[test]
+    fn keeps_messages_in_file() {
+        let path = env::temp_dir().join(format!("zeromq-bus-dlq-{}.jsonl", Uuid::new_v4()));
+        let options = DeadLetterQueueOptions {
+            capacity: 10,
+            file: Some(path.clone()),
+        };
+
+        {
+            let mut dead_letter_queue = DeadLetterQueue::open(options.clone()).unwrap();
+            let first_id = dead_letter_queue
+                .push(
+                    DeadLetterReason::RejectedByConsumer("poison".to_string()),
+                    b"first".to_vec(),
+                )
+                .unwrap();
+            let _ = dead_letter_queue
+                .push(
+                    DeadLetterReason::RejectedByConsumer("poison".to_string()),
+                    b"second".to_vec(),
+                )
+                .unwrap();
+            let _ = dead_letter_queue.remove(Some(first_id)).unwrap();
+        }
+
+        let mut dead_letter_queue = DeadLetterQueue::open(options).unwrap();
+        assert_eq!(1, dead_letter_queue.len());
+        let entry = dead_letter_queue.get(1).unwrap();
+        assert_eq!(b"second".to_vec(), entry.message_bytes);
+        assert_eq!(
+            DeadLetterReason::RejectedByConsumer("poison".to_string()),
+            entry.reason
+        );
+
+        let id = dead_letter_queue
+            .push(DeadLetterReason::RetryBudgetExhausted(1), b"third".to_vec())
+            .unwrap();
+        assert_eq!(2, id);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
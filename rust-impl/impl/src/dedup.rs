@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::hash::Hash;
+use std::time::Duration;
+use std::time::Instant;
+
+//-----------------------------------------------------------------------------------------
+// DeduplicationWindow
+//-----------------------------------------------------------------------------------------
+
+/// Keys which have been seen recently, optionally with a value remembered for each of
+/// them. Key is forgotten once it is older than the max age, or once the window is full
+/// and it is the oldest one, so memory stays bounded whatever the rate of keys is.
+#[derive(Debug)]
+pub struct DeduplicationWindow<K, V = ()> {
+    max_age: Duration,
+    capacity: usize,
+    entries: HashMap<K, V>,
+    insertion_order: VecDeque<(K, Instant)>,
+}
+
+impl<K: Copy + Eq + Hash, V> DeduplicationWindow<K, V> {
+    #[must_use]
+    pub fn new(max_age: Duration, capacity: usize) -> Self {
+        Self {
+            max_age,
+            capacity,
+            entries: HashMap::new(),
+            insertion_order: VecDeque::new(),
+        }
+    }
+
+    /// Value remembered for the key when the key is still inside the window.
+    pub fn get(&mut self, key: &K, now: Instant) -> Option<&V> {
+        self.evict(now);
+        self.entries.get(key)
+    }
+
+    /// Remembers the key with its value, the key which is already inside the window gets
+    /// its value replaced but keeps its place, so it is forgotten no later than before.
+    pub fn insert(&mut self, key: K, value: V, now: Instant) {
+        self.evict(now);
+
+        if let Some(entry) = self.entries.get_mut(&key) {
+            *entry = value;
+            return;
+        }
+
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() >= self.capacity {
+            self.evict_oldest();
+        }
+
+        let _ = self.entries.insert(key, value);
+        self.insertion_order.push_back((key, now));
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn evict(&mut self, now: Instant) {
+        while self
+            .insertion_order
+            .front()
+            .is_some_and(|(_, inserted_at)| {
+                now.saturating_duration_since(*inserted_at) >= self.max_age
+            })
+        {
+            self.evict_oldest();
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        if let Some((key, _)) = self.insertion_order.pop_front() {
+            let _ = self.entries.remove(&key);
+        }
+    }
+}
+
+impl<K: Copy + Eq + Hash> DeduplicationWindow<K> {
+    /// Whether the key is inside the window, the key is remembered when it is not.
+    pub fn is_duplicate(&mut self, key: K, now: Instant) -> bool {
+        if self.get(&key, now).is_some() {
+            return true;
+        }
+
+        self.insert(key, (), now);
+        false
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// Tests
+//-----------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::dedup::DeduplicationWindow;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    #[test]
+    fn forgets_keys_by_age_and_count() {
+        let mut window = DeduplicationWindow::new(Duration::from_millis(100), 2);
+        let start = Instant::now();
+
+        assert!(!window.is_duplicate(1, start));
+        assert!(window.is_duplicate(1, start + Duration::from_millis(50)));
+        assert!(!window.is_duplicate(2, start + Duration::from_millis(60)));
+
+        // Full window makes room for the new key by forgetting the oldest one.
+        assert!(!window.is_duplicate(3, start + Duration::from_millis(70)));
+        assert_eq!(2, window.len());
+        assert!(!window.is_duplicate(1, start + Duration::from_millis(80)));
+
+        // Duplicate does not extend the time during which the key is remembered.
+        assert!(window.is_duplicate(3, start + Duration::from_millis(90)));
+        assert!(!window.is_duplicate(3, start + Duration::from_millis(170)));
+        assert!(window.is_duplicate(1, start + Duration::from_millis(175)));
+        assert!(!window.is_duplicate(1, start + Duration::from_millis(180)));
+    }
+}
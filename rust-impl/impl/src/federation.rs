@@ -0,0 +1,250 @@
+use crate::BusStats;
+use crate::ShutdownSignal;
+use crate::SHUTDOWN_CHECK_INTERVAL_MILLISECONDS;
+use std::collections::HashSet;
+use std::fmt;
+use std::iter::Iterator;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::Duration;
+use zeromq_messages::codec::decode_message_header;
+use zeromq_messages::codec::decode_message_kind_and_uuid;
+use zeromq_messages::codec::decode_message_payload;
+use zeromq_messages::codec::message_payload_bytes;
+use zeromq_messages::codec::replace_message_header;
+use zeromq_messages::kind::ZeromqMessageKind;
+use zeromq_messages::messages::PublishConfirmation;
+use zmq::Context;
+use zmq::Socket;
+use zmq::SocketType;
+
+//-----------------------------------------------------------------------------------------
+// Federation
+//-----------------------------------------------------------------------------------------
+
+/// Receiver side of the federation. Hands published messages of federated kinds over to
+/// the forwarder and recognizes messages which have already passed this BUS.
+#[derive(Debug)]
+pub struct Federation {
+    instance_id: String,
+    kinds: HashSet<ZeromqMessageKind>,
+    messages: mpsc::SyncSender<Vec<u8>>,
+    stats: Arc<BusStats>,
+}
+
+impl Federation {
+    /// Connects to peers, the returned forwarder should run in its own thread. Messages
+    /// which do not fit into the queue of the forwarder are dropped, linger bounds the time
+    /// which forwarded messages are given to reach peers on shutdown.
+    pub fn connect(
+        context: &Context,
+        instance_id: String,
+        kinds: &[ZeromqMessageKind],
+        peer_endpoints: &[String],
+        queue_capacity: usize,
+        linger: i32,
+        stats: Arc<BusStats>,
+    ) -> Result<(Self, FederationForwarder), zmq::Error> {
+        let mut peers = Vec::with_capacity(peer_endpoints.len());
+        for endpoint in peer_endpoints {
+            let socket = context.socket(SocketType::DEALER)?;
+            socket.set_identity(instance_id.as_bytes())?;
+            socket.set_linger(linger)?;
+            socket.connect(endpoint.as_str())?;
+            peers.push(FederationPeer {
+                endpoint: endpoint.clone(),
+                socket,
+            });
+        }
+
+        let (sender, receiver) = mpsc::sync_channel(queue_capacity);
+
+        Ok((
+            Self {
+                instance_id: instance_id.clone(),
+                kinds: kinds.iter().copied().collect(),
+                messages: sender,
+                stats: Arc::clone(&stats),
+            },
+            FederationForwarder {
+                instance_id,
+                peers,
+                messages: receiver,
+                stats,
+            },
+        ))
+    }
+
+    #[must_use]
+    pub fn instance_id(&self) -> &str {
+        self.instance_id.as_str()
+    }
+
+    /// Whether the message has been forwarded by this BUS before, so it came back through
+    /// a loop of peers and should not be routed again.
+    #[must_use]
+    pub fn has_forwarded(&self, message_bytes: &[u8]) -> bool {
+        decode_message_header(message_bytes).is_ok_and(|header| {
+            header
+                .hops
+                .iter()
+                .any(|instance_id| instance_id == &self.instance_id)
+        })
+    }
+
+    /// Queues published message for peers when its kind is federated.
+    pub fn forward(&self, kind: ZeromqMessageKind, message_bytes: &[u8]) {
+        if !self.kinds.contains(&kind) {
+            return;
+        }
+
+        match self.messages.try_send(message_bytes.to_vec()) {
+            Ok(()) => {}
+            Err(mpsc::TrySendError::Full(_)) => {
+                log::warn!("federation queue is full, dropped {:?} message", kind);
+                self.stats.federation_dropped_messages.increment();
+            }
+            Err(mpsc::TrySendError::Disconnected(_)) => {
+                log::trace!(
+                    "federation forwarder has stopped, dropped {:?} message",
+                    kind
+                );
+                self.stats.federation_dropped_messages.increment();
+            }
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// FederationForwarder
+//-----------------------------------------------------------------------------------------
+
+struct FederationPeer {
+    endpoint: String,
+    socket: Socket,
+}
+
+/// Sends federated messages to router sockets of peer BUSes, the same way as services
+/// publish them. Instance id of this BUS is appended to hops of every forwarded message,
+/// and peers drop messages which contain their own instance id, so messages never loop.
+pub struct FederationForwarder {
+    instance_id: String,
+    peers: Vec<FederationPeer>,
+    messages: mpsc::Receiver<Vec<u8>>,
+    stats: Arc<BusStats>,
+}
+
+impl FederationForwarder {
+    /// Forwards messages until shutdown is requested, messages which are queued by then
+    /// are forwarded before return.
+    pub fn run(&mut self, shutdown_signal: &ShutdownSignal) {
+        let check_interval =
+            Duration::from_millis(u64::from(SHUTDOWN_CHECK_INTERVAL_MILLISECONDS));
+
+        while !shutdown_signal.is_requested() {
+            self.receive_confirmations();
+
+            match self.messages.recv_timeout(check_interval) {
+                Ok(message_bytes) => self.forward(message_bytes.as_slice()),
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        while let Ok(message_bytes) = self.messages.try_recv() {
+            self.forward(message_bytes.as_slice());
+        }
+
+        log::debug!("federation loop has stopped");
+    }
+
+    fn forward(&self, message_bytes: &[u8]) {
+        let forwarded_message_bytes =
+            match decode_message_header(message_bytes).and_then(|mut header| {
+                header.hops.push(self.instance_id.clone());
+                replace_message_header(message_bytes, &header)
+            }) {
+                Ok(forwarded_message_bytes) => forwarded_message_bytes,
+                Err(error) => {
+                    log::error!("failed to forward message because of: {}", error);
+                    self.stats.federation_dropped_messages.increment();
+                    return;
+                }
+            };
+
+        for peer in &self.peers {
+            // Peer which is not reachable must not stop forwarding to the other ones.
+            match peer
+                .socket
+                .send(forwarded_message_bytes.as_slice(), zmq::DONTWAIT)
+            {
+                Ok(()) => {
+                    log::trace!("> [PEER] {} {:?}", peer.endpoint, forwarded_message_bytes);
+                    self.stats.federated_messages.increment();
+                }
+                Err(error) => {
+                    log::warn!(
+                        "failed to forward message to {} because of: {}",
+                        peer.endpoint,
+                        error
+                    );
+                    self.stats.federation_dropped_messages.increment();
+                }
+            }
+        }
+    }
+
+    /// Peers confirm forwarded messages as they do for services, rejections are logged
+    /// and counted.
+    fn receive_confirmations(&self) {
+        for peer in &self.peers {
+            while let Ok(message_bytes) = peer.socket.recv_bytes(zmq::DONTWAIT) {
+                let confirmation = match decode_message_kind_and_uuid(message_bytes.as_slice())
+                {
+                    Ok((ZeromqMessageKind::PublishConfirmation, uuid)) => {
+                        message_payload_bytes(message_bytes.as_slice())
+                            .and_then(decode_message_payload::<PublishConfirmation>)
+                            .map(|confirmation| (uuid, confirmation))
+                    }
+                    _ => continue,
+                };
+
+                match confirmation {
+                    Ok((_, confirmation)) if confirmation.accepted => {}
+                    Ok((uuid, confirmation)) => {
+                        log::warn!(
+                            "peer {} rejected forwarded message {} because of: {}",
+                            peer.endpoint,
+                            uuid,
+                            confirmation.reason.unwrap_or_default()
+                        );
+                        self.stats.federation_dropped_messages.increment();
+                    }
+                    Err(error) => log::error!(
+                        "failed to decode confirmation of peer {} because of: {}",
+                        peer.endpoint,
+                        error
+                    ),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for FederationForwarder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FederationForwarder")
+            .field("instance_id", &self.instance_id)
+            .field(
+                "peers",
+                &self
+                    .peers
+                    .iter()
+                    .map(|peer| peer.endpoint.as_str())
+                    .collect::<Vec<&str>>(),
+            )
+            .field("messages", &self.messages)
+            .field("stats", &self.stats)
+            .finish()
+    }
+}
@@ -1,19 +1,21 @@
 use core::panic;
 use std::convert::From;
 use std::fmt;
+use std::fmt::Write as _;
 use std::ops::Deref;
 use std::ops::DerefMut;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::sync::RwLock;
 use std::time::Instant;
+use zeromq_messages::kind::ZeromqMessageKind;
 use zmq::Socket;
 
 //-----------------------------------------------------------------------------------------
 // DeadLockSafeRwLock
 //-----------------------------------------------------------------------------------------
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct DeadLockSafeRwLock<T>(Arc<RwLock<T>>);
 
 impl<T> DeadLockSafeRwLock<T> {
@@ -37,6 +39,13 @@ impl<T> DeadLockSafeRwLock<T> {
     }
 }
 
+// Implemented manually, because derive requires `T: Clone` while only `Arc` is cloned.
+impl<T> Clone for DeadLockSafeRwLock<T> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
 impl<T: Default> Default for DeadLockSafeRwLock<T> {
     fn default() -> Self {
         Self::new(T::default())
@@ -53,7 +62,7 @@ impl<T> From<T> for DeadLockSafeRwLock<T> {
 // DeadLockSafeMutex
 //-----------------------------------------------------------------------------------------
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct DeadLockSafeMutex<T>(Arc<Mutex<T>>);
 
 impl<T> DeadLockSafeMutex<T> {
@@ -71,6 +80,13 @@ impl<T> DeadLockSafeMutex<T> {
     }
 }
 
+// Implemented manually, because derive requires `T: Clone` while only `Arc` is cloned.
+impl<T> Clone for DeadLockSafeMutex<T> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
 impl<T: Default> Default for DeadLockSafeMutex<T> {
     fn default() -> Self {
         Self::new(T::default())
@@ -133,3 +149,42 @@ impl fmt::Debug for BusPublisherData {
             .finish()
     }
 }
+
+/// Lowercase hex form of client identities and subscription prefixes in BUS replies.
+#[must_use]
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut output, byte| {
+        let _ = write!(output, "{:02x}", byte);
+        output
+    })
+}
+
+/// FNV-1a, which is enough to detect torn writes of write-ahead log and capture records.
+#[must_use]
+pub fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5_u32, |hash, byte| {
+        (hash ^ u32::from(*byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Kinds which BUS consumes or sends itself, they are neither persisted, deduplicated nor
+/// paused.
+#[must_use]
+pub fn is_control_message(message_kind: ZeromqMessageKind) -> bool {
+    matches!(
+        message_kind,
+        ZeromqMessageKind::WorkerReady
+            | ZeromqMessageKind::Heartbeat
+            | ZeromqMessageKind::MessageRejection
+            | ZeromqMessageKind::DeadLetterCommand
+            | ZeromqMessageKind::ServiceRegistration
+            | ZeromqMessageKind::ServiceQuery
+            | ZeromqMessageKind::ServiceQueryReply
+            | ZeromqMessageKind::DeliveryCancellation
+            | ZeromqMessageKind::ControlCommand
+            | ZeromqMessageKind::ControlReply
+            | ZeromqMessageKind::ControlStatsReply
+            | ZeromqMessageKind::ControlClientsReply
+            | ZeromqMessageKind::ControlSubscriptionsReply
+    )
+}
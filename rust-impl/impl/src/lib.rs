@@ -21,6 +21,10 @@
 #![allow(clippy::module_name_repetitions)]
 #![allow(clippy::missing_panics_doc)]
 #![allow(clippy::missing_errors_doc)]
+#![allow(clippy::uninlined_format_args)]
+#![allow(clippy::non_std_lazy_statics)]
+
+use zeromq_messages::kind::ZeromqMessageKind;
 
 #[macro_export]
 macro_rules! __format_endpoint {
@@ -44,12 +48,173 @@ pub const REQUESTS_COUNT_INSIDE_ONE_GROUP: usize = 25_000;
 pub const ZEROMQ_ZERO_FLAG: i32 = 0;
 pub const LOG_LEVEL: &str = "debug";
 pub const RUST_LOG_ENVIRONMENT_VARIABLE_NAME: &str = "RUST_LOG";
+pub const SHUTDOWN_DEADLINE_MS: u64 = 5_000;
+pub const SHUTDOWN_CHECK_INTERVAL_MILLISECONDS: u16 = 100;
+pub const HEARTBEAT_INTERVAL_MS: u64 = 1_000;
+pub const BUS_HEARTBEAT_GRACE_MS: u64 = 3_000;
+pub const BUS_CLIENT_ACTIVITY_TTL_MS: u64 = 60 * 60 * 1_000;
+pub const BUS_DEAD_LETTER_QUEUE_CAPACITY: usize = 10_000;
+pub const BUS_DEDUP_WINDOW_CAPACITY: usize = 100_000;
+pub const BUS_EXPIRY_POLICY: ExpiryPolicy = ExpiryPolicy::Drop;
+pub const BUS_FEDERATION_QUEUE_CAPACITY: usize = 10_000;
+pub const BUS_PAUSED_MESSAGES_CAPACITY: usize = 100_000;
+pub const BUS_PAUSED_MESSAGES_OVERFLOW_POLICY: OverflowPolicy = OverflowPolicy::RejectWithNack;
+pub const BUS_PRIORITY_WEIGHTS: &[u32] = &[8, 4, 1];
+pub const BUS_PUBLISH_RETRY_BUDGET: usize = 10;
+pub const BUS_PUBLISHING_QUEUE_CAPACITY: usize = 100_000;
+pub const BUS_PUBLISHING_QUEUE_OVERFLOW_POLICY: OverflowPolicy = OverflowPolicy::Block;
+pub const BUS_RATE_LIMIT_POLICY: RateLimitPolicy = RateLimitPolicy::Nack;
+pub const BUS_RETRY_BUFFER_CAPACITY: usize = 10_000;
+pub const BUS_SCHEDULED_MESSAGES_CAPACITY: usize = 100_000;
+pub const BUS_SCHEDULE_MAX_DELAY_MS: u64 = 7 * 24 * 60 * 60 * 1_000;
+pub const BUS_ROUTER_HIGH_WATER_MARK: i32 = 100_000;
+pub const BUS_PUBLISHERS_HIGH_WATER_MARK: i32 = 100_000;
+pub const BUS_PUBLISHER_SELECTION_STRATEGY: PublisherSelectionStrategy =
+    PublisherSelectionStrategy::LeastRecentlyUsed;
+pub const BUS_UNROUTABLE_POLICY: UnroutablePolicy = UnroutablePolicy::Publish;
+pub const BUS_WAL_FSYNC_POLICY: WriteAheadLogFsyncPolicy =
+    WriteAheadLogFsyncPolicy::EveryRecords(1_000);
+pub const BUS_WAL_SEGMENT_MAX_BYTES: u64 = 64 * 1024 * 1024;
+pub const BUS_WORK_QUEUE_CAPACITY: usize = 100_000;
+pub const BUS_WORK_QUEUE_KINDS: &[ZeromqMessageKind] =
+    &[ZeromqMessageKind::ValueMultiplicationRequest];
+pub const BUS_WORK_QUEUE_OVERFLOW_POLICY: OverflowPolicy = OverflowPolicy::RejectWithNack;
+pub const BUS_WORK_QUEUE_STRATEGY: WorkerSelectionStrategy =
+    WorkerSelectionStrategy::LeastLoaded;
+
+mod acl;
+pub use acl::AccessControl;
+pub use acl::AclRule;
+
+mod bounded_queue;
+pub use bounded_queue::BoundedQueue;
+pub use bounded_queue::BoundedQueuePushOutcome;
+pub use bounded_queue::OverflowPolicy;
+
+mod bus;
+pub use bus::Bus;
+pub use bus::BusBuilder;
+pub use bus::BusError;
+pub use bus::BusShutdownSummary;
+
+mod capture;
+pub use capture::CaptureReader;
+pub use capture::CaptureRecord;
+pub use capture::CaptureSource;
+pub use capture::CaptureWriter;
+
+mod client;
+pub use client::BusClient;
+pub use client::BusClientError;
+pub use client::BusClientEvent;
+pub use client::ProcessedRequests;
+pub use client::PublishConfirmationOutcome;
+
+mod config;
+pub use config::load_config_from_args;
+pub use config::validate_endpoint;
+pub use config::BusConfig;
+pub use config::Config;
+pub use config::ConfigError;
+pub use config::ConfigOptions;
+pub use config::EndpointsConfig;
+
+mod control;
+pub use control::BusClientActivity;
+pub use control::BusControlState;
+
+mod dead_letter;
+pub use dead_letter::DeadLetter;
+pub use dead_letter::DeadLetterQueue;
+pub use dead_letter::DeadLetterQueueOptions;
+pub use dead_letter::DeadLetterReason;
+pub use dead_letter::ExpiryPolicy;
+pub use dead_letter::UnroutablePolicy;
+
+mod dedup;
+pub use dedup::DeduplicationWindow;
+
+mod federation;
+pub use federation::Federation;
+pub use federation::FederationForwarder;
 
 mod helpers;
 pub use helpers::BusPublisherData;
 pub use helpers::DeadLockSafeMutex;
 pub use helpers::DeadLockSafeRwLock;
 
+mod membership;
+pub use membership::Member;
+pub use membership::Membership;
+
+mod metrics;
+pub use metrics::render_metrics;
+pub use metrics::MetricsServer;
+
+mod paused;
+pub use paused::PausedMessage;
+pub use paused::PausedMessageHoldOutcome;
+pub use paused::PausedMessages;
+
+mod publisher_selection;
+pub use publisher_selection::HashSelector;
+pub use publisher_selection::LeastRecentlyUsedSelector;
+pub use publisher_selection::PublisherSelectionStrategy;
+pub use publisher_selection::PublisherSelector;
+pub use publisher_selection::RoundRobinSelector;
+
+mod rate_limit;
+pub use rate_limit::RateLimit;
+pub use rate_limit::RateLimitPolicy;
+pub use rate_limit::RateLimitedClient;
+pub use rate_limit::RateLimiter;
+
+mod registry;
+pub use registry::ServiceInstance;
+pub use registry::ServiceRegistry;
+
+mod replication;
+pub use replication::BusTakeover;
+pub use replication::Replication;
+pub use replication::Standby;
+
+mod schedule;
+pub use schedule::ScheduledMessage;
+pub use schedule::ScheduledMessages;
+
+mod security;
+pub use security::decode_curve_key;
+pub use security::secure_client_socket;
+pub use security::secure_server_socket;
+pub use security::AllowedClients;
+pub use security::SecurityError;
+pub use security::ZapHandler;
+
+mod shutdown;
+pub use shutdown::ShutdownSignal;
+
+mod stats;
+pub use stats::BusCounter;
+pub use stats::BusHistogram;
+pub use stats::BusKindStats;
+pub use stats::BusStats;
+pub use stats::BusStatsSnapshot;
+pub use stats::BUS_LATENCY_BUCKETS_MILLISECONDS;
+
+mod wal;
+pub use wal::WriteAheadLog;
+pub use wal::WriteAheadLogFsyncPolicy;
+pub use wal::WriteAheadLogOptions;
+pub use wal::WriteAheadLogRecord;
+pub use wal::WriteAheadLogReplica;
+
+mod work_queue;
+pub use work_queue::WorkQueue;
+pub use work_queue::WorkQueueDispatch;
+pub use work_queue::WorkQueueDispatchOutcome;
+pub use work_queue::WorkerIdentity;
+pub use work_queue::WorkerSelectionStrategy;
+
 pub use __format_endpoint as format_endpoint;
 pub use __BUS_PUBLISHERS_SOCKET_ADDRS as BUS_PUBLISHERS_SOCKET_ADDRS;
 pub use __BUS_ROUTER_SOCKET_ADDR as BUS_ROUTER_SOCKET_ADDR;
@@ -0,0 +1,152 @@
+use crate::WorkerIdentity;
+use std::collections::HashMap;
+use std::iter::Iterator;
+use std::time::Duration;
+use std::time::Instant;
+use zeromq_messages::header::milliseconds_since_unix_epoch;
+
+//-----------------------------------------------------------------------------------------
+// Member
+//-----------------------------------------------------------------------------------------
+
+/// Client which sends heartbeats and has sent something during the grace time.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Member {
+    pub identity: WorkerIdentity,
+    pub heartbeat_interval_ms: u64,
+    /// Milliseconds since Unix epoch.
+    pub last_seen_at: u64,
+    last_seen_time: Instant,
+}
+
+//-----------------------------------------------------------------------------------------
+// Membership
+//-----------------------------------------------------------------------------------------
+
+/// Live membership table of the BUS. Client joins with its first heartbeat, every frame
+/// received from it afterwards proves it is alive, and it leaves when nothing is received
+/// during the grace time.
+#[derive(Debug)]
+pub struct Membership {
+    grace: Duration,
+    members: HashMap<WorkerIdentity, Member>,
+}
+
+impl Membership {
+    #[must_use]
+    pub fn new(grace: Duration) -> Self {
+        Self {
+            grace,
+            members: HashMap::new(),
+        }
+    }
+
+    /// Returns `true` when the client has joined with this heartbeat.
+    pub fn heartbeat(
+        &mut self,
+        identity: &[u8],
+        heartbeat_interval_ms: u64,
+        now: Instant,
+    ) -> bool {
+        let last_seen_at = milliseconds_since_unix_epoch();
+
+        if let Some(member) = self.members.get_mut(identity) {
+            member.heartbeat_interval_ms = heartbeat_interval_ms;
+            member.last_seen_at = last_seen_at;
+            member.last_seen_time = now;
+            return false;
+        }
+
+        let _ = self.members.insert(
+            identity.to_vec(),
+            Member {
+                identity: identity.to_vec(),
+                heartbeat_interval_ms,
+                last_seen_at,
+                last_seen_time: now,
+            },
+        );
+        true
+    }
+
+    /// Records frame received from the client, frames of clients which are not members
+    /// are ignored.
+    pub fn touch(&mut self, identity: &[u8], now: Instant) {
+        if let Some(member) = self.members.get_mut(identity) {
+            member.last_seen_at = milliseconds_since_unix_epoch();
+            member.last_seen_time = now;
+        }
+    }
+
+    /// Removes and returns members which have sent nothing during the grace time.
+    pub fn expire(&mut self, now: Instant) -> Vec<Member> {
+        let grace = self.grace;
+        let expired_identities = self
+            .members
+            .values()
+            .filter(|member| now.saturating_duration_since(member.last_seen_time) > grace)
+            .map(|member| member.identity.clone())
+            .collect::<Vec<WorkerIdentity>>();
+
+        expired_identities
+            .iter()
+            .filter_map(|identity| self.members.remove(identity))
+            .collect()
+    }
+
+    #[must_use]
+    pub fn is_alive(&self, identity: &[u8]) -> bool {
+        self.members.contains_key(identity)
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// Tests
+//-----------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::membership::Member;
+    use crate::membership::Membership;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    #[test]
+    fn members_leave_after_grace_time() {
+        let mut membership = Membership::new(Duration::from_millis(300));
+        let start = Instant::now();
+
+        assert!(membership.heartbeat(b"a", 100, start));
+        assert!(membership.heartbeat(b"b", 100, start));
+        assert!(!membership.heartbeat(b"a", 100, start + Duration::from_millis(100)));
+
+        // Any frame of the member proves it is alive, frames of other clients are ignored.
+        membership.touch(b"b", start + Duration::from_millis(250));
+        membership.touch(b"c", start + Duration::from_millis(250));
+        assert_eq!(2, membership.len());
+
+        let expired_members = membership.expire(start + Duration::from_millis(450));
+        assert_eq!(
+            vec![b"a".to_vec()],
+            expired_members
+                .into_iter()
+                .map(|member: Member| member.identity)
+                .collect::<Vec<Vec<u8>>>()
+        );
+        assert!(!membership.is_alive(b"a"));
+        assert!(membership.is_alive(b"b"));
+
+        // Dead client joins again with its next heartbeat.
+        assert!(membership.heartbeat(b"a", 100, start + Duration::from_millis(500)));
+    }
+}
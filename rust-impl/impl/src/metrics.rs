@@ -0,0 +1,550 @@
+use crate::BusCounter;
+use crate::BusKindStats;
+use crate::BusStats;
+use crate::ShutdownSignal;
+use crate::SHUTDOWN_CHECK_INTERVAL_MILLISECONDS;
+use std::fmt::Write as _;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::iter::Iterator;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+const METRICS_PATH: &str = "/metrics";
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+const METRICS_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+type KindCounter = fn(&BusKindStats) -> &BusCounter;
+
+//-----------------------------------------------------------------------------------------
+// Rendering
+//-----------------------------------------------------------------------------------------
+
+/// Renders BUS counters in Prometheus text exposition format.
+#[must_use]
+pub fn render_metrics(stats: &BusStats) -> String {
+    let mut output = String::new();
+    write_totals(&mut output, stats);
+    write_protection_totals(&mut output, stats);
+    write_gauges(&mut output, stats);
+    write_kind_totals(&mut output, stats);
+    write_publisher_sends(&mut output, stats);
+    write_publisher_subscriptions(&mut output, stats);
+    write_throttled_clients(&mut output, stats);
+    write_latency(&mut output, stats);
+    output
+}
+
+fn write_totals(output: &mut String, stats: &BusStats) {
+    let counters: &[(&str, &str, &BusCounter)] = &[
+        (
+            "bus_received_messages_total",
+            "Messages received on the router socket",
+            &stats.received_messages,
+        ),
+        (
+            "bus_received_bytes_total",
+            "Bytes of messages received on the router socket",
+            &stats.received_bytes,
+        ),
+        (
+            "bus_published_messages_total",
+            "Messages published to subscribers",
+            &stats.published_messages,
+        ),
+        (
+            "bus_published_bytes_total",
+            "Bytes of messages published to subscribers",
+            &stats.published_bytes,
+        ),
+        (
+            "bus_publish_failures_total",
+            "Failed attempts to publish message",
+            &stats.publish_failures,
+        ),
+        (
+            "bus_dead_lettered_messages_total",
+            "Messages moved to the dead-letter queue",
+            &stats.dead_lettered_messages,
+        ),
+        (
+            "bus_publishing_queue_blocked_pushes_total",
+            "Pushes which waited for free space in the publishing queue",
+            &stats.publishing_queue_blocked_pushes,
+        ),
+        (
+            "bus_publishing_queue_dropped_oldest_messages_total",
+            "Messages dropped from the full publishing queue in favour of new ones",
+            &stats.publishing_queue_dropped_oldest_messages,
+        ),
+        (
+            "bus_publishing_queue_dropped_newest_messages_total",
+            "New messages dropped because the publishing queue was full",
+            &stats.publishing_queue_dropped_newest_messages,
+        ),
+        (
+            "bus_publishing_queue_rejected_messages_total",
+            "New messages refused to senders because the publishing queue was full",
+            &stats.publishing_queue_rejected_messages,
+        ),
+        (
+            "bus_retry_buffer_overflows_total",
+            "Messages dead-lettered because the retry buffer was full",
+            &stats.retry_buffer_overflows,
+        ),
+        (
+            "bus_work_queue_overflows_total",
+            "Work-queue requests dropped or refused because held requests were at capacity",
+            &stats.work_queue_overflows,
+        ),
+        (
+            "bus_federated_messages_total",
+            "Messages forwarded to peer BUSes, counted once per peer",
+            &stats.federated_messages,
+        ),
+        (
+            "bus_federation_dropped_messages_total",
+            "Messages which were not forwarded to peer BUSes or were rejected by them",
+            &stats.federation_dropped_messages,
+        ),
+        (
+            "bus_federation_looped_messages_total",
+            "Messages dropped because they came back to the BUS which has forwarded them",
+            &stats.federation_looped_messages,
+        ),
+        (
+            "bus_expired_messages_total",
+            "Messages discarded because their deadline has passed before delivery",
+            &stats.expired_messages,
+        ),
+        (
+            "bus_cancelled_deliveries_total",
+            "Scheduled messages which were cancelled before their deliver-at time",
+            &stats.cancelled_deliveries,
+        ),
+        (
+            "bus_unroutable_messages_total",
+            "Messages which no subscriber wanted",
+            &stats.unroutable_messages,
+        ),
+    ];
+    for (name, help, counter) in counters {
+        write_header(output, name, help, "counter");
+        let _ = writeln!(output, "{} {}", name, counter.get());
+    }
+}
+
+/// Counters of messages and connections which BUS turns away to protect itself and other
+/// clients.
+fn write_protection_totals(output: &mut String, stats: &BusStats) {
+    let counters: &[(&str, &str, &BusCounter)] = &[
+        (
+            "bus_deduplicated_messages_total",
+            "Messages dropped because the same message has been received recently",
+            &stats.deduplicated_messages,
+        ),
+        (
+            "bus_throttled_messages_total",
+            "Messages dropped because their client has exceeded its rate limit",
+            &stats.throttled_messages,
+        ),
+        (
+            "bus_authentication_failures_total",
+            "Connections which were denied by the ZAP handler",
+            &stats.authentication_failures,
+        ),
+        (
+            "bus_denied_publications_total",
+            "Messages refused because their client may not publish their kind",
+            &stats.denied_publications,
+        ),
+        (
+            "bus_denied_subscriptions_total",
+            "Subscriptions refused because their client may not receive all matched kinds",
+            &stats.denied_subscriptions,
+        ),
+    ];
+    for (name, help, counter) in counters {
+        write_header(output, name, help, "counter");
+        let _ = writeln!(output, "{} {}", name, counter.get());
+    }
+}
+
+fn write_gauges(output: &mut String, stats: &BusStats) {
+    let gauges: &[(&str, &str, &BusCounter)] = &[
+        (
+            "bus_publishing_queue_depth",
+            "Messages waiting in the publishing queue",
+            &stats.publishing_queue_depth,
+        ),
+        (
+            "bus_retry_buffer_depth",
+            "Messages waiting in the retry buffer",
+            &stats.retry_buffer_depth,
+        ),
+        (
+            "bus_scheduled_messages",
+            "Messages held until their deliver-at time",
+            &stats.scheduled_messages,
+        ),
+        (
+            "bus_paused_messages",
+            "Messages of paused kinds held until the kinds are resumed",
+            &stats.paused_messages,
+        ),
+    ];
+    for (name, help, gauge) in gauges {
+        write_header(output, name, help, "gauge");
+        let _ = writeln!(output, "{} {}", name, gauge.get());
+    }
+}
+
+fn write_kind_totals(output: &mut String, stats: &BusStats) {
+    let kind_counters: &[(&str, &str, KindCounter)] = &[
+        (
+            "bus_kind_received_messages_total",
+            "Messages received on the router socket by kind",
+            |kind_stats| &kind_stats.received_messages,
+        ),
+        (
+            "bus_kind_received_bytes_total",
+            "Bytes of messages received on the router socket by kind",
+            |kind_stats| &kind_stats.received_bytes,
+        ),
+        (
+            "bus_kind_published_messages_total",
+            "Messages published to subscribers by kind",
+            |kind_stats| &kind_stats.published_messages,
+        ),
+        (
+            "bus_kind_published_bytes_total",
+            "Bytes of messages published to subscribers by kind",
+            |kind_stats| &kind_stats.published_bytes,
+        ),
+        (
+            "bus_kind_publish_failures_total",
+            "Failed attempts to publish message by kind",
+            |kind_stats| &kind_stats.publish_failures,
+        ),
+        (
+            "bus_kind_expired_messages_total",
+            "Messages discarded because their deadline has passed by kind",
+            |kind_stats| &kind_stats.expired_messages,
+        ),
+        (
+            "bus_kind_deduplicated_messages_total",
+            "Messages dropped because the same message has been received recently by kind",
+            |kind_stats| &kind_stats.deduplicated_messages,
+        ),
+        (
+            "bus_kind_throttled_messages_total",
+            "Messages dropped because their client has exceeded its rate limit by kind",
+            |kind_stats| &kind_stats.throttled_messages,
+        ),
+        (
+            "bus_kind_unroutable_messages_total",
+            "Messages which no subscriber wanted by kind",
+            |kind_stats| &kind_stats.unroutable_messages,
+        ),
+    ];
+    for (name, help, kind_counter) in kind_counters {
+        write_header(output, name, help, "counter");
+        for (kind, kind_stats) in stats.kinds() {
+            let _ = writeln!(
+                output,
+                "{}{{kind=\"{:?}\"}} {}",
+                name,
+                kind,
+                kind_counter(kind_stats).get()
+            );
+        }
+    }
+}
+
+fn write_publisher_sends(output: &mut String, stats: &BusStats) {
+    write_header(
+        output,
+        "bus_publisher_sent_messages_total",
+        "Messages sent through every publisher socket",
+        "counter",
+    );
+    for (index, counter) in stats.publisher_sends().iter().enumerate() {
+        let _ = writeln!(
+            output,
+            "bus_publisher_sent_messages_total{{publisher=\"{}\"}} {}",
+            index,
+            counter.get()
+        );
+    }
+}
+
+fn write_publisher_subscriptions(output: &mut String, stats: &BusStats) {
+    write_header(
+        output,
+        "bus_publisher_subscriptions",
+        "Prefixes which subscribers of every publisher socket are subscribed to",
+        "gauge",
+    );
+    for (index, gauge) in stats.publisher_subscriptions().iter().enumerate() {
+        let _ = writeln!(
+            output,
+            "bus_publisher_subscriptions{{publisher=\"{}\"}} {}",
+            index,
+            gauge.get()
+        );
+    }
+}
+
+fn write_throttled_clients(output: &mut String, stats: &BusStats) {
+    write_header(
+        output,
+        "bus_client_throttled_messages_total",
+        "Messages dropped because their client has exceeded its rate limit by client user id, \
+         or by identity of the connection when client is not authenticated",
+        "counter",
+    );
+    for (client, count) in stats.throttled_clients() {
+        let _ = writeln!(
+            output,
+            "bus_client_throttled_messages_total{{identity=\"{}\"}} {}",
+            client, count
+        );
+    }
+}
+
+fn write_latency(output: &mut String, stats: &BusStats) {
+    write_header(
+        output,
+        "bus_message_latency_seconds",
+        "Time between message header timestamp and its publishing",
+        "histogram",
+    );
+    for (upper_bound, count) in stats.latency.cumulative_buckets() {
+        let upper_bound = upper_bound.map_or_else(|| "+Inf".to_string(), format_seconds);
+        let _ = writeln!(
+            output,
+            "bus_message_latency_seconds_bucket{{le=\"{}\"}} {}",
+            upper_bound, count
+        );
+    }
+    let _ = writeln!(
+        output,
+        "bus_message_latency_seconds_sum {}",
+        format_seconds(stats.latency.sum())
+    );
+    let _ = writeln!(
+        output,
+        "bus_message_latency_seconds_count {}",
+        stats.latency.count()
+    );
+}
+
+fn write_header(output: &mut String, name: &str, help: &str, metric_type: &str) {
+    let _ = writeln!(output, "# HELP {} {}", name, help);
+    let _ = writeln!(output, "# TYPE {} {}", name, metric_type);
+}
+
+fn format_seconds(milliseconds: u64) -> String {
+    format!("{}.{:03}", milliseconds / 1_000, milliseconds % 1_000)
+}
+
+//-----------------------------------------------------------------------------------------
+// MetricsServer
+//-----------------------------------------------------------------------------------------
+
+/// HTTP server which answers `GET /metrics` with BUS counters.
+#[derive(Debug)]
+pub struct MetricsServer {
+    address: SocketAddr,
+    shutdown_signal: ShutdownSignal,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    /// Binds the address and serves requests on a separate thread until stopped.
+    pub fn start(address: &str, stats: Arc<BusStats>) -> io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+
+        // Accepting is polled, so server notices shutdown request without connections.
+        listener.set_nonblocking(true)?;
+
+        let shutdown_signal = ShutdownSignal::new();
+        let thread_shutdown_signal = shutdown_signal.clone();
+
+        log::debug!("serving metrics on http://{}{}", address, METRICS_PATH);
+
+        let thread = thread::spawn(move || {
+            while !thread_shutdown_signal.is_requested() {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        if let Err(error) = serve(stream, stats.as_ref()) {
+                            log::debug!(
+                                "failed to serve metrics request because of: {}",
+                                error
+                            );
+                        }
+                    }
+                    Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(u64::from(
+                            SHUTDOWN_CHECK_INTERVAL_MILLISECONDS,
+                        )));
+                    }
+                    Err(error) => {
+                        log::error!(
+                            "failed to accept metrics connection because of: {}",
+                            error
+                        );
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            address,
+            shutdown_signal,
+            thread: Some(thread),
+        })
+    }
+
+    /// Actually bound address, which differs from the requested one for port zero.
+    #[must_use]
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn stop(&mut self) {
+        self.shutdown_signal.request();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                log::error!("metrics server thread has panicked");
+            }
+        }
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn serve(stream: TcpStream, stats: &BusStats) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(METRICS_REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(METRICS_REQUEST_TIMEOUT))?;
+
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    let _ = reader.read_line(&mut request_line)?;
+
+    // Request headers are not needed, but they are read so client does not get reset
+    // connection while it is still sending them.
+    let mut header_line = String::new();
+    while reader.read_line(&mut header_line)? > 2 {
+        header_line.clear();
+    }
+
+    let mut request_parts = request_line.split_whitespace();
+    let (status_line, content_type, body) = match (request_parts.next(), request_parts.next())
+    {
+        (Some("GET"), Some(METRICS_PATH)) => {
+            ("200 OK", METRICS_CONTENT_TYPE, render_metrics(stats))
+        }
+        _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+    };
+
+    let mut stream = reader.into_inner();
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status_line,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+//-----------------------------------------------------------------------------------------
+// Tests
+//-----------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::render_metrics;
+    use crate::metrics::MetricsServer;
+    use crate::BusStats;
+    use crate::RateLimitedClient;
+    use std::io::Read;
+    use std::io::Write;
+    use std::net::TcpStream;
+    use std::sync::Arc;
+    use zeromq_messages::kind::ZeromqMessageKind;
+
+    #[test]
+    fn renders_counters_and_histogram() {
+        let stats = BusStats::new(2);
+        stats.received_messages.add(3);
+        stats
+            .kind(ZeromqMessageKind::ValueMultiplicationRequest)
+            .published_bytes
+            .add(42);
+        stats.publisher_sends()[1].increment();
+        stats.publisher_subscriptions()[0].set(2);
+        stats.latency.observe(7);
+        stats.record_throttled(
+            &RateLimitedClient::new(None, &[1, 2]),
+            ZeromqMessageKind::ValueMultiplicationRequest,
+        );
+
+        let metrics = render_metrics(&stats);
+        assert!(metrics.contains("# TYPE bus_received_messages_total counter\n"));
+        assert!(metrics.contains("\nbus_received_messages_total 3\n"));
+        assert!(metrics.contains(
+            "\nbus_kind_published_bytes_total{kind=\"ValueMultiplicationRequest\"} 42\n"
+        ));
+        assert!(metrics.contains("\nbus_publisher_sent_messages_total{publisher=\"1\"} 1\n"));
+        assert!(metrics.contains("\nbus_publisher_subscriptions{publisher=\"0\"} 2\n"));
+        assert!(
+            metrics.contains("\nbus_client_throttled_messages_total{identity=\"0102\"} 1\n")
+        );
+        assert!(metrics.contains("\nbus_message_latency_seconds_bucket{le=\"0.005\"} 0\n"));
+        assert!(metrics.contains("\nbus_message_latency_seconds_bucket{le=\"0.010\"} 1\n"));
+        assert!(metrics.contains("\nbus_message_latency_seconds_bucket{le=\"+Inf\"} 1\n"));
+        assert!(metrics.contains("\nbus_message_latency_seconds_sum 0.007\n"));
+    }
+
+    #[test]
+    fn serves_metrics_over_http() {
+        let stats = Arc::new(BusStats::default());
+        stats.published_messages.add(5);
+        let mut metrics_server =
+            MetricsServer::start("127.0.0.1:0", Arc::clone(&stats)).unwrap();
+
+        let mut stream = TcpStream::connect(metrics_server.address()).unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\nbus_published_messages_total 5\n"));
+
+        let mut stream = TcpStream::connect(metrics_server.address()).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        metrics_server.stop();
+    }
+}
@@ -0,0 +1,246 @@
+use crate::OverflowPolicy;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::iter::Iterator;
+use uuid::Uuid;
+use zeromq_messages::kind::ZeromqMessageKind;
+
+//-----------------------------------------------------------------------------------------
+// PausedMessage
+//-----------------------------------------------------------------------------------------
+
+/// Accepted message of paused kind which waits until the kind is resumed.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PausedMessage {
+    pub kind: ZeromqMessageKind,
+    pub uuid: Uuid,
+    pub wal_sequence: Option<u64>,
+    pub message_bytes: Vec<u8>,
+}
+
+//-----------------------------------------------------------------------------------------
+// PausedMessageHoldOutcome
+//-----------------------------------------------------------------------------------------
+
+/// What has happened with the message which was given to the paused messages.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum PausedMessageHoldOutcome {
+    /// Message is held until its kind is resumed.
+    Held,
+    /// Message is held instead of the oldest held one, which is returned.
+    DroppedOldest(PausedMessage),
+    /// Held messages are at capacity, so the given message is dropped.
+    DroppedNewest(PausedMessage),
+    /// Held messages are at capacity, so the given message is refused to its sender.
+    Refused(PausedMessage),
+}
+
+//-----------------------------------------------------------------------------------------
+// PausedMessages
+//-----------------------------------------------------------------------------------------
+
+/// Messages of paused kinds which BUS holds until their kinds are resumed. Every message
+/// gets the number of its arrival, so the oldest one of all kinds is known.
+#[derive(Debug)]
+pub struct PausedMessages {
+    messages: HashMap<ZeromqMessageKind, VecDeque<(u64, PausedMessage)>>,
+    messages_count: usize,
+    next_number: u64,
+    capacity: usize,
+    overflow_policy: OverflowPolicy,
+}
+
+impl PausedMessages {
+    /// Overflow policy applies to messages which come when held messages are at capacity.
+    /// Caller can't wait for the kinds to be resumed, so blocking policy refuses messages.
+    #[must_use]
+    pub fn new(capacity: usize, overflow_policy: OverflowPolicy) -> Self {
+        Self {
+            messages: HashMap::new(),
+            messages_count: 0,
+            next_number: 0,
+            capacity,
+            overflow_policy,
+        }
+    }
+
+    pub fn hold(&mut self, message: PausedMessage) -> PausedMessageHoldOutcome {
+        if self.messages_count < self.capacity {
+            self.push(message);
+            return PausedMessageHoldOutcome::Held;
+        }
+
+        match self.overflow_policy {
+            OverflowPolicy::DropOldest => match self.pop_oldest() {
+                Some(dropped_message) => {
+                    self.push(message);
+                    PausedMessageHoldOutcome::DroppedOldest(dropped_message)
+                }
+                None => PausedMessageHoldOutcome::Refused(message),
+            },
+            OverflowPolicy::DropNewest => PausedMessageHoldOutcome::DroppedNewest(message),
+            OverflowPolicy::Block | OverflowPolicy::RejectWithNack => {
+                PausedMessageHoldOutcome::Refused(message)
+            }
+        }
+    }
+
+    /// Removes and returns messages of kinds which are not paused anymore, messages of
+    /// every kind are in order of their arrival.
+    pub fn take_resumed<P: Fn(ZeromqMessageKind) -> bool>(
+        &mut self,
+        is_paused: P,
+    ) -> Vec<PausedMessage> {
+        let resumed_kinds = self
+            .messages
+            .keys()
+            .copied()
+            .filter(|kind| !is_paused(*kind))
+            .collect::<Vec<ZeromqMessageKind>>();
+
+        let mut resumed_messages = Vec::new();
+        for kind in resumed_kinds {
+            let messages = self.messages.remove(&kind).unwrap_or_default();
+            self.messages_count -= messages.len();
+            resumed_messages.extend(messages.into_iter().map(|(_, message)| message));
+        }
+
+        resumed_messages
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.messages_count
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.messages_count == 0
+    }
+
+    fn push(&mut self, message: PausedMessage) {
+        let number = self.next_number;
+        self.next_number += 1;
+        self.messages
+            .entry(message.kind)
+            .or_default()
+            .push_back((number, message));
+        self.messages_count += 1;
+    }
+
+    fn pop_oldest(&mut self) -> Option<PausedMessage> {
+        let oldest_kind = self
+            .messages
+            .iter()
+            .filter_map(|(kind, messages)| {
+                messages.front().map(|(number, _)| (*number, *kind))
+            })
+            .min_by_key(|(number, _)| *number)
+            .map(|(_, kind)| kind)?;
+
+        let messages = self.messages.get_mut(&oldest_kind)?;
+        let (_, message) = messages.pop_front()?;
+        if messages.is_empty() {
+            let _ = self.messages.remove(&oldest_kind);
+        }
+        self.messages_count -= 1;
+
+        Some(message)
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// Tests
+//-----------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::paused::PausedMessage;
+    use crate::paused::PausedMessageHoldOutcome;
+    use crate::paused::PausedMessages;
+    use crate::OverflowPolicy;
+    use uuid::Uuid;
+    use zeromq_messages::kind::ZeromqMessageKind;
+
+    const KIND: ZeromqMessageKind = ZeromqMessageKind::ValueMultiplicationResponse;
+    const OTHER_KIND: ZeromqMessageKind = ZeromqMessageKind::ValueMultiplicationRequest;
+
+    fn paused_message(kind: ZeromqMessageKind) -> PausedMessage {
+        PausedMessage {
+            kind,
+            uuid: Uuid::new_v4(),
+            wal_sequence: None,
+            message_bytes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn releases_messages_of_resumed_kinds_only() {
+        let mut paused_messages = PausedMessages::new(10, OverflowPolicy::RejectWithNack);
+        let messages = vec![
+            paused_message(KIND),
+            paused_message(OTHER_KIND),
+            paused_message(KIND),
+        ];
+        for message in &messages {
+            assert_eq!(
+                PausedMessageHoldOutcome::Held,
+                paused_messages.hold(message.clone())
+            );
+        }
+        assert_eq!(3, paused_messages.len());
+
+        assert!(paused_messages.take_resumed(|_| true).is_empty());
+        assert_eq!(
+            vec![messages[0].clone(), messages[2].clone()],
+            paused_messages.take_resumed(|kind| kind == OTHER_KIND)
+        );
+        assert_eq!(1, paused_messages.len());
+        assert_eq!(
+            vec![messages[1].clone()],
+            paused_messages.take_resumed(|_| false)
+        );
+        assert!(paused_messages.is_empty());
+    }
+
+    #[test]
+    fn held_messages_are_bounded() {
+        let messages = (0..3)
+            .map(|index| paused_message(if index == 0 { OTHER_KIND } else { KIND }))
+            .collect::<Vec<PausedMessage>>();
+
+        let mut paused_messages = PausedMessages::new(2, OverflowPolicy::RejectWithNack);
+        for message in &messages[..2] {
+            let _ = paused_messages.hold(message.clone());
+        }
+        assert_eq!(
+            PausedMessageHoldOutcome::Refused(messages[2].clone()),
+            paused_messages.hold(messages[2].clone())
+        );
+
+        let mut paused_messages = PausedMessages::new(2, OverflowPolicy::DropNewest);
+        for message in &messages[..2] {
+            let _ = paused_messages.hold(message.clone());
+        }
+        assert_eq!(
+            PausedMessageHoldOutcome::DroppedNewest(messages[2].clone()),
+            paused_messages.hold(messages[2].clone())
+        );
+        assert_eq!(2, paused_messages.len());
+
+        // The oldest message is dropped, even when it is of another kind.
+        let mut paused_messages = PausedMessages::new(2, OverflowPolicy::DropOldest);
+        for message in &messages[..2] {
+            let _ = paused_messages.hold(message.clone());
+        }
+        assert_eq!(
+            PausedMessageHoldOutcome::DroppedOldest(messages[0].clone()),
+            paused_messages.hold(messages[2].clone())
+        );
+        assert_eq!(2, paused_messages.len());
+        assert_eq!(
+            messages[1..].to_vec(),
+            paused_messages.take_resumed(|_| false)
+        );
+    }
+}
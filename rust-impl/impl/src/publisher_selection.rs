@@ -0,0 +1,225 @@
+use crate::BusPublisherData;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::convert::TryFrom;
+use std::fmt;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::str::FromStr;
+use zeromq_messages::codec::MESSAGE_KIND_AND_UUID_LENGTH;
+
+const MESSAGE_KIND_LENGTH: usize = 4;
+
+//-----------------------------------------------------------------------------------------
+// PublisherSelectionStrategy
+//-----------------------------------------------------------------------------------------
+
+/// Strategy which is used to choose one of publisher sockets for a published message.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PublisherSelectionStrategy {
+    /// Publishers send messages one after another.
+    RoundRobin,
+    /// Message goes to the publisher which has been sending for the longest time ago.
+    LeastRecentlyUsed,
+    /// Messages of one kind always go to the same publisher, so they keep their order.
+    HashByKind,
+    /// Messages with one uuid always go to the same publisher, so resent copies of
+    /// message and responses to request keep their order.
+    HashByUuid,
+}
+
+impl PublisherSelectionStrategy {
+    #[must_use]
+    pub fn selector(self) -> Box<dyn PublisherSelector> {
+        match self {
+            Self::RoundRobin => Box::new(RoundRobinSelector::default()),
+            Self::LeastRecentlyUsed => Box::new(LeastRecentlyUsedSelector),
+            Self::HashByKind => Box::new(HashSelector::by_kind()),
+            Self::HashByUuid => Box::new(HashSelector::by_uuid()),
+        }
+    }
+}
+
+impl FromStr for PublisherSelectionStrategy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "round-robin" => Ok(Self::RoundRobin),
+            "least-recently-used" => Ok(Self::LeastRecentlyUsed),
+            "hash-by-kind" => Ok(Self::HashByKind),
+            "hash-by-uuid" => Ok(Self::HashByUuid),
+            _ => Err(format!("unknown publisher selection strategy '{}'", value)),
+        }
+    }
+}
+
+impl fmt::Display for PublisherSelectionStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::RoundRobin => "round-robin",
+            Self::LeastRecentlyUsed => "least-recently-used",
+            Self::HashByKind => "hash-by-kind",
+            Self::HashByUuid => "hash-by-uuid",
+        })
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// PublisherSelector
+//-----------------------------------------------------------------------------------------
+
+/// Chooses publisher socket which message is sent through. Subscribers are connected to
+/// all publishers, so messages which go through different ones may overtake each other.
+pub trait PublisherSelector: fmt::Debug + Send {
+    /// Index of the chosen publisher, there is at least one publisher.
+    fn select(&mut self, publishers: &[BusPublisherData], message_bytes: &[u8]) -> usize;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RoundRobinSelector {
+    next_index: usize,
+}
+
+impl PublisherSelector for RoundRobinSelector {
+    fn select(&mut self, publishers: &[BusPublisherData], _message_bytes: &[u8]) -> usize {
+        let index = self.next_index % publishers.len();
+        self.next_index = index + 1;
+        index
+    }
+}
+
+/// Relies on sender updating last action time of the publisher after every send.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LeastRecentlyUsedSelector;
+
+impl PublisherSelector for LeastRecentlyUsedSelector {
+    fn select(&mut self, publishers: &[BusPublisherData], _message_bytes: &[u8]) -> usize {
+        publishers
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, publisher)| publisher.get_last_action_time())
+            .map_or(0, |(index, _)| index)
+    }
+}
+
+/// Hashes part of message bytes, message which is too short to have them is hashed as a
+/// whole.
+#[derive(Debug, Clone, Copy)]
+pub struct HashSelector {
+    hashed_bytes_start: usize,
+    hashed_bytes_end: usize,
+}
+
+impl HashSelector {
+    #[must_use]
+    pub fn by_kind() -> Self {
+        Self {
+            hashed_bytes_start: 0,
+            hashed_bytes_end: MESSAGE_KIND_LENGTH,
+        }
+    }
+
+    #[must_use]
+    pub fn by_uuid() -> Self {
+        Self {
+            hashed_bytes_start: MESSAGE_KIND_LENGTH,
+            hashed_bytes_end: MESSAGE_KIND_AND_UUID_LENGTH,
+        }
+    }
+}
+
+impl PublisherSelector for HashSelector {
+    fn select(&mut self, publishers: &[BusPublisherData], message_bytes: &[u8]) -> usize {
+        let hashed_bytes = message_bytes
+            .get(self.hashed_bytes_start..self.hashed_bytes_end)
+            .unwrap_or(message_bytes);
+
+        let mut hasher = DefaultHasher::new();
+        hashed_bytes.hash(&mut hasher);
+        usize::try_from(hasher.finish() % publishers.len() as u64).unwrap_or_default()
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// Tests
+//-----------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::publisher_selection::PublisherSelectionStrategy;
+    use crate::BusPublisherData;
+    use std::collections::HashSet;
+    use uuid::Uuid;
+    use zeromq_messages::codec::encode_message;
+    use zeromq_messages::messages::ValueMultiplicationRequest;
+    use zeromq_messages::messages::ValueMultiplicationResponse;
+    use zmq::Context;
+    use zmq::SocketType;
+
+    fn publishers(context: &Context, count: usize) -> Vec<BusPublisherData> {
+        (0..count)
+            .map(|_| BusPublisherData::new(context.socket(SocketType::XPUB).unwrap()))
+            .collect()
+    }
+
+    fn request(uuid: Uuid) -> Vec<u8> {
+        encode_message(
+            uuid,
+            ValueMultiplicationRequest {
+                value: 2,
+                multiplier: 3,
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn spreads_messages_evenly() {
+        let context = Context::new();
+        for strategy in &[
+            PublisherSelectionStrategy::RoundRobin,
+            PublisherSelectionStrategy::LeastRecentlyUsed,
+        ] {
+            let mut publishers = publishers(&context, 3);
+            let mut selector = strategy.selector();
+            let mut sends = vec![0; publishers.len()];
+
+            for _ in 0..30 {
+                let index = selector.select(&publishers, &request(Uuid::new_v4()));
+                sends[index] += 1;
+                publishers[index].update_last_action_time();
+            }
+
+            assert_eq!(vec![10, 10, 10], sends, "{}", strategy);
+        }
+    }
+
+    #[test]
+    fn keeps_messages_of_one_kind_or_uuid_on_one_publisher() {
+        let context = Context::new();
+        let publishers = publishers(&context, 4);
+
+        let mut selector = PublisherSelectionStrategy::HashByKind.selector();
+        let request_indexes = (0..20)
+            .map(|_| selector.select(&publishers, &request(Uuid::new_v4())))
+            .collect::<HashSet<usize>>();
+        assert_eq!(1, request_indexes.len());
+
+        let mut selector = PublisherSelectionStrategy::HashByUuid.selector();
+        let uuid = Uuid::new_v4();
+        let response =
+            encode_message(uuid, ValueMultiplicationResponse { result: 6 }).unwrap();
+        let index = selector.select(&publishers, &request(uuid));
+        assert_eq!(index, selector.select(&publishers, &response));
+        assert_eq!(index, selector.select(&publishers, &request(uuid)));
+
+        // Different uuids are spread over all publishers.
+        let indexes = (0..200)
+            .map(|_| selector.select(&publishers, &request(Uuid::new_v4())))
+            .collect::<HashSet<usize>>();
+        assert_eq!(4, indexes.len());
+    }
+}
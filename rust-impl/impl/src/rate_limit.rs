@@ -0,0 +1,291 @@
+use crate::helpers::hex;
+use crate::WorkerIdentity;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Instant;
+use zeromq_messages::kind::ZeromqMessageKind;
+
+//-----------------------------------------------------------------------------------------
+// RateLimitPolicy
+//-----------------------------------------------------------------------------------------
+
+/// Defines what happens with a message which exceeds the rate limit of its sender.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RateLimitPolicy {
+    /// Message is dropped without confirmation, so sender resends it once it gives up
+    /// waiting.
+    Drop,
+    /// Message is dropped and refused to the sender.
+    Nack,
+}
+
+impl FromStr for RateLimitPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "drop" => Ok(Self::Drop),
+            "nack" => Ok(Self::Nack),
+            _ => Err(format!("unknown rate limit policy '{}'", value)),
+        }
+    }
+}
+
+impl fmt::Display for RateLimitPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Drop => "drop",
+            Self::Nack => "nack",
+        })
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// RateLimit
+//-----------------------------------------------------------------------------------------
+
+/// Sustained rate of messages and count of messages which may come at once above it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct RateLimit {
+    pub messages_per_second: u32,
+    pub burst: u32,
+}
+
+//-----------------------------------------------------------------------------------------
+// TokenBucket
+//-----------------------------------------------------------------------------------------
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn full(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(limit.burst),
+            refilled_at: now,
+        }
+    }
+
+    fn refill(&mut self, limit: RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        self.tokens = (self.tokens
+            + elapsed.as_secs_f64() * f64::from(limit.messages_per_second))
+        .min(f64::from(limit.burst));
+        self.refilled_at = now;
+    }
+
+    fn try_take(&mut self, limit: RateLimit, now: Instant) -> bool {
+        self.refill(limit, now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// RateLimitedClient
+//-----------------------------------------------------------------------------------------
+
+/// Client which rate limit applies to. Router socket gives every connection a new
+/// identity, so authenticated client is known by its user id instead and does not get
+/// a full bucket by reconnecting.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum RateLimitedClient {
+    /// User id which ZAP handler has given to the client.
+    User(String),
+    /// Identity of the connection of client which is not authenticated.
+    Connection(WorkerIdentity),
+}
+
+impl RateLimitedClient {
+    #[must_use]
+    pub fn new(user_id: Option<&str>, identity: &[u8]) -> Self {
+        match user_id {
+            Some(user_id) => Self::User(user_id.to_string()),
+            None => Self::Connection(identity.to_vec()),
+        }
+    }
+}
+
+impl fmt::Display for RateLimitedClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::User(user_id) => f.write_str(user_id),
+            Self::Connection(identity) => f.write_str(hex(identity.as_slice()).as_str()),
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// RateLimiter
+//-----------------------------------------------------------------------------------------
+
+/// Token buckets of clients connected to the router socket. Every client has one bucket
+/// for all its messages, or one bucket per limited kind when kinds are given, so a flood
+/// of one kind does not hold back the other kinds of the same client.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    kinds: HashSet<ZeromqMessageKind>,
+    buckets: HashMap<RateLimitedClient, HashMap<Option<ZeromqMessageKind>, TokenBucket>>,
+}
+
+impl RateLimiter {
+    /// Messages of all kinds are limited when no kinds are given.
+    #[must_use]
+    pub fn new(limit: RateLimit, kinds: &[ZeromqMessageKind]) -> Self {
+        Self {
+            limit,
+            kinds: kinds.iter().copied().collect(),
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Whether message of the client fits within its limit, the message uses up a token
+    /// when it does.
+    pub fn allow(
+        &mut self,
+        client: &RateLimitedClient,
+        kind: ZeromqMessageKind,
+        now: Instant,
+    ) -> bool {
+        let bucket_kind = if self.kinds.is_empty() {
+            None
+        } else if self.kinds.contains(&kind) {
+            Some(kind)
+        } else {
+            return true;
+        };
+
+        let limit = self.limit;
+        let buckets = match self.buckets.get_mut(client) {
+            Some(buckets) => buckets,
+            None => self.buckets.entry(client.clone()).or_default(),
+        };
+        buckets
+            .entry(bucket_kind)
+            .or_insert_with(|| TokenBucket::full(limit, now))
+            .try_take(limit, now)
+    }
+
+    /// Forgets buckets which have refilled completely, they are no different from new
+    /// ones, so memory is not kept for clients which are gone.
+    pub fn forget_idle(&mut self, now: Instant) {
+        let limit = self.limit;
+        self.buckets.retain(|_, buckets| {
+            buckets.retain(|_, bucket| {
+                bucket.refill(limit, now);
+                bucket.tokens < f64::from(limit.burst)
+            });
+            !buckets.is_empty()
+        });
+    }
+
+    /// Count of clients which have used some of their tokens recently.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.buckets.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// Tests
+//-----------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::rate_limit::RateLimit;
+    use crate::rate_limit::RateLimitedClient;
+    use crate::rate_limit::RateLimiter;
+    use std::time::Duration;
+    use std::time::Instant;
+    use zeromq_messages::kind::ZeromqMessageKind;
+
+    const LIMIT: RateLimit = RateLimit {
+        messages_per_second: 10,
+        burst: 2,
+    };
+
+    #[test]
+    fn limits_every_client_separately() {
+        let mut rate_limiter = RateLimiter::new(LIMIT, &[]);
+        let kind = ZeromqMessageKind::ValueMultiplicationResponse;
+        let flooding = RateLimitedClient::new(None, b"flooding");
+        let quiet = RateLimitedClient::new(None, b"quiet");
+        let start = Instant::now();
+
+        assert!(rate_limiter.allow(&flooding, kind, start));
+        assert!(rate_limiter.allow(&flooding, kind, start));
+        assert!(!rate_limiter.allow(&flooding, kind, start));
+        assert!(rate_limiter.allow(&quiet, kind, start));
+
+        // One token is refilled every 100 milliseconds.
+        assert!(!rate_limiter.allow(&flooding, kind, start + Duration::from_millis(50)));
+        assert!(rate_limiter.allow(&flooding, kind, start + Duration::from_millis(150)));
+        assert!(!rate_limiter.allow(&flooding, kind, start + Duration::from_millis(150)));
+
+        rate_limiter.forget_idle(start + Duration::from_millis(200));
+        assert_eq!(1, rate_limiter.len());
+        rate_limiter.forget_idle(start + Duration::from_millis(500));
+        assert!(rate_limiter.is_empty());
+    }
+
+    #[test]
+    fn limits_given_kinds_only() {
+        let limited_kind = ZeromqMessageKind::ValueMultiplicationRequest;
+        let other_limited_kind = ZeromqMessageKind::ValueMultiplicationResponse;
+        let mut rate_limiter = RateLimiter::new(LIMIT, &[limited_kind, other_limited_kind]);
+        let client = RateLimitedClient::new(None, b"client");
+        let now = Instant::now();
+
+        for _ in 0..2 {
+            assert!(rate_limiter.allow(&client, limited_kind, now));
+        }
+        assert!(!rate_limiter.allow(&client, limited_kind, now));
+        assert!(rate_limiter.allow(&client, other_limited_kind, now));
+        for _ in 0..10 {
+            assert!(rate_limiter.allow(&client, ZeromqMessageKind::ServiceQuery, now));
+        }
+    }
+
+    #[test]
+    fn authenticated_client_keeps_its_bucket_across_connections() {
+        let mut rate_limiter = RateLimiter::new(LIMIT, &[]);
+        let kind = ZeromqMessageKind::ValueMultiplicationResponse;
+        let now = Instant::now();
+
+        assert!(rate_limiter.allow(
+            &RateLimitedClient::new(Some("user"), b"first"),
+            kind,
+            now
+        ));
+        assert!(rate_limiter.allow(
+            &RateLimitedClient::new(Some("user"), b"second"),
+            kind,
+            now
+        ));
+        assert!(!rate_limiter.allow(
+            &RateLimitedClient::new(Some("user"), b"third"),
+            kind,
+            now
+        ));
+        assert!(rate_limiter.allow(&RateLimitedClient::new(None, b"third"), kind, now));
+        assert_eq!(2, rate_limiter.len());
+    }
+}
@@ -0,0 +1,191 @@
+use crate::helpers::hex;
+use crate::WorkerIdentity;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::iter::Iterator;
+use zeromq_messages::kind::ZeromqMessageKind;
+use zeromq_messages::messages::ServiceQueryReplyItemProviders;
+use zeromq_messages::messages::ServiceRegistration;
+
+//-----------------------------------------------------------------------------------------
+// ServiceInstance
+//-----------------------------------------------------------------------------------------
+
+/// Service which has registered itself through the BUS connection with the identity.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ServiceInstance {
+    pub identity: WorkerIdentity,
+    pub name: String,
+    pub instance_id: String,
+    pub version: String,
+    pub handled_kinds: Vec<ZeromqMessageKind>,
+    pub produced_kinds: Vec<ZeromqMessageKind>,
+}
+
+impl ServiceInstance {
+    /// Validates registration payload, every listed kind should be known to the BUS.
+    pub fn from_registration(
+        identity: WorkerIdentity,
+        registration: ServiceRegistration,
+    ) -> Result<Self, String> {
+        if registration.name.is_empty() {
+            return Err("service name is required".to_string());
+        }
+
+        Ok(Self {
+            identity,
+            name: registration.name,
+            instance_id: registration.instance_id,
+            version: registration.version,
+            handled_kinds: known_kinds(registration.handled_kinds.as_slice())?,
+            produced_kinds: known_kinds(registration.produced_kinds.as_slice())?,
+        })
+    }
+
+    #[must_use]
+    pub fn handles(&self, kind: ZeromqMessageKind) -> bool {
+        self.handled_kinds.contains(&kind)
+    }
+
+    #[must_use]
+    pub fn report_entry(&self) -> ServiceQueryReplyItemProviders {
+        ServiceQueryReplyItemProviders {
+            identity: hex(self.identity.as_slice()),
+            name: self.name.clone(),
+            instance_id: self.instance_id.clone(),
+            version: self.version.clone(),
+            handled_kinds: self
+                .handled_kinds
+                .iter()
+                .map(|kind| i64::from(*kind as u32))
+                .collect(),
+            produced_kinds: self
+                .produced_kinds
+                .iter()
+                .map(|kind| i64::from(*kind as u32))
+                .collect(),
+        }
+    }
+}
+
+fn known_kinds(kinds: &[i64]) -> Result<Vec<ZeromqMessageKind>, String> {
+    kinds
+        .iter()
+        .map(|kind| {
+            u32::try_from(*kind)
+                .ok()
+                .and_then(|kind| ZeromqMessageKind::try_from(kind).ok())
+                .ok_or_else(|| format!("unknown message kind {}", kind))
+        })
+        .collect()
+}
+
+//-----------------------------------------------------------------------------------------
+// ServiceRegistry
+//-----------------------------------------------------------------------------------------
+
+/// Services which are connected to the BUS, one registration per connection.
+#[derive(Debug, Default)]
+pub struct ServiceRegistry {
+    services: BTreeMap<WorkerIdentity, ServiceInstance>,
+}
+
+impl ServiceRegistry {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the previous registration of the same connection.
+    pub fn register(&mut self, service: ServiceInstance) -> Option<ServiceInstance> {
+        self.services.insert(service.identity.clone(), service)
+    }
+
+    pub fn unregister(&mut self, identity: &[u8]) -> Option<ServiceInstance> {
+        self.services.remove(identity)
+    }
+
+    /// Registered services which handle the kind and have the name, when they are given.
+    #[must_use]
+    pub fn query(
+        &self,
+        kind: Option<ZeromqMessageKind>,
+        name: Option<&str>,
+    ) -> Vec<&ServiceInstance> {
+        self.services
+            .values()
+            .filter(|service| kind.is_none_or(|kind| service.handles(kind)))
+            .filter(|service| name.is_none_or(|name| service.name == name))
+            .collect()
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.services.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.services.is_empty()
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// Tests
+//-----------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::registry::ServiceInstance;
+    use crate::registry::ServiceRegistry;
+    use zeromq_messages::kind::ZeromqMessageKind;
+    use zeromq_messages::messages::ServiceRegistration;
+
+    fn registration(name: &str, handled_kinds: &[ZeromqMessageKind]) -> ServiceRegistration {
+        ServiceRegistration {
+            name: name.to_string(),
+            instance_id: format!("{}-1", name),
+            version: "1.0.0".to_string(),
+            handled_kinds: handled_kinds
+                .iter()
+                .map(|kind| i64::from(*kind as u32))
+                .collect(),
+            produced_kinds: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn queries_providers_of_kind() {
+        let mut registry = ServiceRegistry::new();
+        let request_kind = ZeromqMessageKind::ValueMultiplicationRequest;
+
+        let responder = ServiceInstance::from_registration(
+            b"a".to_vec(),
+            registration("responder", &[request_kind]),
+        )
+        .unwrap();
+        assert!(registry.register(responder).is_none());
+        let sender = ServiceInstance::from_registration(
+            b"b".to_vec(),
+            registration("sender", &[ZeromqMessageKind::ValueMultiplicationResponse]),
+        )
+        .unwrap();
+        assert!(registry.register(sender).is_none());
+
+        let providers = registry.query(Some(request_kind), None);
+        assert_eq!(1, providers.len());
+        assert_eq!("responder", providers[0].name);
+        assert_eq!(1, registry.query(None, Some("sender")).len());
+        assert_eq!(2, registry.query(None, None).len());
+
+        assert!(registry.unregister(b"a").is_some());
+        assert!(registry.query(Some(request_kind), None).is_empty());
+
+        let mut unknown_kind_registration = registration("broken", &[]);
+        unknown_kind_registration.handled_kinds = vec![i64::from(u32::MAX)];
+        assert!(
+            ServiceInstance::from_registration(b"c".to_vec(), unknown_kind_registration)
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,618 @@
+use crate::DeadLockSafeMutex;
+use crate::ShutdownSignal;
+use crate::WriteAheadLogRecord;
+use crate::WriteAheadLogReplica;
+use crate::SHUTDOWN_CHECK_INTERVAL_MILLISECONDS;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::iter::Iterator;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use zmq::Context;
+use zmq::Socket;
+use zmq::SocketType;
+
+const HEARTBEAT_EVENT_TYPE: u8 = 1;
+const RESET_EVENT_TYPE: u8 = 2;
+const APPENDED_EVENT_TYPE: u8 = 3;
+const DELIVERED_EVENT_TYPE: u8 = 4;
+const RETRYING_EVENT_TYPE: u8 = 5;
+const SUBSCRIPTION_EVENT_TYPE: u8 = 6;
+
+const XPUB_SUBSCRIBE_EVENT: u8 = 1;
+
+/// Time which standby waits for the requested snapshot before it requests another one.
+const SNAPSHOT_REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+
+//-----------------------------------------------------------------------------------------
+// ReplicationEvent
+//-----------------------------------------------------------------------------------------
+
+/// Change of the active BUS state which is streamed to standby BUSes. Sequences are the
+/// ones of the write-ahead log of the active BUS.
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum ReplicationEvent {
+    Heartbeat,
+    /// Standby should forget mirrored state, snapshot of the current one follows.
+    Reset,
+    Appended(u64, Vec<u8>),
+    Delivered(u64),
+    /// Message has failed to be published and waits in the retry buffer.
+    Retrying(u64),
+    /// Subscription event of the publisher socket with the given index.
+    Subscription(usize, Vec<u8>),
+}
+
+impl ReplicationEvent {
+    // Every event is a multipart message: event type (1 byte), event number (8 bytes)
+    // and its fields, sequences are 8 bytes and publisher index is 2 bytes, all
+    // big-endian. Numbers of consecutive events differ by one, so standby notices missed
+    // events.
+    fn encode(&self, number: u64) -> Vec<Vec<u8>> {
+        let mut frames = match self {
+            Self::Heartbeat => vec![vec![HEARTBEAT_EVENT_TYPE]],
+            Self::Reset => vec![vec![RESET_EVENT_TYPE]],
+            Self::Appended(sequence, message_bytes) => vec![
+                vec![APPENDED_EVENT_TYPE],
+                sequence.to_be_bytes().to_vec(),
+                message_bytes.clone(),
+            ],
+            Self::Delivered(sequence) => {
+                vec![vec![DELIVERED_EVENT_TYPE], sequence.to_be_bytes().to_vec()]
+            }
+            Self::Retrying(sequence) => {
+                vec![vec![RETRYING_EVENT_TYPE], sequence.to_be_bytes().to_vec()]
+            }
+            Self::Subscription(publisher_index, event) => vec![
+                vec![SUBSCRIPTION_EVENT_TYPE],
+                u16::try_from(*publisher_index)
+                    .expect("publisher index is too large")
+                    .to_be_bytes()
+                    .to_vec(),
+                event.clone(),
+            ],
+        };
+        frames.insert(1, number.to_be_bytes().to_vec());
+        frames
+    }
+
+    fn decode(frames: Vec<Vec<u8>>) -> Result<(u64, Self), String> {
+        let mut frames = frames.into_iter();
+        let event_type = frames
+            .next()
+            .and_then(|frame| frame.first().copied())
+            .ok_or_else(|| "event type is absent".to_string())?;
+        let number = frames
+            .next()
+            .and_then(|frame| <[u8; 8]>::try_from(frame.as_slice()).ok())
+            .map(u64::from_be_bytes)
+            .ok_or_else(|| "event number is malformed".to_string())?;
+
+        Ok((number, Self::decode_fields(event_type, frames)?))
+    }
+
+    fn decode_fields(
+        event_type: u8,
+        mut frames: impl Iterator<Item = Vec<u8>>,
+    ) -> Result<Self, String> {
+        let sequence = |frame: Option<Vec<u8>>| {
+            frame
+                .and_then(|frame| <[u8; 8]>::try_from(frame.as_slice()).ok())
+                .map(u64::from_be_bytes)
+                .ok_or_else(|| "sequence is malformed".to_string())
+        };
+
+        match event_type {
+            HEARTBEAT_EVENT_TYPE => Ok(Self::Heartbeat),
+            RESET_EVENT_TYPE => Ok(Self::Reset),
+            APPENDED_EVENT_TYPE => Ok(Self::Appended(
+                sequence(frames.next())?,
+                frames
+                    .next()
+                    .ok_or_else(|| "message is absent".to_string())?,
+            )),
+            DELIVERED_EVENT_TYPE => Ok(Self::Delivered(sequence(frames.next())?)),
+            RETRYING_EVENT_TYPE => Ok(Self::Retrying(sequence(frames.next())?)),
+            SUBSCRIPTION_EVENT_TYPE => Ok(Self::Subscription(
+                frames
+                    .next()
+                    .and_then(|frame| <[u8; 2]>::try_from(frame.as_slice()).ok())
+                    .map(|bytes| usize::from(u16::from_be_bytes(bytes)))
+                    .ok_or_else(|| "publisher index is malformed".to_string())?,
+                frames
+                    .next()
+                    .ok_or_else(|| "subscription event is absent".to_string())?,
+            )),
+            event_type => Err(format!("unknown event type {}", event_type)),
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// ReplicatedState
+//-----------------------------------------------------------------------------------------
+
+/// State of the active BUS which standby needs to continue its work: accepted messages
+/// which were not delivered yet, which of them wait in the retry buffer, and
+/// subscriptions of publisher sockets.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+struct ReplicatedState {
+    undelivered_messages: BTreeMap<u64, Vec<u8>>,
+    retrying_sequences: BTreeSet<u64>,
+    subscriptions: BTreeSet<(usize, Vec<u8>)>,
+}
+
+impl ReplicatedState {
+    fn apply(&mut self, event: ReplicationEvent) {
+        match event {
+            ReplicationEvent::Heartbeat => {}
+            ReplicationEvent::Reset => *self = Self::default(),
+            ReplicationEvent::Appended(sequence, message_bytes) => {
+                let _ = self.undelivered_messages.insert(sequence, message_bytes);
+            }
+            ReplicationEvent::Delivered(sequence) => {
+                let _ = self.undelivered_messages.remove(&sequence);
+                let _ = self.retrying_sequences.remove(&sequence);
+            }
+            ReplicationEvent::Retrying(sequence) => {
+                if self.undelivered_messages.contains_key(&sequence) {
+                    let _ = self.retrying_sequences.insert(sequence);
+                }
+            }
+            ReplicationEvent::Subscription(publisher_index, event) => {
+                match event.split_first() {
+                    Some((&XPUB_SUBSCRIBE_EVENT, prefix)) => {
+                        let _ = self
+                            .subscriptions
+                            .insert((publisher_index, prefix.to_vec()));
+                    }
+                    Some((_, prefix)) => {
+                        let _ = self
+                            .subscriptions
+                            .remove(&(publisher_index, prefix.to_vec()));
+                    }
+                    None => {}
+                }
+            }
+        }
+    }
+
+    /// Events which make an empty standby state equal to this one.
+    fn snapshot(&self) -> Vec<ReplicationEvent> {
+        std::iter::once(ReplicationEvent::Reset)
+            .chain(
+                self.undelivered_messages
+                    .iter()
+                    .map(|(sequence, message_bytes)| {
+                        ReplicationEvent::Appended(*sequence, message_bytes.clone())
+                    }),
+            )
+            .chain(
+                self.retrying_sequences
+                    .iter()
+                    .map(|sequence| ReplicationEvent::Retrying(*sequence)),
+            )
+            .chain(self.subscriptions.iter().map(|(publisher_index, prefix)| {
+                let mut event = vec![XPUB_SUBSCRIBE_EVENT];
+                event.extend_from_slice(prefix.as_slice());
+                ReplicationEvent::Subscription(*publisher_index, event)
+            }))
+            .collect()
+    }
+
+    fn into_takeover(self) -> BusTakeover {
+        let Self {
+            mut undelivered_messages,
+            retrying_sequences,
+            subscriptions,
+        } = self;
+
+        // Messages of the retry buffer were accepted earlier than queued ones, and sender
+        // publishes them first as well.
+        let mut messages = retrying_sequences
+            .iter()
+            .filter_map(|sequence| undelivered_messages.remove(sequence))
+            .collect::<Vec<Vec<u8>>>();
+        messages.extend(undelivered_messages.into_values());
+
+        BusTakeover {
+            messages,
+            subscriptions: subscriptions.into_iter().collect(),
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// BusTakeover
+//-----------------------------------------------------------------------------------------
+
+/// What standby has mirrored from the active BUS by the moment it takes over.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct BusTakeover {
+    /// Messages which active BUS has accepted but not delivered, in order of delivery.
+    pub messages: Vec<Vec<u8>>,
+    /// Subscribed prefixes with indexes of publishers which they were seen on.
+    pub subscriptions: Vec<(usize, Vec<u8>)>,
+}
+
+//-----------------------------------------------------------------------------------------
+// Replication
+//-----------------------------------------------------------------------------------------
+
+struct ReplicationPublisher {
+    socket: Socket,
+    state: ReplicatedState,
+    next_event_number: u64,
+    /// Event has failed to be sent, so standby BUSes have missed it.
+    is_snapshot_needed: bool,
+}
+
+impl ReplicationPublisher {
+    /// Number is used up even when event fails to be sent, so standby sees the gap.
+    fn send(&mut self, event: ReplicationEvent) {
+        let number = self.next_event_number;
+        self.next_event_number = self.next_event_number.wrapping_add(1);
+
+        if let Err(error) = self
+            .socket
+            .send_multipart(event.encode(number), zmq::DONTWAIT)
+        {
+            log::error!("failed to send replication event because of: {}", error);
+            self.is_snapshot_needed = true;
+        }
+        self.state.apply(event);
+    }
+
+    fn send_snapshot(&mut self) {
+        self.is_snapshot_needed = false;
+        for event in self.state.snapshot() {
+            self.send(event);
+        }
+    }
+}
+
+impl fmt::Debug for ReplicationPublisher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReplicationPublisher")
+            .field("socket", &self.socket.get_last_endpoint())
+            .field("state", &self.state)
+            .field("next_event_number", &self.next_event_number)
+            .field("is_snapshot_needed", &self.is_snapshot_needed)
+            .finish()
+    }
+}
+
+/// Active side of the replication. Streams state changes and heartbeats to standby BUSes
+/// through the XPUB socket, every standby which subscribes gets a snapshot of the whole
+/// state first. Standby which has missed events subscribes again to get a new snapshot,
+/// and every standby gets one after event has failed to be sent. Cloned handles share the
+/// socket.
+#[derive(Debug, Clone)]
+pub struct Replication(DeadLockSafeMutex<ReplicationPublisher>);
+
+impl Replication {
+    /// Binds replication socket, undelivered records are the ones which write-ahead log
+    /// has returned on open.
+    pub fn bind(
+        context: &Context,
+        endpoint: &str,
+        linger: i32,
+        undelivered_records: &[WriteAheadLogRecord],
+    ) -> Result<Self, zmq::Error> {
+        let socket = context.socket(SocketType::XPUB)?;
+        // Every standby should get snapshot, even when another one is already subscribed.
+        socket.set_xpub_verbose(true)?;
+        socket.set_linger(linger)?;
+        socket.bind(endpoint)?;
+
+        let state = ReplicatedState {
+            undelivered_messages: undelivered_records
+                .iter()
+                .map(|record| (record.sequence, record.message_bytes.clone()))
+                .collect(),
+            ..ReplicatedState::default()
+        };
+
+        Ok(Self(DeadLockSafeMutex::new(ReplicationPublisher {
+            socket,
+            state,
+            next_event_number: 0,
+            is_snapshot_needed: false,
+        })))
+    }
+
+    /// Message with the sequence was put into the retry buffer.
+    pub fn retrying(&self, sequence: u64) {
+        self.0
+            .lock(move |publisher| publisher.send(ReplicationEvent::Retrying(sequence)));
+    }
+
+    pub fn subscription(&self, publisher_index: usize, event: &[u8]) {
+        let event = ReplicationEvent::Subscription(publisher_index, event.to_vec());
+        self.0.lock(move |publisher| publisher.send(event));
+    }
+
+    /// Sends heartbeats and snapshots for joined standby BUSes until shutdown is requested.
+    pub fn run(&self, heartbeat_interval: Duration, shutdown_signal: &ShutdownSignal) {
+        let mut last_heartbeat_time: Option<Instant> = None;
+
+        while !shutdown_signal.is_requested() {
+            if last_heartbeat_time.is_none_or(|time| time.elapsed() >= heartbeat_interval) {
+                self.0
+                    .lock(|publisher| publisher.send(ReplicationEvent::Heartbeat));
+                last_heartbeat_time = Some(Instant::now());
+            }
+
+            // Socket is shared with writers of the write-ahead log, so it is never polled
+            // with timeout while locked.
+            self.0.lock(|publisher| {
+                let mut has_joined_standby = false;
+                while let Ok(event) = publisher.socket.recv_bytes(zmq::DONTWAIT) {
+                    has_joined_standby |= event.first() == Some(&XPUB_SUBSCRIBE_EVENT);
+                }
+
+                if has_joined_standby || publisher.is_snapshot_needed {
+                    log::info!(
+                        "standby BUS has joined or missed events, sending {} undelivered \
+                         messages",
+                        publisher.state.undelivered_messages.len()
+                    );
+                    publisher.send_snapshot();
+                }
+            });
+
+            thread::sleep(heartbeat_interval.min(Duration::from_millis(u64::from(
+                SHUTDOWN_CHECK_INTERVAL_MILLISECONDS,
+            ))));
+        }
+
+        log::debug!("replication loop has stopped");
+    }
+}
+
+impl WriteAheadLogReplica for Replication {
+    fn appended(&mut self, sequence: u64, message_bytes: &[u8]) {
+        let event = ReplicationEvent::Appended(sequence, message_bytes.to_vec());
+        self.0.lock(move |publisher| publisher.send(event));
+    }
+
+    fn delivered(&mut self, sequence: u64) {
+        self.0
+            .lock(move |publisher| publisher.send(ReplicationEvent::Delivered(sequence)));
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// Standby
+//-----------------------------------------------------------------------------------------
+
+/// Passive side of the replication. Mirrors state of the active BUS until its heartbeats
+/// stop, so it can be started with that state in place of the active one.
+pub struct Standby {
+    primary_endpoint: String,
+    socket: Socket,
+    state: ReplicatedState,
+    last_event_number: Option<u64>,
+    snapshot_requested_at: Option<Instant>,
+}
+
+impl Standby {
+    pub fn connect(context: &Context, primary_endpoint: &str) -> Result<Self, zmq::Error> {
+        let socket = context.socket(SocketType::SUB)?;
+        socket.set_linger(0)?;
+        socket.connect(primary_endpoint)?;
+        socket.set_subscribe(b"")?;
+
+        Ok(Self {
+            primary_endpoint: primary_endpoint.to_string(),
+            socket,
+            state: ReplicatedState::default(),
+            last_event_number: None,
+            snapshot_requested_at: None,
+        })
+    }
+
+    /// Mirrors state until nothing is received from the active BUS during the grace time,
+    /// which is counted from the start when it has never been seen. Returns `None` when
+    /// shutdown is requested before that.
+    #[must_use]
+    pub fn wait_for_takeover(
+        mut self,
+        grace: Duration,
+        shutdown_signal: &ShutdownSignal,
+    ) -> Option<BusTakeover> {
+        let mut last_seen_time = Instant::now();
+
+        while last_seen_time.elapsed() <= grace {
+            if shutdown_signal.is_requested() {
+                return None;
+            }
+
+            match self.socket.recv_multipart(zmq::DONTWAIT) {
+                Ok(frames) => match ReplicationEvent::decode(frames) {
+                    Ok((number, event)) => {
+                        last_seen_time = Instant::now();
+                        self.apply(number, event);
+                    }
+                    Err(reason) => {
+                        log::error!("ignored replication event because of: {}", reason);
+                    }
+                },
+                Err(zmq::Error::EAGAIN) => {
+                    let _ = self
+                        .socket
+                        .poll(zmq::POLLIN, i64::from(SHUTDOWN_CHECK_INTERVAL_MILLISECONDS));
+                }
+                Err(error) => {
+                    log::error!("failed to receive replication event because of: {}", error);
+                }
+            }
+        }
+
+        log::warn!(
+            "active BUS {} has missed heartbeats, taking over with {} undelivered messages, \
+             {} of them from retry buffer, and {} subscriptions",
+            self.primary_endpoint,
+            self.state.undelivered_messages.len(),
+            self.state.retrying_sequences.len(),
+            self.state.subscriptions.len()
+        );
+
+        Some(self.state.into_takeover())
+    }
+
+    /// Mirrored state is wrong once an event is missed, so snapshot is requested, unless
+    /// the next event is the reset which starts one.
+    fn apply(&mut self, number: u64, event: ReplicationEvent) {
+        let has_missed_events = self
+            .last_event_number
+            .is_some_and(|last_event_number| number != last_event_number.wrapping_add(1));
+        self.last_event_number = Some(number);
+
+        if event == ReplicationEvent::Reset {
+            self.snapshot_requested_at = None;
+        } else if has_missed_events {
+            self.request_snapshot();
+        }
+
+        self.state.apply(event);
+    }
+
+    /// Active BUS sends snapshot to every standby which subscribes, subscribing again
+    /// does not change what is received.
+    fn request_snapshot(&mut self) {
+        if self
+            .snapshot_requested_at
+            .is_some_and(|time| time.elapsed() < SNAPSHOT_REQUEST_INTERVAL)
+        {
+            return;
+        }
+
+        log::warn!(
+            "missed replication events of active BUS {}, requesting snapshot",
+            self.primary_endpoint
+        );
+        if let Err(error) = self.socket.set_subscribe(b"") {
+            log::error!(
+                "failed to request replication snapshot because of: {}",
+                error
+            );
+        }
+        self.snapshot_requested_at = Some(Instant::now());
+    }
+}
+
+impl fmt::Debug for Standby {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Standby")
+            .field("primary_endpoint", &self.primary_endpoint)
+            .field("socket", &self.socket.get_socket_type())
+            .field("state", &self.state)
+            .field("last_event_number", &self.last_event_number)
+            .field("snapshot_requested_at", &self.snapshot_requested_at)
+            .finish()
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// Tests
+//-----------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::replication::BusTakeover;
+    use crate::replication::ReplicatedState;
+    use crate::replication::Replication;
+    use crate::replication::ReplicationEvent;
+    use crate::replication::Standby;
+    use crate::ShutdownSignal;
+    use crate::WriteAheadLogReplica;
+    use std::thread;
+    use std::time::Duration;
+    use zmq::Context;
+
+    #[test]
+    fn standby_state_follows_events_and_snapshots() {
+        let events = vec![
+            ReplicationEvent::Appended(1, b"first".to_vec()),
+            ReplicationEvent::Appended(2, b"second".to_vec()),
+            ReplicationEvent::Appended(3, b"third".to_vec()),
+            ReplicationEvent::Retrying(3),
+            ReplicationEvent::Delivered(1),
+            ReplicationEvent::Subscription(0, b"\x01topic".to_vec()),
+            ReplicationEvent::Subscription(1, b"\x01other".to_vec()),
+            ReplicationEvent::Subscription(1, b"\x00other".to_vec()),
+        ];
+
+        let mut active_state = ReplicatedState::default();
+        for (number, event) in (0..).zip(events) {
+            let (decoded_number, decoded_event) =
+                ReplicationEvent::decode(event.encode(number)).unwrap();
+            assert_eq!((number, &event), (decoded_number, &decoded_event));
+            active_state.apply(decoded_event);
+        }
+
+        // Standby which has joined late gets the same state from snapshot.
+        let mut standby_state = ReplicatedState::default();
+        standby_state.apply(ReplicationEvent::Appended(7, b"stale".to_vec()));
+        for event in active_state.snapshot() {
+            standby_state.apply(event);
+        }
+        assert_eq!(active_state, standby_state);
+
+        assert_eq!(
+            BusTakeover {
+                messages: vec![b"third".to_vec(), b"second".to_vec()],
+                subscriptions: vec![(0, b"topic".to_vec())],
+            },
+            standby_state.into_takeover()
+        );
+        assert!(ReplicationEvent::decode(vec![vec![3], vec![0; 8], vec![0; 4]]).is_err());
+        assert!(ReplicationEvent::decode(vec![vec![1], vec![0; 4]]).is_err());
+    }
+
+    #[test]
+    fn standby_requests_snapshot_after_missed_event() {
+        let context = Context::new();
+        let replication =
+            Replication::bind(&context, "inproc://replication-gap-test", 0, &[]).unwrap();
+        let standby = Standby::connect(&context, "inproc://replication-gap-test").unwrap();
+
+        let primary_shutdown_signal = ShutdownSignal::new();
+        let primary_thread = {
+            let replication = replication.clone();
+            let shutdown_signal = primary_shutdown_signal.clone();
+            thread::spawn(move || replication.run(Duration::from_millis(50), &shutdown_signal))
+        };
+        let standby_thread = thread::spawn(move || {
+            standby.wait_for_takeover(Duration::from_millis(500), &ShutdownSignal::new())
+        });
+
+        // Standby gets the snapshot of joining first, so nothing after it is missed
+        // because of joining late.
+        thread::sleep(Duration::from_millis(300));
+        let mut replication_replica = replication.clone();
+        replication_replica.appended(1, b"first");
+        // The second event is lost on the way, standby sees it by the number of the next
+        // one.
+        replication.0.lock(|publisher| {
+            publisher
+                .state
+                .apply(ReplicationEvent::Appended(2, b"second".to_vec()));
+            publisher.next_event_number += 1;
+        });
+        replication_replica.appended(3, b"third");
+
+        thread::sleep(Duration::from_millis(300));
+        primary_shutdown_signal.request();
+        primary_thread.join().unwrap();
+
+        assert_eq!(
+            vec![b"first".to_vec(), b"second".to_vec(), b"third".to_vec()],
+            standby_thread.join().unwrap().unwrap().messages
+        );
+    }
+}
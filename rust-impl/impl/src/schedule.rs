@@ -0,0 +1,256 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::collections::HashMap;
+use uuid::Uuid;
+use zeromq_messages::kind::ZeromqMessageKind;
+
+//-----------------------------------------------------------------------------------------
+// ScheduledMessage
+//-----------------------------------------------------------------------------------------
+
+/// Accepted message which waits for its deliver-at time.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ScheduledMessage {
+    pub kind: ZeromqMessageKind,
+    pub uuid: Uuid,
+    /// Milliseconds since Unix epoch.
+    pub deliver_at: u64,
+    pub wal_sequence: Option<u64>,
+    pub message_bytes: Vec<u8>,
+}
+
+//-----------------------------------------------------------------------------------------
+// ScheduledMessages
+//-----------------------------------------------------------------------------------------
+
+/// Messages which BUS holds until their deliver-at time, the heap gives the earliest one
+/// first. Messages are told apart by kind and uuid, because responses reuse uuids of their
+/// requests. Cancelled and replaced messages leave their heap entries behind, the entries
+/// are skipped once they come up.
+#[derive(Debug)]
+pub struct ScheduledMessages {
+    deliver_times: BinaryHeap<Reverse<(u64, ZeromqMessageKind, Uuid)>>,
+    messages: HashMap<(ZeromqMessageKind, Uuid), ScheduledMessage>,
+    capacity: usize,
+    max_delay_ms: u64,
+}
+
+impl ScheduledMessages {
+    /// Holds at most the given count of messages which are due no later than the given
+    /// delay from the moment they are scheduled.
+    #[must_use]
+    pub fn new(capacity: usize, max_delay_ms: u64) -> Self {
+        Self {
+            deliver_times: BinaryHeap::new(),
+            messages: HashMap::new(),
+            capacity,
+            max_delay_ms,
+        }
+    }
+
+    /// Message with the same kind and uuid replaces the scheduled one, which is returned, so
+    /// resent messages are delivered once. Message is refused when it is due too late or
+    /// when there are too many scheduled messages already.
+    pub fn schedule(
+        &mut self,
+        message: ScheduledMessage,
+        now: u64,
+    ) -> Result<Option<ScheduledMessage>, String> {
+        if message.deliver_at > now.saturating_add(self.max_delay_ms) {
+            return Err(format!(
+                "deliver-at time is more than {} ms ahead",
+                self.max_delay_ms
+            ));
+        }
+        let key = (message.kind, message.uuid);
+        if self.messages.len() >= self.capacity && !self.messages.contains_key(&key) {
+            return Err(format!(
+                "scheduled messages are at capacity of {}",
+                self.capacity
+            ));
+        }
+
+        // Entries of replaced and cancelled messages are dropped before they outnumber
+        // the messages, so resending one message again and again takes no memory.
+        if self.deliver_times.len() > 2 * self.capacity {
+            let messages = &self.messages;
+            self.deliver_times
+                .retain(|Reverse((deliver_at, kind, uuid))| {
+                    messages
+                        .get(&(*kind, *uuid))
+                        .is_some_and(|message| message.deliver_at == *deliver_at)
+                });
+        }
+
+        self.deliver_times
+            .push(Reverse((message.deliver_at, message.kind, message.uuid)));
+        Ok(self.messages.insert(key, message))
+    }
+
+    pub fn cancel(&mut self, kind: ZeromqMessageKind, uuid: Uuid) -> Option<ScheduledMessage> {
+        self.messages.remove(&(kind, uuid))
+    }
+
+    /// Removes and returns messages which are due at the given milliseconds since Unix
+    /// epoch, in order of their deliver-at times.
+    pub fn take_due(&mut self, now: u64) -> Vec<ScheduledMessage> {
+        let mut due_messages = Vec::new();
+
+        while let Some(Reverse((deliver_at, kind, uuid))) = self.deliver_times.peek().copied()
+        {
+            if deliver_at > now {
+                break;
+            }
+            let _ = self.deliver_times.pop();
+
+            if self
+                .messages
+                .get(&(kind, uuid))
+                .is_some_and(|message| message.deliver_at == deliver_at)
+            {
+                due_messages.extend(self.messages.remove(&(kind, uuid)));
+            }
+        }
+
+        due_messages
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// Tests
+//-----------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::schedule::ScheduledMessage;
+    use crate::schedule::ScheduledMessages;
+    use uuid::Uuid;
+    use zeromq_messages::kind::ZeromqMessageKind;
+
+    const KIND: ZeromqMessageKind = ZeromqMessageKind::ValueMultiplicationResponse;
+
+    fn scheduled_message(uuid: Uuid, deliver_at: u64) -> ScheduledMessage {
+        ScheduledMessage {
+            kind: KIND,
+            uuid,
+            deliver_at,
+            wal_sequence: None,
+            message_bytes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn takes_due_messages_in_order() {
+        let mut scheduled_messages = ScheduledMessages::new(10, 1_000);
+        let (first_uuid, second_uuid, cancelled_uuid, replaced_uuid) = (
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+        );
+
+        assert_eq!(
+            None,
+            scheduled_messages
+                .schedule(scheduled_message(second_uuid, 200), 0)
+                .unwrap()
+        );
+        assert_eq!(
+            None,
+            scheduled_messages
+                .schedule(scheduled_message(first_uuid, 100), 0)
+                .unwrap()
+        );
+        assert_eq!(
+            None,
+            scheduled_messages
+                .schedule(scheduled_message(cancelled_uuid, 150), 0)
+                .unwrap()
+        );
+        assert_eq!(
+            None,
+            scheduled_messages
+                .schedule(scheduled_message(replaced_uuid, 50), 0)
+                .unwrap()
+        );
+        assert_eq!(
+            Some(scheduled_message(replaced_uuid, 50)),
+            scheduled_messages
+                .schedule(scheduled_message(replaced_uuid, 300), 0)
+                .unwrap()
+        );
+        assert_eq!(
+            Some(scheduled_message(cancelled_uuid, 150)),
+            scheduled_messages.cancel(KIND, cancelled_uuid)
+        );
+        assert_eq!(None, scheduled_messages.cancel(KIND, cancelled_uuid));
+        assert_eq!(3, scheduled_messages.len());
+
+        assert!(scheduled_messages.take_due(99).is_empty());
+        assert_eq!(
+            vec![first_uuid, second_uuid],
+            scheduled_messages
+                .take_due(250)
+                .into_iter()
+                .map(|message| message.uuid)
+                .collect::<Vec<Uuid>>()
+        );
+        assert_eq!(1, scheduled_messages.take_due(300).len());
+        assert!(scheduled_messages.is_empty());
+    }
+
+    #[test]
+    fn refuses_messages_beyond_limits() {
+        let mut scheduled_messages = ScheduledMessages::new(1, 1_000);
+        let scheduled_uuid = Uuid::new_v4();
+
+        assert!(scheduled_messages
+            .schedule(scheduled_message(Uuid::new_v4(), 1_501), 500)
+            .is_err());
+        assert_eq!(
+            Ok(None),
+            scheduled_messages.schedule(scheduled_message(scheduled_uuid, 1_500), 500)
+        );
+        assert!(scheduled_messages
+            .schedule(scheduled_message(Uuid::new_v4(), 1_000), 500)
+            .is_err());
+
+        // Replacing message does not take more room.
+        assert!(scheduled_messages
+            .schedule(scheduled_message(scheduled_uuid, 1_000), 500)
+            .is_ok());
+        assert_eq!(1, scheduled_messages.len());
+    }
+    #[test]
+    fn messages_of_other_kinds_keep_their_uuids() {
+        let mut scheduled_messages = ScheduledMessages::new(10, 1_000);
+        let uuid = Uuid::new_v4();
+        let request = ScheduledMessage {
+            kind: ZeromqMessageKind::ValueMultiplicationRequest,
+            ..scheduled_message(uuid, 100)
+        };
+
+        assert_eq!(Ok(None), scheduled_messages.schedule(request.clone(), 0));
+        assert_eq!(
+            Ok(None),
+            scheduled_messages.schedule(scheduled_message(uuid, 200), 0)
+        );
+        assert_eq!(2, scheduled_messages.len());
+
+        assert_eq!(
+            Some(scheduled_message(uuid, 200)),
+            scheduled_messages.cancel(KIND, uuid)
+        );
+        assert_eq!(vec![request], scheduled_messages.take_due(300));
+    }
+}
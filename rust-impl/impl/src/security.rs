@@ -0,0 +1,375 @@
+use crate::BusStats;
+use crate::EndpointsConfig;
+use crate::ShutdownSignal;
+use crate::SHUTDOWN_CHECK_INTERVAL_MILLISECONDS;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use zmq::Context;
+use zmq::Socket;
+use zmq::SocketType;
+
+/// Endpoint which libzmq asks for authentication of every connection to a server socket
+/// with ZAP domain.
+const ZAP_ENDPOINT: &str = "inproc://zeromq.zap.01";
+const ZAP_VERSION: &[u8] = b"1.0";
+const ZAP_DOMAIN: &str = "bus";
+const ZAP_STATUS_ALLOWED: &[u8] = b"200";
+const ZAP_STATUS_DENIED: &[u8] = b"400";
+const CURVE_MECHANISM: &[u8] = b"CURVE";
+const CURVE_KEY_LENGTH: usize = 32;
+
+//-----------------------------------------------------------------------------------------
+// Errors
+//-----------------------------------------------------------------------------------------
+
+#[derive(Debug, thiserror::Error)]
+pub enum SecurityError {
+    #[error("ZeroMQ library is built without CURVE support")]
+    CurveUnsupported,
+
+    #[error("Failed to read allowed clients file {path}")]
+    ReadAllowedClients {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("Invalid allowed clients file {path}: {reason}")]
+    ParseAllowedClients { path: PathBuf, reason: String },
+
+    #[error("Failed to bind ZAP handler socket")]
+    Zap(#[source] zmq::Error),
+}
+
+//-----------------------------------------------------------------------------------------
+// Keys
+//-----------------------------------------------------------------------------------------
+
+/// Decodes Z85 form of CURVE key, which is 40 characters long.
+pub fn decode_curve_key(key: &str) -> Result<Vec<u8>, String> {
+    match zmq::z85_decode(key) {
+        Ok(key) if key.len() == CURVE_KEY_LENGTH => Ok(key),
+        _ => Err(format!(
+            "expected {} bytes key in Z85 form of 40 characters",
+            CURVE_KEY_LENGTH
+        )),
+    }
+}
+
+/// Makes socket which BUS binds a CURVE server, when BUS has a secret key. Clients are
+/// authenticated by the ZAP handler, so it should be running before clients connect.
+pub fn secure_server_socket(socket: &Socket, endpoints: &EndpointsConfig) -> zmq::Result<()> {
+    let Some(secret_key) = &endpoints.curve_server_secret_key else {
+        return Ok(());
+    };
+
+    socket.set_zap_domain(ZAP_DOMAIN)?;
+    socket.set_curve_server(true)?;
+    socket.set_curve_secretkey(decode_configured_key(secret_key)?.as_slice())
+}
+
+/// Makes socket which service connects to BUS a CURVE client, when client keys are
+/// configured. Keys should be set before the socket is connected.
+pub fn secure_client_socket(socket: &Socket, endpoints: &EndpointsConfig) -> zmq::Result<()> {
+    let (Some(server_public_key), Some(public_key), Some(secret_key)) = (
+        &endpoints.curve_server_public_key,
+        &endpoints.curve_client_public_key,
+        &endpoints.curve_client_secret_key,
+    ) else {
+        return Ok(());
+    };
+
+    socket.set_curve_serverkey(decode_configured_key(server_public_key)?.as_slice())?;
+    socket.set_curve_publickey(decode_configured_key(public_key)?.as_slice())?;
+    socket.set_curve_secretkey(decode_configured_key(secret_key)?.as_slice())
+}
+
+/// Configuration is validated, so keys are well-formed unless it has been changed since.
+fn decode_configured_key(key: &str) -> zmq::Result<Vec<u8>> {
+    decode_curve_key(key).map_err(|_| zmq::Error::EINVAL)
+}
+
+//-----------------------------------------------------------------------------------------
+// AllowedClients
+//-----------------------------------------------------------------------------------------
+
+/// Public keys of clients which are allowed to connect to BUS sockets. File has one key
+/// in Z85 form per line, empty lines and lines starting with `#` are skipped.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct AllowedClients {
+    public_keys: HashSet<Vec<u8>>,
+}
+
+impl AllowedClients {
+    pub fn load(path: &Path) -> Result<Self, SecurityError> {
+        let content =
+            fs::read_to_string(path).map_err(|source| SecurityError::ReadAllowedClients {
+                path: path.to_path_buf(),
+                source,
+            })?;
+
+        Self::parse(content.as_str()).map_err(|reason| SecurityError::ParseAllowedClients {
+            path: path.to_path_buf(),
+            reason,
+        })
+    }
+
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let mut public_keys = HashSet::new();
+
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let public_key = decode_curve_key(line)
+                .map_err(|reason| format!("line {}: {}", index + 1, reason))?;
+            let _ = public_keys.insert(public_key);
+        }
+
+        Ok(Self { public_keys })
+    }
+
+    #[must_use]
+    pub fn contains(&self, public_key: &[u8]) -> bool {
+        self.public_keys.contains(public_key)
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.public_keys.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.public_keys.is_empty()
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// ZapHandler
+//-----------------------------------------------------------------------------------------
+
+/// Answers authentication requests which libzmq makes for every CURVE connection to BUS
+/// sockets. Any client which completes CURVE handshake is allowed when there is no
+/// allow-list. Public key of the allowed client is its user id, which later frames of the
+/// connection carry.
+pub struct ZapHandler {
+    socket: Socket,
+    allowed_clients: Option<AllowedClients>,
+    stats: Arc<BusStats>,
+}
+
+impl ZapHandler {
+    /// Handler is bound in the context of BUS sockets, libzmq looks for it there.
+    pub fn bind(
+        context: &Context,
+        allowed_clients: Option<AllowedClients>,
+        stats: Arc<BusStats>,
+    ) -> Result<Self, SecurityError> {
+        if zmq::has("curve") != Some(true) {
+            return Err(SecurityError::CurveUnsupported);
+        }
+
+        let socket = context
+            .socket(SocketType::REP)
+            .map_err(SecurityError::Zap)?;
+        socket.set_linger(0).map_err(SecurityError::Zap)?;
+        socket.bind(ZAP_ENDPOINT).map_err(SecurityError::Zap)?;
+
+        Ok(Self {
+            socket,
+            allowed_clients,
+            stats,
+        })
+    }
+
+    /// Answers requests until shutdown is requested.
+    pub fn run(&mut self, shutdown_signal: &ShutdownSignal) {
+        log::debug!("running ZAP handler loop");
+
+        while !shutdown_signal.is_requested() {
+            match self
+                .socket
+                .poll(zmq::POLLIN, i64::from(SHUTDOWN_CHECK_INTERVAL_MILLISECONDS))
+            {
+                Ok(0) => continue,
+                Ok(_) => {}
+                Err(error) => {
+                    log::error!("failed to poll ZAP handler socket because of: {}", error);
+                    continue;
+                }
+            }
+
+            let request = match self.socket.recv_multipart(zmq::DONTWAIT) {
+                Ok(request) => request,
+                Err(error) => {
+                    log::error!("failed to receive ZAP request because of: {}", error);
+                    continue;
+                }
+            };
+
+            let reply = self.reply(request.as_slice());
+            if let Err(error) = self.socket.send_multipart(reply.iter(), zmq::DONTWAIT) {
+                log::error!("failed to send ZAP reply because of: {}", error);
+            }
+        }
+
+        log::debug!("ZAP handler loop has stopped");
+    }
+
+    fn reply(&self, request: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        let request_id = request.get(1).cloned().unwrap_or_default();
+        let address = request
+            .get(3)
+            .map(|address| String::from_utf8_lossy(address).into_owned())
+            .unwrap_or_default();
+
+        match authenticate(request, self.allowed_clients.as_ref()) {
+            Ok(public_key) => {
+                let user_id = zmq::z85_encode(public_key.as_slice()).unwrap_or_default();
+                log::debug!("authenticated client {} from {}", user_id, address);
+                zap_reply(request_id, ZAP_STATUS_ALLOWED, "OK", user_id.as_str())
+            }
+            Err(reason) => {
+                log::warn!("denied client from {} because of: {}", address, reason);
+                self.stats.authentication_failures.increment();
+                zap_reply(request_id, ZAP_STATUS_DENIED, reason.as_str(), "")
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ZapHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ZapHandler")
+            .field("socket", &self.socket.get_socket_type())
+            .field("allowed_clients", &self.allowed_clients)
+            .field("stats", &self.stats)
+            .finish()
+    }
+}
+
+/// Checks ZAP request, which frames are version, request id, domain, address, routing id,
+/// mechanism and its credentials. Public key of the allowed client is returned.
+fn authenticate(
+    request: &[Vec<u8>],
+    allowed_clients: Option<&AllowedClients>,
+) -> Result<Vec<u8>, String> {
+    let [version, _, _, _, _, mechanism, credentials] = request else {
+        return Err(format!(
+            "unexpected {} frames of ZAP request",
+            request.len()
+        ));
+    };
+
+    if version.as_slice() != ZAP_VERSION {
+        return Err("unsupported ZAP version".to_string());
+    }
+    if mechanism.as_slice() != CURVE_MECHANISM {
+        return Err("only CURVE mechanism is accepted".to_string());
+    }
+    if credentials.len() != CURVE_KEY_LENGTH {
+        return Err("malformed public key".to_string());
+    }
+    if allowed_clients.is_some_and(|allowed_clients| !allowed_clients.contains(credentials)) {
+        return Err("public key is not allowed".to_string());
+    }
+
+    Ok(credentials.clone())
+}
+
+fn zap_reply(
+    request_id: Vec<u8>,
+    status_code: &[u8],
+    status_text: &str,
+    user_id: &str,
+) -> Vec<Vec<u8>> {
+    vec![
+        ZAP_VERSION.to_vec(),
+        request_id,
+        status_code.to_vec(),
+        status_text.as_bytes().to_vec(),
+        user_id.as_bytes().to_vec(),
+        Vec::new(),
+    ]
+}
+
+//-----------------------------------------------------------------------------------------
+// Tests
+//-----------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::security::authenticate;
+    use crate::security::decode_curve_key;
+    use crate::security::AllowedClients;
+
+    /// Public keys from the test vectors of libzmq documentation.
+    const CLIENT_PUBLIC_KEY: &str = "Yne@$w-vo<fVvi]a<NY6T1ed:M$fCG*[IaLV{hID";
+    const SERVER_PUBLIC_KEY: &str = "rq:rM>}U?@Lns47E1%kR.o@n%FcmmsL/@{H8]yf7";
+
+    fn zap_request(mechanism: &[u8], public_key: &str) -> Vec<Vec<u8>> {
+        vec![
+            b"1.0".to_vec(),
+            b"1".to_vec(),
+            b"bus".to_vec(),
+            b"127.0.0.1".to_vec(),
+            Vec::new(),
+            mechanism.to_vec(),
+            decode_curve_key(public_key).unwrap(),
+        ]
+    }
+
+    #[test]
+    fn parses_allowed_clients() {
+        let allowed_clients = AllowedClients::parse(
+            format!(
+                "# service_sender\n{}\n\n  {}  \n",
+                CLIENT_PUBLIC_KEY, SERVER_PUBLIC_KEY
+            )
+            .as_str(),
+        )
+        .unwrap();
+        assert_eq!(2, allowed_clients.len());
+        assert!(
+            allowed_clients.contains(decode_curve_key(CLIENT_PUBLIC_KEY).unwrap().as_slice())
+        );
+
+        assert_eq!(
+            Err("line 2: expected 32 bytes key in Z85 form of 40 characters".to_string()),
+            AllowedClients::parse(format!("{}\nnot a key\n", CLIENT_PUBLIC_KEY).as_str())
+        );
+    }
+
+    #[test]
+    fn authenticates_allowed_curve_clients_only() {
+        let allowed_clients = AllowedClients::parse(CLIENT_PUBLIC_KEY).unwrap();
+
+        assert_eq!(
+            Ok(decode_curve_key(CLIENT_PUBLIC_KEY).unwrap()),
+            authenticate(
+                zap_request(b"CURVE", CLIENT_PUBLIC_KEY).as_slice(),
+                Some(&allowed_clients)
+            )
+        );
+        assert!(authenticate(
+            zap_request(b"CURVE", SERVER_PUBLIC_KEY).as_slice(),
+            Some(&allowed_clients)
+        )
+        .is_err());
+        assert!(
+            authenticate(zap_request(b"CURVE", SERVER_PUBLIC_KEY).as_slice(), None).is_ok()
+        );
+        assert!(
+            authenticate(zap_request(b"PLAIN", CLIENT_PUBLIC_KEY).as_slice(), None).is_err()
+        );
+        assert!(authenticate(&[b"1.0".to_vec()], None).is_err());
+    }
+}
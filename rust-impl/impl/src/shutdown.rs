@@ -0,0 +1,108 @@
+use signal_hook::consts::TERM_SIGNALS;
+use signal_hook::flag;
+use std::io;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+//-----------------------------------------------------------------------------------------
+// ShutdownSignal
+//-----------------------------------------------------------------------------------------
+
+/// Flag which asks long running loops to stop at the nearest safe point, cloned handles
+/// share the same flag.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownSignal(Arc<AtomicBool>);
+
+impl ShutdownSignal {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Raises the flag on SIGTERM and SIGINT. The second signal terminates the process
+    /// immediately, so a stuck drain can still be interrupted.
+    pub fn register_termination_signals(&self) -> io::Result<()> {
+        for signal in TERM_SIGNALS {
+            // Order matters: the conditional shutdown sees the flag raised by the previous
+            // signal only when it is registered before the flag itself.
+            let _ = flag::register_conditional_shutdown(*signal, 1, Arc::clone(&self.0))?;
+            let _ = flag::register(*signal, Arc::clone(&self.0))?;
+        }
+        Ok(())
+    }
+
+    pub fn request(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    #[must_use]
+    pub fn is_requested(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Blocks until shutdown is requested or the condition stops holding.
+    pub fn wait_while<F: FnMut() -> bool>(&self, mut condition: F) {
+        while !self.is_requested() && condition() {
+            thread::sleep(check_interval());
+        }
+    }
+
+    /// Blocks until the condition stops holding or the deadline passes, returns whether
+    /// the condition still holds.
+    pub fn wait_while_until<F: FnMut() -> bool>(deadline: Instant, mut condition: F) -> bool {
+        while condition() {
+            let now = Instant::now();
+            if now >= deadline {
+                return true;
+            }
+            thread::sleep(check_interval().min(deadline - now));
+        }
+        false
+    }
+}
+
+fn check_interval() -> Duration {
+    Duration::from_millis(u64::from(crate::SHUTDOWN_CHECK_INTERVAL_MILLISECONDS))
+}
+
+//-----------------------------------------------------------------------------------------
+// Tests
+//-----------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::shutdown::ShutdownSignal;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    #[test]
+    fn clones_share_flag() {
+        let shutdown_signal = ShutdownSignal::new();
+        let cloned_shutdown_signal = shutdown_signal.clone();
+        assert!(!cloned_shutdown_signal.is_requested());
+
+        shutdown_signal.request();
+        assert!(cloned_shutdown_signal.is_requested());
+        shutdown_signal.wait_while(|| true);
+    }
+
+    #[test]
+    fn wait_while_until_stops_at_deadline() {
+        let deadline = Instant::now() + Duration::from_millis(50);
+        assert!(ShutdownSignal::wait_while_until(deadline, || true));
+        assert!(Instant::now() >= deadline);
+
+        let mut remaining_checks = 2;
+        assert!(!ShutdownSignal::wait_while_until(
+            Instant::now() + Duration::from_secs(5),
+            move || {
+                remaining_checks -= 1;
+                remaining_checks > 0
+            }
+        ));
+    }
+}
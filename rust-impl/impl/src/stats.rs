@@ -0,0 +1,395 @@
+use crate::DeadLockSafeMutex;
+use crate::RateLimitedClient;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::iter::Iterator;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use zeromq_messages::kind::ZeromqMessageKind;
+
+/// Upper bounds of latency histogram buckets in milliseconds.
+pub const BUS_LATENCY_BUCKETS_MILLISECONDS: &[u64] =
+    &[1, 5, 10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000];
+
+//-----------------------------------------------------------------------------------------
+// BusCounter
+//-----------------------------------------------------------------------------------------
+
+/// Value which is shared between BUS threads without locking.
+#[derive(Debug, Default)]
+pub struct BusCounter(AtomicU64);
+
+impl BusCounter {
+    pub fn increment(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, value: u64) {
+        let _ = self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Used for gauges, which reflect current state instead of counting events.
+    pub fn set(&self, value: u64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    #[must_use]
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// BusHistogram
+//-----------------------------------------------------------------------------------------
+
+/// Distribution of observed values over `BUS_LATENCY_BUCKETS_MILLISECONDS`.
+#[derive(Debug)]
+pub struct BusHistogram {
+    /// Count of values which fall into every bucket, the last one has no upper bound.
+    buckets: Vec<BusCounter>,
+    count: BusCounter,
+    sum: BusCounter,
+}
+
+impl BusHistogram {
+    pub fn observe(&self, value: u64) {
+        let index = BUS_LATENCY_BUCKETS_MILLISECONDS
+            .iter()
+            .position(|upper_bound| value <= *upper_bound)
+            .unwrap_or(BUS_LATENCY_BUCKETS_MILLISECONDS.len());
+        self.buckets[index].increment();
+        self.count.increment();
+        self.sum.add(value);
+    }
+
+    /// Upper bounds with count of values which are less or equal to them, the last bound
+    /// is absent and stands for infinity.
+    #[must_use]
+    pub fn cumulative_buckets(&self) -> Vec<(Option<u64>, u64)> {
+        let mut cumulative_count = 0;
+        self.buckets
+            .iter()
+            .enumerate()
+            .map(|(index, bucket)| {
+                cumulative_count += bucket.get();
+                (
+                    BUS_LATENCY_BUCKETS_MILLISECONDS.get(index).copied(),
+                    cumulative_count,
+                )
+            })
+            .collect()
+    }
+
+    #[must_use]
+    pub fn count(&self) -> u64 {
+        self.count.get()
+    }
+
+    #[must_use]
+    pub fn sum(&self) -> u64 {
+        self.sum.get()
+    }
+}
+
+impl Default for BusHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: (0..=BUS_LATENCY_BUCKETS_MILLISECONDS.len())
+                .map(|_| BusCounter::default())
+                .collect(),
+            count: BusCounter::default(),
+            sum: BusCounter::default(),
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// BusKindStats
+//-----------------------------------------------------------------------------------------
+
+/// Counters of messages of one kind.
+#[derive(Debug, Default)]
+pub struct BusKindStats {
+    pub received_messages: BusCounter,
+    pub received_bytes: BusCounter,
+    pub published_messages: BusCounter,
+    pub published_bytes: BusCounter,
+    pub publish_failures: BusCounter,
+    pub expired_messages: BusCounter,
+    pub deduplicated_messages: BusCounter,
+    pub throttled_messages: BusCounter,
+    pub unroutable_messages: BusCounter,
+}
+
+//-----------------------------------------------------------------------------------------
+// BusStats
+//-----------------------------------------------------------------------------------------
+
+/// Counters of the BUS pipeline, updated by all BUS threads.
+#[derive(Debug)]
+pub struct BusStats {
+    pub received_messages: BusCounter,
+    pub received_bytes: BusCounter,
+    pub published_messages: BusCounter,
+    pub published_bytes: BusCounter,
+    pub publish_failures: BusCounter,
+    pub dead_lettered_messages: BusCounter,
+    pub publishing_queue_blocked_pushes: BusCounter,
+    pub publishing_queue_dropped_oldest_messages: BusCounter,
+    pub publishing_queue_dropped_newest_messages: BusCounter,
+    pub publishing_queue_rejected_messages: BusCounter,
+    pub retry_buffer_overflows: BusCounter,
+    pub work_queue_overflows: BusCounter,
+    pub federated_messages: BusCounter,
+    pub federation_dropped_messages: BusCounter,
+    pub federation_looped_messages: BusCounter,
+    pub expired_messages: BusCounter,
+    pub cancelled_deliveries: BusCounter,
+    pub deduplicated_messages: BusCounter,
+    pub throttled_messages: BusCounter,
+    pub unroutable_messages: BusCounter,
+    pub authentication_failures: BusCounter,
+    pub denied_publications: BusCounter,
+    pub denied_subscriptions: BusCounter,
+    pub publishing_queue_depth: BusCounter,
+    pub retry_buffer_depth: BusCounter,
+    pub scheduled_messages: BusCounter,
+    pub paused_messages: BusCounter,
+    /// Time between message timestamp from its header and its publishing, in milliseconds.
+    pub latency: BusHistogram,
+    /// Counters of every known kind, created upfront so they are updated without locking.
+    kinds: HashMap<ZeromqMessageKind, BusKindStats>,
+    /// Count of messages sent through every publisher socket, in order of endpoints.
+    publisher_sends: Vec<BusCounter>,
+    /// Count of prefixes which subscribers of every publisher socket are subscribed to.
+    publisher_subscriptions: Vec<BusCounter>,
+    /// Count of messages above the rate limit of every client which has exceeded it.
+    throttled_clients: DeadLockSafeMutex<BTreeMap<RateLimitedClient, u64>>,
+}
+
+impl BusStats {
+    #[must_use]
+    pub fn new(publishers_count: usize) -> Self {
+        Self {
+            received_messages: BusCounter::default(),
+            received_bytes: BusCounter::default(),
+            published_messages: BusCounter::default(),
+            published_bytes: BusCounter::default(),
+            publish_failures: BusCounter::default(),
+            dead_lettered_messages: BusCounter::default(),
+            publishing_queue_blocked_pushes: BusCounter::default(),
+            publishing_queue_dropped_oldest_messages: BusCounter::default(),
+            publishing_queue_dropped_newest_messages: BusCounter::default(),
+            publishing_queue_rejected_messages: BusCounter::default(),
+            retry_buffer_overflows: BusCounter::default(),
+            work_queue_overflows: BusCounter::default(),
+            federated_messages: BusCounter::default(),
+            federation_dropped_messages: BusCounter::default(),
+            federation_looped_messages: BusCounter::default(),
+            expired_messages: BusCounter::default(),
+            cancelled_deliveries: BusCounter::default(),
+            deduplicated_messages: BusCounter::default(),
+            throttled_messages: BusCounter::default(),
+            unroutable_messages: BusCounter::default(),
+            authentication_failures: BusCounter::default(),
+            denied_publications: BusCounter::default(),
+            denied_subscriptions: BusCounter::default(),
+            publishing_queue_depth: BusCounter::default(),
+            retry_buffer_depth: BusCounter::default(),
+            scheduled_messages: BusCounter::default(),
+            paused_messages: BusCounter::default(),
+            latency: BusHistogram::default(),
+            kinds: ZeromqMessageKind::ALL
+                .iter()
+                .map(|kind| (*kind, BusKindStats::default()))
+                .collect(),
+            publisher_sends: (0..publishers_count)
+                .map(|_| BusCounter::default())
+                .collect(),
+            publisher_subscriptions: (0..publishers_count)
+                .map(|_| BusCounter::default())
+                .collect(),
+            throttled_clients: DeadLockSafeMutex::new(BTreeMap::new()),
+        }
+    }
+
+    #[must_use]
+    pub fn kind(&self, kind: ZeromqMessageKind) -> &BusKindStats {
+        self.kinds
+            .get(&kind)
+            .expect("stats are created for every known kind")
+    }
+
+    /// Counters of all known kinds in order of kind values.
+    pub fn kinds(&self) -> impl Iterator<Item = (ZeromqMessageKind, &BusKindStats)> {
+        ZeromqMessageKind::ALL
+            .iter()
+            .map(move |kind| (*kind, self.kind(*kind)))
+    }
+
+    #[must_use]
+    pub fn publisher_sends(&self) -> &[BusCounter] {
+        self.publisher_sends.as_slice()
+    }
+
+    #[must_use]
+    pub fn publisher_subscriptions(&self) -> &[BusCounter] {
+        self.publisher_subscriptions.as_slice()
+    }
+
+    /// Counts message which has exceeded the rate limit of its client.
+    pub fn record_throttled(&self, client: &RateLimitedClient, kind: ZeromqMessageKind) {
+        self.throttled_messages.increment();
+        self.kind(kind).throttled_messages.increment();
+
+        let client = client.clone();
+        self.throttled_clients.lock(move |throttled_clients| {
+            *throttled_clients.entry(client).or_default() += 1;
+        });
+    }
+
+    /// Clients which have exceeded their rate limit with counts of their throttled
+    /// messages, ordered by clients.
+    #[must_use]
+    pub fn throttled_clients(&self) -> Vec<(RateLimitedClient, u64)> {
+        self.throttled_clients.lock(|throttled_clients| {
+            throttled_clients
+                .iter()
+                .map(|(client, count)| (client.clone(), *count))
+                .collect()
+        })
+    }
+
+    #[must_use]
+    pub fn snapshot(&self) -> BusStatsSnapshot {
+        BusStatsSnapshot {
+            received_messages: self.received_messages.get(),
+            received_bytes: self.received_bytes.get(),
+            published_messages: self.published_messages.get(),
+            published_bytes: self.published_bytes.get(),
+            publish_failures: self.publish_failures.get(),
+            dead_lettered_messages: self.dead_lettered_messages.get(),
+            publishing_queue_blocked_pushes: self.publishing_queue_blocked_pushes.get(),
+            publishing_queue_dropped_oldest_messages: self
+                .publishing_queue_dropped_oldest_messages
+                .get(),
+            publishing_queue_dropped_newest_messages: self
+                .publishing_queue_dropped_newest_messages
+                .get(),
+            publishing_queue_rejected_messages: self.publishing_queue_rejected_messages.get(),
+            retry_buffer_overflows: self.retry_buffer_overflows.get(),
+            work_queue_overflows: self.work_queue_overflows.get(),
+            federated_messages: self.federated_messages.get(),
+            federation_dropped_messages: self.federation_dropped_messages.get(),
+            federation_looped_messages: self.federation_looped_messages.get(),
+            expired_messages: self.expired_messages.get(),
+            cancelled_deliveries: self.cancelled_deliveries.get(),
+            deduplicated_messages: self.deduplicated_messages.get(),
+            throttled_messages: self.throttled_messages.get(),
+            unroutable_messages: self.unroutable_messages.get(),
+            authentication_failures: self.authentication_failures.get(),
+            denied_publications: self.denied_publications.get(),
+            denied_subscriptions: self.denied_subscriptions.get(),
+            publishing_queue_depth: self.publishing_queue_depth.get(),
+            retry_buffer_depth: self.retry_buffer_depth.get(),
+            scheduled_messages: self.scheduled_messages.get(),
+            paused_messages: self.paused_messages.get(),
+            subscriptions: self
+                .publisher_subscriptions
+                .iter()
+                .map(BusCounter::get)
+                .sum(),
+        }
+    }
+}
+
+impl Default for BusStats {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// BusStatsSnapshot
+//-----------------------------------------------------------------------------------------
+
+/// Values of BUS counters at some moment.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct BusStatsSnapshot {
+    pub received_messages: u64,
+    pub received_bytes: u64,
+    pub published_messages: u64,
+    pub published_bytes: u64,
+    pub publish_failures: u64,
+    pub dead_lettered_messages: u64,
+    pub publishing_queue_blocked_pushes: u64,
+    pub publishing_queue_dropped_oldest_messages: u64,
+    pub publishing_queue_dropped_newest_messages: u64,
+    pub publishing_queue_rejected_messages: u64,
+    pub retry_buffer_overflows: u64,
+    pub work_queue_overflows: u64,
+    pub federated_messages: u64,
+    pub federation_dropped_messages: u64,
+    pub federation_looped_messages: u64,
+    pub expired_messages: u64,
+    pub cancelled_deliveries: u64,
+    pub deduplicated_messages: u64,
+    pub throttled_messages: u64,
+    pub unroutable_messages: u64,
+    pub authentication_failures: u64,
+    pub denied_publications: u64,
+    pub denied_subscriptions: u64,
+    pub publishing_queue_depth: u64,
+    pub retry_buffer_depth: u64,
+    pub scheduled_messages: u64,
+    pub paused_messages: u64,
+    /// Subscribed prefixes of all publisher sockets.
+    pub subscriptions: u64,
+}
+
+//-----------------------------------------------------------------------------------------
+// Tests
+//-----------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::stats::BusHistogram;
+    use crate::stats::BusStats;
+    use zeromq_messages::kind::ZeromqMessageKind;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let histogram = BusHistogram::default();
+        for value in &[0, 1, 3, 7, 20_000] {
+            histogram.observe(*value);
+        }
+
+        let buckets = histogram.cumulative_buckets();
+        assert_eq!((Some(1), 2), buckets[0]);
+        assert_eq!((Some(5), 3), buckets[1]);
+        assert_eq!((Some(10), 4), buckets[2]);
+        assert_eq!((None, 5), *buckets.last().unwrap());
+        assert_eq!(5, histogram.count());
+        assert_eq!(20_011, histogram.sum());
+    }
+
+    #[test]
+    fn every_kind_has_counters() {
+        let stats = BusStats::new(2);
+        stats
+            .kind(ZeromqMessageKind::WorkerReady)
+            .received_messages
+            .increment();
+
+        assert_eq!(ZeromqMessageKind::ALL.len(), stats.kinds().count());
+        assert_eq!(
+            1,
+            stats
+                .kind(ZeromqMessageKind::WorkerReady)
+                .received_messages
+                .get()
+        );
+        assert_eq!(2, stats.publisher_sends().len());
+    }
+}
@@ -0,0 +1,560 @@
+use crate::helpers::checksum;
+use crate::BUS_WAL_FSYNC_POLICY;
+use crate::BUS_WAL_SEGMENT_MAX_BYTES;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::iter::Iterator;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+const SEGMENT_FILE_EXTENSION: &str = "wal";
+const APPEND_RECORD_TYPE: u8 = 1;
+const DELIVERED_RECORD_TYPE: u8 = 2;
+
+/// Type, sequence and payload of a record read from segment file.
+type SegmentRecord = (u8, u64, Vec<u8>);
+
+//-----------------------------------------------------------------------------------------
+// WriteAheadLogFsyncPolicy
+//-----------------------------------------------------------------------------------------
+
+/// Defines how often written records are flushed from OS buffers to the disk. It is
+/// written as `always`, `never` or `every-records:<count>` in configuration.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum WriteAheadLogFsyncPolicy {
+    /// Every record is synchronized before write is reported as successful.
+    Always,
+    /// Records are synchronized once per the given count of writes.
+    EveryRecords(usize),
+    /// Synchronization is left to the OS, records survive crash of the process only.
+    Never,
+}
+
+impl FromStr for WriteAheadLogFsyncPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once(':') {
+            None if value == "always" => Ok(Self::Always),
+            None if value == "never" => Ok(Self::Never),
+            Some(("every-records", count)) => match count.parse::<usize>() {
+                Ok(count) if count > 0 => Ok(Self::EveryRecords(count)),
+                _ => Err(format!(
+                    "records count of fsync policy '{}' should be a positive number",
+                    value
+                )),
+            },
+            _ => Err(format!("unknown fsync policy '{}'", value)),
+        }
+    }
+}
+
+impl TryFrom<String> for WriteAheadLogFsyncPolicy {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<WriteAheadLogFsyncPolicy> for String {
+    fn from(policy: WriteAheadLogFsyncPolicy) -> Self {
+        policy.to_string()
+    }
+}
+
+impl fmt::Display for WriteAheadLogFsyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Always => f.write_str("always"),
+            Self::EveryRecords(count) => write!(f, "every-records:{}", count),
+            Self::Never => f.write_str("never"),
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// WriteAheadLogOptions
+//-----------------------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct WriteAheadLogOptions {
+    pub directory: PathBuf,
+    pub segment_max_bytes: u64,
+    pub fsync_policy: WriteAheadLogFsyncPolicy,
+}
+
+impl WriteAheadLogOptions {
+    pub fn new<P: AsRef<Path>>(directory: P) -> Self {
+        Self {
+            directory: directory.as_ref().to_path_buf(),
+            segment_max_bytes: BUS_WAL_SEGMENT_MAX_BYTES,
+            fsync_policy: BUS_WAL_FSYNC_POLICY,
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// WriteAheadLogRecord
+//-----------------------------------------------------------------------------------------
+
+/// Message which was appended to the log and has not been marked as delivered.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct WriteAheadLogRecord {
+    pub sequence: u64,
+    pub message_bytes: Vec<u8>,
+}
+
+//-----------------------------------------------------------------------------------------
+// WriteAheadLogReplica
+//-----------------------------------------------------------------------------------------
+
+/// Receives changes of the log while it is locked by the writer, so replica sees them in
+/// the same order as they were written even when they come from different threads.
+pub trait WriteAheadLogReplica: fmt::Debug + Send {
+    fn appended(&mut self, sequence: u64, message_bytes: &[u8]);
+
+    fn delivered(&mut self, sequence: u64);
+}
+
+//-----------------------------------------------------------------------------------------
+// WriteAheadLog
+//-----------------------------------------------------------------------------------------
+
+#[derive(Debug)]
+struct SegmentData {
+    path: PathBuf,
+    first_sequence: u64,
+    undelivered_sequences: HashSet<u64>,
+}
+
+/// Append-only log of accepted messages, which is split into segment files. Segment is
+/// removed as soon as every message appended into it was delivered and all older segments
+/// were removed, so markers of delivery are never lost while their messages are on disk.
+#[derive(Debug)]
+pub struct WriteAheadLog {
+    options: WriteAheadLogOptions,
+    segments: VecDeque<SegmentData>,
+    writer: BufWriter<File>,
+    written_bytes_inside_active_segment: u64,
+    writes_since_last_sync: usize,
+    next_sequence: u64,
+    replica: Option<Box<dyn WriteAheadLogReplica>>,
+}
+
+impl WriteAheadLog {
+    /// Opens log inside the directory and returns messages which were not delivered by
+    /// previous run, in order of their appending. Records after the first damaged one in
+    /// a segment are ignored, because they could be partially written during crash.
+    pub fn open(
+        options: WriteAheadLogOptions,
+    ) -> io::Result<(Self, Vec<WriteAheadLogRecord>)> {
+        fs::create_dir_all(&options.directory)?;
+
+        let mut segments_paths = fs::read_dir(&options.directory)?
+            .map(|result| result.map(|entry| entry.path()))
+            .collect::<Result<Vec<PathBuf>, io::Error>>()?
+            .into_iter()
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == SEGMENT_FILE_EXTENSION)
+            })
+            .collect::<Vec<PathBuf>>();
+        segments_paths.sort_unstable();
+
+        let mut appended_records: BTreeMap<u64, Vec<u8>> = BTreeMap::new();
+        let mut delivered_sequences: HashSet<u64> = HashSet::new();
+        let mut segments: VecDeque<SegmentData> = VecDeque::new();
+        let mut next_sequence = 0_u64;
+
+        for path in segments_paths {
+            let Some(first_sequence) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+            else {
+                continue;
+            };
+
+            let mut undelivered_sequences = HashSet::new();
+            for (record_type, sequence, message_bytes) in read_segment_records(&path)? {
+                next_sequence = next_sequence.max(sequence + 1);
+                match record_type {
+                    APPEND_RECORD_TYPE => {
+                        let _ = undelivered_sequences.insert(sequence);
+                        let _ = appended_records.insert(sequence, message_bytes);
+                    }
+                    _ => {
+                        let _ = delivered_sequences.insert(sequence);
+                    }
+                }
+            }
+
+            segments.push_back(SegmentData {
+                path,
+                first_sequence,
+                undelivered_sequences,
+            });
+        }
+
+        for segment in &mut segments {
+            segment
+                .undelivered_sequences
+                .retain(|sequence| !delivered_sequences.contains(sequence));
+        }
+
+        let undelivered_records = appended_records
+            .into_iter()
+            .filter(|(sequence, _)| !delivered_sequences.contains(sequence))
+            .map(|(sequence, message_bytes)| WriteAheadLogRecord {
+                sequence,
+                message_bytes,
+            })
+            .collect::<Vec<WriteAheadLogRecord>>();
+
+        // New segment is always started, so records are never appended after a damaged tail.
+        if let Some(last_segment) = segments.back() {
+            next_sequence = next_sequence.max(last_segment.first_sequence + 1);
+        }
+        let (path, writer) = create_segment(&options.directory, next_sequence)?;
+        segments.push_back(SegmentData {
+            path,
+            first_sequence: next_sequence,
+            undelivered_sequences: HashSet::new(),
+        });
+
+        let mut write_ahead_log = Self {
+            options,
+            segments,
+            writer,
+            written_bytes_inside_active_segment: 0,
+            writes_since_last_sync: 0,
+            next_sequence,
+            replica: None,
+        };
+        write_ahead_log.remove_delivered_segments()?;
+
+        Ok((write_ahead_log, undelivered_records))
+    }
+
+    /// Replica gets changes made after this call only, so it should be given undelivered
+    /// records returned by `open` separately.
+    pub fn set_replica(&mut self, replica: Box<dyn WriteAheadLogReplica>) {
+        self.replica = Some(replica);
+    }
+
+    #[must_use]
+    pub fn segments_count(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// Persists message and returns its sequence, which should be passed to
+    /// `mark_delivered` when message leaves the BUS.
+    pub fn append(&mut self, message_bytes: &[u8]) -> io::Result<u64> {
+        if self.written_bytes_inside_active_segment >= self.options.segment_max_bytes {
+            self.rotate()?;
+        }
+
+        let sequence = self.next_sequence;
+        self.write_record(APPEND_RECORD_TYPE, sequence, message_bytes)?;
+        self.next_sequence += 1;
+
+        let _ = self
+            .segments
+            .back_mut()
+            .expect("active segment always exists")
+            .undelivered_sequences
+            .insert(sequence);
+
+        if let Some(replica) = self.replica.as_mut() {
+            replica.appended(sequence, message_bytes);
+        }
+
+        Ok(sequence)
+    }
+
+    pub fn mark_delivered(&mut self, sequence: u64) -> io::Result<()> {
+        let is_known = self
+            .segments
+            .iter_mut()
+            .rev()
+            .find(|segment| segment.first_sequence <= sequence)
+            .is_some_and(|segment| segment.undelivered_sequences.remove(&sequence));
+
+        if !is_known {
+            return Ok(());
+        }
+
+        self.write_record(DELIVERED_RECORD_TYPE, sequence, &[])?;
+
+        if let Some(replica) = self.replica.as_mut() {
+            replica.delivered(sequence);
+        }
+
+        self.remove_delivered_segments()
+    }
+
+    /// Forces synchronization of written records regardless of fsync policy.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.writes_since_last_sync = 0;
+        Ok(())
+    }
+
+    fn write_record(
+        &mut self,
+        record_type: u8,
+        sequence: u64,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let record_bytes = encode_record(record_type, sequence, payload);
+        self.writer.write_all(record_bytes.as_slice())?;
+        self.writer.flush()?;
+        self.written_bytes_inside_active_segment += record_bytes.len() as u64;
+        self.writes_since_last_sync += 1;
+
+        match self.options.fsync_policy {
+            WriteAheadLogFsyncPolicy::Always => self.sync(),
+            WriteAheadLogFsyncPolicy::EveryRecords(records_count)
+                if self.writes_since_last_sync >= records_count =>
+            {
+                self.sync()
+            }
+            WriteAheadLogFsyncPolicy::EveryRecords(_) | WriteAheadLogFsyncPolicy::Never => {
+                Ok(())
+            }
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.sync()?;
+
+        let (path, writer) = create_segment(&self.options.directory, self.next_sequence)?;
+        self.writer = writer;
+        self.written_bytes_inside_active_segment = 0;
+        self.segments.push_back(SegmentData {
+            path,
+            first_sequence: self.next_sequence,
+            undelivered_sequences: HashSet::new(),
+        });
+
+        log::debug!("write-ahead log rotated to segment {}", self.next_sequence);
+
+        self.remove_delivered_segments()
+    }
+
+    fn remove_delivered_segments(&mut self) -> io::Result<()> {
+        while self.segments.len() > 1
+            && self
+                .segments
+                .front()
+                .is_some_and(|segment| segment.undelivered_sequences.is_empty())
+        {
+            let segment = self.segments.pop_front().expect("segment exists");
+            fs::remove_file(&segment.path)?;
+            log::debug!("write-ahead log segment {} removed", segment.first_sequence);
+        }
+
+        Ok(())
+    }
+}
+
+fn create_segment(
+    directory: &Path,
+    first_sequence: u64,
+) -> io::Result<(PathBuf, BufWriter<File>)> {
+    let path = directory.join(format!("{:020}.{}", first_sequence, SEGMENT_FILE_EXTENSION));
+    let file = OpenOptions::new().create(true).append(true).open(&path)?;
+    Ok((path, BufWriter::new(file)))
+}
+
+// Record layout: type (1 byte), sequence (8 bytes), payload length (4 bytes), payload and
+// checksum (4 bytes) of all previous fields.
+fn encode_record(record_type: u8, sequence: u64, payload: &[u8]) -> Vec<u8> {
+    let payload_length = u32::try_from(payload.len()).expect("record payload is too large");
+    let mut record_bytes = Vec::with_capacity(payload.len() + 17);
+    record_bytes.push(record_type);
+    record_bytes.extend_from_slice(&sequence.to_be_bytes());
+    record_bytes.extend_from_slice(&payload_length.to_be_bytes());
+    record_bytes.extend_from_slice(payload);
+    let checksum = checksum(record_bytes.as_slice());
+    record_bytes.extend_from_slice(&checksum.to_be_bytes());
+    record_bytes
+}
+
+fn read_segment_records(path: &Path) -> io::Result<Vec<SegmentRecord>> {
+    let mut segment_bytes = Vec::new();
+    let _ = File::open(path)?.read_to_end(&mut segment_bytes)?;
+
+    let mut records = Vec::new();
+    let mut offset = 0_usize;
+    while let Some((record, record_length)) = decode_record(&segment_bytes[offset..]) {
+        records.push(record);
+        offset += record_length;
+    }
+
+    if offset < segment_bytes.len() {
+        log::warn!(
+            "write-ahead log segment {} contains {} damaged bytes at its end",
+            path.display(),
+            segment_bytes.len() - offset
+        );
+    }
+
+    Ok(records)
+}
+
+fn decode_record(bytes: &[u8]) -> Option<(SegmentRecord, usize)> {
+    let header = bytes.get(..13)?;
+    let record_type = header[0];
+    let sequence = u64::from_be_bytes(<[u8; 8]>::try_from(&header[1..9]).ok()?);
+    let payload_length = usize::try_from(u32::from_be_bytes(
+        <[u8; 4]>::try_from(&header[9..13]).ok()?,
+    ))
+    .ok()?;
+    let record_length = 13 + payload_length + 4;
+    let record_bytes = bytes.get(..record_length)?;
+    let expected_checksum =
+        u32::from_be_bytes(<[u8; 4]>::try_from(&record_bytes[record_length - 4..]).ok()?);
+
+    if checksum(&record_bytes[..record_length - 4]) != expected_checksum
+        || !matches!(record_type, APPEND_RECORD_TYPE | DELIVERED_RECORD_TYPE)
+    {
+        return None;
+    }
+
+    Some((
+        (
+            record_type,
+            sequence,
+            record_bytes[13..record_length - 4].to_vec(),
+        ),
+        record_length,
+    ))
+}
+
+//-----------------------------------------------------------------------------------------
+// Tests
+//-----------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::wal::WriteAheadLog;
+    use crate::wal::WriteAheadLogFsyncPolicy;
+    use crate::wal::WriteAheadLogOptions;
+    use std::env;
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use uuid::Uuid;
+
+    fn temporary_directory() -> PathBuf {
+        env::temp_dir().join(format!("zeromq-bus-wal-{}", Uuid::new_v4()))
+    }
+
+    #[test]
+    fn replays_undelivered_records() {
+        let directory = temporary_directory();
+
+        {
+            let (mut write_ahead_log, records) =
+                WriteAheadLog::open(WriteAheadLogOptions::new(&directory)).unwrap();
+            assert!(records.is_empty());
+
+            let first_sequence = write_ahead_log.append(b"first").unwrap();
+            let _ = write_ahead_log.append(b"second").unwrap();
+            let _ = write_ahead_log.append(b"third").unwrap();
+            write_ahead_log.mark_delivered(first_sequence).unwrap();
+            // Log is dropped without any shutdown, as it happens when process is killed.
+        }
+
+        let (mut write_ahead_log, records) =
+            WriteAheadLog::open(WriteAheadLogOptions::new(&directory)).unwrap();
+        assert_eq!(
+            vec![b"second".to_vec(), b"third".to_vec()],
+            records
+                .iter()
+                .map(|record| record.message_bytes.clone())
+                .collect::<Vec<Vec<u8>>>()
+        );
+
+        for record in records {
+            write_ahead_log.mark_delivered(record.sequence).unwrap();
+        }
+        drop(write_ahead_log);
+
+        let (_, records) = WriteAheadLog::open(WriteAheadLogOptions::new(&directory)).unwrap();
+        assert!(records.is_empty());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn ignores_damaged_tail() {
+        let directory = temporary_directory();
+
+        {
+            let (mut write_ahead_log, _) =
+                WriteAheadLog::open(WriteAheadLogOptions::new(&directory)).unwrap();
+            let _ = write_ahead_log.append(b"complete").unwrap();
+        }
+
+        let segment_path = fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .max()
+            .unwrap();
+        OpenOptions::new()
+            .append(true)
+            .open(segment_path)
+            .unwrap()
+            .write_all(&[1, 0, 0, 0])
+            .unwrap();
+
+        let (_, records) = WriteAheadLog::open(WriteAheadLogOptions::new(&directory)).unwrap();
+        assert_eq!(1, records.len());
+        assert_eq!(b"complete".to_vec(), records[0].message_bytes);
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn rotates_and_removes_delivered_segments() {
+        let directory = temporary_directory();
+        let options = WriteAheadLogOptions {
+            segment_max_bytes: 1,
+            fsync_policy: WriteAheadLogFsyncPolicy::Always,
+            ..WriteAheadLogOptions::new(&directory)
+        };
+
+        let (mut write_ahead_log, _) = WriteAheadLog::open(options).unwrap();
+        let sequences = (0..3)
+            .map(|_| write_ahead_log.append(b"message").unwrap())
+            .collect::<Vec<u64>>();
+        assert_eq!(3, write_ahead_log.segments_count());
+
+        write_ahead_log.mark_delivered(sequences[1]).unwrap();
+        assert_eq!(3, write_ahead_log.segments_count());
+
+        write_ahead_log.mark_delivered(sequences[0]).unwrap();
+        assert_eq!(1, write_ahead_log.segments_count());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
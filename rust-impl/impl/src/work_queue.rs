@@ -0,0 +1,661 @@
+use crate::OverflowPolicy;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::fmt;
+use std::iter::Iterator;
+use std::str::FromStr;
+use uuid::Uuid;
+use zeromq_messages::kind::ZeromqMessageKind;
+
+pub type WorkerIdentity = Vec<u8>;
+
+//-----------------------------------------------------------------------------------------
+// WorkerSelectionStrategy
+//-----------------------------------------------------------------------------------------
+
+/// Strategy which is used to choose one of registered workers for a work-queue request.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WorkerSelectionStrategy {
+    /// Workers receive requests one after another.
+    RoundRobin,
+    /// Request goes to the worker with the smallest count of in-flight requests.
+    LeastLoaded,
+    /// Request goes to the next worker which still has unused credit, requests are held
+    /// inside the bus while every worker has exhausted its credit.
+    CreditBased,
+}
+
+impl FromStr for WorkerSelectionStrategy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "round-robin" => Ok(Self::RoundRobin),
+            "least-loaded" => Ok(Self::LeastLoaded),
+            "credit-based" => Ok(Self::CreditBased),
+            _ => Err(format!("unknown worker selection strategy '{}'", value)),
+        }
+    }
+}
+
+impl fmt::Display for WorkerSelectionStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::RoundRobin => "round-robin",
+            Self::LeastLoaded => "least-loaded",
+            Self::CreditBased => "credit-based",
+        })
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// WorkQueueDispatch
+//-----------------------------------------------------------------------------------------
+
+/// Request which should be sent to the chosen worker through the BUS router socket.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct WorkQueueDispatch {
+    pub worker_identity: WorkerIdentity,
+    pub kind: ZeromqMessageKind,
+    pub uuid: Uuid,
+    pub message_bytes: Vec<u8>,
+}
+
+//-----------------------------------------------------------------------------------------
+// WorkQueueDispatchOutcome
+//-----------------------------------------------------------------------------------------
+
+/// What has happened with the request which was given to the work queue.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum WorkQueueDispatchOutcome {
+    /// Request should be sent to the chosen worker.
+    Dispatched(WorkQueueDispatch),
+    /// No worker is able to accept request, it is held until one of them is.
+    Held,
+    /// Request is held instead of the oldest held one with the given uuid.
+    DroppedOldest(Uuid),
+    /// Held requests are at capacity, so request is dropped.
+    Refused,
+}
+
+//-----------------------------------------------------------------------------------------
+// WorkQueue
+//-----------------------------------------------------------------------------------------
+
+#[derive(Debug)]
+struct WorkerData {
+    identity: WorkerIdentity,
+    kinds: HashSet<ZeromqMessageKind>,
+    credit: usize,
+    /// Requests are kept in the order they were given until they are answered, so they
+    /// are given to another worker in the same order when this one fails.
+    in_flight_requests: VecDeque<WorkRequest>,
+    is_suspended: bool,
+}
+
+impl WorkerData {
+    fn can_accept(&self, kind: ZeromqMessageKind, strategy: WorkerSelectionStrategy) -> bool {
+        !self.is_suspended
+            && self.kinds.contains(&kind)
+            && (strategy != WorkerSelectionStrategy::CreditBased
+                || self.in_flight_requests.len() < self.credit)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct WorkRequest {
+    kind: ZeromqMessageKind,
+    uuid: Uuid,
+    message_bytes: Vec<u8>,
+}
+
+/// Competing-consumer delivery of designated request kinds: every request is given to
+/// exactly one registered worker instead of being published to all subscribers.
+#[derive(Debug)]
+pub struct WorkQueue {
+    strategy: WorkerSelectionStrategy,
+    kinds: HashSet<ZeromqMessageKind>,
+    workers: Vec<WorkerData>,
+    next_worker_index: usize,
+    pending_requests: VecDeque<WorkRequest>,
+    pending_requests_capacity: usize,
+    overflow_policy: OverflowPolicy,
+}
+
+impl WorkQueue {
+    /// Overflow policy applies to requests which are held while no worker is able to
+    /// accept them. Caller can't wait for workers, so blocking policy refuses requests.
+    #[must_use]
+    pub fn new(
+        strategy: WorkerSelectionStrategy,
+        kinds: &[ZeromqMessageKind],
+        pending_requests_capacity: usize,
+        overflow_policy: OverflowPolicy,
+    ) -> Self {
+        Self {
+            strategy,
+            kinds: kinds.iter().copied().collect(),
+            workers: Vec::new(),
+            next_worker_index: 0,
+            pending_requests: VecDeque::new(),
+            pending_requests_capacity,
+            overflow_policy,
+        }
+    }
+
+    #[must_use]
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        self.overflow_policy
+    }
+
+    #[must_use]
+    pub fn is_work_queue_kind(&self, kind: ZeromqMessageKind) -> bool {
+        self.kinds.contains(&kind)
+    }
+
+    #[must_use]
+    pub fn workers_count(&self) -> usize {
+        self.workers.len()
+    }
+
+    #[must_use]
+    pub fn pending_requests_count(&self) -> usize {
+        self.pending_requests.len()
+    }
+
+    /// Registers worker or replaces kinds and credit of already registered one. Returns
+    /// held requests that became dispatchable.
+    pub fn register_worker(
+        &mut self,
+        identity: WorkerIdentity,
+        kinds: &[ZeromqMessageKind],
+        credit: usize,
+    ) -> Vec<WorkQueueDispatch> {
+        let kinds = kinds
+            .iter()
+            .copied()
+            .filter(|kind| self.kinds.contains(kind))
+            .collect::<HashSet<ZeromqMessageKind>>();
+
+        match self
+            .workers
+            .iter_mut()
+            .find(|worker| worker.identity == identity)
+        {
+            Some(worker) => {
+                worker.kinds = kinds;
+                worker.credit = credit;
+                worker.is_suspended = false;
+            }
+            None => self.workers.push(WorkerData {
+                identity,
+                kinds,
+                credit,
+                in_flight_requests: VecDeque::new(),
+                is_suspended: false,
+            }),
+        }
+
+        self.dispatch_pending_requests()
+    }
+
+    /// Forgets the worker and gives its in-flight requests, which it will never answer, to
+    /// the other workers. Requests which none of them is able to accept are held ahead of
+    /// the others. Returns requests that became dispatchable.
+    pub fn unregister_worker(&mut self, identity: &[u8]) -> Vec<WorkQueueDispatch> {
+        let Some(index) = self
+            .workers
+            .iter()
+            .position(|worker| worker.identity == identity)
+        else {
+            return Vec::new();
+        };
+
+        let worker = self.workers.remove(index);
+        if self.next_worker_index > index {
+            self.next_worker_index -= 1;
+        }
+        self.hold_ahead(worker.in_flight_requests);
+
+        self.dispatch_pending_requests()
+    }
+
+    /// Stops giving requests to the worker which is considered dead, while keeping its
+    /// registration for the case it comes back. Its in-flight requests are given to the
+    /// other workers the same way as when it is unregistered. Returns requests that became
+    /// dispatchable.
+    pub fn suspend_worker(&mut self, identity: &[u8]) -> Vec<WorkQueueDispatch> {
+        let Some(worker) = self
+            .workers
+            .iter_mut()
+            .find(|worker| worker.identity == identity)
+        else {
+            return Vec::new();
+        };
+
+        worker.is_suspended = true;
+        let in_flight_requests = std::mem::take(&mut worker.in_flight_requests);
+        self.hold_ahead(in_flight_requests);
+
+        self.dispatch_pending_requests()
+    }
+
+    /// Takes back the request which could not be sent to the worker right now and holds
+    /// it ahead of the others, it is given to a worker once one of them completes a
+    /// request, registers or resumes.
+    pub fn hold_unsent(&mut self, identity: &[u8], uuid: Uuid) {
+        let request = self
+            .workers
+            .iter_mut()
+            .find(|worker| worker.identity == identity)
+            .and_then(|worker| take_request(&mut worker.in_flight_requests, uuid));
+
+        if let Some(request) = request {
+            self.pending_requests.push_front(request);
+        }
+    }
+
+    /// Gives requests to the suspended worker again. Returns held requests that became
+    /// dispatchable.
+    pub fn resume_worker(&mut self, identity: &[u8]) -> Vec<WorkQueueDispatch> {
+        let is_resumed = self
+            .workers
+            .iter_mut()
+            .find(|worker| worker.identity == identity && worker.is_suspended)
+            .map(|worker| worker.is_suspended = false)
+            .is_some();
+
+        if is_resumed {
+            self.dispatch_pending_requests()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Chooses worker for the request. When no worker is able to accept it, request is
+    /// held until one of workers registers or completes another request, unless held
+    /// requests are at capacity.
+    pub fn dispatch(
+        &mut self,
+        kind: ZeromqMessageKind,
+        uuid: Uuid,
+        message_bytes: Vec<u8>,
+    ) -> WorkQueueDispatchOutcome {
+        if let Some(index) = self.select_worker_index(kind) {
+            return WorkQueueDispatchOutcome::Dispatched(self.assign(
+                index,
+                kind,
+                uuid,
+                message_bytes,
+            ));
+        }
+
+        let pending_request = WorkRequest {
+            kind,
+            uuid,
+            message_bytes,
+        };
+        if self.pending_requests.len() < self.pending_requests_capacity {
+            self.pending_requests.push_back(pending_request);
+            return WorkQueueDispatchOutcome::Held;
+        }
+
+        match self.overflow_policy {
+            OverflowPolicy::DropOldest => match self.pending_requests.pop_front() {
+                Some(dropped_request) => {
+                    self.pending_requests.push_back(pending_request);
+                    WorkQueueDispatchOutcome::DroppedOldest(dropped_request.uuid)
+                }
+                None => WorkQueueDispatchOutcome::Refused,
+            },
+            OverflowPolicy::Block
+            | OverflowPolicy::DropNewest
+            | OverflowPolicy::RejectWithNack => WorkQueueDispatchOutcome::Refused,
+        }
+    }
+
+    /// Marks request as answered by the worker. Returns held requests that became
+    /// dispatchable.
+    pub fn complete(&mut self, identity: &[u8], uuid: Uuid) -> Vec<WorkQueueDispatch> {
+        let is_completed = self
+            .workers
+            .iter_mut()
+            .find(|worker| worker.identity == identity)
+            .is_some_and(|worker| {
+                take_request(&mut worker.in_flight_requests, uuid).is_some()
+            });
+
+        if is_completed {
+            self.dispatch_pending_requests()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Holds requests ahead of the others even above capacity, they were accepted before
+    /// them. The oldest request stays the first one.
+    fn hold_ahead(&mut self, requests: VecDeque<WorkRequest>) {
+        for request in requests.into_iter().rev() {
+            self.pending_requests.push_front(request);
+        }
+    }
+
+    fn dispatch_pending_requests(&mut self) -> Vec<WorkQueueDispatch> {
+        let mut dispatches = Vec::new();
+        let mut still_pending_requests = VecDeque::with_capacity(self.pending_requests.len());
+
+        while let Some(pending_request) = self.pending_requests.pop_front() {
+            match self.select_worker_index(pending_request.kind) {
+                Some(index) => dispatches.push(self.assign(
+                    index,
+                    pending_request.kind,
+                    pending_request.uuid,
+                    pending_request.message_bytes,
+                )),
+                None => still_pending_requests.push_back(pending_request),
+            }
+        }
+
+        self.pending_requests = still_pending_requests;
+        dispatches
+    }
+
+    fn assign(
+        &mut self,
+        index: usize,
+        kind: ZeromqMessageKind,
+        uuid: Uuid,
+        message_bytes: Vec<u8>,
+    ) -> WorkQueueDispatch {
+        let worker = &mut self.workers[index];
+        worker.in_flight_requests.push_back(WorkRequest {
+            kind,
+            uuid,
+            message_bytes: message_bytes.clone(),
+        });
+
+        WorkQueueDispatch {
+            worker_identity: worker.identity.clone(),
+            kind,
+            uuid,
+            message_bytes,
+        }
+    }
+
+    fn select_worker_index(&mut self, kind: ZeromqMessageKind) -> Option<usize> {
+        let strategy = self.strategy;
+
+        match strategy {
+            WorkerSelectionStrategy::RoundRobin | WorkerSelectionStrategy::CreditBased => {
+                let workers_count = self.workers.len();
+                let index = (0..workers_count)
+                    .map(|offset| (self.next_worker_index + offset) % workers_count)
+                    .find(|index| self.workers[*index].can_accept(kind, strategy))?;
+                self.next_worker_index = (index + 1) % workers_count;
+                Some(index)
+            }
+            WorkerSelectionStrategy::LeastLoaded => self
+                .workers
+                .iter()
+                .enumerate()
+                .filter(|(_, worker)| worker.can_accept(kind, strategy))
+                .min_by_key(|(_, worker)| worker.in_flight_requests.len())
+                .map(|(index, _)| index),
+        }
+    }
+}
+
+fn take_request(requests: &mut VecDeque<WorkRequest>, uuid: Uuid) -> Option<WorkRequest> {
+    let index = requests.iter().position(|request| request.uuid == uuid)?;
+    requests.remove(index)
+}
+
+//-----------------------------------------------------------------------------------------
+// Tests
+//-----------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::work_queue::WorkQueue;
+    use crate::work_queue::WorkQueueDispatchOutcome;
+    use crate::work_queue::WorkerIdentity;
+    use crate::work_queue::WorkerSelectionStrategy;
+    use crate::OverflowPolicy;
+    use uuid::Uuid;
+    use zeromq_messages::kind::ZeromqMessageKind;
+
+    const KIND: ZeromqMessageKind = ZeromqMessageKind::ValueMultiplicationRequest;
+
+    fn new_work_queue(strategy: WorkerSelectionStrategy) -> WorkQueue {
+        WorkQueue::new(strategy, &[KIND], 100, OverflowPolicy::RejectWithNack)
+    }
+
+    fn dispatch_to(work_queue: &mut WorkQueue, uuid: Uuid) -> Option<WorkerIdentity> {
+        match work_queue.dispatch(KIND, uuid, Vec::new()) {
+            WorkQueueDispatchOutcome::Dispatched(dispatch) => Some(dispatch.worker_identity),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn round_robin() {
+        let mut work_queue = new_work_queue(WorkerSelectionStrategy::RoundRobin);
+        assert!(work_queue
+            .register_worker(b"a".to_vec(), &[KIND], 0)
+            .is_empty());
+        assert!(work_queue
+            .register_worker(b"b".to_vec(), &[KIND], 0)
+            .is_empty());
+
+        let identities = (0..4)
+            .map(|_| dispatch_to(&mut work_queue, Uuid::new_v4()).unwrap())
+            .collect::<Vec<WorkerIdentity>>();
+        assert_eq!(
+            vec![b"a".to_vec(), b"b".to_vec(), b"a".to_vec(), b"b".to_vec()],
+            identities
+        );
+    }
+
+    #[test]
+    fn least_loaded() {
+        let mut work_queue = new_work_queue(WorkerSelectionStrategy::LeastLoaded);
+        let _ = work_queue.register_worker(b"a".to_vec(), &[KIND], 0);
+        let _ = work_queue.register_worker(b"b".to_vec(), &[KIND], 0);
+
+        let first_uuid = Uuid::new_v4();
+        assert_eq!(
+            Some(b"a".to_vec()),
+            dispatch_to(&mut work_queue, first_uuid)
+        );
+        assert_eq!(
+            Some(b"b".to_vec()),
+            dispatch_to(&mut work_queue, Uuid::new_v4())
+        );
+        assert_eq!(
+            Some(b"a".to_vec()),
+            dispatch_to(&mut work_queue, Uuid::new_v4())
+        );
+
+        assert_eq!(
+            Some(b"b".to_vec()),
+            dispatch_to(&mut work_queue, Uuid::new_v4())
+        );
+
+        let _ = work_queue.complete(b"a", first_uuid);
+        assert_eq!(
+            Some(b"a".to_vec()),
+            dispatch_to(&mut work_queue, Uuid::new_v4())
+        );
+    }
+
+    #[test]
+    fn credit_based_holds_requests() {
+        let mut work_queue = new_work_queue(WorkerSelectionStrategy::CreditBased);
+        let first_uuid = Uuid::new_v4();
+        assert_eq!(None, dispatch_to(&mut work_queue, first_uuid));
+
+        let dispatches = work_queue.register_worker(b"a".to_vec(), &[KIND], 1);
+        assert_eq!(1, dispatches.len());
+
+        assert_eq!(None, dispatch_to(&mut work_queue, Uuid::new_v4()));
+        assert_eq!(1, work_queue.pending_requests_count());
+
+        let dispatches = work_queue.complete(b"a", first_uuid);
+        assert_eq!(1, dispatches.len());
+        assert_eq!(0, work_queue.pending_requests_count());
+    }
+
+    #[test]
+    fn unregister_redispatches_in_flight_requests() {
+        let mut work_queue = new_work_queue(WorkerSelectionStrategy::RoundRobin);
+        let _ = work_queue.register_worker(b"a".to_vec(), &[KIND], 0);
+        let _ = work_queue.register_worker(b"b".to_vec(), &[KIND], 0);
+        let uuid = Uuid::new_v4();
+        assert_eq!(Some(b"a".to_vec()), dispatch_to(&mut work_queue, uuid));
+
+        let dispatches = work_queue.unregister_worker(b"a");
+        assert_eq!(
+            vec![(b"b".to_vec(), uuid)],
+            dispatches
+                .into_iter()
+                .map(|dispatch| (dispatch.worker_identity, dispatch.uuid))
+                .collect::<Vec<(WorkerIdentity, Uuid)>>()
+        );
+        assert_eq!(1, work_queue.workers_count());
+
+        // Requests which nobody else is able to accept are held.
+        assert!(work_queue.unregister_worker(b"b").is_empty());
+        assert_eq!(0, work_queue.workers_count());
+        assert_eq!(1, work_queue.pending_requests_count());
+        assert_eq!(None, dispatch_to(&mut work_queue, Uuid::new_v4()));
+    }
+
+    #[test]
+    fn redispatches_in_flight_requests_oldest_first() {
+        let mut work_queue = new_work_queue(WorkerSelectionStrategy::RoundRobin);
+        let _ = work_queue.register_worker(b"a".to_vec(), &[KIND], 0);
+        let uuids = (0..3)
+            .map(|_| {
+                let uuid = Uuid::new_v4();
+                assert_eq!(Some(b"a".to_vec()), dispatch_to(&mut work_queue, uuid));
+                uuid
+            })
+            .collect::<Vec<Uuid>>();
+
+        let _ = work_queue.register_worker(b"b".to_vec(), &[KIND], 0);
+        let dispatched_uuids = work_queue
+            .unregister_worker(b"a")
+            .into_iter()
+            .map(|dispatch| dispatch.uuid)
+            .collect::<Vec<Uuid>>();
+        assert_eq!(uuids, dispatched_uuids);
+    }
+
+    #[test]
+    fn unsent_request_is_held_until_worker_completes_another() {
+        let mut work_queue = new_work_queue(WorkerSelectionStrategy::RoundRobin);
+        let _ = work_queue.register_worker(b"a".to_vec(), &[KIND], 0);
+        let sent_uuid = Uuid::new_v4();
+        let unsent_uuid = Uuid::new_v4();
+        assert_eq!(Some(b"a".to_vec()), dispatch_to(&mut work_queue, sent_uuid));
+        assert_eq!(
+            Some(b"a".to_vec()),
+            dispatch_to(&mut work_queue, unsent_uuid)
+        );
+
+        work_queue.hold_unsent(b"a", unsent_uuid);
+        assert_eq!(1, work_queue.pending_requests_count());
+
+        let dispatches = work_queue.complete(b"a", sent_uuid);
+        assert_eq!(1, dispatches.len());
+        assert_eq!(unsent_uuid, dispatches[0].uuid);
+    }
+
+    #[test]
+    fn suspended_worker_gets_no_requests() {
+        let mut work_queue = new_work_queue(WorkerSelectionStrategy::RoundRobin);
+        let _ = work_queue.register_worker(b"a".to_vec(), &[KIND], 0);
+        let _ = work_queue.register_worker(b"b".to_vec(), &[KIND], 0);
+
+        let in_flight_uuid = Uuid::new_v4();
+        assert_eq!(
+            Some(b"a".to_vec()),
+            dispatch_to(&mut work_queue, in_flight_uuid)
+        );
+
+        // Request which suspended worker did not answer goes to the other one.
+        let dispatches = work_queue.suspend_worker(b"a");
+        assert_eq!(
+            vec![(b"b".to_vec(), in_flight_uuid)],
+            dispatches
+                .into_iter()
+                .map(|dispatch| (dispatch.worker_identity, dispatch.uuid))
+                .collect::<Vec<(WorkerIdentity, Uuid)>>()
+        );
+
+        assert_eq!(
+            Some(b"b".to_vec()),
+            dispatch_to(&mut work_queue, Uuid::new_v4())
+        );
+        assert_eq!(
+            Some(b"b".to_vec()),
+            dispatch_to(&mut work_queue, Uuid::new_v4())
+        );
+
+        assert!(work_queue.suspend_worker(b"b").is_empty());
+        assert_eq!(3, work_queue.pending_requests_count());
+        assert_eq!(None, dispatch_to(&mut work_queue, Uuid::new_v4()));
+
+        let dispatches = work_queue.resume_worker(b"a");
+        assert_eq!(4, dispatches.len());
+        assert_eq!(in_flight_uuid, dispatches[0].uuid);
+        assert_eq!(b"a".to_vec(), dispatches[0].worker_identity);
+    }
+
+    #[test]
+    fn held_requests_are_bounded() {
+        let uuids = (0..3).map(|_| Uuid::new_v4()).collect::<Vec<Uuid>>();
+
+        let mut work_queue = WorkQueue::new(
+            WorkerSelectionStrategy::RoundRobin,
+            &[KIND],
+            2,
+            OverflowPolicy::RejectWithNack,
+        );
+        for uuid in &uuids[..2] {
+            assert_eq!(
+                WorkQueueDispatchOutcome::Held,
+                work_queue.dispatch(KIND, *uuid, Vec::new())
+            );
+        }
+        assert_eq!(
+            WorkQueueDispatchOutcome::Refused,
+            work_queue.dispatch(KIND, uuids[2], Vec::new())
+        );
+        assert_eq!(2, work_queue.pending_requests_count());
+
+        let mut work_queue = WorkQueue::new(
+            WorkerSelectionStrategy::RoundRobin,
+            &[KIND],
+            2,
+            OverflowPolicy::DropOldest,
+        );
+        for uuid in &uuids[..2] {
+            let _ = work_queue.dispatch(KIND, *uuid, Vec::new());
+        }
+        assert_eq!(
+            WorkQueueDispatchOutcome::DroppedOldest(uuids[0]),
+            work_queue.dispatch(KIND, uuids[2], Vec::new())
+        );
+
+        let dispatched_uuids = work_queue
+            .register_worker(b"a".to_vec(), &[KIND], 0)
+            .into_iter()
+            .map(|dispatch| dispatch.uuid)
+            .collect::<Vec<Uuid>>();
+        assert_eq!(uuids[1..].to_vec(), dispatched_uuids);
+    }
+}
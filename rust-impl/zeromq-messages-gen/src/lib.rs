@@ -21,6 +21,7 @@
 #![allow(clippy::module_name_repetitions)]
 #![allow(clippy::missing_panics_doc)]
 #![allow(clippy::missing_errors_doc)]
+#![allow(clippy::uninlined_format_args)]
 
 use inflector::Inflector;
 use proc_macro::TokenStream;
@@ -88,6 +89,8 @@ pub fn generate_zeromq_messages_kinds_enum(_input: TokenStream) -> TokenStream {
     assert_eq!(kinds.len(), titles.len());
 
     let mut variants = quote! {};
+    let mut all_variants = quote! {};
+    let mut names_arms = quote! {};
     for current_index in 0..kinds.len() {
         let kind_literal = proc_macro2::Literal::u32_unsuffixed(kinds[current_index]);
 
@@ -97,14 +100,40 @@ pub fn generate_zeromq_messages_kinds_enum(_input: TokenStream) -> TokenStream {
             .expect("failed to parse title into field");
 
         variants.extend(quote! { #syn_title = #kind_literal, });
+        all_variants.extend(quote! { Self::#syn_title, });
+
+        let name_literal = proc_macro2::Literal::string(camel_case_title_string.as_str());
+        let title_literal = proc_macro2::Literal::string(titles[current_index].as_str());
+        names_arms.extend(quote! { #name_literal | #title_literal => Ok(Self::#syn_title), });
     }
 
     let output = quote! {
         #[repr(u32)]
-        #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, TryFromPrimitive)]
+        #[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, TryFromPrimitive)]
         pub enum ZeromqMessageKind {
             #variants
         }
+
+        impl ZeromqMessageKind {
+            /// All known kinds in the order of their schema files.
+            pub const ALL: &'static [Self] = &[#all_variants];
+        }
+
+        /// Parses kind from its name, from its schema file title or from its number.
+        impl std::str::FromStr for ZeromqMessageKind {
+            type Err = String;
+
+            fn from_str(value: &str) -> Result<Self, Self::Err> {
+                match value {
+                    #names_arms
+                    _ => value
+                        .parse::<u32>()
+                        .ok()
+                        .and_then(|kind| <Self as std::convert::TryFrom<u32>>::try_from(kind).ok())
+                        .ok_or_else(|| format!("unknown message kind '{}'", value)),
+                }
+            }
+        }
     };
 
     output.into()
@@ -147,6 +176,7 @@ pub fn generate_zeromq_messages_structs(_input: TokenStream) -> TokenStream {
     assert_eq!(file_name_strings.len(), paths_to_files.len());
 
     let mut output = quote! {};
+    let mut normalize_arms = quote! {};
     for current_index in 0..file_name_strings.len() {
         let struct_name_with_first_symbol_lower_case = file_name_strings[current_index]
             .split('.')
@@ -173,8 +203,26 @@ pub fn generate_zeromq_messages_structs(_input: TokenStream) -> TokenStream {
                 }
             }
         });
+
+        normalize_arms.extend(quote! {
+            ZeromqMessageKind::#struct_name_ident => serde_json::from_value::<#struct_name_ident>(payload)
+                .and_then(serde_json::to_value),
+        });
     }
 
+    output.extend(quote! {
+        /// Checks JSON payload against the schema of the given kind and returns it the way
+        /// generated struct serializes it.
+        pub fn normalize_message_payload(
+            kind: ZeromqMessageKind,
+            payload: serde_json::Value,
+        ) -> Result<serde_json::Value, serde_json::Error> {
+            match kind {
+                #normalize_arms
+            }
+        }
+    });
+
     output.into()
 }
 
@@ -1,4 +1,8 @@
+use crate::header::MessageHeader;
+use crate::header::MESSAGE_HEADER_MARKER;
+use crate::header::MESSAGE_HEADER_PREFIX_LENGTH;
 use crate::kind::ZeromqMessageKind;
+use crate::messages::normalize_message_payload;
 use crate::template::ZeromqMessageTrait;
 use bytes::Buf;
 use bytes::BufMut;
@@ -8,6 +12,9 @@ use std::convert::TryFrom;
 use std::string::ToString;
 use uuid::Uuid;
 
+/// Count of bytes occupied by message kind and uuid at the beginning of every message.
+pub const MESSAGE_KIND_AND_UUID_LENGTH: usize = 20;
+
 //-----------------------------------------------------------------------------------------
 // Errors
 //-----------------------------------------------------------------------------------------
@@ -19,6 +26,12 @@ pub enum MessageDecodeError {
 
     #[error("Failed to parse json into struct")]
     CantParseJson(#[source] serde_json::Error),
+
+    #[error("Message contains {0} bytes which is not enough for kind and uuid")]
+    NotEnoughBytes(usize),
+
+    #[error("Message contains {0} bytes which is not enough for its header")]
+    TruncatedHeader(usize),
 }
 
 impl Clone for MessageDecodeError {
@@ -30,6 +43,8 @@ impl Clone for MessageDecodeError {
             Self::CantParseJson(error) => {
                 Self::CantParseJson(serde_json::Error::custom(error.to_string()))
             }
+            Self::NotEnoughBytes(length) => Self::NotEnoughBytes(*length),
+            Self::TruncatedHeader(length) => Self::TruncatedHeader(*length),
         }
     }
 }
@@ -48,6 +63,14 @@ impl PartialEq for MessageDecodeError {
                 }
                 _ => false,
             },
+            Self::NotEnoughBytes(length) => match other {
+                Self::NotEnoughBytes(other_length) => length == other_length,
+                _ => false,
+            },
+            Self::TruncatedHeader(length) => match other {
+                Self::TruncatedHeader(other_length) => length == other_length,
+                _ => false,
+            },
         }
     }
 }
@@ -90,23 +113,89 @@ pub fn encode_message<'de, P: ZeromqMessageTrait<'de>>(
     uuid: Uuid,
     payload: P,
 ) -> Result<Vec<u8>, MessageEncodeError> {
+    encode_message_with_header(uuid, &MessageHeader::default(), payload)
+}
+
+/// Encodes message with header between uuid and payload, empty header is omitted.
+pub fn encode_message_with_header<'de, P: ZeromqMessageTrait<'de>>(
+    uuid: Uuid,
+    header: &MessageHeader,
+    payload: P,
+) -> Result<Vec<u8>, MessageEncodeError> {
+    let payload = serde_json::to_value(payload)
+        .map_err(MessageEncodeError::CantCreateJsonFromMessagePayload)?;
+
+    Ok(encode_message_parts(
+        <P as ZeromqMessageTrait<'de>>::kind(),
+        uuid,
+        header,
+        &payload,
+    ))
+}
+
+/// Encodes message of the given kind from JSON payload, which is checked against the kind
+/// schema first. Used by tools which do not know message types at compile time.
+pub fn encode_message_json(
+    kind: ZeromqMessageKind,
+    uuid: Uuid,
+    header: &MessageHeader,
+    payload: serde_json::Value,
+) -> Result<Vec<u8>, MessageEncodeError> {
+    let payload = normalize_message_payload(kind, payload)
+        .map_err(MessageEncodeError::CantCreateJsonFromMessagePayload)?;
+
+    Ok(encode_message_parts(kind, uuid, header, &payload))
+}
+
+fn encode_message_parts(
+    kind: ZeromqMessageKind,
+    uuid: Uuid,
+    header: &MessageHeader,
+    payload: &serde_json::Value,
+) -> Vec<u8> {
     let mut output_message_bytes: Vec<u8> = Vec::default();
 
-    output_message_bytes.put_u32(<P as ZeromqMessageTrait<'de>>::kind() as u32);
+    output_message_bytes.put_u32(kind as u32);
 
     output_message_bytes.put_u128(uuid.as_u128());
 
-    let payload_string = serde_json::to_value(payload)
-        .map_err(MessageEncodeError::CantCreateJsonFromMessagePayload)?
-        .to_string();
+    put_message_header(&mut output_message_bytes, header);
+
+    let payload_string = payload.to_string();
 
     for byte in payload_string.as_bytes() {
         output_message_bytes.put_u8(*byte);
     }
 
+    output_message_bytes
+}
+
+/// Copies already encoded message with its header replaced, payload is kept as is.
+pub fn replace_message_header(
+    message_bytes: &[u8],
+    header: &MessageHeader,
+) -> Result<Vec<u8>, MessageDecodeError> {
+    let (_, payload_bytes) = split_message_header(message_bytes)?;
+
+    let mut output_message_bytes = message_bytes[..MESSAGE_KIND_AND_UUID_LENGTH].to_vec();
+    put_message_header(&mut output_message_bytes, header);
+    output_message_bytes.extend_from_slice(payload_bytes);
+
     Ok(output_message_bytes)
 }
 
+fn put_message_header(output_message_bytes: &mut Vec<u8>, header: &MessageHeader) {
+    if !header.is_empty() {
+        let header_bytes =
+            serde_json::to_vec(header).expect("message header is always serializable");
+        output_message_bytes.put_u8(MESSAGE_HEADER_MARKER);
+        output_message_bytes.put_u32(
+            u32::try_from(header_bytes.len()).expect("message header does not fit in u32"),
+        );
+        output_message_bytes.extend_from_slice(header_bytes.as_slice());
+    }
+}
+
 //-----------------------------------------------------------------------------------------
 // Decode
 //-----------------------------------------------------------------------------------------
@@ -135,6 +224,67 @@ pub fn decode_message_uuid(message_bytes_without_kind: Vec<u8>) -> (Uuid, Vec<u8
     )
 }
 
+/// Reads kind and uuid without copying the message, so it can be used for routing
+/// decisions by the BUS. Unlike other decode functions it does not panic on short input.
+pub fn decode_message_kind_and_uuid(
+    message_bytes: &[u8],
+) -> Result<(ZeromqMessageKind, Uuid), MessageDecodeError> {
+    if message_bytes.len() < MESSAGE_KIND_AND_UUID_LENGTH {
+        return Err(MessageDecodeError::NotEnoughBytes(message_bytes.len()));
+    }
+
+    let mut message_bytes_slice = message_bytes;
+    let kind = ZeromqMessageKind::try_from(message_bytes_slice.get_u32())
+        .map_err(MessageDecodeError::UnexpectedZeromqMessageKind)?;
+
+    Ok((kind, Uuid::from_u128(message_bytes_slice.get_u128())))
+}
+
+/// Splits bytes which follow kind and uuid into header and payload, header bytes are empty
+/// when message has no header.
+pub fn split_message_header(
+    message_bytes: &[u8],
+) -> Result<(&[u8], &[u8]), MessageDecodeError> {
+    if message_bytes.len() < MESSAGE_KIND_AND_UUID_LENGTH {
+        return Err(MessageDecodeError::NotEnoughBytes(message_bytes.len()));
+    }
+
+    let message_bytes_without_kind_and_uuid = &message_bytes[MESSAGE_KIND_AND_UUID_LENGTH..];
+    if message_bytes_without_kind_and_uuid.first() != Some(&MESSAGE_HEADER_MARKER) {
+        return Ok((&[], message_bytes_without_kind_and_uuid));
+    }
+
+    let mut header_length_bytes = message_bytes_without_kind_and_uuid
+        .get(1..MESSAGE_HEADER_PREFIX_LENGTH)
+        .ok_or(MessageDecodeError::TruncatedHeader(message_bytes.len()))?;
+    let header_end = usize::try_from(header_length_bytes.get_u32())
+        .ok()
+        .and_then(|header_length| header_length.checked_add(MESSAGE_HEADER_PREFIX_LENGTH))
+        .filter(|header_end| *header_end <= message_bytes_without_kind_and_uuid.len())
+        .ok_or(MessageDecodeError::TruncatedHeader(message_bytes.len()))?;
+
+    Ok((
+        &message_bytes_without_kind_and_uuid[MESSAGE_HEADER_PREFIX_LENGTH..header_end],
+        &message_bytes_without_kind_and_uuid[header_end..],
+    ))
+}
+
+/// Reads header of the whole message, default header is returned when it is absent.
+pub fn decode_message_header(
+    message_bytes: &[u8],
+) -> Result<MessageHeader, MessageDecodeError> {
+    let (header_bytes, _) = split_message_header(message_bytes)?;
+    if header_bytes.is_empty() {
+        return Ok(MessageHeader::default());
+    }
+    serde_json::from_slice(header_bytes).map_err(MessageDecodeError::CantParseJson)
+}
+
+/// Payload bytes of the whole message, header is skipped without decoding.
+pub fn message_payload_bytes(message_bytes: &[u8]) -> Result<&[u8], MessageDecodeError> {
+    split_message_header(message_bytes).map(|(_, payload_bytes)| payload_bytes)
+}
+
 pub fn decode_message_payload<'de, T: ZeromqMessageTrait<'de>>(
     message_bytes_without_kind_and_uuid: &'de [u8],
 ) -> Result<T, MessageDecodeError> {
@@ -148,12 +298,20 @@ pub fn decode_message_payload<'de, T: ZeromqMessageTrait<'de>>(
 
 #[cfg(test)]
 mod tests {
+    use crate::codec::decode_message_header;
     use crate::codec::decode_message_kind;
+    use crate::codec::decode_message_kind_and_uuid;
     use crate::codec::decode_message_payload;
     use crate::codec::decode_message_uuid;
     use crate::codec::encode_message;
+    use crate::codec::encode_message_json;
+    use crate::codec::encode_message_with_header;
+    use crate::codec::message_payload_bytes;
+    use crate::codec::replace_message_header;
     use crate::codec::MessageDecodeError;
     use crate::codec::MessageEncodeError;
+    use crate::header::MessageHeader;
+    use crate::header::MessagePriority;
     use crate::kind::ZeromqMessageKind;
     use crate::messages::ValueMultiplicationRequest;
     use crate::template::ZeromqMessageTrait;
@@ -204,6 +362,140 @@ mod tests {
         assert_eq!(payload, decoded_payload);
     }
 
+    #[test]
+    fn kind_and_uuid() {
+        let uuid = Uuid::new_v4();
+        let message_bytes = encode_message(
+            uuid,
+            ValueMultiplicationRequest {
+                value: 1,
+                multiplier: 2,
+            },
+        )
+        .expect("failed to encode message");
+
+        assert_eq!(
+            Ok((ZeromqMessageKind::ValueMultiplicationRequest, uuid)),
+            decode_message_kind_and_uuid(message_bytes.as_slice())
+        );
+        assert_eq!(
+            Err(MessageDecodeError::NotEnoughBytes(4)),
+            decode_message_kind_and_uuid(&message_bytes[..4])
+        );
+    }
+
+    #[test]
+    fn header() {
+        let uuid = Uuid::new_v4();
+        let payload = ValueMultiplicationRequest {
+            value: 3,
+            multiplier: 4,
+        };
+        let header = MessageHeader {
+            timestamp: Some(1_000),
+            deadline: Some(2_000),
+            deliver_at: Some(1_500),
+            priority: Some(MessagePriority::High),
+            hops: Vec::new(),
+        };
+
+        let message_bytes = encode_message_with_header(uuid, &header, payload.clone())
+            .expect("failed to encode message");
+        assert_eq!(
+            Ok((ZeromqMessageKind::ValueMultiplicationRequest, uuid)),
+            decode_message_kind_and_uuid(message_bytes.as_slice())
+        );
+        assert_eq!(
+            Ok(header.clone()),
+            decode_message_header(message_bytes.as_slice())
+        );
+        assert_eq!(
+            payload,
+            decode_message_payload(message_payload_bytes(message_bytes.as_slice()).unwrap())
+                .unwrap()
+        );
+        assert_eq!(
+            Err(MessageDecodeError::TruncatedHeader(30)),
+            message_payload_bytes(&message_bytes[..30])
+        );
+
+        // Replaced header keeps kind, uuid and payload of the message.
+        let forwarded_header = MessageHeader {
+            hops: vec!["bus-a".to_string()],
+            ..header
+        };
+        let forwarded_message_bytes =
+            replace_message_header(message_bytes.as_slice(), &forwarded_header).unwrap();
+        assert_eq!(
+            Ok(forwarded_header),
+            decode_message_header(forwarded_message_bytes.as_slice())
+        );
+        assert_eq!(
+            message_payload_bytes(message_bytes.as_slice()),
+            message_payload_bytes(forwarded_message_bytes.as_slice())
+        );
+
+        // Empty header is not written, so message is the same as without header.
+        let message_bytes =
+            encode_message_with_header(uuid, &MessageHeader::default(), payload.clone())
+                .expect("failed to encode message");
+        assert_eq!(
+            encode_message(uuid, payload.clone()).unwrap(),
+            message_bytes
+        );
+        assert_eq!(
+            message_bytes,
+            replace_message_header(
+                forwarded_message_bytes.as_slice(),
+                &MessageHeader::default()
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            Ok(MessageHeader::default()),
+            decode_message_header(message_bytes.as_slice())
+        );
+    }
+
+    #[test]
+    fn json_payload() {
+        let uuid = Uuid::new_v4();
+        let kind = "value-multiplication-request"
+            .parse::<ZeromqMessageKind>()
+            .unwrap();
+        assert_eq!(ZeromqMessageKind::ValueMultiplicationRequest, kind);
+        assert_eq!(Ok(kind), "ValueMultiplicationRequest".parse());
+        assert_eq!(Ok(kind), "1".parse());
+        assert!("ValueDivisionRequest".parse::<ZeromqMessageKind>().is_err());
+
+        let message_bytes = encode_message_json(
+            kind,
+            uuid,
+            &MessageHeader::default(),
+            serde_json::json!({ "multiplier": 2, "value": 1 }),
+        )
+        .unwrap();
+        assert_eq!(
+            encode_message(
+                uuid,
+                ValueMultiplicationRequest {
+                    value: 1,
+                    multiplier: 2,
+                },
+            )
+            .unwrap(),
+            message_bytes
+        );
+
+        assert!(encode_message_json(
+            kind,
+            uuid,
+            &MessageHeader::default(),
+            serde_json::json!({ "value": 1 }),
+        )
+        .is_err());
+    }
+
     #[test]
     fn encode_error_eq() {
         assert_eq!(
@@ -0,0 +1,152 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// Byte which follows message uuid when message carries header. Payload is JSON which
+/// never starts with this byte, so messages without header stay unchanged.
+pub const MESSAGE_HEADER_MARKER: u8 = 0;
+
+/// Count of bytes occupied by header marker and header length before header itself.
+pub const MESSAGE_HEADER_PREFIX_LENGTH: usize = 5;
+
+//-----------------------------------------------------------------------------------------
+// MessageHeader
+//-----------------------------------------------------------------------------------------
+
+/// Optional metadata of the message. Every field is optional and unknown fields are
+/// ignored, so producers and consumers of different versions understand each other.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MessageHeader {
+    /// Milliseconds since unix epoch when the message was created by its producer.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    /// Milliseconds since unix epoch after which nobody waits for the message, so BUS
+    /// discards it instead of delivering.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deadline: Option<u64>,
+    /// Milliseconds since unix epoch before which BUS holds the message instead of
+    /// delivering.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deliver_at: Option<u64>,
+    /// Overrides priority which BUS gives to the message by its kind.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<MessagePriority>,
+    /// Instance ids of federated BUSes which have forwarded the message, in order of
+    /// forwarding, so the message never comes back to the BUS it has passed.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub hops: Vec<String>,
+}
+
+impl MessageHeader {
+    /// Header with timestamp of the current moment.
+    #[must_use]
+    pub fn now() -> Self {
+        Self {
+            timestamp: Some(milliseconds_since_unix_epoch()),
+            deadline: None,
+            deliver_at: None,
+            priority: None,
+            hops: Vec::new(),
+        }
+    }
+
+    /// Header with timestamp of the current moment and deadline which is the given time
+    /// to live later.
+    #[must_use]
+    pub fn with_ttl(ttl: Duration) -> Self {
+        let timestamp = milliseconds_since_unix_epoch();
+        Self {
+            timestamp: Some(timestamp),
+            deadline: Some(timestamp.saturating_add(milliseconds(ttl))),
+            deliver_at: None,
+            priority: None,
+            hops: Vec::new(),
+        }
+    }
+
+    /// Header with timestamp of the current moment which makes BUS deliver the message
+    /// after the given delay.
+    #[must_use]
+    pub fn with_delay(delay: Duration) -> Self {
+        let timestamp = milliseconds_since_unix_epoch();
+        Self {
+            timestamp: Some(timestamp),
+            deadline: None,
+            deliver_at: Some(timestamp.saturating_add(milliseconds(delay))),
+            priority: None,
+            hops: Vec::new(),
+        }
+    }
+
+    /// Messages without deadline never expire.
+    #[must_use]
+    pub fn is_expired_at(&self, milliseconds_since_unix_epoch: u64) -> bool {
+        self.deadline
+            .is_some_and(|deadline| deadline <= milliseconds_since_unix_epoch)
+    }
+
+    /// Empty header is not written into the message at all.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+//-----------------------------------------------------------------------------------------
+// MessagePriority
+//-----------------------------------------------------------------------------------------
+
+/// Importance of the message for BUS scheduling, more important messages overtake the
+/// queued less important ones. Variants go in order of importance.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MessagePriority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+impl MessagePriority {
+    pub const ALL: [Self; 3] = [Self::High, Self::Normal, Self::Low];
+}
+
+impl FromStr for MessagePriority {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "high" => Ok(Self::High),
+            "normal" => Ok(Self::Normal),
+            "low" => Ok(Self::Low),
+            _ => Err(format!("unknown message priority '{}'", value)),
+        }
+    }
+}
+
+impl fmt::Display for MessagePriority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::High => "high",
+            Self::Normal => "normal",
+            Self::Low => "low",
+        })
+    }
+}
+
+#[must_use]
+pub fn milliseconds_since_unix_epoch() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, milliseconds)
+}
+
+fn milliseconds(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
@@ -21,8 +21,10 @@
 #![allow(clippy::module_name_repetitions)]
 #![allow(clippy::missing_panics_doc)]
 #![allow(clippy::missing_errors_doc)]
+#![allow(clippy::uninlined_format_args)]
 
 pub mod codec;
+pub mod header;
 pub mod kind;
 pub mod messages;
 pub mod template;